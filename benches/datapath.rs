@@ -0,0 +1,101 @@
+//! Micro-benchmarks of the primitives every segment goes through, as a baseline for work on
+//! the datapath. Run with `cargo bench --features benchmarks`.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+use trust::bench;
+
+const MTU: usize = 1500;
+
+fn local() -> SocketAddrV4 {
+   SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 1), 9000)
+}
+
+fn remote() -> SocketAddrV4 {
+   SocketAddrV4::new(Ipv4Addr::new(192, 168, 0, 2), 51000)
+}
+
+/// An IPv4 packet with a 20-byte TCP header, a zeroed checksum and `payload_len` bytes of
+/// data.
+fn packet(payload_len: usize) -> Vec<u8> {
+   let total = 40 + payload_len;
+   let mut p = vec![0u8; total];
+   p[0] = 0x45;
+   p[2..4].copy_from_slice(&(total as u16).to_be_bytes());
+   p[8] = 64;
+   p[9] = 6;
+   p[12..16].copy_from_slice(&local().ip().octets());
+   p[16..20].copy_from_slice(&remote().ip().octets());
+   p[32] = 5 << 4;
+   for (i, b) in p[40..].iter_mut().enumerate() {
+      *b = i as u8;
+   }
+   p
+}
+
+fn checksum(c: &mut Criterion) {
+   let mut group = c.benchmark_group("checksum");
+   for &len in &[0usize, 536, 1460] {
+      let p = packet(len);
+      group.throughput(Throughput::Bytes(p.len() as u64 - 20));
+      group.bench_with_input(BenchmarkId::new("device", len), &p, |b, p| {
+         b.iter(|| bench::checksum(black_box(p)))
+      });
+      let tcp = etherparse::TcpHeader::new(local().port(), remote().port(), 1, 0xffff);
+      let ip = etherparse::Ipv4Header::new(
+         tcp.header_len() + len as u16,
+         64,
+         etherparse::IpTrafficClass::Tcp,
+         local().ip().octets(),
+         remote().ip().octets(),
+      );
+      group.bench_with_input(BenchmarkId::new("headers", len), &p[40..], |b, payload| {
+         b.iter(|| bench::header_checksum(&ip, &tcp, black_box(payload)))
+      });
+   }
+   group.finish();
+}
+
+fn headers(c: &mut Criterion) {
+   let mut buf = Vec::with_capacity(MTU);
+   let mut seq = 0u32;
+   c.bench_function("write_headers", |b| {
+      b.iter(|| {
+         seq = seq.wrapping_add(1460);
+         bench::write_headers(&mut buf, local(), remote(), black_box(seq), 1460);
+      })
+   });
+}
+
+fn sequence_space(c: &mut Criterion) {
+   // in window, across the wrap, and outside
+   let cases = [(1000u32, 5000u32, 70000u32), (u32::MAX - 100, 50, 2000), (1000, 500, 70000)];
+   c.bench_function("is_between_wrapped", |b| {
+      b.iter(|| {
+         for &(start, x, end) in &cases {
+            black_box(bench::is_between_wrapped(black_box(start), black_box(x), black_box(end)));
+         }
+      })
+   });
+}
+
+fn segmentation(c: &mut Criterion) {
+   let mut group = c.benchmark_group("segmentation");
+   for &len in &[16 * 1024usize, 64 * 1024, 256 * 1024] {
+      let data = vec![0x5au8; len];
+      group.throughput(Throughput::Bytes(len as u64));
+      group.bench_with_input(BenchmarkId::from_parameter(len), &data, |b, data| {
+         b.iter_batched(
+            || bench::Sender::new(local(), remote(), MTU),
+            |mut s| s.write(data),
+            BatchSize::SmallInput,
+         )
+      });
+   }
+   group.finish();
+}
+
+criterion_group!(benches, checksum, headers, sequence_space, segmentation);
+criterion_main!(benches);
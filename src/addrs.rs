@@ -0,0 +1,105 @@
+//! The IPv4 addresses configured on our device. Connections are opened from one of them
+//! unless the caller asks for an address the device doesn't have yet, such as a virtual IP
+//! that moves here on failover. Like the routes in `rpf`, they are read from the kernel on
+//! request, not per packet.
+
+use std::ffi::CStr;
+use std::io;
+use std::net::Ipv4Addr;
+
+#[derive(Debug, Clone, Default)]
+pub struct LocalAddrs {
+   /// the device's addresses, or `None` if they couldn't be read, in which case any
+   /// address may be bound
+   addrs: Option<Vec<Ipv4Addr>>,
+}
+
+impl LocalAddrs {
+   #[cfg(test)]
+   pub fn new(addrs: Vec<Ipv4Addr>) -> Self {
+      LocalAddrs { addrs: Some(addrs) }
+   }
+
+   /// The IPv4 addresses configured on `device`.
+   pub fn load(device: &str) -> io::Result<Self> {
+      let mut head: *mut libc::ifaddrs = std::ptr::null_mut();
+      if unsafe { libc::getifaddrs(&mut head) } < 0 {
+         return Err(io::Error::last_os_error());
+      }
+      let mut addrs = Vec::new();
+      let mut at = head;
+      while !at.is_null() {
+         // the list and everything it points to stay valid until freeifaddrs() below
+         let ifa = unsafe { &*at };
+         at = ifa.ifa_next;
+         if ifa.ifa_addr.is_null() || unsafe { (*ifa.ifa_addr).sa_family } as i32 != libc::AF_INET {
+            continue;
+         }
+         if unsafe { CStr::from_ptr(ifa.ifa_name) }.to_bytes() != device.as_bytes() {
+            continue;
+         }
+         let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+         addrs.push(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)));
+      }
+      unsafe { libc::freeifaddrs(head) };
+      Ok(LocalAddrs { addrs: Some(addrs) })
+   }
+
+   /// Whether `addr` is known to be configured on the device.
+   pub fn contains(&self, addr: Ipv4Addr) -> bool {
+      self.addrs.as_ref().map_or(false, |addrs| addrs.contains(&addr))
+   }
+
+   /// Check that a connection may be opened from `addr`: a unicast address that is either
+   /// configured on the device or, with `freebind`, one that isn't (yet).
+   pub fn check_bind(&self, addr: Ipv4Addr, freebind: bool) -> io::Result<()> {
+      if addr.is_unspecified() || addr.is_broadcast() || addr.is_multicast() || addr.is_loopback() {
+         return Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("{} is not a unicast address connections can be opened from", addr),
+         ));
+      }
+      if !freebind && self.addrs.is_some() && !self.contains(addr) {
+         return Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("{} is not configured on the interface; set freebind to use it anyway", addr),
+         ));
+      }
+      Ok(())
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn device() -> LocalAddrs {
+      LocalAddrs::new(vec![Ipv4Addr::new(10, 0, 0, 1)])
+   }
+
+   #[test]
+   fn configured_address_can_be_bound() {
+      assert!(device().check_bind(Ipv4Addr::new(10, 0, 0, 1), false).is_ok());
+   }
+
+   #[test]
+   fn foreign_address_needs_freebind() {
+      let vip = Ipv4Addr::new(10, 0, 0, 100);
+      let err = device().check_bind(vip, false).unwrap_err();
+      assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+      assert!(device().check_bind(vip, true).is_ok());
+   }
+
+   #[test]
+   fn non_unicast_address_is_never_bound() {
+      for addr in [Ipv4Addr::UNSPECIFIED, Ipv4Addr::BROADCAST, Ipv4Addr::new(224, 0, 0, 1), Ipv4Addr::LOCALHOST].iter() {
+         let err = device().check_bind(*addr, true).unwrap_err();
+         assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+      }
+   }
+
+   #[test]
+   fn unreadable_addresses_allow_any() {
+      assert!(LocalAddrs::default().check_bind(Ipv4Addr::new(192, 0, 2, 7), false).is_ok());
+   }
+}
@@ -0,0 +1,156 @@
+//! Control socket for operators. Commands are single lines of text and every reply ends
+//! with a line reading `ok` or `error: <reason>`.
+//!
+//! ```text
+//! list                               one line per connection
+//! stats                              drop and reset counters and totals
+//! kill <local> <remote>              reset a connection
+//! verbose <local> <remote> on|off    trace one connection to stderr
+//! capture start <path> [filter]      record segments to a pcap file
+//! capture stop
+//! tsg <pcap> <local> <remote> <out>  time-sequence graph of a captured connection
+//! compliance strict|permissive       how strictly segments are held to the RFCs
+//! audit                              segments strict mode dropped, and why
+//! resets                             connections reset on purpose, and why
+//! ```
+
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::SocketAddrV4;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+pub fn serve<P: AsRef<Path>>(mut iface: trust::Interface, path: P) -> io::Result<()> {
+   remove_stale(path.as_ref())?;
+   let listener = UnixListener::bind(&path)?;
+   for client in listener.incoming() {
+      if let Err(e) = client.and_then(|c| session(&mut iface, c)) {
+         eprintln!("control connection failed: {}", e);
+      }
+   }
+   Ok(())
+}
+
+/// A socket left behind by a previous run would make bind fail, so remove it, but only if
+/// it is a socket and nothing answers on it: not another instance's, and not some file that
+/// happens to sit at the path.
+fn remove_stale(path: &Path) -> io::Result<()> {
+   let meta = match std::fs::symlink_metadata(path) {
+      Ok(meta) => meta,
+      Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+      Err(e) => return Err(e),
+   };
+   if !meta.file_type().is_socket() {
+      return Err(io::Error::new(io::ErrorKind::AlreadyExists, "not a socket"));
+   }
+   match UnixStream::connect(path) {
+      Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, "another instance is listening")),
+      Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+      Err(e) => Err(e),
+   }
+}
+
+fn session(iface: &mut trust::Interface, client: UnixStream) -> io::Result<()> {
+   let mut out = client.try_clone()?;
+   for line in BufReader::new(client).lines() {
+      let line = line?;
+      let words: Vec<&str> = line.split_whitespace().collect();
+      if words.is_empty() {
+         continue;
+      }
+      match command(iface, &words, &mut out) {
+         Ok(()) => writeln!(out, "ok")?,
+         Err(e) => writeln!(out, "error: {}", e)?,
+      }
+   }
+   Ok(())
+}
+
+fn command(iface: &mut trust::Interface, words: &[&str], out: &mut UnixStream) -> io::Result<()> {
+   match words {
+      ["list"] => {
+         for c in iface.connections() {
+            writeln!(
+               out,
+               "{} {} {} delivered={} unacked={} unread={} srtt={:?} rto={:?} retransmits={} cwnd={} \
+                send_goodput={:.0} recv_goodput={:.0}",
+               c.local, c.remote, c.state, c.delivered, c.unacked, c.unread, c.srtt, c.rto, c.retransmits, c.cwnd,
+               c.send_goodput, c.recv_goodput
+            )?;
+         }
+         Ok(())
+      }
+      ["stats"] => {
+         let d = iface.drop_counters();
+         writeln!(out, "connections {}", iface.connections().len())?;
+         writeln!(out, "drop.truncated_ip {}", d.truncated_ip)?;
+         writeln!(out, "drop.not_ipv4 {}", d.not_ipv4)?;
+         writeln!(out, "drop.bad_ip_header_len {}", d.bad_ip_header_len)?;
+         writeln!(out, "drop.bad_ip_total_len {}", d.bad_ip_total_len)?;
+         writeln!(out, "drop.fragments {}", d.fragments)?;
+         writeln!(out, "drop.not_tcp {}", d.not_tcp)?;
+         writeln!(out, "drop.truncated_tcp {}", d.truncated_tcp)?;
+         writeln!(out, "drop.bad_data_offset {}", d.bad_data_offset)?;
+         writeln!(out, "drop.bad_options {}", d.bad_options)?;
+         writeln!(out, "drop.self_connect {}", d.self_connect)?;
+         writeln!(out, "drop.own_source {}", d.own_source)?;
+         writeln!(out, "drop.martian_source {}", d.martian_source)?;
+         writeln!(out, "drop.unroutable_source {}", d.unroutable_source)?;
+         writeln!(out, "drop.unparsable {}", d.unparsable)?;
+         writeln!(out, "drop.ingress_queue_full {}", d.ingress_queue_full)?;
+         for (why, n) in iface.reset_counts() {
+            writeln!(out, "reset.{} {}", why.name(), n)?;
+         }
+         Ok(())
+      }
+      ["kill", local, remote] => iface.kill(addr(local)?, addr(remote)?),
+      ["verbose", local, remote, on] => {
+         let on = match *on {
+            "on" => true,
+            "off" => false,
+            _ => return Err(invalid("expected `on` or `off`")),
+         };
+         iface.set_verbose(addr(local)?, addr(remote)?, on)
+      }
+      ["capture", "start", path] => iface.start_capture(path),
+      ["capture", "start", path, filter @ ..] => iface.start_capture_filtered(path, &filter.join(" ")),
+      ["capture", "stop"] => iface.stop_capture(),
+      ["compliance", mode] => {
+         let mode = match *mode {
+            "strict" => trust::Compliance::Strict,
+            "permissive" => trust::Compliance::Permissive,
+            _ => return Err(invalid("expected `strict` or `permissive`")),
+         };
+         iface.set_compliance(mode);
+         Ok(())
+      }
+      ["audit"] => {
+         for e in iface.audit_log() {
+            let at = e.at.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+            writeln!(out, "{} {} {} {}", at.as_micros(), e.src, e.dst, e.rule.describe())?;
+         }
+         Ok(())
+      }
+      ["resets"] => {
+         for e in iface.reset_log() {
+            let at = e.at.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+            writeln!(out, "{} {} {} {}", at.as_micros(), e.local, e.remote, e.reason.name())?;
+         }
+         Ok(())
+      }
+      ["tsg", pcap, local, remote, path] => {
+         trust::time_sequence_graph(Path::new(pcap), addr(local)?, addr(remote)?, Path::new(path))
+      }
+      _ => Err(invalid("unknown command")),
+   }
+}
+
+fn addr(s: &str) -> io::Result<SocketAddrV4> {
+   s.parse().map_err(|_| invalid("expected an address like 10.0.0.1:9000"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+   io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
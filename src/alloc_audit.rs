@@ -0,0 +1,36 @@
+//! Counting allocator behind the `alloc-audit` feature, used to check that processing segments
+//! for established connections never touches the heap.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+thread_local! {
+   static ALLOCATIONS: Cell<u64> = Cell::new(0);
+}
+
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+   unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+      // try_with: the thread-local may already be gone while a thread shuts down
+      let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+      System.alloc(layout)
+   }
+
+   unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+      System.dealloc(ptr, layout)
+   }
+
+   unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+      let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+      System.realloc(ptr, layout, new_size)
+   }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Number of allocations made by the current thread so far.
+pub fn allocations() -> u64 {
+   ALLOCATIONS.with(|a| a.get())
+}
@@ -0,0 +1,100 @@
+//! Entry points into the datapath for the benchmarks in benches/, which can't reach its
+//! internals otherwise. Only built with the `benchmarks` feature; nothing here is meant
+//! for applications.
+
+use std::net::SocketAddrV4;
+
+use crate::cc;
+use crate::egress::Egress;
+use crate::options::{self, OptionWriter, SackBlocks, TcpOption};
+use crate::tcp::{self, Connection, ListenOptions, RepairState};
+
+/// The TCP checksum of an IPv4 packet with a 20-byte header and a zeroed checksum field,
+/// as the device computes it for every segment cut from a GSO super-packet.
+pub fn checksum(packet: &[u8]) -> u16 {
+   crate::device::tcp_checksum(packet, 20)
+}
+
+/// The checksum of a segment as every send computes it, before the headers are written.
+pub fn header_checksum(ip: &etherparse::Ipv4Header, tcp: &etherparse::TcpHeader, payload: &[u8]) -> u16 {
+   tcp.calc_checksum_ipv4(ip, payload).expect("payload fits a segment")
+}
+
+/// Whether `start < x < end`, modulo 2^32.
+pub fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
+   tcp::is_between_wrapped(start, x, end)
+}
+
+/// Lay out the headers of a data segment carrying `payload_len` bytes the way every send
+/// does: timestamps and a D-SACK block as options, then IPv4 and TCP headers into `buf`.
+pub fn write_headers(buf: &mut Vec<u8>, local: SocketAddrV4, remote: SocketAddrV4, seq: u32, payload_len: usize) {
+   let mut tcp = etherparse::TcpHeader::new(local.port(), remote.port(), seq, 0xffff);
+   tcp.ack = true;
+   tcp.acknowledgment_number = 1;
+   let mut options = OptionWriter::default();
+   options.push(&TcpOption::Timestamps { val: seq, ecr: 1 });
+   let block = options::sack_block(seq.wrapping_sub(1000), seq);
+   options.push(&TcpOption::Sack(SackBlocks::from_raw(&block)));
+   tcp.set_options_raw(options.as_slice()).expect("options fit in the header");
+   let ip = etherparse::Ipv4Header::new(
+      tcp.header_len() + payload_len as u16,
+      64,
+      etherparse::IpTrafficClass::Tcp,
+      local.ip().octets(),
+      remote.ip().octets(),
+   );
+   buf.clear();
+   ip.write(buf).expect("writing to a Vec cannot fail");
+   tcp.write(buf).expect("writing to a Vec cannot fail");
+}
+
+/// An established connection whose peer has opened its window wide, for measuring how
+/// large writes are cut into segments.
+pub struct Sender {
+   conn: Connection,
+   egress: Egress,
+}
+
+impl Sender {
+   pub fn new(local: SocketAddrV4, remote: SocketAddrV4, mtu: usize) -> Self {
+      let st = RepairState {
+         local,
+         remote,
+         snd_iss: 0,
+         snd_una: 1,
+         snd_nxt: 1,
+         snd_wnd: 1 << 30,
+         snd_wl1: 1,
+         snd_wl2: 1,
+         rcv_irs: 0,
+         rcv_nxt: 1,
+         rcv_wnd: 0xffff,
+         ttl: 64,
+         peer_mss: 1460,
+         snd_wscale: 14,
+         rcv_wscale: 0,
+         ts_clock: Some(0),
+         ts_recent: 0,
+         ecn: false,
+         unacked: Vec::new(),
+         incoming: Vec::new(),
+         sack_ok: false,
+         cwnd: cc::initial_window(1460),
+         ssthresh: usize::MAX,
+         // room for the largest write the benchmarks make
+         opts: ListenOptions { send_buffer: 1 << 20, ..ListenOptions::default() },
+      };
+      let mut egress = Egress::default();
+      egress.set_mtu(mtu);
+      let mut conn = Connection::repair_import(st, mtu);
+      conn.set_repair(&mut egress, false).expect("nothing queued yet");
+      Sender { conn, egress }
+   }
+
+   /// Queue `data` and cut all of it into segments. Returns the number of segments, not
+   /// counting those the congestion window let out right away.
+   pub fn write(&mut self, data: &[u8]) -> usize {
+      self.conn.enqueue(&mut self.egress, data).expect("the connection is established");
+      self.conn.segment_queued(&mut self.egress).expect("segments fit the device")
+   }
+}
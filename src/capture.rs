@@ -0,0 +1,126 @@
+use std::io;
+use std::io::prelude::*;
+use std::fs::File;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::time;
+
+use crate::capture_filter::CaptureFilter;
+
+/// pcap link type for packets that start directly with the IP header, which is what the tun
+/// device gives us
+const LINKTYPE_RAW: u32 = 101;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+   In,
+   Out,
+}
+
+/// Writes every segment to a pcap file, plus an index file next to it (`<path>.idx`) with one
+/// line per packet:
+///
+/// ```text
+/// <packet number> <microseconds since epoch> <in|out> <src>:<port> <dst>:<port> <state>
+/// ```
+///
+/// where state is that of the owning connection at capture time, so traces can be lined up
+/// with state transitions. With a filter, only segments it matches are written.
+pub struct Capture {
+   pcap: io::BufWriter<File>,
+   index: io::BufWriter<File>,
+   packets: u64,
+   filter: Option<CaptureFilter>,
+}
+
+impl Capture {
+   pub fn create(path: &Path, filter: Option<CaptureFilter>) -> io::Result<Self> {
+      let mut pcap = io::BufWriter::new(File::create(path)?);
+      let mut index_path = PathBuf::from(path).into_os_string();
+      index_path.push(".idx");
+      let index = io::BufWriter::new(File::create(index_path)?);
+
+      // global header: magic, version 2.4, GMT, accuracy, snaplen, link type
+      pcap.write_all(&0xa1b2_c3d4u32.to_le_bytes())?;
+      pcap.write_all(&2u16.to_le_bytes())?;
+      pcap.write_all(&4u16.to_le_bytes())?;
+      pcap.write_all(&0i32.to_le_bytes())?;
+      pcap.write_all(&0u32.to_le_bytes())?;
+      pcap.write_all(&65535u32.to_le_bytes())?;
+      pcap.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+
+      Ok(Capture { pcap, index, packets: 0, filter })
+   }
+
+   pub fn record(&mut self, dir: Direction, state: &str, packet: &[u8]) -> io::Result<()> {
+      let addrs = addresses(packet);
+      if let Some(filter) = &self.filter {
+         match addrs {
+            Some((src, dst)) if filter.matches(src, dst) => {}
+            _ => return Ok(()),
+         }
+      }
+
+      let ts = time::SystemTime::now()
+         .duration_since(time::UNIX_EPOCH)
+         .unwrap_or_default();
+
+      self.pcap.write_all(&(ts.as_secs() as u32).to_le_bytes())?;
+      self.pcap.write_all(&ts.subsec_micros().to_le_bytes())?;
+      self.pcap.write_all(&(packet.len() as u32).to_le_bytes())?;
+      self.pcap.write_all(&(packet.len() as u32).to_le_bytes())?;
+      self.pcap.write_all(packet)?;
+
+      let (src, dst) = match addrs {
+         Some((src, dst)) => (src.to_string(), dst.to_string()),
+         None => ("-".to_string(), "-".to_string()),
+      };
+      writeln!(
+         self.index,
+         "{} {} {} {} {} {}",
+         self.packets,
+         ts.as_micros(),
+         match dir {
+            Direction::In => "in",
+            Direction::Out => "out",
+         },
+         src,
+         dst,
+         state,
+      )?;
+      self.packets += 1;
+      Ok(())
+   }
+
+   pub fn flush(&mut self) -> io::Result<()> {
+      self.pcap.flush()?;
+      self.index.flush()
+   }
+}
+
+/// Record a packet if capturing, and stop capturing if the files can't be written, rather
+/// than taking the whole stack down with it.
+pub fn record(capture: &mut Option<Capture>, dir: Direction, state: &str, packet: &[u8]) {
+   if let Some(cap) = capture {
+      if let Err(e) = cap.record(dir, state, packet) {
+         eprintln!("stopping capture: {}", e);
+         *capture = None;
+      }
+   }
+}
+
+/// Source and destination as written on the wire.
+fn addresses(packet: &[u8]) -> Option<(SocketAddrV4, SocketAddrV4)> {
+   if packet.len() < 20 {
+      return None;
+   }
+   let ihl = (packet[0] & 0x0f) as usize * 4;
+   if packet.len() < ihl + 4 {
+      return None;
+   }
+   let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+   let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+   let sport = u16::from_be_bytes([packet[ihl], packet[ihl + 1]]);
+   let dport = u16::from_be_bytes([packet[ihl + 2], packet[ihl + 3]]);
+   Some((SocketAddrV4::new(src, sport), SocketAddrV4::new(dst, dport)))
+}
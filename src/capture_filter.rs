@@ -0,0 +1,277 @@
+//! A small subset of tcpdump's filter syntax, e.g. `port 80 and host 10.0.0.5` or
+//! `src host 10.0.0.5 and not (port 22 or port 23)`.
+//!
+//! Supported primitives are `host ADDR` and `port NUM`, optionally qualified with `src` or
+//! `dst`, plus `tcp` (which matches everything, since that is all we capture). They can be
+//! combined with `and`/`&&`, `or`/`||`, `not`/`!` and parentheses. Filters come from
+//! operators over the control socket, so parentheses and negations nest at most
+//! `MAX_DEPTH` deep and a filter has at most `MAX_TERMS` primitives; anything larger is
+//! rejected rather than parsed and evaluated by recursion without bound.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+/// how deep parentheses and negations may nest
+const MAX_DEPTH: usize = 32;
+/// how many primitives a filter may have
+const MAX_TERMS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dir {
+   Src,
+   Dst,
+   Either,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+   True,
+   Host(Dir, Ipv4Addr),
+   Port(Dir, u16),
+   Not(Box<Expr>),
+   And(Box<Expr>, Box<Expr>),
+   Or(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureFilter {
+   expr: Expr,
+}
+
+impl CaptureFilter {
+   pub fn parse(s: &str) -> io::Result<Self> {
+      let spaced = s.replace('(', " ( ").replace(')', " ) ");
+      let tokens: Vec<&str> = spaced.split_whitespace().collect();
+      if tokens.is_empty() {
+         return Ok(CaptureFilter { expr: Expr::True });
+      }
+      let mut p = Parser { tokens, at: 0, depth: 0, terms: 0 };
+      let expr = p.or()?;
+      if let Some(t) = p.peek() {
+         return Err(invalid(format!("unexpected `{}`", t)));
+      }
+      Ok(CaptureFilter { expr })
+   }
+
+   /// Whether the segment from `src` to `dst` should be captured.
+   pub fn matches(&self, src: SocketAddrV4, dst: SocketAddrV4) -> bool {
+      eval(&self.expr, src, dst)
+   }
+}
+
+fn eval(e: &Expr, src: SocketAddrV4, dst: SocketAddrV4) -> bool {
+   match e {
+      Expr::True => true,
+      Expr::Host(Dir::Src, a) => src.ip() == a,
+      Expr::Host(Dir::Dst, a) => dst.ip() == a,
+      Expr::Host(Dir::Either, a) => src.ip() == a || dst.ip() == a,
+      Expr::Port(Dir::Src, p) => src.port() == *p,
+      Expr::Port(Dir::Dst, p) => dst.port() == *p,
+      Expr::Port(Dir::Either, p) => src.port() == *p || dst.port() == *p,
+      Expr::Not(e) => !eval(e, src, dst),
+      Expr::And(l, r) => eval(l, src, dst) && eval(r, src, dst),
+      Expr::Or(l, r) => eval(l, src, dst) || eval(r, src, dst),
+   }
+}
+
+fn invalid(msg: String) -> io::Error {
+   io::Error::new(io::ErrorKind::InvalidInput, format!("bad capture filter: {}", msg))
+}
+
+struct Parser<'a> {
+   tokens: Vec<&'a str>,
+   at: usize,
+   /// parentheses and negations around the current token
+   depth: usize,
+   /// primitives so far
+   terms: usize,
+}
+
+impl<'a> Parser<'a> {
+   fn peek(&self) -> Option<&'a str> {
+      self.tokens.get(self.at).copied()
+   }
+
+   fn next(&mut self) -> io::Result<&'a str> {
+      let t = self.peek().ok_or_else(|| invalid("unexpected end of expression".to_string()))?;
+      self.at += 1;
+      Ok(t)
+   }
+
+   fn or(&mut self) -> io::Result<Expr> {
+      let mut e = self.and()?;
+      while let Some("or") | Some("||") = self.peek() {
+         self.at += 1;
+         e = Expr::Or(Box::new(e), Box::new(self.and()?));
+      }
+      Ok(e)
+   }
+
+   fn and(&mut self) -> io::Result<Expr> {
+      let mut e = self.not()?;
+      while let Some("and") | Some("&&") = self.peek() {
+         self.at += 1;
+         e = Expr::And(Box::new(e), Box::new(self.not()?));
+      }
+      Ok(e)
+   }
+
+   fn not(&mut self) -> io::Result<Expr> {
+      match self.next()? {
+         "not" | "!" => {
+            self.enter()?;
+            let e = Expr::Not(Box::new(self.not()?));
+            self.depth -= 1;
+            Ok(e)
+         }
+         "(" => {
+            self.enter()?;
+            let e = self.or()?;
+            self.depth -= 1;
+            match self.next()? {
+               ")" => Ok(e),
+               t => Err(invalid(format!("expected `)`, found `{}`", t))),
+            }
+         }
+         "tcp" => {
+            self.term()?;
+            Ok(Expr::True)
+         }
+         "src" => self.primitive(Dir::Src),
+         "dst" => self.primitive(Dir::Dst),
+         _ => {
+            self.at -= 1;
+            self.primitive(Dir::Either)
+         }
+      }
+   }
+
+   /// Go one level deeper into parentheses or negations.
+   fn enter(&mut self) -> io::Result<()> {
+      self.depth += 1;
+      if self.depth > MAX_DEPTH {
+         return Err(invalid(format!("nested more than {} deep", MAX_DEPTH)));
+      }
+      Ok(())
+   }
+
+   /// Count one more primitive.
+   fn term(&mut self) -> io::Result<()> {
+      self.terms += 1;
+      if self.terms > MAX_TERMS {
+         return Err(invalid(format!("more than {} primitives", MAX_TERMS)));
+      }
+      Ok(())
+   }
+
+   fn primitive(&mut self, dir: Dir) -> io::Result<Expr> {
+      self.term()?;
+      match self.next()? {
+         "host" => {
+            let a = self.next()?;
+            let a = a.parse().map_err(|_| invalid(format!("`{}` is not an IPv4 address", a)))?;
+            Ok(Expr::Host(dir, a))
+         }
+         "port" => {
+            let p = self.next()?;
+            let p = p.parse().map_err(|_| invalid(format!("`{}` is not a port", p)))?;
+            Ok(Expr::Port(dir, p))
+         }
+         t => Err(invalid(format!("expected `host` or `port`, found `{}`", t))),
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn addr(last: u8, port: u16) -> SocketAddrV4 {
+      SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, last), port)
+   }
+
+   fn parsed(s: &str) -> Expr {
+      CaptureFilter::parse(s).unwrap().expr
+   }
+
+   fn port(p: u16) -> Box<Expr> {
+      Box::new(Expr::Port(Dir::Either, p))
+   }
+
+   #[test]
+   fn and_binds_tighter_than_or() {
+      let e = Expr::Or(port(1), Box::new(Expr::And(port(2), port(3))));
+      assert_eq!(parsed("port 1 or port 2 and port 3"), e);
+      assert_eq!(parsed("port 1 || port 2 && port 3"), e);
+      let e = Expr::Or(Box::new(Expr::And(port(1), port(2))), port(3));
+      assert_eq!(parsed("port 1 and port 2 or port 3"), e);
+   }
+
+   #[test]
+   fn not_binds_tightest() {
+      let e = Expr::And(Box::new(Expr::Not(port(1))), port(2));
+      assert_eq!(parsed("not port 1 and port 2"), e);
+      assert_eq!(parsed("! port 1 && port 2"), e);
+      assert_eq!(parsed("not not port 1"), Expr::Not(Box::new(Expr::Not(port(1)))));
+   }
+
+   #[test]
+   fn parentheses_group() {
+      let e = Expr::And(port(1), Box::new(Expr::Or(port(2), port(3))));
+      assert_eq!(parsed("port 1 and (port 2 or port 3)"), e);
+      // tokens needn't be spaced out from parentheses
+      assert_eq!(parsed("port 1 and(port 2 or port 3)"), e);
+      assert_eq!(parsed("((port 1))"), Expr::Port(Dir::Either, 1));
+   }
+
+   #[test]
+   fn matches_by_direction() {
+      let f = CaptureFilter::parse("src host 10.0.0.1 and not (port 22 or dst port 23)").unwrap();
+      assert!(f.matches(addr(1, 5000), addr(2, 80)));
+      assert!(!f.matches(addr(2, 5000), addr(1, 80)));
+      assert!(!f.matches(addr(1, 22), addr(2, 80)));
+      assert!(!f.matches(addr(1, 5000), addr(2, 23)));
+      assert!(f.matches(addr(1, 23), addr(2, 80)));
+      // an empty filter, like `tcp`, captures everything
+      assert!(CaptureFilter::parse("").unwrap().matches(addr(1, 1), addr(2, 2)));
+      assert!(CaptureFilter::parse("tcp").unwrap().matches(addr(1, 1), addr(2, 2)));
+   }
+
+   #[test]
+   fn malformed_filters_are_rejected() {
+      for s in [
+         "port",
+         "port http",
+         "port 70000",
+         "host 10.0.0",
+         "src tcp",
+         "dst",
+         "ip",
+         "port 1 and",
+         "or port 1",
+         "(port 1",
+         "port 1)",
+         "()",
+         "port 1 port 2",
+      ]
+      .iter()
+      {
+         let err = CaptureFilter::parse(s).unwrap_err();
+         assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{}", s);
+      }
+   }
+
+   #[test]
+   fn nesting_is_limited() {
+      let nested = |depth| format!("{}port 1{}", "(".repeat(depth), ")".repeat(depth));
+      assert!(CaptureFilter::parse(&nested(MAX_DEPTH)).is_ok());
+      assert!(CaptureFilter::parse(&nested(MAX_DEPTH + 1)).is_err());
+      // deep enough to overflow the stack, were it parsed by recursion
+      assert!(CaptureFilter::parse(&nested(1_000_000)).is_err());
+      assert!(CaptureFilter::parse(&format!("{}port 1", "not ".repeat(1_000_000))).is_err());
+      // long chains would make for deep trees too
+      let chain = |terms: usize| vec!["port 1"; terms].join(" and ");
+      assert!(CaptureFilter::parse(&chain(MAX_TERMS)).is_ok());
+      assert!(CaptureFilter::parse(&chain(MAX_TERMS + 1)).is_err());
+   }
+}
@@ -0,0 +1,498 @@
+//! Congestion control. A connection asks its controller how much data may be in flight
+//! and tells it about ACKs and losses; the algorithms differ only in how they react.
+
+use std::time::{Duration, Instant};
+
+mod bbr;
+
+pub use bbr::Bbr;
+
+/// Which congestion control algorithm a connection runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CongestionAlgorithm {
+   Reno,
+   Cubic,
+   Bbr,
+}
+
+impl Default for CongestionAlgorithm {
+   fn default() -> Self {
+      CongestionAlgorithm::Reno
+   }
+}
+
+/// A fresh controller running `alg`.
+pub fn new(alg: CongestionAlgorithm, mss: usize) -> Box<dyn CongestionControl> {
+   match alg {
+      CongestionAlgorithm::Reno => Box::new(Reno::new(mss)),
+      CongestionAlgorithm::Cubic => Box::new(Cubic::new(mss)),
+      CongestionAlgorithm::Bbr => Box::new(Bbr::new(mss)),
+   }
+}
+
+/// A controller running `alg` to take over from `old` mid-connection. It starts from the
+/// old window and slow start threshold, so the switch neither bursts nor stalls; a window
+/// inflated for fast recovery is deflated first, since the new controller doesn't know it is
+/// recovering.
+pub fn switch(
+   alg: CongestionAlgorithm,
+   old: &dyn CongestionControl,
+   recovering: bool,
+   mss: usize,
+) -> Box<dyn CongestionControl> {
+   let ssthresh = old.ssthresh();
+   let cwnd = if recovering { std::cmp::min(old.cwnd(), ssthresh) } else { old.cwnd() };
+   resume(alg, cwnd, ssthresh, mss)
+}
+
+/// A controller running `alg` that picks up at a known window and slow start threshold,
+/// such as those of a connection restored from a repair export.
+pub fn resume(alg: CongestionAlgorithm, cwnd: usize, ssthresh: usize, mss: usize) -> Box<dyn CongestionControl> {
+   match alg {
+      CongestionAlgorithm::Reno => Box::new(Reno { cwnd, ssthresh, ..Reno::new(mss) }),
+      CongestionAlgorithm::Cubic => Box::new(Cubic {
+         cwnd,
+         ssthresh,
+         // plateau around the current window, as if it had been reached before a loss
+         w_max: cwnd as f64 / mss as f64,
+         ..Cubic::new(mss)
+      }),
+      CongestionAlgorithm::Bbr => Box::new(Bbr::resume(cwnd, mss)),
+   }
+}
+
+/// Everything a congestion control algorithm needs to hear about. Windows are in bytes.
+pub trait CongestionControl: Send {
+   fn cwnd(&self) -> usize;
+   fn ssthresh(&self) -> usize;
+   /// `acked` bytes of new data were cumulatively acknowledged, leaving `flight` bytes
+   /// outstanding
+   fn on_ack(&mut self, acked: usize, flight: usize, mss: usize);
+   /// new data was acknowledged during fast recovery, but not everything that was
+   /// outstanding when it began; another segment has been retransmitted
+   fn on_partial_ack(&mut self, acked: usize, mss: usize);
+   /// a further duplicate ACK while in fast recovery
+   fn on_dup_ack(&mut self, mss: usize);
+   /// three duplicate ACKs: a segment was lost and has just been retransmitted, with `flight`
+   /// bytes outstanding
+   fn on_fast_retransmit(&mut self, flight: usize, mss: usize);
+   /// the retransmission timer fired for the first time for the segment at SND.UNA
+   fn on_timeout(&mut self, flight: usize, mss: usize);
+   /// the peer echoed a congestion mark, at most once per window: back off as for a loss,
+   /// though nothing needs resending (RFC 3168 S6.1.2)
+   fn on_ecn(&mut self, flight: usize, mss: usize);
+   /// a new round-trip time measurement was taken
+   fn on_rtt_sample(&mut self, _rtt: Duration) {}
+   /// the application sent nothing for `rtos` retransmission timeouts and is about to send
+   /// again
+   fn on_idle(&mut self, _rtos: u32, _mss: usize) {}
+   /// the last timeout turned out to be spurious: go back to the state from before it
+   fn undo_timeout(&mut self) {}
+   /// bytes per second to pace transmissions at, for algorithms that pace rather than rely
+   /// on the ACK clock alone
+   fn pacing_rate(&self) -> Option<f64> {
+      None
+   }
+}
+
+/// Most a single ACK may grow the window by in slow start, in segments, however much it
+/// acknowledges (RFC 3465 S2.2), so a stretch ACK can't set off a line-rate burst.
+const ABC_LIMIT: usize = 2;
+
+/// Slow start growth for an ACK of `acked` bytes, counted in bytes rather than ACKs
+/// (RFC 3465).
+fn slow_start_increase(acked: usize, mss: usize) -> usize {
+   std::cmp::min(acked, ABC_LIMIT * mss)
+}
+
+/// Decay a window that went unused for `rtos` retransmission timeouts: it no longer
+/// reflects what the path can take, so halve it once per RTO, down to the initial window,
+/// remembering most of it in ssthresh so slow start can quickly get back there (RFC 7661
+/// S4.3, after RFC 2861).
+fn decay_idle(cwnd: &mut usize, ssthresh: &mut usize, rtos: u32, mss: usize) {
+   let restart = initial_window(mss);
+   if *cwnd <= restart {
+      return;
+   }
+   *ssthresh = std::cmp::max(*ssthresh, *cwnd / 4 * 3);
+   *cwnd = std::cmp::max(cwnd.checked_shr(rtos).unwrap_or(0), restart);
+}
+
+/// Initial window (RFC 5681 S3.1).
+pub fn initial_window(mss: usize) -> usize {
+   std::cmp::min(4 * mss, std::cmp::max(2 * mss, 4380))
+}
+
+/// TCP Reno (RFC 5681): slow start, congestion avoidance and fast recovery, with NewReno's
+/// handling of partial ACKs (RFC 6582).
+pub struct Reno {
+   cwnd: usize,
+   ssthresh: usize,
+   /// between three duplicate ACKs and the ACK that covers everything then outstanding
+   recovering: bool,
+   /// bytes acknowledged in congestion avoidance since cwnd last grew
+   bytes_acked: usize,
+   /// cwnd and ssthresh from before the last timeout
+   before_timeout: Option<(usize, usize)>,
+}
+
+impl Reno {
+   pub fn new(mss: usize) -> Self {
+      Reno {
+         cwnd: initial_window(mss),
+         // "arbitrarily high", so that slow start ends at the first loss
+         ssthresh: usize::MAX,
+         recovering: false,
+         bytes_acked: 0,
+         before_timeout: None,
+      }
+   }
+}
+
+impl CongestionControl for Reno {
+   fn cwnd(&self) -> usize {
+      self.cwnd
+   }
+
+   fn ssthresh(&self) -> usize {
+      self.ssthresh
+   }
+
+   fn on_ack(&mut self, acked: usize, _flight: usize, mss: usize) {
+      if self.recovering {
+         // deflate the window inflated by the duplicate ACKs
+         self.recovering = false;
+         self.cwnd = self.ssthresh;
+         return;
+      }
+      if self.cwnd < self.ssthresh {
+         self.cwnd += slow_start_increase(acked, mss);
+      } else {
+         // congestion avoidance: one MSS per window's worth of acknowledged bytes, however
+         // many ACKs it took (RFC 3465 S2.1)
+         self.bytes_acked += acked;
+         if self.bytes_acked >= self.cwnd {
+            self.bytes_acked -= self.cwnd;
+            self.cwnd += mss;
+         }
+      }
+   }
+
+   fn on_partial_ack(&mut self, acked: usize, mss: usize) {
+      // deflate by the amount acked, then add back one MSS for the retransmission, so about
+      // ssthresh worth of data stays in flight once recovery ends
+      self.cwnd = self.cwnd.saturating_sub(acked);
+      if acked >= mss {
+         self.cwnd += mss;
+      }
+   }
+
+   fn on_dup_ack(&mut self, mss: usize) {
+      if self.recovering {
+         // another segment has left the network
+         self.cwnd += mss;
+      }
+   }
+
+   fn on_fast_retransmit(&mut self, flight: usize, mss: usize) {
+      self.bytes_acked = 0;
+      self.ssthresh = std::cmp::max(flight / 2, 2 * mss);
+      self.cwnd = self.ssthresh + 3 * mss;
+      self.recovering = true;
+   }
+
+   fn on_ecn(&mut self, flight: usize, mss: usize) {
+      self.bytes_acked = 0;
+      self.ssthresh = std::cmp::max(flight / 2, 2 * mss);
+      self.cwnd = self.ssthresh;
+   }
+
+   fn on_timeout(&mut self, flight: usize, mss: usize) {
+      self.before_timeout = Some((self.cwnd, self.ssthresh));
+      self.bytes_acked = 0;
+      self.ssthresh = std::cmp::max(flight / 2, 2 * mss);
+      self.cwnd = mss;
+      self.recovering = false;
+   }
+
+   fn on_idle(&mut self, rtos: u32, mss: usize) {
+      decay_idle(&mut self.cwnd, &mut self.ssthresh, rtos, mss);
+   }
+
+   fn undo_timeout(&mut self) {
+      if let Some((cwnd, ssthresh)) = self.before_timeout.take() {
+         self.cwnd = cwnd;
+         self.ssthresh = ssthresh;
+      }
+   }
+}
+
+/// Multiplicative decrease factor (RFC 8312 S4.5).
+const CUBIC_BETA: f64 = 0.7;
+/// Aggressiveness of window growth (RFC 8312 S5).
+const CUBIC_C: f64 = 0.4;
+
+/// CUBIC (RFC 8312). After a loss the window grows as a cubic function of the time since,
+/// flattening out around the size it had before the loss and then probing beyond it, so
+/// growth depends on time rather than on the round trip time and keeps up on high-BDP paths.
+/// Windows below are in segments, as in the RFC.
+pub struct Cubic {
+   cwnd: usize,
+   ssthresh: usize,
+   recovering: bool,
+   /// window just before the last reduction
+   w_max: f64,
+   /// start of the current congestion avoidance epoch
+   epoch_start: Option<Instant>,
+   /// time the cubic function takes to grow back to `w_max` (RFC 8312 S4.1)
+   k: f64,
+   /// what Reno would have grown the window to over this epoch (RFC 8312 S4.2)
+   w_est: f64,
+   /// latest round-trip time measurement
+   rtt: Duration,
+   /// cwnd, ssthresh and w_max from before the last timeout
+   before_timeout: Option<(usize, usize, f64)>,
+}
+
+impl Cubic {
+   pub fn new(mss: usize) -> Self {
+      Cubic {
+         cwnd: initial_window(mss),
+         ssthresh: usize::MAX,
+         recovering: false,
+         w_max: 0.0,
+         epoch_start: None,
+         k: 0.0,
+         w_est: 0.0,
+         rtt: Duration::from_millis(100),
+         before_timeout: None,
+      }
+   }
+
+   /// Remember the window the loss happened at and cut ssthresh.
+   fn reduce(&mut self, mss: usize) {
+      let cwnd = self.cwnd as f64 / mss as f64;
+      // fast convergence (RFC 8312 S4.6): a flow whose window keeps shrinking releases
+      // bandwidth to newcomers by aiming lower
+      self.w_max = if cwnd < self.w_max { cwnd * (1.0 + CUBIC_BETA) / 2.0 } else { cwnd };
+      self.ssthresh = std::cmp::max((self.cwnd as f64 * CUBIC_BETA) as usize, 2 * mss);
+      self.epoch_start = None;
+   }
+}
+
+impl CongestionControl for Cubic {
+   fn cwnd(&self) -> usize {
+      self.cwnd
+   }
+
+   fn ssthresh(&self) -> usize {
+      self.ssthresh
+   }
+
+   fn on_ack(&mut self, acked: usize, _flight: usize, mss: usize) {
+      if self.recovering {
+         self.recovering = false;
+         self.cwnd = self.ssthresh;
+         return;
+      }
+      if self.cwnd < self.ssthresh {
+         self.cwnd += slow_start_increase(acked, mss);
+         return;
+      }
+      let now = Instant::now();
+      let cwnd = self.cwnd as f64 / mss as f64;
+      let epoch_start = match self.epoch_start {
+         Some(at) => at,
+         None => {
+            if cwnd < self.w_max {
+               self.k = ((self.w_max - cwnd) / CUBIC_C).cbrt();
+            } else {
+               self.k = 0.0;
+               self.w_max = cwnd;
+            }
+            self.w_est = cwnd;
+            self.epoch_start = Some(now);
+            now
+         }
+      };
+      // aim for where the curve will be one round trip from now (RFC 8312 S4.1)
+      let t = (now - epoch_start + self.rtt).as_secs_f64();
+      let target = CUBIC_C * (t - self.k).powi(3) + self.w_max;
+      let target = target.min(cwnd * 1.5);
+      self.w_est += 3.0 * (1.0 - CUBIC_BETA) / (1.0 + CUBIC_BETA) * acked as f64 / self.cwnd as f64;
+      if target < self.w_est {
+         // TCP-friendly region: at least as fast as Reno would be
+         self.cwnd = std::cmp::max(self.cwnd, (self.w_est * mss as f64) as usize);
+      } else if target > cwnd {
+         // (target - cwnd) / cwnd segments per segment acknowledged
+         let segments = acked as f64 / mss as f64;
+         self.cwnd += std::cmp::max(((target - cwnd) / cwnd * segments * mss as f64) as usize, 1);
+      }
+   }
+
+   fn on_partial_ack(&mut self, acked: usize, mss: usize) {
+      self.cwnd = self.cwnd.saturating_sub(acked);
+      if acked >= mss {
+         self.cwnd += mss;
+      }
+   }
+
+   fn on_dup_ack(&mut self, mss: usize) {
+      if self.recovering {
+         self.cwnd += mss;
+      }
+   }
+
+   fn on_fast_retransmit(&mut self, _flight: usize, mss: usize) {
+      self.reduce(mss);
+      self.cwnd = self.ssthresh + 3 * mss;
+      self.recovering = true;
+   }
+
+   fn on_ecn(&mut self, _flight: usize, mss: usize) {
+      self.reduce(mss);
+      self.cwnd = self.ssthresh;
+   }
+
+   fn on_timeout(&mut self, _flight: usize, mss: usize) {
+      self.before_timeout = Some((self.cwnd, self.ssthresh, self.w_max));
+      self.reduce(mss);
+      self.cwnd = mss;
+      self.recovering = false;
+   }
+
+   fn on_rtt_sample(&mut self, rtt: Duration) {
+      self.rtt = rtt;
+   }
+
+   fn on_idle(&mut self, rtos: u32, mss: usize) {
+      decay_idle(&mut self.cwnd, &mut self.ssthresh, rtos, mss);
+      // the curve restarts from the decayed window
+      self.epoch_start = None;
+   }
+
+   fn undo_timeout(&mut self) {
+      if let Some((cwnd, ssthresh, w_max)) = self.before_timeout.take() {
+         self.cwnd = cwnd;
+         self.ssthresh = ssthresh;
+         self.w_max = w_max;
+         self.epoch_start = None;
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   const MSS: usize = 1000;
+
+   #[test]
+   fn reno_slow_start_counts_bytes() {
+      let mut cc = Reno::new(MSS);
+      assert_eq!(cc.cwnd(), 4 * MSS);
+      cc.on_ack(MSS, 0, MSS);
+      assert_eq!(cc.cwnd(), 5 * MSS);
+      // a stretch ACK grows the window by no more than ABC_LIMIT segments
+      cc.on_ack(5 * MSS, 0, MSS);
+      assert_eq!(cc.cwnd(), 7 * MSS);
+   }
+
+   #[test]
+   fn reno_fast_recovery() {
+      let mut cc = Reno::new(MSS);
+      cc.on_fast_retransmit(20 * MSS, MSS);
+      // half the flight, inflated by the three duplicate ACKs
+      assert_eq!(cc.ssthresh(), 10 * MSS);
+      assert_eq!(cc.cwnd(), 13 * MSS);
+      cc.on_dup_ack(MSS);
+      assert_eq!(cc.cwnd(), 14 * MSS);
+      // a partial ACK deflates by what it covers and makes room for the retransmission
+      cc.on_partial_ack(3 * MSS, MSS);
+      assert_eq!(cc.cwnd(), 12 * MSS);
+      // the ACK that ends recovery deflates to ssthresh
+      cc.on_ack(MSS, 5 * MSS, MSS);
+      assert_eq!(cc.cwnd(), 10 * MSS);
+      // and congestion avoidance grows one segment per window acknowledged
+      cc.on_ack(5 * MSS, 5 * MSS, MSS);
+      assert_eq!(cc.cwnd(), 10 * MSS);
+      cc.on_ack(5 * MSS, 5 * MSS, MSS);
+      assert_eq!(cc.cwnd(), 11 * MSS);
+   }
+
+   #[test]
+   fn reno_ssthresh_floor() {
+      let mut cc = Reno::new(MSS);
+      cc.on_fast_retransmit(MSS, MSS);
+      assert_eq!(cc.ssthresh(), 2 * MSS);
+      cc.on_ecn(MSS, MSS);
+      assert_eq!((cc.cwnd(), cc.ssthresh()), (2 * MSS, 2 * MSS));
+   }
+
+   #[test]
+   fn reno_timeout_and_undo() {
+      let mut cc = Reno::new(MSS);
+      for _ in 0..8 {
+         cc.on_ack(2 * MSS, 0, MSS);
+      }
+      let before = (cc.cwnd(), cc.ssthresh());
+      cc.on_timeout(16 * MSS, MSS);
+      assert_eq!((cc.cwnd(), cc.ssthresh()), (MSS, 8 * MSS));
+      // slow start again, up to the new ssthresh
+      cc.on_ack(MSS, 0, MSS);
+      assert_eq!(cc.cwnd(), 2 * MSS);
+      // a spurious timeout is undone
+      cc.undo_timeout();
+      assert_eq!((cc.cwnd(), cc.ssthresh()), before);
+   }
+
+   fn cubic_at(segments: usize) -> Cubic {
+      Cubic { cwnd: segments * MSS, ..Cubic::new(MSS) }
+   }
+
+   #[test]
+   fn cubic_backs_off_by_beta() {
+      let mut cc = cubic_at(100);
+      cc.on_fast_retransmit(100 * MSS, MSS);
+      assert_eq!(cc.ssthresh(), 70 * MSS);
+      assert_eq!(cc.cwnd(), 73 * MSS);
+      assert_eq!(cc.w_max, 100.0);
+      cc.on_ack(MSS, 70 * MSS, MSS);
+      assert_eq!(cc.cwnd(), 70 * MSS);
+      // a second loss below the last peak aims lower still (fast convergence)
+      cc.on_ecn(70 * MSS, MSS);
+      assert_eq!(cc.ssthresh(), 49 * MSS);
+      assert!((cc.w_max - 70.0 * 1.7 / 2.0).abs() < 1e-9, "{}", cc.w_max);
+   }
+
+   #[test]
+   fn cubic_grows_back_to_the_plateau() {
+      let mut cc = cubic_at(100);
+      cc.on_ecn(100 * MSS, MSS);
+      // the first ACK starts the epoch, a long way from the plateau
+      cc.on_ack(MSS, 70 * MSS, MSS);
+      assert!(cc.cwnd() > 70 * MSS && cc.cwnd() < 71 * MSS, "{}", cc.cwnd());
+      assert!((cc.k - (30.0f64 / CUBIC_C).cbrt()).abs() < 1e-9);
+      // K seconds into the epoch the curve is back at the window the loss happened at, and
+      // a window's worth of ACKs gets there
+      cc.epoch_start = Some(Instant::now() - Duration::from_secs_f64(cc.k));
+      let flight = cc.cwnd();
+      cc.on_ack(flight, flight, MSS);
+      assert!(cc.cwnd() >= 99 * MSS && cc.cwnd() <= 101 * MSS, "{}", cc.cwnd());
+      // where it flattens out
+      let before = cc.cwnd();
+      let flight = cc.cwnd();
+      cc.on_ack(flight, flight, MSS);
+      assert!(cc.cwnd() - before < MSS, "{} -> {}", before, cc.cwnd());
+   }
+
+   #[test]
+   fn cubic_timeout_and_undo() {
+      let mut cc = cubic_at(100);
+      cc.on_timeout(100 * MSS, MSS);
+      assert_eq!((cc.cwnd(), cc.ssthresh()), (MSS, 70 * MSS));
+      cc.undo_timeout();
+      assert_eq!((cc.cwnd(), cc.ssthresh()), (100 * MSS, usize::MAX));
+      assert_eq!(cc.w_max, 0.0);
+   }
+}
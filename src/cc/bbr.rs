@@ -0,0 +1,317 @@
+//! BBR congestion control (draft-cardwell-iccrg-bbr-congestion-control, version 1). Rather
+//! than reacting to loss, BBR models the path by its bottleneck bandwidth and its round-trip
+//! propagation delay, paces at the bandwidth and keeps about one bandwidth-delay product in
+//! flight.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::{initial_window, CongestionControl};
+
+/// 2/ln(2), the smallest gain that doubles the sending rate every round in startup
+const HIGH_GAIN: f64 = 2.885;
+/// pacing gains ProbeBW cycles through, one per round
+const PACING_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+const CWND_GAIN: f64 = 2.0;
+/// rounds the bandwidth estimate is the maximum over
+const BW_WINDOW: usize = 10;
+/// how long a minimum RTT measurement stays valid before ProbeRTT re-measures it
+const MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+/// time spent with a minimal window in ProbeRTT
+const PROBE_RTT_TIME: Duration = Duration::from_millis(200);
+/// smallest window, in segments, that still keeps delayed ACKs coming
+const MIN_PIPE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+   /// ramp up until the bandwidth stops growing
+   Startup,
+   /// empty the queue startup built
+   Drain,
+   /// cruise at the estimated bandwidth, probing for more now and then
+   ProbeBw,
+   /// shrink the window to re-measure the propagation delay
+   ProbeRtt,
+}
+
+pub struct Bbr {
+   mode: Mode,
+   cwnd: usize,
+   /// windowed maximum of the delivery rate samples, in bytes per second
+   btl_bw: f64,
+   /// delivery rate of each of the last `BW_WINDOW` rounds
+   bw_samples: VecDeque<f64>,
+   min_rtt: Option<Duration>,
+   /// when `min_rtt` was last lowered or confirmed
+   min_rtt_stamp: Instant,
+   /// the minimum RTT has expired and ProbeRTT should run
+   probe_rtt_due: bool,
+   /// when the current ProbeRTT may end
+   probe_rtt_done: Instant,
+   /// start of the current round, and bytes delivered in it
+   round_start: Instant,
+   round_delivered: usize,
+   /// bandwidth startup last saw grow by a quarter, and rounds since
+   full_bw: f64,
+   full_bw_rounds: u32,
+   /// startup found the bottleneck
+   filled_pipe: bool,
+   /// position in `PACING_GAINS`
+   cycle: usize,
+   /// cwnd from before the last timeout
+   before_timeout: Option<usize>,
+}
+
+impl Bbr {
+   pub fn new(mss: usize) -> Self {
+      let now = Instant::now();
+      Bbr {
+         mode: Mode::Startup,
+         cwnd: initial_window(mss),
+         btl_bw: 0.0,
+         bw_samples: VecDeque::with_capacity(BW_WINDOW + 1),
+         min_rtt: None,
+         min_rtt_stamp: now,
+         probe_rtt_due: false,
+         probe_rtt_done: now,
+         round_start: now,
+         round_delivered: 0,
+         full_bw: 0.0,
+         full_bw_rounds: 0,
+         filled_pipe: false,
+         cycle: 0,
+         before_timeout: None,
+      }
+   }
+
+   /// Take over a connection at window `cwnd`. The model still has to be measured, so this
+   /// starts in startup, just not from the initial window.
+   pub(super) fn resume(cwnd: usize, mss: usize) -> Self {
+      Bbr { cwnd: std::cmp::max(cwnd, MIN_PIPE * mss), ..Bbr::new(mss) }
+   }
+
+   /// Estimated bandwidth-delay product in bytes, once both halves have been measured.
+   fn bdp(&self) -> Option<f64> {
+      match self.min_rtt {
+         Some(rtt) if self.btl_bw > 0.0 => Some(self.btl_bw * rtt.as_secs_f64()),
+         _ => None,
+      }
+   }
+
+   fn pacing_gain(&self) -> f64 {
+      match self.mode {
+         Mode::Startup => HIGH_GAIN,
+         Mode::Drain => 1.0 / HIGH_GAIN,
+         Mode::ProbeBw => PACING_GAINS[self.cycle],
+         Mode::ProbeRtt => 1.0,
+      }
+   }
+
+   /// A round trip's worth of ACKs has come in.
+   fn on_round(&mut self, sample: f64) {
+      self.bw_samples.push_back(sample);
+      if self.bw_samples.len() > BW_WINDOW {
+         self.bw_samples.pop_front();
+      }
+      self.btl_bw = self.bw_samples.iter().cloned().fold(0.0, f64::max);
+      match self.mode {
+         Mode::Startup => {
+            if self.btl_bw >= self.full_bw * 1.25 {
+               self.full_bw = self.btl_bw;
+               self.full_bw_rounds = 0;
+            } else {
+               self.full_bw_rounds += 1;
+               if self.full_bw_rounds >= 3 {
+                  self.filled_pipe = true;
+                  self.mode = Mode::Drain;
+               }
+            }
+         }
+         Mode::ProbeBw => {
+            self.cycle = (self.cycle + 1) % PACING_GAINS.len();
+         }
+         _ => {}
+      }
+   }
+}
+
+impl CongestionControl for Bbr {
+   fn cwnd(&self) -> usize {
+      self.cwnd
+   }
+
+   fn ssthresh(&self) -> usize {
+      usize::MAX
+   }
+
+   fn on_ack(&mut self, acked: usize, flight: usize, mss: usize) {
+      let now = Instant::now();
+      self.round_delivered += acked;
+      if let Some(rtt) = self.min_rtt {
+         let elapsed = now - self.round_start;
+         if elapsed >= rtt {
+            let sample = self.round_delivered as f64 / elapsed.as_secs_f64();
+            self.round_start = now;
+            self.round_delivered = 0;
+            self.on_round(sample);
+         }
+      }
+
+      if self.probe_rtt_due && self.mode != Mode::ProbeRtt {
+         self.probe_rtt_due = false;
+         self.mode = Mode::ProbeRtt;
+         self.probe_rtt_done = now + std::cmp::max(PROBE_RTT_TIME, self.min_rtt.unwrap_or_default());
+      }
+      if self.mode == Mode::ProbeRtt && now >= self.probe_rtt_done {
+         self.min_rtt_stamp = now;
+         self.mode = if self.filled_pipe { Mode::ProbeBw } else { Mode::Startup };
+      }
+      if self.mode == Mode::Drain && self.bdp().map_or(false, |bdp| flight as f64 <= bdp) {
+         self.mode = Mode::ProbeBw;
+         self.cycle = 0;
+      }
+
+      let floor = MIN_PIPE * mss;
+      if self.mode == Mode::ProbeRtt {
+         self.cwnd = floor;
+         return;
+      }
+      match self.bdp() {
+         Some(bdp) => {
+            let gain = if self.filled_pipe { CWND_GAIN } else { HIGH_GAIN };
+            let target = std::cmp::max((gain * bdp) as usize, floor);
+            if self.filled_pipe {
+               self.cwnd = std::cmp::min(self.cwnd + acked, target);
+            } else if self.cwnd < target {
+               self.cwnd += acked;
+            }
+         }
+         // no model yet: grow like slow start
+         None => self.cwnd += acked,
+      }
+      self.cwnd = std::cmp::max(self.cwnd, floor);
+   }
+
+   // BBR keeps to its model through losses; the retransmissions themselves are enough
+   fn on_partial_ack(&mut self, _acked: usize, _mss: usize) {}
+
+   fn on_dup_ack(&mut self, _mss: usize) {}
+
+   fn on_fast_retransmit(&mut self, _flight: usize, _mss: usize) {}
+
+   // BBRv1 takes no hints from ECN, any more than from loss
+   fn on_ecn(&mut self, _flight: usize, _mss: usize) {}
+
+   fn on_timeout(&mut self, _flight: usize, mss: usize) {
+      // the model can't explain a timeout; start over from one segment, and let ACKs grow
+      // the window back to the model's target
+      self.before_timeout = Some(self.cwnd);
+      self.cwnd = mss;
+   }
+
+   fn undo_timeout(&mut self) {
+      if let Some(cwnd) = self.before_timeout.take() {
+         self.cwnd = std::cmp::max(self.cwnd, cwnd);
+      }
+   }
+
+   fn on_rtt_sample(&mut self, rtt: Duration) {
+      let now = Instant::now();
+      let expired = now - self.min_rtt_stamp > MIN_RTT_WINDOW;
+      if expired && self.mode != Mode::ProbeRtt {
+         self.probe_rtt_due = true;
+      }
+      if self.min_rtt.map_or(true, |min| rtt <= min) || expired {
+         self.min_rtt = Some(rtt);
+         self.min_rtt_stamp = now;
+      }
+   }
+
+   fn pacing_rate(&self) -> Option<f64> {
+      if self.btl_bw > 0.0 {
+         Some(self.pacing_gain() * self.btl_bw)
+      } else {
+         None
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   const MSS: usize = 1000;
+   /// long enough that no round ends on its own while a test runs
+   const RTT: Duration = Duration::from_secs(1);
+
+   /// A BBR that has measured the path: startup has seen the bandwidth stop growing at
+   /// `bw` bytes per second.
+   fn measured(bw: f64) -> Bbr {
+      let mut b = Bbr::new(MSS);
+      b.min_rtt = Some(RTT);
+      for &sample in [bw / 4.0, bw / 2.0, bw].iter() {
+         b.on_round(sample);
+         assert_eq!(b.mode, Mode::Startup);
+      }
+      for _ in 0..3 {
+         b.on_round(bw);
+      }
+      b
+   }
+
+   #[test]
+   fn startup_ends_when_bandwidth_stops_growing() {
+      let b = measured(40_000.0);
+      assert_eq!(b.mode, Mode::Drain);
+      assert!(b.filled_pipe);
+      assert_eq!(b.btl_bw, 40_000.0);
+      assert_eq!(b.bdp(), Some(40_000.0));
+   }
+
+   #[test]
+   fn drain_hands_over_to_probe_bw() {
+      let mut b = measured(40_000.0);
+      b.cwnd = 200 * MSS;
+      // still more in flight than the path holds
+      b.on_ack(MSS, 100 * MSS, MSS);
+      assert_eq!(b.mode, Mode::Drain);
+      assert_eq!(b.pacing_rate(), Some(1.0 / HIGH_GAIN * 40_000.0));
+      b.on_ack(MSS, 40 * MSS, MSS);
+      assert_eq!(b.mode, Mode::ProbeBw);
+      // the window is held to twice the bandwidth-delay product
+      assert_eq!(b.cwnd(), 80 * MSS);
+      assert_eq!(b.pacing_rate(), Some(1.25 * 40_000.0));
+      // each round moves on through the gain cycle
+      b.on_round(40_000.0);
+      assert_eq!(b.pacing_rate(), Some(0.75 * 40_000.0));
+   }
+
+   #[test]
+   fn losses_leave_the_model_alone() {
+      let mut b = measured(40_000.0);
+      let cwnd = b.cwnd();
+      b.on_fast_retransmit(cwnd, MSS);
+      b.on_dup_ack(MSS);
+      b.on_partial_ack(MSS, MSS);
+      b.on_ecn(cwnd, MSS);
+      assert_eq!(b.cwnd(), cwnd);
+      assert_eq!(b.ssthresh(), usize::MAX);
+      b.on_timeout(cwnd, MSS);
+      assert_eq!(b.cwnd(), MSS);
+      b.undo_timeout();
+      assert_eq!(b.cwnd(), cwnd);
+   }
+
+   #[test]
+   fn stale_min_rtt_triggers_probe_rtt() {
+      let mut b = measured(40_000.0);
+      b.min_rtt_stamp = Instant::now() - MIN_RTT_WINDOW - Duration::from_secs(1);
+      // a larger sample replaces the expired minimum
+      b.on_rtt_sample(2 * RTT);
+      assert!(b.probe_rtt_due);
+      assert_eq!(b.min_rtt, Some(2 * RTT));
+      b.on_ack(MSS, 10 * MSS, MSS);
+      assert_eq!(b.mode, Mode::ProbeRtt);
+      assert_eq!(b.cwnd(), MIN_PIPE * MSS);
+   }
+}
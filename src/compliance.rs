@@ -0,0 +1,156 @@
+//! How strictly segments are held to the RFCs. Permissive mode, the default, takes what
+//! real-world stacks send as long as it can be made sense of: the FIN of a SYN+FIN is
+//! ignored, the data of a segment without ACK is still taken, and data past the peer's FIN
+//! is ignored while the rest of the segment is processed. Strict mode drops every segment
+//! that breaks a MUST, and notes which rule it broke in the audit log.
+
+use std::collections::VecDeque;
+use std::net::SocketAddrV4;
+use std::time::SystemTime;
+
+/// entries kept in the audit log before the oldest are discarded
+const AUDIT_LOG_LEN: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compliance {
+   Permissive,
+   Strict,
+}
+
+impl Default for Compliance {
+   fn default() -> Self {
+      Compliance::Permissive
+   }
+}
+
+/// A protocol rule a segment broke.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+   /// SYN and FIN together
+   SynFin,
+   /// SYN and RST together
+   SynRst,
+   /// no control bits at all
+   NoFlags,
+   /// neither SYN nor RST, and no ACK
+   NoAck,
+   /// data beyond the FIN the peer has already sent
+   DataAfterFin,
+}
+
+impl Rule {
+   /// The rule and where it comes from.
+   pub fn describe(self) -> &'static str {
+      match self {
+         Rule::SynFin => "SYN and FIN in one segment (RFC 793 S3.9: a SYN opens, a FIN closes)",
+         Rule::SynRst => "SYN and RST in one segment (RFC 793 S3.9)",
+         Rule::NoFlags => "no control bits set (RFC 793 S3.9: every segment after the SYN carries ACK)",
+         Rule::NoAck => "ACK bit off outside of SYN and RST (RFC 793 S3.9: drop the segment)",
+         Rule::DataAfterFin => "data beyond the peer's FIN (RFC 793 S3.9: the FIN ends the sequence space)",
+      }
+   }
+}
+
+/// Check the control bits of a segment against the rules the mode enforces.
+pub fn check_flags(mode: Compliance, tcph: &etherparse::TcpHeaderSlice) -> Result<(), Rule> {
+   if mode == Compliance::Permissive {
+      return Ok(());
+   }
+   let (syn, fin, rst, ack) = (tcph.syn(), tcph.fin(), tcph.rst(), tcph.ack());
+   if syn && fin {
+      return Err(Rule::SynFin);
+   }
+   if syn && rst {
+      return Err(Rule::SynRst);
+   }
+   if !(syn || fin || rst || ack || tcph.psh() || tcph.urg()) {
+      return Err(Rule::NoFlags);
+   }
+   if !syn && !rst && !ack {
+      return Err(Rule::NoAck);
+   }
+   Ok(())
+}
+
+/// A segment strict mode dropped.
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+   pub at: SystemTime,
+   pub src: SocketAddrV4,
+   pub dst: SocketAddrV4,
+   pub rule: Rule,
+}
+
+/// The most recent compliance drops.
+#[derive(Debug, Default)]
+pub struct AuditLog {
+   entries: VecDeque<AuditEntry>,
+}
+
+impl AuditLog {
+   pub fn record(&mut self, src: SocketAddrV4, dst: SocketAddrV4, rule: Rule) {
+      if self.entries.len() == AUDIT_LOG_LEN {
+         self.entries.pop_front();
+      }
+      self.entries.push_back(AuditEntry { at: SystemTime::now(), src, dst, rule });
+   }
+
+   pub fn entries(&self) -> Vec<AuditEntry> {
+      self.entries.iter().cloned().collect()
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use std::net::Ipv4Addr;
+
+   const FIN: u8 = 0x01;
+   const SYN: u8 = 0x02;
+   const RST: u8 = 0x04;
+   const PSH: u8 = 0x08;
+   const ACK: u8 = 0x10;
+
+   /// Check a bare TCP header with the given control bits.
+   fn check(mode: Compliance, flags: u8) -> Result<(), Rule> {
+      let mut raw = [0u8; 20];
+      raw[12] = 5 << 4;
+      raw[13] = flags;
+      let tcph = etherparse::TcpHeaderSlice::from_slice(&raw).expect("a valid header");
+      check_flags(mode, &tcph)
+   }
+
+   #[test]
+   fn strict_mode_enforces_the_flag_rules() {
+      let strict = Compliance::Strict;
+      assert_eq!(check(strict, SYN | FIN), Err(Rule::SynFin));
+      assert_eq!(check(strict, SYN | RST), Err(Rule::SynRst));
+      assert_eq!(check(strict, 0), Err(Rule::NoFlags));
+      assert_eq!(check(strict, PSH), Err(Rule::NoAck));
+      assert_eq!(check(strict, FIN), Err(Rule::NoAck));
+      for &ok in [SYN, SYN | ACK, ACK, ACK | PSH, ACK | FIN, RST, RST | ACK].iter() {
+         assert_eq!(check(strict, ok), Ok(()), "flags {:#x}", ok);
+      }
+   }
+
+   #[test]
+   fn permissive_mode_takes_anything() {
+      for &flags in [SYN | FIN, SYN | RST, 0, PSH, FIN].iter() {
+         assert_eq!(check(Compliance::Permissive, flags), Ok(()));
+      }
+   }
+
+   #[test]
+   fn audit_log_keeps_the_latest() {
+      let src = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 50000);
+      let dst = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 80);
+      let mut log = AuditLog::default();
+      log.record(src, dst, Rule::SynFin);
+      for _ in 0..AUDIT_LOG_LEN {
+         log.record(src, dst, Rule::NoAck);
+      }
+      let entries = log.entries();
+      assert_eq!(entries.len(), AUDIT_LOG_LEN);
+      assert!(entries.iter().all(|e| e.rule == Rule::NoAck));
+   }
+}
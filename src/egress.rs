@@ -0,0 +1,360 @@
+use std::io;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::capture::{self, Capture, Direction};
+use crate::device::Device;
+use crate::metrics::{Metrics, RateEvent};
+
+/// MTU assumed when the device's can't be read
+pub const DEFAULT_MTU: usize = 1500;
+/// packets are built and received in buffers of this size, so larger device MTUs are capped
+pub const MAX_MTU: usize = 9216;
+
+/// How urgently a connection's segments should reach the device relative to others.
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub enum Priority {
+   /// control-plane traffic that should never wait behind bulk transfers
+   High,
+   Normal,
+   /// bulk transfers that can yield to everything else
+   Low,
+}
+
+impl Default for Priority {
+   fn default() -> Self {
+      Priority::Normal
+   }
+}
+
+/// Which connection a packet belongs to, and its share of the link under fair queueing.
+#[derive(Debug, Clone, Copy)]
+pub struct Flow {
+   pub id: u64,
+   pub weight: u32,
+}
+
+impl Flow {
+   /// segments sent on behalf of no connection
+   pub const NONE: Flow = Flow { id: 0, weight: 1 };
+}
+
+type Packet = (Vec<u8>, &'static str);
+
+#[derive(Default)]
+struct FlowQueue {
+   packets: VecDeque<Packet>,
+   weight: u32,
+   /// bytes this flow may still send in the current round
+   deficit: usize,
+}
+
+/// The packets of one priority class.
+#[derive(Default)]
+struct Class {
+   fifo: VecDeque<Packet>,
+   /// per-flow queues, under fair queueing
+   flows: HashMap<u64, FlowQueue>,
+   /// flows with packets queued, in round-robin order
+   active: VecDeque<u64>,
+}
+
+/// Segments ready to be written to the device. Connections queue their output here and the
+/// interface flushes it once it is done processing an event, so when several connections
+/// have segments ready at the same time the higher priority classes go out first. Each
+/// segment carries the state of its connection when it was queued, for capture.
+///
+/// Within a class packets go out in the order they were queued, unless fair queueing is on:
+/// then each connection gets its own queue and the queues are served by deficit round robin,
+/// so concurrent bulk transfers share the device in proportion to their weights rather than
+/// whoever queued the most going first.
+///
+/// A flush first lines everything up in one batch, in transmission order, and then writes
+/// the batch out in one go.
+///
+/// Packet buffers are recycled once written out, so steady-state sending doesn't allocate.
+///
+/// Retransmission timeouts across all connections can be limited to a rate, so that when the
+/// device comes back after an outage the connections that timed out meanwhile don't all
+/// resend at once and flood it again.
+#[derive(Default)]
+pub struct Egress {
+   high: Class,
+   normal: Class,
+   low: Class,
+   /// packets taken off the queues but not written yet, in transmission order
+   batch: Vec<Packet>,
+   free: Vec<Vec<u8>>,
+   /// bytes a flow of weight 1 may send per round, if fair queueing is on
+   quantum: Option<usize>,
+   /// largest packet the device takes, if it could be read
+   mtu: Option<usize>,
+   /// timeout retransmissions allowed, if they are limited
+   rto_budget: Option<TokenBucket>,
+}
+
+/// Allows `rate` events per second, in bursts of up to a second's worth.
+struct TokenBucket {
+   rate: f64,
+   tokens: f64,
+   refilled: Instant,
+}
+
+impl TokenBucket {
+   fn new(rate: u32) -> Self {
+      TokenBucket { rate: rate as f64, tokens: rate as f64, refilled: Instant::now() }
+   }
+
+   /// Take a token, or say how long until one is available.
+   fn take(&mut self, now: Instant) -> Result<(), Duration> {
+      let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+      self.tokens = f64::min(self.tokens + elapsed * self.rate, self.rate);
+      self.refilled = now;
+      if self.tokens >= 1.0 {
+         self.tokens -= 1.0;
+         Ok(())
+      } else {
+         Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+      }
+   }
+}
+
+impl Egress {
+   pub fn push(&mut self, prio: Priority, flow: Flow, state: &'static str, packet: &[u8]) {
+      let mut buf = self.buffer();
+      buf.extend_from_slice(packet);
+      self.push_owned(prio, flow, state, buf);
+   }
+
+   /// Queue a packet already built in a buffer from `buffer()`.
+   pub fn push_owned(&mut self, prio: Priority, flow: Flow, state: &'static str, packet: Vec<u8>) {
+      let fair = self.quantum.is_some();
+      let class = self.class(prio);
+      if !fair {
+         class.fifo.push_back((packet, state));
+         return;
+      }
+      let q = class.flows.entry(flow.id).or_default();
+      q.weight = flow.weight;
+      if q.packets.is_empty() {
+         class.active.push_back(flow.id);
+      }
+      q.packets.push_back((packet, state));
+   }
+
+   /// An empty packet buffer, reused from packets already sent if possible.
+   pub fn buffer(&mut self) -> Vec<u8> {
+      let mtu = self.mtu();
+      let mut buf = self.free.pop().unwrap_or_else(|| Vec::with_capacity(mtu));
+      buf.clear();
+      buf
+   }
+
+   /// Largest packet the device takes.
+   pub fn mtu(&self) -> usize {
+      self.mtu.unwrap_or(DEFAULT_MTU)
+   }
+
+   pub fn set_mtu(&mut self, mtu: usize) {
+      self.mtu = Some(std::cmp::min(mtu, MAX_MTU));
+   }
+
+   /// Limit retransmission timeouts across all connections to `per_second`, or lift the
+   /// limit with `None`.
+   pub fn set_retransmit_limit(&mut self, per_second: Option<u32>) {
+      self.rto_budget = per_second.filter(|&r| r > 0).map(TokenBucket::new);
+   }
+
+   /// Whether a connection may retransmit after a timeout now; if not, how long until the
+   /// next one may.
+   pub fn admit_retransmit(&mut self, now: Instant) -> Result<(), Duration> {
+      match self.rto_budget.as_mut() {
+         Some(budget) => budget.take(now),
+         None => Ok(()),
+      }
+   }
+
+   /// Turn fair queueing on with the given quantum in bytes, or off with `None`.
+   pub fn set_fair_queueing(&mut self, quantum: Option<usize>) {
+      self.quantum = quantum;
+   }
+
+   /// Forget the queue state of a connection that has gone away.
+   pub fn forget(&mut self, flow: u64) {
+      for &prio in [Priority::High, Priority::Normal, Priority::Low].iter() {
+         let class = self.class(prio);
+         if class.flows.get(&flow).map_or(false, |q| q.packets.is_empty()) {
+            class.flows.remove(&flow);
+         }
+      }
+   }
+
+   fn class(&mut self, prio: Priority) -> &mut Class {
+      match prio {
+         Priority::High => &mut self.high,
+         Priority::Normal => &mut self.normal,
+         Priority::Low => &mut self.low,
+      }
+   }
+
+   /// Write out everything queued, highest priority first.
+   pub fn flush(
+      &mut self,
+      nic: &Device,
+      cap: &mut Option<Capture>,
+      metrics: &mut Metrics,
+   ) -> io::Result<()> {
+      self.collect();
+      send_batch(nic, cap, metrics, &mut self.batch, &mut self.free)
+   }
+
+   /// Take everything queued, highest priority first, instead of writing it to a device.
+   #[cfg(test)]
+   pub fn drain(&mut self) -> Vec<Vec<u8>> {
+      self.collect();
+      self.batch.drain(..).map(|(packet, _)| packet).collect()
+   }
+
+   /// Move everything queued to the end of the batch.
+   fn collect(&mut self) {
+      let quantum = self.quantum.unwrap_or(0);
+      for &prio in [Priority::High, Priority::Normal, Priority::Low].iter() {
+         let class = match prio {
+            Priority::High => &mut self.high,
+            Priority::Normal => &mut self.normal,
+            Priority::Low => &mut self.low,
+         };
+         // left over from before fair queueing was switched on, if nothing else
+         self.batch.extend(class.fifo.drain(..));
+         while let Some(id) = class.active.pop_front() {
+            let q = class.flows.get_mut(&id).expect("active flows have a queue");
+            q.deficit += std::cmp::max(quantum, 1) * std::cmp::max(q.weight, 1) as usize;
+            while q.packets.front().map_or(false, |(p, _)| p.len() <= q.deficit) {
+               let packet = q.packets.pop_front().unwrap();
+               q.deficit -= packet.0.len();
+               self.batch.push(packet);
+            }
+            if q.packets.is_empty() {
+               q.deficit = 0;
+            } else {
+               class.active.push_back(id);
+            }
+         }
+      }
+   }
+}
+
+/// Write out a batch with as few syscalls as the device allows. A TUN device takes exactly
+/// one packet per write, unless GSO lets a run of segments go as one super-packet, but
+/// either way the queues are walked once per flush rather than once per packet. If a write
+/// fails, it and everything after it stay in the batch for the next flush.
+fn send_batch(
+   nic: &Device,
+   cap: &mut Option<Capture>,
+   metrics: &mut Metrics,
+   batch: &mut Vec<Packet>,
+   free: &mut Vec<Vec<u8>>,
+) -> io::Result<()> {
+   let mut written = 0;
+   let mut result = Ok(());
+   while written < batch.len() {
+      let n = match nic.send_run(batch[written..].iter().map(|(packet, _)| &packet[..])) {
+         Ok(n) => n,
+         Err(e) => {
+            result = Err(e);
+            break;
+         }
+      };
+      for (packet, state) in &batch[written..written + n] {
+         capture::record(cap, Direction::Out, state, packet);
+         if is_rst(packet) {
+            metrics.record(RateEvent::RstSent, Instant::now());
+         }
+      }
+      written += n;
+   }
+   free.extend(batch.drain(..written).map(|(packet, _)| packet));
+   result
+}
+
+fn is_rst(packet: &[u8]) -> bool {
+   let ihl = (packet[0] & 0x0f) as usize * 4;
+   packet.get(ihl + 13).map_or(false, |flags| flags & 0x04 != 0)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn flow(id: u64, weight: u32) -> Flow {
+      Flow { id, weight }
+   }
+
+   /// Queue `n` packets of `len` bytes for `f`, each filled with its flow id.
+   fn queue(e: &mut Egress, prio: Priority, f: Flow, n: usize, len: usize) {
+      for _ in 0..n {
+         e.push(prio, f, "ESTABLISHED", &vec![f.id as u8; len]);
+      }
+   }
+
+   /// The flow of each packet drained, in order.
+   fn order(e: &mut Egress) -> Vec<u8> {
+      e.drain().iter().map(|p| p[0]).collect()
+   }
+
+   #[test]
+   fn without_fair_queueing_packets_keep_their_order() {
+      let mut e = Egress::default();
+      queue(&mut e, Priority::Normal, flow(1, 1), 3, 100);
+      queue(&mut e, Priority::Normal, flow(2, 1), 1, 100);
+      assert_eq!(order(&mut e), vec![1, 1, 1, 2]);
+   }
+
+   #[test]
+   fn equal_flows_take_turns() {
+      let mut e = Egress::default();
+      e.set_fair_queueing(Some(1000));
+      queue(&mut e, Priority::Normal, flow(1, 1), 3, 1000);
+      queue(&mut e, Priority::Normal, flow(2, 1), 3, 1000);
+      assert_eq!(order(&mut e), vec![1, 2, 1, 2, 1, 2]);
+   }
+
+   #[test]
+   fn weights_set_the_share() {
+      let mut e = Egress::default();
+      e.set_fair_queueing(Some(1000));
+      queue(&mut e, Priority::Normal, flow(1, 2), 4, 1000);
+      queue(&mut e, Priority::Normal, flow(2, 1), 2, 1000);
+      assert_eq!(order(&mut e), vec![1, 1, 2, 1, 1, 2]);
+   }
+
+   #[test]
+   fn small_packets_share_a_quantum() {
+      let mut e = Egress::default();
+      e.set_fair_queueing(Some(1000));
+      // bytes count, not packets: four 250-byte packets are one 1000-byte one
+      queue(&mut e, Priority::Normal, flow(1, 1), 8, 250);
+      queue(&mut e, Priority::Normal, flow(2, 1), 2, 1000);
+      assert_eq!(order(&mut e), vec![1, 1, 1, 1, 2, 1, 1, 1, 1, 2]);
+   }
+
+   #[test]
+   fn large_packets_wait_for_enough_deficit() {
+      let mut e = Egress::default();
+      e.set_fair_queueing(Some(500));
+      // a 1500-byte packet takes three rounds' worth of quantum
+      queue(&mut e, Priority::Normal, flow(1, 1), 2, 1500);
+      queue(&mut e, Priority::Normal, flow(2, 1), 6, 500);
+      assert_eq!(order(&mut e), vec![2, 2, 1, 2, 2, 2, 1, 2]);
+   }
+
+   #[test]
+   fn higher_classes_go_first() {
+      let mut e = Egress::default();
+      e.set_fair_queueing(Some(1000));
+      queue(&mut e, Priority::Low, flow(3, 1), 1, 100);
+      queue(&mut e, Priority::Normal, flow(2, 1), 1, 100);
+      queue(&mut e, Priority::High, flow(1, 1), 1, 100);
+      assert_eq!(order(&mut e), vec![1, 2, 3]);
+   }
+}
@@ -0,0 +1,172 @@
+//! TCP Fast Open (RFC 7413), server side. A client that has a cookie from an earlier
+//! connection may put data on its SYN; if the cookie checks out the data is taken right
+//! away, saving the application a round trip. Cookies are a keyed hash of the client's
+//! address, so they cost no state to hand out, and the key can be rotated: cookies made with
+//! the previous key are still honoured, and answered with a fresh one.
+//!
+//! SYN data can be replayed by anyone who captured it, so the same SYN seen twice within the
+//! replay window only gets its data taken the first time.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+pub const COOKIE_LEN: usize = 8;
+/// how long a SYN whose data was taken is remembered
+const REPLAY_WINDOW: Duration = Duration::from_secs(60);
+/// most SYNs remembered at once; beyond this the oldest are forgotten early
+const REPLAY_ENTRIES: usize = 64 * 1024;
+
+pub type Cookie = [u8; COOKIE_LEN];
+
+/// What to do with a SYN that carries the Fast Open option.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Verdict {
+   /// take the data on the SYN
+   pub accept_data: bool,
+   /// put this cookie in the SYN-ACK
+   pub cookie: Option<Cookie>,
+}
+
+pub struct FastOpen {
+   key: RandomState,
+   previous: Option<RandomState>,
+   /// SYNs whose data was taken, oldest first, by client and initial sequence number
+   seen: VecDeque<(Instant, SocketAddrV4, u32)>,
+   seen_set: HashSet<(SocketAddrV4, u32)>,
+}
+
+impl Default for FastOpen {
+   fn default() -> Self {
+      FastOpen {
+         key: RandomState::new(),
+         previous: None,
+         seen: VecDeque::new(),
+         seen_set: HashSet::new(),
+      }
+   }
+}
+
+impl FastOpen {
+   /// Start issuing cookies under a new key. Cookies under the current one stay valid until
+   /// the next rotation.
+   pub fn rotate(&mut self) {
+      self.previous = Some(std::mem::replace(&mut self.key, RandomState::new()));
+   }
+
+   /// Judge a SYN from `client` with initial sequence number `isn` whose Fast Open option
+   /// holds `cookie` (empty for a cookie request).
+   pub fn check(&mut self, client: SocketAddrV4, isn: u32, cookie: &[u8], now: Instant) -> Verdict {
+      let ip = *client.ip();
+      let current = make_cookie(&self.key, ip);
+      if cookie == current {
+         return Verdict { accept_data: self.first_sighting(client, isn, now), cookie: None };
+      }
+      if let Some(previous) = &self.previous {
+         if cookie == make_cookie(previous, ip) {
+            let accept_data = self.first_sighting(client, isn, now);
+            return Verdict { accept_data, cookie: Some(current) };
+         }
+      }
+      // a cookie request, or a cookie that doesn't check out: the data waits for the
+      // handshake, and the client gets a cookie for next time
+      Verdict { accept_data: false, cookie: Some(current) }
+   }
+
+   fn first_sighting(&mut self, client: SocketAddrV4, isn: u32, now: Instant) -> bool {
+      while let Some(&(at, c, i)) = self.seen.front() {
+         if now.saturating_duration_since(at) < REPLAY_WINDOW && self.seen.len() < REPLAY_ENTRIES {
+            break;
+         }
+         self.seen.pop_front();
+         self.seen_set.remove(&(c, i));
+      }
+      if !self.seen_set.insert((client, isn)) {
+         return false;
+      }
+      self.seen.push_back((now, client, isn));
+      true
+   }
+}
+
+fn make_cookie(key: &RandomState, ip: Ipv4Addr) -> Cookie {
+   let mut h = key.build_hasher();
+   ip.hash(&mut h);
+   h.finish().to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn client() -> SocketAddrV4 {
+      SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 50000)
+   }
+
+   /// A cookie for `client()`, as a cookie request would get it.
+   fn cookie(f: &mut FastOpen, now: Instant) -> Cookie {
+      let v = f.check(client(), 1, &[], now);
+      assert!(!v.accept_data);
+      v.cookie.expect("a cookie request gets a cookie")
+   }
+
+   #[test]
+   fn valid_cookie_takes_the_data() {
+      let now = Instant::now();
+      let mut f = FastOpen::default();
+      let c = cookie(&mut f, now);
+      let v = f.check(client(), 100, &c, now);
+      assert!(v.accept_data);
+      // nothing new to hand out
+      assert_eq!(v.cookie, None);
+      // the cookie is for the address, whatever the port
+      let other_port = SocketAddrV4::new(*client().ip(), 50001);
+      assert!(f.check(other_port, 200, &c, now).accept_data);
+   }
+
+   #[test]
+   fn invalid_cookie_waits_for_the_handshake() {
+      let now = Instant::now();
+      let mut f = FastOpen::default();
+      let mut c = cookie(&mut f, now);
+      c[0] ^= 1;
+      let v = f.check(client(), 100, &c, now);
+      assert!(!v.accept_data);
+      assert!(v.cookie.is_some());
+      // nor is another address's cookie any good
+      let other = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 9), 50000);
+      let c = cookie(&mut f, now);
+      assert!(!f.check(other, 100, &c, now).accept_data);
+   }
+
+   #[test]
+   fn rotation_honours_the_previous_key_once() {
+      let now = Instant::now();
+      let mut f = FastOpen::default();
+      let old = cookie(&mut f, now);
+      f.rotate();
+      // still good, and answered with a cookie under the new key
+      let v = f.check(client(), 100, &old, now);
+      assert!(v.accept_data);
+      let new = v.cookie.expect("a fresh cookie");
+      assert_ne!(new, old);
+      assert!(f.check(client(), 101, &new, now).accept_data);
+      // two rotations on, the old key is gone
+      f.rotate();
+      assert!(f.check(client(), 102, &new, now).accept_data);
+      assert!(!f.check(client(), 103, &old, now).accept_data);
+   }
+
+   #[test]
+   fn replayed_syn_data_is_taken_once() {
+      let now = Instant::now();
+      let mut f = FastOpen::default();
+      let c = cookie(&mut f, now);
+      assert!(f.check(client(), 100, &c, now).accept_data);
+      assert!(!f.check(client(), 100, &c, now).accept_data);
+      // until it is out of the replay window
+      assert!(f.check(client(), 100, &c, now + REPLAY_WINDOW).accept_data);
+   }
+}
@@ -0,0 +1,179 @@
+use std::io;
+
+use crate::{listener_mut, ConnectionManager, Quad, ResetReason};
+
+/// What an accept filter decided about a new connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterVerdict {
+   /// hand the connection to this listener's accept()
+   Accept,
+   /// reset the connection
+   Reject,
+   /// hand the connection to the accept() of the listener bound to this port instead
+   Route(u16),
+}
+
+pub type AcceptFilter = Box<dyn FnMut(&[u8]) -> FilterVerdict + Send>;
+
+/// Run the accept filter for `q` if it is a deferred connection that has received its first
+/// data. Returns whether the connection was queued for accept().
+pub fn run(cm: &mut ConnectionManager, q: Quad) -> io::Result<bool> {
+   let l = match listener_mut(&mut cm.listeners, q.dst.1) {
+      Some(l) => l,
+      None => return Ok(false),
+   };
+   let i = match l.deferred.iter().position(|d| *d == q) {
+      Some(i) => i,
+      None => return Ok(false),
+   };
+   let c = match cm.connections.get_mut(&q) {
+      Some(c) => c,
+      None => return Ok(false),
+   };
+   if c.incoming.is_empty() {
+      return Ok(false);
+   }
+
+   l.deferred.swap_remove(i);
+   let verdict = match l.filter.as_mut() {
+      Some(f) => f(c.incoming.make_contiguous()),
+      None => FilterVerdict::Accept,
+   };
+   let target = match verdict {
+      FilterVerdict::Accept => q.dst.1,
+      FilterVerdict::Route(port) => port,
+      FilterVerdict::Reject => {
+         c.reset(&mut cm.egress, ResetReason::Rejected)?;
+         return Ok(false);
+      }
+   };
+   match listener_mut(&mut cm.listeners, target) {
+      Some(l) => {
+         l.pending.push_back(q);
+         Ok(true)
+      }
+      None => {
+         // nobody to route to
+         c.reset(&mut cm.egress, ResetReason::NoRoute)?;
+         Ok(false)
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use std::collections::VecDeque;
+   use std::net::{Ipv4Addr, SocketAddrV4};
+
+   use crate::egress::Egress;
+   use crate::fastopen::FastOpen;
+   use crate::tcp::{Connection, ListenOptions};
+   use crate::Listener;
+
+   fn client() -> SocketAddrV4 {
+      SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 50000)
+   }
+
+   fn server() -> SocketAddrV4 {
+      SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 9000)
+   }
+
+   fn opts() -> ListenOptions {
+      ListenOptions { delayed_ack: None, ..ListenOptions::default() }
+   }
+
+   fn split(p: &[u8]) -> (etherparse::Ipv4HeaderSlice<'_>, etherparse::TcpHeaderSlice<'_>, &[u8]) {
+      let iph = etherparse::Ipv4HeaderSlice::from_slice(p).unwrap();
+      let tcph = etherparse::TcpHeaderSlice::from_slice(&p[20..]).unwrap();
+      let data = &p[20 + tcph.slice().len()..];
+      (iph, tcph, data)
+   }
+
+   /// Hand everything queued in `from` to `to`.
+   fn deliver(from: &mut Egress, to: &mut Connection, egress: &mut Egress) {
+      for p in from.drain() {
+         let (iph, tcph, data) = split(&p);
+         to.on_packet(egress, iph, tcph, data).unwrap();
+      }
+   }
+
+   /// A connection manager whose listener on the server port has the accept filter
+   /// `filter`, and a connection to it that is waiting for the filter after its first data,
+   /// `request`, came in.
+   fn deferred(filter: AcceptFilter, request: &[u8]) -> (ConnectionManager, Quad, Connection, Egress) {
+      let mut cm = ConnectionManager::default();
+      cm.egress.set_mtu(1500);
+      let mut egress = Egress::default();
+      egress.set_mtu(1500);
+      let mut a = Connection::connect(&mut egress, &opts(), client(), server()).unwrap();
+      let syn = egress.drain().pop().expect("a SYN");
+      let (iph, tcph, data) = split(&syn);
+      let mut b = Connection::accept(&mut cm.egress, &opts(), &mut FastOpen::default(), iph, tcph, data)
+         .unwrap()
+         .expect("the SYN is accepted");
+      deliver(&mut cm.egress, &mut a, &mut egress);
+      a.enqueue(&mut egress, request).unwrap();
+      deliver(&mut egress, &mut b, &mut cm.egress);
+      assert_eq!(b.incoming.len(), request.len());
+      cm.egress.drain();
+
+      let q = Quad::from_addrs(server(), client());
+      cm.connections.insert(q, b);
+      cm.listeners.insert(server().port(), Listener {
+         ports: server().port()..=server().port(),
+         opts: opts(),
+         embryonic: Vec::new(),
+         pending: VecDeque::new(),
+         paused: false,
+         filter: Some(filter),
+         deferred: vec![q],
+      });
+      (cm, q, a, egress)
+   }
+
+   fn is_rst(p: &[u8]) -> bool {
+      p[33] & 0x04 != 0
+   }
+
+   #[test]
+   fn accepted_connection_is_queued() {
+      let filter = Box::new(|data: &[u8]| {
+         assert_eq!(data, b"GET / HTTP/1.1\r\n");
+         FilterVerdict::Accept
+      });
+      let (mut cm, q, _, _) = deferred(filter, b"GET / HTTP/1.1\r\n");
+      assert!(run(&mut cm, q).unwrap());
+      let l = &cm.listeners[&server().port()];
+      assert!(l.deferred.is_empty());
+      assert_eq!(l.pending, vec![q]);
+      assert!(!cm.egress.drain().iter().any(|p| is_rst(p)));
+      // the filter runs once
+      assert!(!run(&mut cm, q).unwrap());
+   }
+
+   #[test]
+   fn rejected_connection_is_reset() {
+      let (mut cm, q, mut a, mut egress) = deferred(Box::new(|_: &[u8]| FilterVerdict::Reject), b"junk");
+      assert!(!run(&mut cm, q).unwrap());
+      let l = &cm.listeners[&server().port()];
+      assert!(l.deferred.is_empty() && l.pending.is_empty());
+      assert_eq!(cm.connections[&q].reset_reason(), Some(ResetReason::Rejected));
+      let sent = cm.egress.drain();
+      assert_eq!(sent.iter().filter(|p| is_rst(p)).count(), 1);
+      // and the client hears about it
+      for p in sent {
+         let (iph, tcph, data) = split(&p);
+         a.on_packet(&mut egress, iph, tcph, data).unwrap();
+      }
+      assert!(a.is_closed());
+   }
+
+   #[test]
+   fn connection_routed_nowhere_is_reset() {
+      let (mut cm, q, _, _) = deferred(Box::new(|_: &[u8]| FilterVerdict::Route(9001)), b"x");
+      assert!(!run(&mut cm, q).unwrap());
+      assert_eq!(cm.connections[&q].reset_reason(), Some(ResetReason::NoRoute));
+      assert!(cm.listeners[&server().port()].pending.is_empty());
+   }
+}
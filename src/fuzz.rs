@@ -0,0 +1,164 @@
+//! Structure-aware fuzzing support. Fuzzers that only mutate raw bytes spend nearly all
+//! their time on packets sanitize::check() throws out; these types let them generate
+//! segments, option sets and whole sequences of events instead, and turn them into wire
+//! format with `build()`.
+//!
+//! Everything is laid out to shrink well: sequence and acknowledgment numbers are offsets
+//! from what the receiver expects, so a shrunk input means "in window" rather than some
+//! arbitrary number, and all-zero values are the unremarkable case (no flags, no options,
+//! no payload, in order).
+
+use std::net::SocketAddrV4;
+
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::tcp::SegmentFlags;
+
+/// payloads are cut to this, so inputs stay small and segments fit the device MTU
+const MAX_PAYLOAD: usize = 1400;
+
+/// One TCP option.
+#[derive(Debug, Clone, Arbitrary)]
+pub enum TcpOption {
+   Nop,
+   Mss(u16),
+   WindowScale(u8),
+   SackPermitted,
+   /// SACK blocks as offsets from the acknowledgment number, at most four of them
+   Sack(Vec<(u16, u16)>),
+   Timestamps(u32, u32),
+   /// anything else, for exercising the option parser
+   Unknown { kind: u8, data: Vec<u8> },
+}
+
+/// The options of one segment.
+#[derive(Debug, Clone, Default, Arbitrary)]
+pub struct OptionSet(pub Vec<TcpOption>);
+
+impl OptionSet {
+   /// Wire format, padded with end-of-list to a multiple of four bytes and cut at the 40
+   /// bytes the header has room for. `ack` anchors SACK blocks.
+   pub fn encode(&self, ack: u32) -> Vec<u8> {
+      let mut out = Vec::new();
+      for opt in &self.0 {
+         match opt {
+            TcpOption::Nop => out.push(1),
+            TcpOption::Mss(mss) => {
+               out.extend_from_slice(&[2, 4]);
+               out.extend_from_slice(&mss.to_be_bytes());
+            }
+            TcpOption::WindowScale(shift) => out.extend_from_slice(&[3, 3, *shift]),
+            TcpOption::SackPermitted => out.extend_from_slice(&[4, 2]),
+            TcpOption::Sack(blocks) => {
+               let blocks = &blocks[..std::cmp::min(blocks.len(), 4)];
+               out.extend_from_slice(&[5, 2 + 8 * blocks.len() as u8]);
+               for &(left, len) in blocks {
+                  let left = ack.wrapping_add(left as u32);
+                  out.extend_from_slice(&left.to_be_bytes());
+                  out.extend_from_slice(&left.wrapping_add(len as u32).to_be_bytes());
+               }
+            }
+            TcpOption::Timestamps(val, ecr) => {
+               out.extend_from_slice(&[8, 10]);
+               out.extend_from_slice(&val.to_be_bytes());
+               out.extend_from_slice(&ecr.to_be_bytes());
+            }
+            TcpOption::Unknown { kind, data } => {
+               let data = &data[..std::cmp::min(data.len(), 38)];
+               out.extend_from_slice(&[*kind, 2 + data.len() as u8]);
+               out.extend_from_slice(data);
+            }
+         }
+      }
+      out.truncate(40);
+      while out.len() % 4 != 0 {
+         out.push(0);
+      }
+      out
+   }
+}
+
+/// A segment from the peer, relative to the connection state it is sent into.
+#[derive(Debug, Clone, Default)]
+pub struct SegmentDesc {
+   pub flags: SegmentFlags,
+   /// added to the receiver's RCV.NXT
+   pub seq_offset: i32,
+   /// added to the receiver's SND.NXT
+   pub ack_offset: i32,
+   pub window: u16,
+   pub options: OptionSet,
+   pub payload: Vec<u8>,
+}
+
+impl<'a> Arbitrary<'a> for SegmentDesc {
+   fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+      let flags = SegmentFlags {
+         syn: u.arbitrary()?,
+         ack: u.arbitrary()?,
+         fin: u.arbitrary()?,
+         rst: u.arbitrary()?,
+         psh: u.arbitrary()?,
+         urg: u.arbitrary()?,
+      };
+      // mostly near the window, where the interesting decisions are made
+      let seq_offset = if u.ratio(7, 8)? { u.int_in_range(-2048..=2048)? } else { u.arbitrary()? };
+      let ack_offset = if u.ratio(7, 8)? { u.int_in_range(-2048..=16)? } else { u.arbitrary()? };
+      let window = u.arbitrary()?;
+      let options = u.arbitrary()?;
+      let len = u.int_in_range(0..=MAX_PAYLOAD)?;
+      let payload = u.bytes(std::cmp::min(len, u.len()))?.to_vec();
+      Ok(SegmentDesc { flags, seq_offset, ack_offset, window, options, payload })
+   }
+}
+
+impl SegmentDesc {
+   /// The IPv4 packet for this segment from `src` to `dst`, given the receiver's RCV.NXT
+   /// and SND.NXT.
+   pub fn build(&self, src: SocketAddrV4, dst: SocketAddrV4, rcv_nxt: u32, snd_nxt: u32) -> Vec<u8> {
+      let seq = rcv_nxt.wrapping_add(self.seq_offset as u32);
+      let ack = snd_nxt.wrapping_add(self.ack_offset as u32);
+      let mut tcp = etherparse::TcpHeader::new(src.port(), dst.port(), seq, self.window);
+      tcp.acknowledgment_number = ack;
+      tcp.syn = self.flags.syn;
+      tcp.ack = self.flags.ack;
+      tcp.fin = self.flags.fin;
+      tcp.rst = self.flags.rst;
+      tcp.psh = self.flags.psh;
+      tcp.urg = self.flags.urg;
+      tcp.set_options_raw(&self.options.encode(ack)).expect("options are cut to fit");
+      let payload = &self.payload[..std::cmp::min(self.payload.len(), MAX_PAYLOAD)];
+      let ip = etherparse::Ipv4Header::new(
+         tcp.header_len() + payload.len() as u16,
+         64,
+         etherparse::IpTrafficClass::Tcp,
+         src.ip().octets(),
+         dst.ip().octets(),
+      );
+      tcp.checksum = tcp.calc_checksum_ipv4(&ip, payload).expect("payload fits a packet");
+      let mut buf = Vec::with_capacity(ip.header_len() + tcp.header_len() as usize + payload.len());
+      ip.write(&mut buf).expect("writing to a Vec cannot fail");
+      tcp.write(&mut buf).expect("writing to a Vec cannot fail");
+      buf.extend_from_slice(payload);
+      buf
+   }
+}
+
+/// Something that happens to a connection.
+#[derive(Debug, Clone, Arbitrary)]
+pub enum FuzzEvent {
+   /// a segment arrives from the peer
+   Segment(SegmentDesc),
+   /// time passes, in milliseconds
+   Advance(u16),
+   /// the application writes this many bytes
+   Write(u16),
+   /// the application reads up to this many bytes
+   Read(u16),
+   /// the application closes its end
+   Close,
+}
+
+/// A connection's life as a sequence of events, starting from an established connection.
+#[derive(Debug, Clone, Default, Arbitrary)]
+pub struct Scenario(pub Vec<FuzzEvent>);
@@ -0,0 +1,55 @@
+//! Goodput estimates: application bytes that made it across, acknowledged by the peer on the
+//! way out or received in order on the way in, averaged over a sliding window. Unlike the
+//! congestion window or BBR's model this counts only useful data, so it is what adaptive
+//! bitrate or load shedding decisions want to look at.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// the window rates are averaged over
+const WINDOW: Duration = Duration::from_secs(1);
+/// the window is kept as this many buckets, so memory stays bounded however many segments
+/// come along
+const BUCKETS: u32 = 10;
+
+#[derive(Debug)]
+pub struct Goodput {
+   /// when counting started; rates are averaged over less than the window before it fills
+   start: Instant,
+   /// start of each bucket and the bytes counted in it, oldest first
+   buckets: VecDeque<(Instant, u64)>,
+}
+
+impl Goodput {
+   pub fn new(now: Instant) -> Self {
+      Goodput { start: now, buckets: VecDeque::with_capacity(BUCKETS as usize + 1) }
+   }
+
+   pub fn record(&mut self, bytes: u64, now: Instant) {
+      self.expire(now);
+      match self.buckets.back_mut() {
+         Some((at, n)) if now.saturating_duration_since(*at) < WINDOW / BUCKETS => *n += bytes,
+         _ => self.buckets.push_back((now, bytes)),
+      }
+   }
+
+   /// Bytes per second over the last window.
+   pub fn rate(&self, now: Instant) -> f64 {
+      let span = std::cmp::min(now.saturating_duration_since(self.start), WINDOW);
+      if span == Duration::from_secs(0) {
+         return 0.0;
+      }
+      let bytes: u64 = self.buckets
+         .iter()
+         .filter(|&&(at, _)| now.saturating_duration_since(at) < WINDOW)
+         .map(|&(_, n)| n)
+         .sum();
+      bytes as f64 / span.as_secs_f64()
+   }
+
+   fn expire(&mut self, now: Instant) {
+      while self.buckets.front().map_or(false, |&(at, _)| now.saturating_duration_since(at) >= WINDOW) {
+         self.buckets.pop_front();
+      }
+   }
+}
@@ -0,0 +1,1673 @@
+extern crate etherparse;
+use std::io;
+use std::io::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4};
+use std::ops::RangeInclusive;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time;
+
+mod addrs;
+#[cfg(feature = "alloc-audit")]
+mod alloc_audit;
+#[cfg(feature = "benchmarks")]
+#[doc(hidden)]
+pub mod bench;
+mod capture;
+mod cc;
+mod compliance;
+mod capture_filter;
+mod device;
+mod egress;
+mod fastopen;
+mod filter;
+#[cfg(feature = "fuzzing")]
+mod fuzz;
+mod goodput;
+mod metrics;
+mod mptcp;
+mod options;
+mod plpmtud;
+mod reassembly;
+mod resets;
+mod rpf;
+mod sanitize;
+mod sendbuf;
+#[cfg(test)]
+mod soak;
+mod synproxy;
+mod tcp;
+mod timer;
+mod tsg;
+
+pub use cc::CongestionAlgorithm;
+pub use compliance::{AuditEntry, Compliance, Rule};
+pub use egress::Priority;
+pub use filter::FilterVerdict;
+pub use metrics::{RateEvent, RateReport, Rates};
+pub use resets::{ResetEntry, ResetReason};
+pub use sanitize::DropCounters;
+pub use tcp::{ConnectionInfo, HandshakeInfo, ListenOptions, RepairState};
+pub use tsg::time_sequence_graph;
+#[cfg(feature = "tooling")]
+pub use tcp::SegmentFlags;
+#[cfg(feature = "fuzzing")]
+pub use fuzz::{FuzzEvent, OptionSet, Scenario, SegmentDesc, TcpOption};
+
+#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq, Ord, PartialOrd)]
+struct Quad{
+   src: (Ipv4Addr, u16),
+   dst: (Ipv4Addr, u16)
+}
+
+impl Quad {
+   fn from_addrs(local: SocketAddrV4, remote: SocketAddrV4) -> Self {
+      Quad {
+         src: (*remote.ip(), remote.port()),
+         dst: (*local.ip(), local.port()),
+      }
+   }
+
+   fn local(&self) -> SocketAddrV4 {
+      SocketAddrV4::new(self.dst.0, self.dst.1)
+   }
+
+   fn remote(&self) -> SocketAddrV4 {
+      SocketAddrV4::new(self.src.0, self.src.1)
+   }
+}
+
+fn to_socket_addr((ip, port): (Ipv4Addr, u16)) -> SocketAddr {
+   SocketAddr::V4(SocketAddrV4::new(ip, port))
+}
+
+fn terminated() -> io::Error {
+   io::Error::new(io::ErrorKind::ConnectionAborted, "stream was terminated unexpectedly")
+}
+
+fn interface_gone() -> io::Error {
+   io::Error::new(io::ErrorKind::NotConnected, "interface has shut down")
+}
+
+fn no_such_connection() -> io::Error {
+   io::Error::new(io::ErrorKind::NotFound, "no such connection")
+}
+
+/// local ports for active opens (RFC 6335 S6)
+const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// State shared between the packet loop and the user-facing handles.
+struct Shared {
+   nic: device::Device,
+   manager: Mutex<ConnectionManager>,
+   /// signalled whenever a listener gets a new pending connection
+   pending_var: Condvar,
+   /// signalled whenever connections receive data or go away
+   rcv_var: Condvar,
+   /// signalled whenever a connection may have gotten an earlier timer deadline
+   tick_var: Condvar,
+   /// written to whenever either condvar is signalled, so foreign event loops can wait on
+   /// `event_rx` instead of polling
+   event_tx: UnixStream,
+   event_rx: UnixStream,
+   /// written to when the interface is dropped, to get the threads reading the device out
+   /// of their wait for the next packet
+   wake_tx: UnixStream,
+   wake_rx: UnixStream,
+   /// packets the reader dropped because the ingress worker's queue was full
+   ingress_drops: AtomicU64,
+}
+
+/// How packets read from the device get to the connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ingress {
+   /// processed in the thread that reads the device, for the lowest latency
+   Inline,
+   /// handed to a worker thread through a queue of this many packets, so a slow stretch
+   /// of processing doesn't hold up reading the device and latency stays smoother under
+   /// load; packets that find the queue full are dropped and counted
+   Worker(usize),
+}
+
+impl Default for Ingress {
+   fn default() -> Self {
+      Ingress::Inline
+   }
+}
+
+/// Settings for opening an interface.
+#[derive(Debug, Clone, Default)]
+pub struct InterfaceOptions {
+   /// turn on the device's offloads, see `Interface::with_offloads()`
+   pub offloads: bool,
+   pub ingress: Ingress,
+}
+
+impl Shared {
+   fn signal_event(&self) {
+      // the write end is non-blocking; if the buffer is full the reader hasn't caught up yet
+      // and is going to wake up anyway
+      let _ = (&self.event_tx).write(&[1]);
+   }
+}
+
+type InterfaceHandle = Arc<Shared>;
+
+#[derive(Default)]
+struct ConnectionManager {
+   terminate: bool,
+   connections: HashMap<Quad, tcp::Connection>,
+   /// by the first port they listen on
+   listeners: HashMap<u16, Listener>,
+   egress: egress::Egress,
+   capture: Option<capture::Capture>,
+   /// errors of aborted connections that were reaped before their handle saw them; only
+   /// kept while the handle is around to pick them up
+   aborted: HashMap<Quad, io::Error>,
+   /// malformed packets dropped on ingress
+   drops: DropCounters,
+   metrics: metrics::Metrics,
+   /// connection timers, for the tick loop
+   timers: timer::Scheduler<Quad>,
+   /// bound on the buffer memory of all connections together, past which SYNs are refused
+   memory_limit: Option<usize>,
+   /// buffer memory all connections hold together
+   memory: tcp::MemoryMeter,
+   compliance: Compliance,
+   /// segments dropped for breaking a rule in strict mode
+   audit: compliance::AuditLog,
+   /// Fast Open cookie keys and replay protection, shared by all listeners
+   fast_open: fastopen::FastOpen,
+   /// routes back out through the device, for listeners with the reverse-path check
+   routes: rpf::Routes,
+   /// addresses configured on the device, which connections are opened from
+   addrs: addrs::LocalAddrs,
+   /// connections we reset on purpose, and why
+   resets: resets::ResetLog,
+   /// how long to busy-poll for the application in low-latency mode
+   low_latency: Option<time::Duration>,
+   /// writes by applications, so the packet loop can tell one has answered
+   writes: u64,
+   /// for listeners in SYN proxy mode
+   syn_cookies: synproxy::SynCookies,
+   /// each end of a SYN proxy's relay, to the other
+   relays: HashMap<Quad, Quad>,
+}
+
+impl ConnectionManager {
+   /// Make sure the tick loop wakes up for the connection's next deadline. Called after
+   /// anything that may have armed a timer or closed the connection; closed connections
+   /// are due immediately, so the tick loop reaps them.
+   fn schedule(&mut self, q: Quad) {
+      let c = match self.connections.get_mut(&q) {
+         Some(c) => c,
+         None => return,
+      };
+      let at = if c.is_closed() {
+         Some(time::Instant::now())
+      } else {
+         c.next_deadline()
+      };
+      if let Some(at) = at {
+         if c.scheduled.map_or(true, |s| at < s) {
+            c.scheduled = Some(at);
+            self.timers.schedule(at, q);
+         }
+      }
+   }
+
+   /// Drop a closed connection, keeping its error for the handle if it still has one.
+   fn reap(&mut self, q: Quad) {
+      if let Some(mut c) = self.connections.remove(&q) {
+         self.egress.forget(c.flow().id);
+         if c.handshake_timed_out() {
+            self.metrics.record(metrics::RateEvent::FailedHandshake, time::Instant::now());
+         }
+         if let Some(why) = c.reset_reason() {
+            self.resets.record(q.local(), q.remote(), why);
+         }
+         match c.take_error() {
+            Some(e) if c.attached => {
+               self.aborted.insert(q, e);
+            }
+            _ => {}
+         }
+      }
+      if let Some(p) = self.relays.remove(&q) {
+         self.relays.remove(&p);
+      }
+      for l in self.listeners.values_mut() {
+         l.embryonic.retain(|&p| p != q);
+         l.pending.retain(|&p| p != q);
+         l.deferred.retain(|&p| p != q);
+      }
+   }
+
+   /// Whether a new connection on `port`, with its receive and reassembly buffers, would
+   /// take buffer memory past the limit.
+   fn short_of_memory(&self, port: u16) -> bool {
+      let l = listener_key(&self.listeners, port).and_then(|k| self.listeners.get(&k));
+      let (limit, l) = match (self.memory_limit, l) {
+         (Some(limit), Some(l)) => (limit, l),
+         _ => return false,
+      };
+      self.memory.total() + 2 * l.opts.recv_window as usize > limit
+   }
+
+   /// A local port for a new connection from `local` to `remote`, one that no listener takes
+   /// and no connection to the same peer uses. The search starts at a random point of the
+   /// range, so the port can't be guessed (RFC 6056).
+   fn ephemeral_port(&self, local: Ipv4Addr, remote: SocketAddrV4) -> Option<u16> {
+      let (first, last) = (*EPHEMERAL_PORTS.start() as u32, *EPHEMERAL_PORTS.end() as u32);
+      let span = last - first + 1;
+      let offset = time::SystemTime::now()
+         .duration_since(time::UNIX_EPOCH)
+         .unwrap_or_default()
+         .subsec_nanos()
+         % span;
+      (0..span).map(|i| (first + (offset + i) % span) as u16).find(|&port| {
+         listener_key(&self.listeners, port).is_none()
+            && !self.connections.contains_key(&Quad::from_addrs(SocketAddrV4::new(local, port), remote))
+      })
+   }
+
+   /// Move a passive open out of its listener's SYN queue once the handshake is done: into
+   /// the accept queue, or to wait for its first data if the listener has a filter. Returns
+   /// whether it went into the accept queue.
+   fn promote(&mut self, q: Quad) -> bool {
+      let done = self.connections.get(&q).map_or(false, |c| !c.is_syn_received() && !c.is_closed());
+      let l = match listener_mut(&mut self.listeners, q.dst.1) {
+         Some(l) => l,
+         None => return false,
+      };
+      let i = match l.embryonic.iter().position(|&e| e == q) {
+         Some(i) if done => i,
+         _ => return false,
+      };
+      l.embryonic.swap_remove(i);
+      if l.filter.is_some() {
+         l.deferred.push(q);
+         false
+      } else {
+         l.pending.push_back(q);
+         true
+      }
+   }
+
+   /// Why the connection behind a handle no longer exists.
+   fn gone(&mut self, q: &Quad) -> io::Error {
+      self.aborted.remove(q).unwrap_or_else(terminated)
+   }
+}
+
+struct Listener {
+   /// the ports it takes connections on
+   ports: RangeInclusive<u16>,
+   opts: ListenOptions,
+   /// connections still in SYN-RECEIVED, which accept() doesn't hand out
+   embryonic: Vec<Quad>,
+   /// established connections that have not been handed out by accept() yet
+   pending: VecDeque<Quad>,
+   /// while set, SYNs for this port are dropped so the client retries later
+   paused: bool,
+   /// decides what to do with new connections once their first data arrives
+   filter: Option<filter::AcceptFilter>,
+   /// connections waiting for their first data before the filter can run
+   deferred: Vec<Quad>,
+}
+
+/// The first port of the listener that takes connections on `port`.
+fn listener_key(listeners: &HashMap<u16, Listener>, port: u16) -> Option<u16> {
+   if listeners.contains_key(&port) {
+      return Some(port);
+   }
+   listeners.iter().find(|(_, l)| l.ports.contains(&port)).map(|(&k, _)| k)
+}
+
+/// The listener that takes connections on `port`.
+fn listener_mut(listeners: &mut HashMap<u16, Listener>, port: u16) -> Option<&mut Listener> {
+   let key = listener_key(listeners, port)?;
+   listeners.get_mut(&key)
+}
+
+impl Listener {
+   /// A full SYN or accept queue backpressures the handshake: SYNs are dropped until
+   /// handshakes complete or accept() makes room, and the client's SYN retransmission gets
+   /// it in later. Handshakes already under way still complete into a full accept queue.
+   fn can_accept(&self) -> bool {
+      !self.paused
+         && self.embryonic.len() < self.opts.syn_backlog
+         && self.pending.len() + self.deferred.len() < self.opts.backlog
+   }
+}
+
+pub struct Interface {
+   ih: InterfaceHandle,
+   jhs: Vec<thread::JoinHandle<io::Result<()>>>,
+}
+
+/// Runs connection timers. Rather than waking up periodically it sleeps until the earliest
+/// deadline any connection has, and is woken through `tick_var` whenever that may have moved.
+fn tick_loop(ih: InterfaceHandle) -> io::Result<()> {
+   let mut cmg = ih.manager.lock().unwrap();
+   loop {
+      if cmg.terminate {
+         return Ok(());
+      }
+      let cm = &mut *cmg;
+
+      let now = time::Instant::now();
+      let mut reaped = false;
+      while let Some((at, q)) = cm.timers.pop_due(now) {
+         let c = match cm.connections.get_mut(&q) {
+            Some(c) => c,
+            None => continue,
+         };
+         if c.scheduled != Some(at) {
+            // superseded by an earlier deadline, which has already been handled
+            continue;
+         }
+         c.scheduled = None;
+         c.on_tick(&mut cm.egress)?;
+         synproxy::relay(cm, q)?;
+         if cm.connections.get(&q).map_or(false, |c| c.is_closed()) {
+            cm.reap(q);
+            reaped = true;
+         } else {
+            cm.schedule(q);
+         }
+      }
+      cm.egress.flush(&ih.nic, &mut cm.capture, &mut cm.metrics)?;
+      if reaped {
+         ih.rcv_var.notify_all();
+         ih.signal_event();
+      }
+
+      cmg = match cm.timers.next() {
+         Some(at) => {
+            let now = time::Instant::now();
+            if at <= now {
+               continue;
+            }
+            ih.tick_var.wait_timeout(cmg, at - now).unwrap().0
+         }
+         None => ih.tick_var.wait(cmg).unwrap(),
+      };
+   }
+}
+
+fn packet_loop(ih: InterfaceHandle) -> io::Result<()> {
+   let mut buf = [0u8; egress::MAX_MTU];
+   loop {
+      if !ih.nic.wait(ih.wake_rx.as_raw_fd())? {
+         return Ok(());
+      }
+      let nbytes = ih.nic.recv(&mut buf[..])?;
+      if !ingress(&ih, &buf[..nbytes])? {
+         return Ok(());
+      }
+   }
+}
+
+/// With `Ingress::Worker`: read packets off the device and queue them for the worker,
+/// dropping those that find the queue full.
+fn reader_loop(ih: InterfaceHandle, queue: mpsc::SyncSender<Vec<u8>>) -> io::Result<()> {
+   let mut buf = [0u8; egress::MAX_MTU];
+   loop {
+      if !ih.nic.wait(ih.wake_rx.as_raw_fd())? {
+         // dropping the queue stops the worker
+         return Ok(());
+      }
+      let nbytes = ih.nic.recv(&mut buf[..])?;
+      match queue.try_send(buf[..nbytes].to_vec()) {
+         Ok(()) => {}
+         Err(mpsc::TrySendError::Full(_)) => {
+            ih.ingress_drops.fetch_add(1, Ordering::Relaxed);
+         }
+         // the worker has seen the interface go away
+         Err(mpsc::TrySendError::Disconnected(_)) => return Ok(()),
+      }
+   }
+}
+
+fn ingress_worker(ih: InterfaceHandle, queue: mpsc::Receiver<Vec<u8>>) -> io::Result<()> {
+   for packet in queue {
+      if !ingress(&ih, &packet)? {
+         return Ok(());
+      }
+   }
+   Ok(())
+}
+
+/// Process one packet read from the device. Returns false once the interface is going away.
+fn ingress(ih: &Shared, buf: &[u8]) -> io::Result<bool> {
+   let mut cmg = ih.manager.lock().unwrap();
+   if cmg.terminate {
+      return Ok(false);
+   }
+   let cm = &mut *cmg;
+   // in low-latency mode, the connection that just got data and what to wait for
+   let mut reply = None;
+
+   let layout = match sanitize::check(buf, &cm.addrs) {
+      Ok(layout) => layout,
+      Err(why) => {
+         cm.drops.count(why);
+         return Ok(true);
+      }
+   };
+   let nbytes = layout.end;
+
+   match etherparse::Ipv4HeaderSlice::from_slice(&buf[..nbytes]){
+      Ok(iph) => {
+         let src = iph.source_addr();
+         let dst = iph.destination_addr();
+
+         match etherparse::TcpHeaderSlice::from_slice(&buf[layout.tcp..nbytes]){
+            Ok(tcph) => {
+               use std::collections::hash_map::Entry;
+               let datai = layout.data;
+               if tcph.rst() {
+                  cm.metrics.record(metrics::RateEvent::RstReceived, time::Instant::now());
+               }
+               let q = Quad{
+                  src: (src, tcph.source_port()),
+                  dst: (dst, tcph.destination_port())
+               };
+               if cm.capture.is_some() {
+                  let state = match cm.connections.get(&q) {
+                     Some(c) => c.state_name(),
+                     None if listener_key(&cm.listeners, q.dst.1).is_some() => "LISTEN",
+                     None => "CLOSED",
+                  };
+                  capture::record(&mut cm.capture, capture::Direction::In, state, &buf[..nbytes]);
+               }
+               let violation = compliance::check_flags(cm.compliance, &tcph).and_then(|()| {
+                  match cm.connections.get(&q) {
+                     Some(c) if cm.compliance == Compliance::Strict
+                        && c.data_after_fin(tcph.sequence_number(), nbytes - datai) =>
+                     {
+                        Err(Rule::DataAfterFin)
+                     }
+                     _ => Ok(()),
+                  }
+               });
+               if let Err(rule) = violation {
+                  cm.audit.record(
+                     SocketAddrV4::new(src, tcph.source_port()),
+                     SocketAddrV4::new(dst, tcph.destination_port()),
+                     rule,
+                  );
+                  return Ok(true);
+               }
+               if cm.connections.get(&q).map_or(false, |c| c.reusable_for(&tcph)) {
+                  // the old incarnation is done; the SYN goes to the listener below
+                  cm.reap(q);
+               }
+               // checked up front, since the entry below borrows the connection table
+               let short_of_memory = tcph.syn()
+                  && !tcph.ack()
+                  && !cm.connections.contains_key(&q)
+                  && cm.short_of_memory(q.dst.1);
+               match cm.connections.entry(q){
+                  Entry::Occupied(mut c) => {
+                     let writable = c.get().is_writable();
+                     #[cfg(feature = "alloc-audit")]
+                     let audit = c.get().is_established() && cm.capture.is_none();
+                     #[cfg(feature = "alloc-audit")]
+                     let before = alloc_audit::allocations();
+
+                     c.get_mut().on_packet(&mut cm.egress, iph, tcph, &buf[datai..nbytes])?;
+                     if let Some(budget) = cm.low_latency {
+                        if c.get().has_readable() {
+                           reply = Some((q, budget, cm.writes));
+                        }
+                     }
+
+                     #[cfg(feature = "alloc-audit")]
+                     assert!(
+                        !audit || alloc_audit::allocations() == before,
+                        "segment processing for an established connection allocated"
+                     );
+
+                     // data that leaves the connection short of its low watermark wakes nobody,
+                     // unless it also made room for blocked writers
+                     let quiet = nbytes > datai
+                        && c.get().below_recv_lowat()
+                        && (writable || !c.get().is_writable());
+                     if !quiet {
+                        ih.rcv_var.notify_all();
+                     }
+                     synproxy::relay(cm, q)?;
+                     let promoted = cm.promote(q);
+                     if filter::run(cm, q)? || promoted {
+                        ih.pending_var.notify_all();
+                     }
+                     if !quiet {
+                        ih.signal_event();
+                     }
+                  },
+                  Entry::Vacant(e) => match listener_mut(&mut cm.listeners, tcph.destination_port()) {
+                     // no ACK can be right in LISTEN (RFC 793 S3.9), e.g. one for a connection
+                     // that has been reaped in the meantime
+                     Some(l) if tcph.ack() => {
+                        let ttl = l.opts.ttl;
+                        let proxy = l.opts.syn_proxy.map(|backend| (backend, l.opts.clone()));
+                        let data = &buf[datai..nbytes];
+                        let admitted = match proxy {
+                           // perhaps the ACK of a cookie SYN-ACK
+                           Some((backend, opts)) => {
+                              synproxy::admit(cm, q, backend, &opts, iph.clone(), tcph.clone(), data)?
+                           }
+                           None => false,
+                        };
+                        if !admitted {
+                           tcp::reset_stray(&mut cm.egress, ttl, iph, tcph, data);
+                        }
+                     }
+                     Some(l) => {
+                        if !l.can_accept() {
+                           return Ok(true);
+                        }
+                        if l.opts.reverse_path_check
+                           && tcph.syn()
+                           && !cm.routes.routable(iph.source_addr())
+                        {
+                           cm.drops.count(sanitize::Malformed::UnroutableSource);
+                           return Ok(true);
+                        }
+                        if short_of_memory {
+                           // a connection accepted now would stall at a zero window, so
+                           // refuse it before allocating anything
+                           if l.opts.rst_when_out_of_memory {
+                              let why = ResetReason::OutOfMemory;
+                              tcp::refuse(&mut cm.egress, &l.opts, why, iph, tcph, &buf[datai..nbytes]);
+                              cm.resets.record(q.local(), q.remote(), why);
+                           }
+                        } else if l.opts.syn_proxy.is_some() {
+                           // nothing is kept until the client's ACK brings the cookie back
+                           tcp::cookie_syn_ack(&mut cm.egress, &l.opts, &cm.syn_cookies, iph, tcph);
+                        } else if let Some(c) = tcp::Connection::accept(
+                           &mut cm.egress,
+                           &l.opts,
+                           &mut cm.fast_open,
+                           iph,
+                           tcph,
+                           &buf[datai..nbytes],
+                        )? {
+                           cm.metrics.record(metrics::RateEvent::PassiveOpen, time::Instant::now());
+                           // Fast Open data is readable before the handshake completes, so
+                           // such connections don't wait for it to be accepted either
+                           let fast_open = c.handshake.fast_open;
+                           e.insert(c).set_memory_meter(cm.memory.clone());
+                           if !fast_open {
+                              l.embryonic.push(q);
+                           } else if l.filter.is_some() {
+                              l.deferred.push(q);
+                           } else {
+                              l.pending.push_back(q);
+                              ih.pending_var.notify_all();
+                              ih.signal_event();
+                           }
+                        }
+                     }
+                     // nobody listens on the port, so it is CLOSED (RFC 793 S3.4)
+                     None => {
+                        tcp::reset_stray(&mut cm.egress, tcp::DEFAULT_TTL, iph, tcph, &buf[datai..nbytes]);
+                     }
+                  },
+               }
+               cm.schedule(q);
+            },
+            Err(_) => cm.drops.count(sanitize::Malformed::Unparsable),
+         }
+      },
+      Err(_) => cm.drops.count(sanitize::Malformed::Unparsable),
+   }
+   cm.egress.flush(&ih.nic, &mut cm.capture, &mut cm.metrics)?;
+   ih.tick_var.notify_all();
+   drop(cmg);
+   if let Some((q, budget, writes)) = reply {
+      await_reply(ih, q, budget, writes);
+   }
+   Ok(true)
+}
+
+/// Low-latency mode: before going back to reading the device, give the application the
+/// chance to read what just arrived on `q` and write its answer, which goes out from
+/// write() itself. The packet loop spins rather than sleeps, as do readers, so neither side
+/// pays for a wakeup in between.
+fn await_reply(ih: &Shared, q: Quad, budget: time::Duration, writes: u64) {
+   let deadline = time::Instant::now() + budget;
+   while time::Instant::now() < deadline {
+      thread::yield_now();
+      let cm = ih.manager.lock().unwrap();
+      if cm.writes != writes || !cm.connections.contains_key(&q) {
+         return;
+      }
+   }
+}
+
+/// Wait for the connections on `port` (on every port with `None`) to finish until
+/// `deadline` and then abort whatever is left, reporting the number of connections
+/// remaining on `progress` whenever it changes. Connections in TIME-WAIT count as finished.
+fn drain(
+   ih: &InterfaceHandle,
+   ports: Option<RangeInclusive<u16>>,
+   deadline: time::Instant,
+   progress: mpsc::Sender<usize>,
+) -> io::Result<()> {
+   let ours = |q: &Quad| ports.as_ref().map_or(true, |p| p.contains(&q.dst.1));
+   let mut cmg = ih.manager.lock().unwrap();
+   let mut last = None;
+   let mut forced = false;
+   loop {
+      if cmg.terminate {
+         return Ok(());
+      }
+      let cm = &mut *cmg;
+      let remaining: Vec<Quad> = cm
+         .connections
+         .iter()
+         .filter(|(q, c)| ours(q) && !c.is_time_wait())
+         .map(|(&q, _)| q)
+         .collect();
+      if last != Some(remaining.len()) {
+         last = Some(remaining.len());
+         // the caller may have stopped listening, which doesn't stop the drain
+         let _ = progress.send(remaining.len());
+      }
+      if remaining.is_empty() {
+         return Ok(());
+      }
+
+      let now = time::Instant::now();
+      if now >= deadline && !forced {
+         forced = true;
+         for &q in &remaining {
+            let c = cm.connections.get_mut(&q).expect("collected above");
+            let err = io::Error::new(io::ErrorKind::ConnectionAborted, "connection drained");
+            c.abort_with(&mut cm.egress, ResetReason::Drained, err)?;
+            cm.schedule(q);
+         }
+         cm.egress.flush(&ih.nic, &mut cm.capture, &mut cm.metrics)?;
+         ih.tick_var.notify_all();
+      }
+      // woken as connections go away
+      cmg = if forced {
+         ih.rcv_var.wait(cmg).unwrap()
+      } else {
+         ih.rcv_var.wait_timeout(cmg, deadline - now).unwrap().0
+      };
+   }
+}
+
+/// Stop new connections on the listener whose first port is `port` (on every listener with
+/// `None`) and drain the existing ones in the background.
+fn start_drain(ih: &InterfaceHandle, port: Option<u16>, deadline: time::Duration) -> mpsc::Receiver<usize> {
+   let mut cm = ih.manager.lock().unwrap();
+   for (&p, l) in cm.listeners.iter_mut() {
+      if port.map_or(true, |port| p == port) {
+         l.paused = true;
+      }
+   }
+   let ports = port.map(|p| cm.listeners.get(&p).map_or(p..=p, |l| l.ports.clone()));
+   drop(cm);
+   let (tx, rx) = mpsc::channel();
+   let ih = ih.clone();
+   let deadline = time::Instant::now() + deadline;
+   thread::spawn(move || {
+      if let Err(e) = drain(&ih, ports, deadline, tx) {
+         eprintln!("drain failed: {}", e);
+      }
+   });
+   rx
+}
+
+/// The MTU the kernel has configured for the device, which the tun API doesn't expose.
+fn device_mtu(name: &str) -> Option<usize> {
+   std::fs::read_to_string(format!("/sys/class/net/{}/mtu", name)).ok()?.trim().parse().ok()
+}
+
+impl Interface {
+   pub fn new() -> io::Result<Self> {
+      Interface::with_options(InterfaceOptions::default())
+   }
+
+   /// Like `new()`, but with the device's offloads on: the kernel may hand over TCP
+   /// super-packets, which are segmented here, and leave checksums to us. See `set_gso()`
+   /// for the sending side.
+   pub fn with_offloads() -> io::Result<Self> {
+      Interface::with_options(InterfaceOptions { offloads: true, ..InterfaceOptions::default() })
+   }
+
+   pub fn with_options(opts: InterfaceOptions) -> io::Result<Self> {
+      let nic = if opts.offloads {
+         device::Device::open_offloaded("tun0")?
+      } else {
+         device::Device::open("tun0")?
+      };
+      Interface::open(nic, opts.ingress)
+   }
+
+   fn open(nic: device::Device, ingress: Ingress) -> io::Result<Self> {
+      let (event_tx, event_rx) = UnixStream::pair()?;
+      event_tx.set_nonblocking(true)?;
+      event_rx.set_nonblocking(true)?;
+      let (wake_tx, wake_rx) = UnixStream::pair()?;
+      let ih: InterfaceHandle = Arc::new(Shared {
+         nic,
+         manager: Mutex::default(),
+         pending_var: Condvar::new(),
+         rcv_var: Condvar::new(),
+         tick_var: Condvar::new(),
+         event_tx,
+         event_rx,
+         wake_tx,
+         wake_rx,
+         ingress_drops: AtomicU64::new(0),
+      });
+      if let Some(mtu) = device_mtu(ih.nic.name()) {
+         ih.manager.lock().unwrap().egress.set_mtu(mtu);
+      }
+      ih.manager.lock().unwrap().routes = rpf::Routes::load(ih.nic.name()).unwrap_or_default();
+      ih.manager.lock().unwrap().addrs = addrs::LocalAddrs::load(ih.nic.name()).unwrap_or_default();
+      let mut jhs = vec![{
+         let ih = ih.clone();
+         thread::spawn(move || tick_loop(ih))
+      }];
+      match ingress {
+         Ingress::Inline => {
+            let ih = ih.clone();
+            jhs.push(thread::spawn(move || packet_loop(ih)));
+         }
+         Ingress::Worker(queue) => {
+            let (tx, rx) = mpsc::sync_channel(queue);
+            let reader = ih.clone();
+            jhs.push(thread::spawn(move || reader_loop(reader, tx)));
+            let worker = ih.clone();
+            jhs.push(thread::spawn(move || ingress_worker(worker, rx)));
+         }
+      }
+      Ok(Interface { ih, jhs })
+   }
+
+   pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
+      self.bind_with(port, ListenOptions::default())
+   }
+
+   pub fn bind_with(&mut self, port: u16, opts: ListenOptions) -> io::Result<TcpListener> {
+      self.bind_range(port..=port, opts)
+   }
+
+   /// Listen on every port in `ports` with a single listener, e.g. for the data connections
+   /// of a passive FTP-style service. Accepted streams report the port they came in on as
+   /// their local address.
+   pub fn bind_range(&mut self, ports: RangeInclusive<u16>, opts: ListenOptions) -> io::Result<TcpListener> {
+      if ports.is_empty() {
+         return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty port range"));
+      }
+      opts.validate()?;
+      let mut cm = self.ih.manager.lock().unwrap();
+      let overlaps = |l: &Listener| l.ports.start() <= ports.end() && ports.start() <= l.ports.end();
+      if cm.listeners.values().any(overlaps) {
+         return Err(io::Error::new(io::ErrorKind::AddrInUse, "port already bound"));
+      }
+      let port = *ports.start();
+      cm.listeners.insert(port, Listener {
+         ports,
+         opts,
+         embryonic: Vec::new(),
+         pending: VecDeque::new(),
+         paused: false,
+         filter: None,
+         deferred: Vec::new(),
+      });
+      drop(cm);
+      Ok(TcpListener { port, h: self.ih.clone(), nonblocking: false })
+   }
+
+   /// Open a connection to `remote` from an ephemeral port on `local`, which must be an
+   /// address configured on the device, or any unicast address with
+   /// `ListenOptions::freebind`. Blocks until the handshake completes; fails with
+   /// `ConnectionRefused` if the peer answers with an RST and `TimedOut` if it doesn't answer.
+   pub fn connect(&mut self, local: Ipv4Addr, remote: SocketAddrV4) -> io::Result<TcpStream> {
+      self.connect_with(local, remote, ListenOptions::default())
+   }
+
+   /// Like `connect()`, with the given settings for the connection.
+   pub fn connect_with(&mut self, local: Ipv4Addr, remote: SocketAddrV4, opts: ListenOptions) -> io::Result<TcpStream> {
+      opts.validate()?;
+      // addresses may have been added since the interface was opened
+      let addrs = addrs::LocalAddrs::load(self.ih.nic.name()).unwrap_or_default();
+      addrs.check_bind(local, opts.freebind)?;
+      let mut cmg = self.ih.manager.lock().unwrap();
+      let cm = &mut *cmg;
+      cm.addrs = addrs;
+      let port = cm
+         .ephemeral_port(local, remote)
+         .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "no free ephemeral port"))?;
+      let quad = Quad::from_addrs(SocketAddrV4::new(local, port), remote);
+      let mut c = tcp::Connection::connect(&mut cm.egress, &opts, quad.local(), remote)?;
+      // the caller waits below to hear how the handshake went
+      c.attached = true;
+      c.set_memory_meter(cm.memory.clone());
+      cm.connections.insert(quad, c);
+      cm.schedule(quad);
+      cm.egress.flush(&self.ih.nic, &mut cm.capture, &mut cm.metrics)?;
+      self.ih.tick_var.notify_all();
+      loop {
+         match cmg.connections.get(&quad) {
+            // SYN-RECEIVED after a simultaneous open
+            Some(c) if c.is_connecting() || c.is_syn_received() => {}
+            Some(_) => return Ok(TcpStream { quad, h: self.ih.clone(), nonblocking: false }),
+            None => return Err(cmg.gone(&quad)),
+         }
+         cmg = self.ih.rcv_var.wait(cmg).unwrap();
+      }
+   }
+
+   /// Craft and transmit a segment on the existing connection between `local` and `remote`,
+   /// with arbitrary flags, sequence and acknowledgment numbers. The connection's own state
+   /// is left alone, so this is for probing peers rather than for sending data.
+   #[cfg(feature = "tooling")]
+   pub fn inject_segment(
+      &mut self,
+      local: SocketAddrV4,
+      remote: SocketAddrV4,
+      flags: SegmentFlags,
+      seq: u32,
+      ack: u32,
+      payload: &[u8],
+   ) -> io::Result<()> {
+      let quad = Quad::from_addrs(local, remote);
+      let mut cmg = self.ih.manager.lock().unwrap();
+      let cm = &mut *cmg;
+      let c = cm.connections.get_mut(&quad).ok_or_else(no_such_connection)?;
+      c.inject(&mut cm.egress, &flags, seq, ack, payload)?;
+      cm.egress.flush(&self.ih.nic, &mut cm.capture, &mut cm.metrics)
+   }
+
+   /// A file descriptor that becomes readable whenever a connection may have become readable
+   /// or writable, or a listener may have a connection to accept, for integrating with event
+   /// loops that can't block in accept() or read(). It is non-blocking; drain it with
+   /// `clear_events()` (or by reading it directly) before checking the handles.
+   pub fn event_fd(&self) -> RawFd {
+      self.ih.event_rx.as_raw_fd()
+   }
+
+   /// Drain pending notifications from `event_fd()`.
+   pub fn clear_events(&self) {
+      let mut buf = [0u8; 64];
+      while let Ok(n) = (&self.ih.event_rx).read(&mut buf) {
+         if n == 0 {
+            break;
+         }
+      }
+   }
+
+   /// Log every segment, state change and timer event of one connection to stderr, e.g. to
+   /// debug a single misbehaving peer without drowning in output about all the others.
+   pub fn set_verbose(&self, local: SocketAddrV4, remote: SocketAddrV4, on: bool) -> io::Result<()> {
+      let quad = Quad::from_addrs(local, remote);
+      let mut cm = self.ih.manager.lock().unwrap();
+      let c = cm.connections.get_mut(&quad).ok_or_else(no_such_connection)?;
+      c.set_verbose(on);
+      Ok(())
+   }
+
+   /// A snapshot of every connection on the interface.
+   pub fn connections(&self) -> Vec<ConnectionInfo> {
+      let cm = self.ih.manager.lock().unwrap();
+      cm.connections.values().map(|c| c.info()).collect()
+   }
+
+   /// Reset a connection on the operator's behalf. Its handle sees ConnectionAborted.
+   pub fn kill(&self, local: SocketAddrV4, remote: SocketAddrV4) -> io::Result<()> {
+      let quad = Quad::from_addrs(local, remote);
+      let mut cmg = self.ih.manager.lock().unwrap();
+      let cm = &mut *cmg;
+      let c = cm.connections.get_mut(&quad).ok_or_else(no_such_connection)?;
+      let err = io::Error::new(io::ErrorKind::ConnectionAborted, "connection killed by administrator");
+      c.abort_with(&mut cm.egress, ResetReason::Killed, err)?;
+      cm.schedule(quad);
+      cm.egress.flush(&self.ih.nic, &mut cm.capture, &mut cm.metrics)?;
+      // the tick loop reaps the connection and wakes up blocked readers
+      self.ih.tick_var.notify_all();
+      Ok(())
+   }
+
+   /// Share the device between connections by deficit round robin within each priority
+   /// class, each connection getting `quantum` bytes per round times its weight (see
+   /// `TcpStream::set_weight()`). `None` goes back to sending in the order packets were
+   /// queued.
+   pub fn set_fair_queueing(&self, quantum: Option<usize>) {
+      self.ih.manager.lock().unwrap().egress.set_fair_queueing(quantum);
+   }
+
+   /// Issue Fast Open cookies under a new key from now on. Cookies under the current key are
+   /// still honoured until the next rotation, so rotating periodically limits how long a
+   /// leaked cookie is good for without making every client fall back to a full handshake.
+   pub fn rotate_fast_open_key(&self) {
+      self.ih.manager.lock().unwrap().fast_open.rotate();
+   }
+
+   /// Hand runs of back-to-back segments of a connection to the kernel as one super-packet
+   /// for it to segment (GSO), rather than one write each. Needs an interface opened with
+   /// `with_offloads()`.
+   pub fn set_gso(&self, on: bool) -> io::Result<()> {
+      self.ih.nic.set_gso(on)
+   }
+
+   /// Re-read the routing table the reverse-path check goes by, after routes through the
+   /// device were added or removed. If it can't be read, every source passes the check.
+   pub fn reload_routes(&self) {
+      let routes = rpf::Routes::load(self.ih.nic.name()).unwrap_or_default();
+      self.ih.manager.lock().unwrap().routes = routes;
+   }
+
+   /// Re-read the addresses configured on the device, which packets from the wire may not
+   /// claim as their source, after some were added or removed.
+   pub fn reload_addresses(&self) {
+      let addrs = addrs::LocalAddrs::load(self.ih.nic.name()).unwrap_or_default();
+      self.ih.manager.lock().unwrap().addrs = addrs;
+   }
+
+   /// Limit how many retransmission timeouts all connections together may act on per
+   /// second. Connections over the limit retry at random points over the following second,
+   /// so after a device outage the backlog drains at a rate the device can take rather than
+   /// in one burst. `None` lifts the limit.
+   pub fn set_retransmit_limit(&self, per_second: Option<u32>) {
+      self.ih.manager.lock().unwrap().egress.set_retransmit_limit(per_second);
+   }
+
+   /// Low-latency mode for request/response workloads: after a segment brings data, the
+   /// packet loop waits up to `busy_poll` for the application to read it and write its
+   /// answer before reading the device again, and blocked readers spin for as long before
+   /// going to sleep. The answer then goes out without any thread sleeping and waking up in
+   /// between, at the cost of CPU time spent spinning. `None` turns it off.
+   pub fn set_low_latency(&self, busy_poll: Option<time::Duration>) {
+      self.ih.manager.lock().unwrap().low_latency = busy_poll;
+   }
+
+   /// Cap the buffer memory all connections may hold together. New connections are refused
+   /// while accepting one would exceed it; see `ListenOptions::rst_when_out_of_memory`.
+   pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+      self.ih.manager.lock().unwrap().memory_limit = limit;
+   }
+
+   /// Drain the interface for a restart: stop accepting connections on every port, give the
+   /// existing ones until `deadline` to finish on their own and then abort the rest. The
+   /// returned channel gets the number of connections left whenever it changes and closes
+   /// once there are none.
+   pub fn drain(&self, deadline: time::Duration) -> mpsc::Receiver<usize> {
+      start_drain(&self.ih, None, deadline)
+   }
+
+   /// Switch between tolerating common deviations from the RFCs and dropping every segment
+   /// that breaks a MUST.
+   pub fn set_compliance(&mut self, mode: Compliance) {
+      self.ih.manager.lock().unwrap().compliance = mode;
+   }
+
+   /// The most recent segments strict mode dropped, oldest first, with the rule each broke.
+   pub fn audit_log(&self) -> Vec<AuditEntry> {
+      self.ih.manager.lock().unwrap().audit.entries()
+   }
+
+   /// The most recent connections we reset on purpose, oldest first.
+   pub fn reset_log(&self) -> Vec<ResetEntry> {
+      self.ih.manager.lock().unwrap().resets.entries()
+   }
+
+   /// How many connections we reset on purpose, by reason, since the interface came up.
+   pub fn reset_counts(&self) -> Vec<(ResetReason, u64)> {
+      self.ih.manager.lock().unwrap().resets.counts()
+   }
+
+   /// Passive opens, failed handshakes and RSTs, over the last second and in total.
+   pub fn rates(&self) -> RateReport {
+      self.ih.manager.lock().unwrap().metrics.report(time::Instant::now())
+   }
+
+   /// Call `alarm` with the event and its count so far whenever an event reaches
+   /// `per_second` occurrences within a second, at most once per second and event. `None`
+   /// disarms the event. The callback runs with the interface locked and must not call
+   /// back into it.
+   pub fn set_rate_alarm(&self, ev: RateEvent, per_second: Option<u64>) {
+      self.ih.manager.lock().unwrap().metrics.set_threshold(ev, per_second);
+   }
+
+   /// The callback for `set_rate_alarm()`.
+   pub fn on_rate_alarm<F>(&self, alarm: F)
+   where
+      F: FnMut(RateEvent, u64) + Send + 'static,
+   {
+      self.ih.manager.lock().unwrap().metrics.set_alarm(Some(Box::new(alarm)));
+   }
+
+   /// Packets dropped on ingress, by reason.
+   pub fn drop_counters(&self) -> DropCounters {
+      let mut drops = self.ih.manager.lock().unwrap().drops.clone();
+      drops.ingress_queue_full = self.ih.ingress_drops.load(Ordering::Relaxed);
+      drops
+   }
+
+   /// Start writing every segment sent or received to a pcap file at `path`, with an index of
+   /// per-packet metadata in `<path>.idx`. Replaces any capture already running.
+   pub fn start_capture<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+      self.start_capture_filtered(path, "")
+   }
+
+   /// Like `start_capture()`, but only record segments matching a tcpdump-style filter such
+   /// as `port 80 and host 10.0.0.5`. See `capture_filter` for the supported syntax.
+   pub fn start_capture_filtered<P: AsRef<Path>>(&mut self, path: P, filter: &str) -> io::Result<()> {
+      let filter = capture_filter::CaptureFilter::parse(filter)?;
+      let cap = capture::Capture::create(path.as_ref(), Some(filter))?;
+      let mut cm = self.ih.manager.lock().unwrap();
+      if let Some(mut old) = cm.capture.replace(cap) {
+         old.flush()?;
+      }
+      Ok(())
+   }
+
+   pub fn stop_capture(&mut self) -> io::Result<()> {
+      let mut cm = self.ih.manager.lock().unwrap();
+      match cm.capture.take() {
+         Some(mut cap) => cap.flush(),
+         None => Ok(()),
+      }
+   }
+
+   /// Recreate a connection exported with `TcpStream::repair_export()`, possibly by another
+   /// process. The stream starts out in repair mode; call `set_repair(false)` to resume it.
+   pub fn repair_import(&mut self, st: RepairState) -> io::Result<TcpStream> {
+      use std::collections::hash_map::Entry;
+      st.opts.validate()?;
+      let quad = Quad::from_addrs(st.local, st.remote);
+      let mut cm = self.ih.manager.lock().unwrap();
+      let mtu = cm.egress.mtu();
+      let memory = cm.memory.clone();
+      match cm.connections.entry(quad) {
+         Entry::Vacant(v) => {
+            v.insert(tcp::Connection::repair_import(st, mtu)).set_memory_meter(memory);
+         }
+         Entry::Occupied(_) => {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "connection already exists"));
+         }
+      }
+      cm.schedule(quad);
+      Ok(TcpStream::attach(&mut cm, quad, &self.ih))
+   }
+}
+
+impl Drop for Interface {
+   fn drop(&mut self) {
+      self.ih.manager.lock().unwrap().terminate = true;
+      self.ih.tick_var.notify_all();
+      // and whoever waits in accept(), read() or write() gets an error
+      self.ih.pending_var.notify_all();
+      self.ih.rcv_var.notify_all();
+      // the packet loop waits for the device, not on a condvar
+      let _ = (&self.ih.wake_tx).write(&[1]);
+      for jh in self.jhs.drain(..) {
+         if let Ok(Err(e)) = jh.join() {
+            eprintln!("interface thread failed: {}", e);
+         }
+      }
+   }
+}
+
+pub struct TcpListener {
+   /// the first port it listens on, which identifies it
+   port: u16,
+   h: InterfaceHandle,
+   nonblocking: bool,
+}
+
+impl TcpListener {
+   /// Wait for the next incoming connection to complete its handshake and return it along
+   /// with the peer's address.
+   pub fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+      let mut cm = self.h.manager.lock().unwrap();
+      loop {
+         if cm.terminate {
+            return Err(interface_gone());
+         }
+         if let Some(quad) = cm
+            .listeners
+            .get_mut(&self.port)
+            .expect("port closed while listener still active")
+            .pending
+            .pop_front()
+         {
+            return Ok((TcpStream::attach(&mut cm, quad, &self.h), to_socket_addr(quad.src)));
+         }
+         if self.nonblocking {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "no pending connections"));
+         }
+         cm = self.h.pending_var.wait(cm).unwrap();
+      }
+   }
+
+   /// In non-blocking mode accept() fails with `WouldBlock` instead of waiting.
+   pub fn set_nonblocking(&mut self, nonblocking: bool) {
+      self.nonblocking = nonblocking;
+   }
+
+   /// Whether accept() would return a connection right away.
+   pub fn is_readable(&self) -> bool {
+      let cm = self.h.manager.lock().unwrap();
+      cm.listeners.get(&self.port).map_or(false, |l| !l.pending.is_empty())
+   }
+
+   /// Defer handing out new connections until their first data arrives, and let `f` look at
+   /// those bytes to accept the connection, reset it, or route it to the listener on another
+   /// port (e.g. to dispatch on SNI or Host). The bytes stay in the stream for the handler.
+   pub fn set_filter<F>(&self, f: F)
+   where
+      F: FnMut(&[u8]) -> FilterVerdict + Send + 'static,
+   {
+      let mut cm = self.h.manager.lock().unwrap();
+      if let Some(l) = cm.listeners.get_mut(&self.port) {
+         l.filter = Some(Box::new(f));
+      }
+   }
+
+   /// The listener accepts on every address routed to the interface, so the address part
+   /// is unspecified. For a listener on a range of ports this is the first of them.
+   pub fn local_addr(&self) -> io::Result<SocketAddr> {
+      Ok(to_socket_addr((Ipv4Addr::UNSPECIFIED, self.port)))
+   }
+
+   /// The ports the listener accepts connections on.
+   pub fn ports(&self) -> RangeInclusive<u16> {
+      let cm = self.h.manager.lock().unwrap();
+      cm.listeners.get(&self.port).map_or(self.port..=self.port, |l| l.ports.clone())
+   }
+
+   /// Stop completing new handshakes without giving up the port. SYNs are dropped while
+   /// paused, so clients retransmit and get through once the listener is resumed.
+   /// Connections already waiting in the accept queue are unaffected.
+   pub fn pause(&self) {
+      self.set_paused(true);
+   }
+
+   /// Like `Interface::drain()`, for this listener's ports only. Connections already in the
+   /// accept queue still count and can still be accepted.
+   pub fn drain(&self, deadline: time::Duration) -> mpsc::Receiver<usize> {
+      start_drain(&self.h, Some(self.port), deadline)
+   }
+
+   /// Start completing handshakes again after `pause()`.
+   pub fn resume(&self) {
+      self.set_paused(false);
+   }
+
+   fn set_paused(&self, paused: bool) {
+      let mut cm = self.h.manager.lock().unwrap();
+      if let Some(l) = cm.listeners.get_mut(&self.port) {
+         l.paused = paused;
+      }
+   }
+}
+
+impl Drop for TcpListener {
+   /// Stop listening, and reset the connections nobody accepted: still in the handshake,
+   /// waiting in the accept queue, or held for an accept filter. Nothing would ever hand
+   /// them out, and they would hold their buffers until the peers gave up.
+   fn drop(&mut self) {
+      let mut cmg = self.h.manager.lock().unwrap();
+      let cm = &mut *cmg;
+      let l = match cm.listeners.remove(&self.port) {
+         Some(l) => l,
+         None => return,
+      };
+      for q in l.embryonic.into_iter().chain(l.pending).chain(l.deferred) {
+         if let Some(c) = cm.connections.get_mut(&q) {
+            let _ = c.reset(&mut cm.egress, ResetReason::ListenerClosed);
+         }
+         cm.reap(q);
+      }
+      let _ = cm.egress.flush(&self.h.nic, &mut cm.capture, &mut cm.metrics);
+   }
+}
+
+pub struct TcpStream {
+   quad: Quad,
+   h: InterfaceHandle,
+   nonblocking: bool,
+}
+
+impl TcpStream {
+   /// The handle for the connection `quad`, which it is from now on.
+   fn attach(cm: &mut ConnectionManager, quad: Quad, h: &InterfaceHandle) -> Self {
+      if let Some(c) = cm.connections.get_mut(&quad) {
+         c.attached = true;
+      }
+      TcpStream { quad, h: h.clone(), nonblocking: false }
+   }
+
+   pub fn local_addr(&self) -> io::Result<SocketAddr> {
+      Ok(to_socket_addr(self.quad.dst))
+   }
+
+   pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+      Ok(to_socket_addr(self.quad.src))
+   }
+
+   /// Artificially delay everything sent on this stream by `delay` plus a random extra of up
+   /// to `jitter`, for testing application timeouts without external netem setup.
+   pub fn set_latency(&self, delay: time::Duration, jitter: time::Duration) -> io::Result<()> {
+      let mut cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.set_latency(Some((delay, jitter)));
+      Ok(())
+   }
+
+   /// Turn off latency injection set with `set_latency()`.
+   pub fn clear_latency(&self) -> io::Result<()> {
+      let mut cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.set_latency(None);
+      Ok(())
+   }
+
+   /// Pick the egress class for this stream's segments, e.g. `Priority::High` for an admin
+   /// channel that should not queue behind bulk transfers.
+   pub fn set_priority(&self, priority: Priority) -> io::Result<()> {
+      let mut cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.set_priority(priority);
+      Ok(())
+   }
+
+   /// This stream's share of the device relative to others of the same priority, when fair
+   /// queueing is on. Defaults to 1.
+   pub fn set_weight(&self, weight: u32) -> io::Result<()> {
+      let mut cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.set_weight(weight);
+      Ok(())
+   }
+
+   /// A snapshot of the connection's state, including how fast data is getting through in
+   /// each direction.
+   pub fn info(&self) -> io::Result<ConnectionInfo> {
+      let cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get(&self.quad).ok_or_else(terminated)?;
+      Ok(c.info())
+   }
+
+   /// Switch the connection to another congestion control algorithm. The new one picks up
+   /// from the current congestion window and slow start threshold.
+   pub fn set_congestion_control(&self, alg: CongestionAlgorithm) -> io::Result<()> {
+      let mut cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.set_congestion_control(alg);
+      Ok(())
+   }
+
+   /// The congestion control algorithm the connection runs.
+   pub fn congestion_control(&self) -> io::Result<CongestionAlgorithm> {
+      let cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get(&self.quad).ok_or_else(terminated)?;
+      Ok(c.congestion_control())
+   }
+
+   /// Never advertise a receive window above `bytes` (`None` lifts the limit), regardless of
+   /// the buffer size, bounding how much the peer can have in flight towards us.
+   pub fn set_window_clamp(&self, bytes: Option<u32>) -> io::Result<()> {
+      let mut cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.set_window_clamp(bytes);
+      Ok(())
+   }
+
+   /// Turn off Nagle's algorithm, like TCP_NODELAY: the last small segment of every write
+   /// goes out at once instead of waiting while an earlier small segment is unacknowledged,
+   /// which suits latency-sensitive exchanges of small messages.
+   pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+      let mut cmg = self.h.manager.lock().unwrap();
+      let cm = &mut *cmg;
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.set_nodelay(&mut cm.egress, nodelay)?;
+      cm.egress.flush(&self.h.nic, &mut cm.capture, &mut cm.metrics)?;
+      Ok(())
+   }
+
+   /// Whether Nagle's algorithm is off.
+   pub fn nodelay(&self) -> io::Result<bool> {
+      let cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get(&self.quad).ok_or_else(terminated)?;
+      Ok(c.nodelay())
+   }
+
+   /// Probe the peer with keepalives once it has been quiet for `idle`, like SO_KEEPALIVE
+   /// with TCP_KEEPIDLE, and reset the connection if enough go unanswered; `None` turns
+   /// keepalives off. Off unless `ListenOptions::keepalive` says otherwise.
+   pub fn set_keepalive(&self, idle: Option<time::Duration>) -> io::Result<()> {
+      let mut cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.set_keepalive(idle);
+      cm.schedule(self.quad);
+      self.h.tick_var.notify_all();
+      Ok(())
+   }
+
+   /// How long the peer may be quiet before keepalive probes start, if they are on.
+   pub fn keepalive(&self) -> io::Result<Option<time::Duration>> {
+      let cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get(&self.quad).ok_or_else(terminated)?;
+      Ok(c.keepalive())
+   }
+
+   /// Time between keepalive probes while they go unanswered, like TCP_KEEPINTVL.
+   pub fn set_keepalive_interval(&self, interval: time::Duration) -> io::Result<()> {
+      let mut cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.set_keepalive_interval(interval);
+      Ok(())
+   }
+
+   pub fn keepalive_interval(&self) -> io::Result<time::Duration> {
+      let cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get(&self.quad).ok_or_else(terminated)?;
+      Ok(c.keepalive_interval())
+   }
+
+   /// How many keepalive probes in a row may go unanswered before the connection is reset,
+   /// like TCP_KEEPCNT.
+   pub fn set_keepalive_count(&self, count: u32) -> io::Result<()> {
+      let mut cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.set_keepalive_count(count);
+      Ok(())
+   }
+
+   pub fn keepalive_count(&self) -> io::Result<u32> {
+      let cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get(&self.quad).ok_or_else(terminated)?;
+      Ok(c.keepalive_count())
+   }
+
+   /// Make reads wait until at least `bytes` are buffered rather than return whatever has
+   /// arrived, like SO_RCVLOWAT: neither blocked readers nor `event_fd()` are woken for
+   /// less. Once the peer has closed its side, what is left is returned however little.
+   /// Capped at the receive window; the default is 1.
+   pub fn set_recv_lowat(&self, bytes: usize) -> io::Result<()> {
+      let mut cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.set_recv_lowat(bytes);
+      Ok(())
+   }
+
+   /// In non-blocking mode reads fail with `WouldBlock` instead of waiting for data, and
+   /// writes instead of waiting for room in the send buffer.
+   pub fn set_nonblocking(&mut self, nonblocking: bool) {
+      self.nonblocking = nonblocking;
+   }
+
+   /// Whether a write would take at least some data right away, as the send buffer has room.
+   pub fn is_writable(&self) -> io::Result<bool> {
+      let cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get(&self.quad).ok_or_else(terminated)?;
+      Ok(c.is_writable())
+   }
+
+   /// Close the connection at once, like close() with SO_LINGER set to zero: whatever is
+   /// queued or unread is discarded, the peer gets an RST and the connection is gone from
+   /// the connection table right away rather than going through FIN-WAIT and TIME-WAIT.
+   pub fn abort(self) -> io::Result<()> {
+      let mut cmg = self.h.manager.lock().unwrap();
+      let cm = &mut *cmg;
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.abort(&mut cm.egress)?;
+      cm.reap(self.quad);
+      cm.egress.flush(&self.h.nic, &mut cm.capture, &mut cm.metrics)?;
+      Ok(())
+   }
+
+   /// Shut down the reading, writing or both halves of the connection, like
+   /// `std::net::TcpStream::shutdown`. After `Shutdown::Write` writes fail and our FIN
+   /// follows whatever is still queued, while reads go on until the peer closes its side.
+   pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+      let mut cmg = self.h.manager.lock().unwrap();
+      let cm = &mut *cmg;
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.shutdown(&mut cm.egress, how)?;
+      cm.schedule(self.quad);
+      cm.egress.flush(&self.h.nic, &mut cm.capture, &mut cm.metrics)?;
+      // blocked readers see end of file after a read shutdown, and the FIN may have armed
+      // the retransmission timer
+      self.h.rcv_var.notify_all();
+      self.h.tick_var.notify_all();
+      Ok(())
+   }
+
+   /// Set the TTL of outgoing segments, overriding the listener's default.
+   pub fn set_ttl(&self, ttl: u8) -> io::Result<()> {
+      let mut cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.set_ttl(ttl);
+      Ok(())
+   }
+
+   /// The TTL currently used for outgoing segments.
+   pub fn ttl(&self) -> io::Result<u8> {
+      let cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get(&self.quad).ok_or_else(terminated)?;
+      Ok(c.ttl())
+   }
+
+   /// Put the connection into (or take it out of) repair mode, in which it is frozen: incoming
+   /// segments are dropped and nothing is sent.
+   pub fn set_repair(&self, on: bool) -> io::Result<()> {
+      let mut cmg = self.h.manager.lock().unwrap();
+      let cm = &mut *cmg;
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.set_repair(&mut cm.egress, on)?;
+      cm.schedule(self.quad);
+      self.h.tick_var.notify_all();
+      cm.egress.flush(&self.h.nic, &mut cm.capture, &mut cm.metrics)
+   }
+
+   /// Export a connection in repair mode and remove it from this interface without telling
+   /// the peer, so it can be imported with `Interface::repair_import()` elsewhere.
+   pub fn repair_export(self) -> io::Result<RepairState> {
+      let mut cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get(&self.quad).ok_or_else(terminated)?;
+      let st = c.repair_export()?;
+      let flow = c.flow().id;
+      cm.connections.remove(&self.quad);
+      cm.egress.forget(flow);
+      Ok(st)
+   }
+
+   /// Log everything that happens on this stream to stderr; see `Interface::set_verbose()`.
+   pub fn set_verbose(&self, on: bool) -> io::Result<()> {
+      let mut cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.set_verbose(on);
+      Ok(())
+   }
+
+   /// Details of the peer's SYN and how the handshake went, for early insight into the client.
+   pub fn handshake(&self) -> io::Result<HandshakeInfo> {
+      let cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get(&self.quad).ok_or_else(terminated)?;
+      Ok(c.handshake.clone())
+   }
+
+   /// Make reads stop at the end of every segment the peer sent with PSH, for protocols that
+   /// frame messages that way. Use `read_record()` to learn where a record ends.
+   pub fn set_record_markers(&self, on: bool) -> io::Result<()> {
+      let mut cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      c.set_record_markers(on);
+      Ok(())
+   }
+
+   /// Like `read()`, but also says whether the data read ends at a PSH boundary. Without
+   /// record markers that is never the case.
+   pub fn read_record(&mut self, buf: &mut [u8]) -> io::Result<(usize, bool)> {
+      self.read_incoming(buf).map(|(n, eor, _)| (n, eor))
+   }
+
+   /// Like `read()`, but also says when the segment that completed the data read arrived
+   /// from the device, so latency measurements can include the time it sat in our buffers.
+   pub fn read_timestamped(&mut self, buf: &mut [u8]) -> io::Result<(usize, Option<time::Instant>)> {
+      self.read_incoming(buf).map(|(n, _, at)| (n, at))
+   }
+
+   /// When in-order data last arrived on this stream, whether or not it has been read.
+   pub fn last_data_instant(&self) -> io::Result<Option<time::Instant>> {
+      let cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get(&self.quad).ok_or_else(terminated)?;
+      Ok(c.last_data_instant())
+   }
+
+   /// Send `buf` as urgent data (the URG flag and urgent pointer). The peer's stack takes
+   /// its last byte out of the stream, for `read_urgent()` at the other end; the rest is
+   /// read in order as usual.
+   pub fn send_urgent(&mut self, buf: &[u8]) -> io::Result<usize> {
+      let mut cmg = self.h.manager.lock().unwrap();
+      let cm = &mut *cmg;
+      let c = match cm.connections.get_mut(&self.quad) {
+         Some(c) => c,
+         None => return Err(cm.gone(&self.quad)),
+      };
+      let n = c.enqueue_urgent(&mut cm.egress, buf)?;
+      cm.writes += 1;
+      cm.schedule(self.quad);
+      cm.egress.flush(&self.h.nic, &mut cm.capture, &mut cm.metrics)?;
+      self.h.tick_var.notify_all();
+      Ok(n)
+   }
+
+   /// The out-of-band byte the peer sent last, if it hasn't been read yet. Only the latest
+   /// is kept: one that arrives before the previous was read replaces it.
+   pub fn read_urgent(&mut self) -> io::Result<Option<u8>> {
+      let mut cm = self.h.manager.lock().unwrap();
+      let c = cm.connections.get_mut(&self.quad).ok_or_else(terminated)?;
+      Ok(c.take_urgent())
+   }
+
+   fn read_incoming(&mut self, buf: &mut [u8]) -> io::Result<(usize, bool, Option<time::Instant>)> {
+      let mut cmg = self.h.manager.lock().unwrap();
+      let mut spin_until = None;
+      loop {
+         let cm = &mut *cmg;
+         if cm.terminate {
+            return Err(interface_gone());
+         }
+         let c = match cm.connections.get_mut(&self.quad) {
+            Some(c) => c,
+            // a connection that went away without an error finished cleanly, e.g. it left
+            // TIME-WAIT, so the peer has nothing more to say
+            None => return cm.aborted.remove(&self.quad).map_or(Ok((0, false, None)), Err),
+         };
+
+         if c.has_readable() {
+            let read = c.read_incoming(buf);
+            c.window_update(&mut cm.egress)?;
+            cm.egress.flush(&self.h.nic, &mut cm.capture, &mut cm.metrics)?;
+            return Ok(read);
+         }
+
+         if c.is_recv_closed() {
+            return Ok((0, false, None));
+         }
+         if self.nonblocking {
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "no data to read"));
+         }
+
+         if let Some(budget) = cm.low_latency {
+            // busy-poll for a while before going to sleep, see `Interface::set_low_latency()`
+            let now = time::Instant::now();
+            if *spin_until.get_or_insert(now + budget) > now {
+               drop(cmg);
+               thread::yield_now();
+               cmg = self.h.manager.lock().unwrap();
+               continue;
+            }
+         }
+         cmg = self.h.rcv_var.wait(cmg).unwrap();
+      }
+   }
+}
+
+impl io::Read for TcpStream {
+   fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      self.read_record(buf).map(|(n, _)| n)
+   }
+}
+
+impl io::Write for TcpStream {
+   /// Queue as much of `buf` as the send buffer has room for, waiting for room if there is
+   /// none, unless in non-blocking mode.
+   fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      let mut cmg = self.h.manager.lock().unwrap();
+      let n = loop {
+         let cm = &mut *cmg;
+         if cm.terminate {
+            return Err(interface_gone());
+         }
+         let c = match cm.connections.get_mut(&self.quad) {
+            Some(c) => c,
+            None => return Err(cm.gone(&self.quad)),
+         };
+         match c.enqueue(&mut cm.egress, buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock && !self.nonblocking => {
+               // woken as ACKs come in
+               cmg = self.h.rcv_var.wait(cmg).unwrap();
+            }
+            r => break r?,
+         }
+      };
+      let cm = &mut *cmg;
+      cm.writes += 1;
+      cm.schedule(self.quad);
+      cm.egress.flush(&self.h.nic, &mut cm.capture, &mut cm.metrics)?;
+      // sending may have armed timers, and injected latency may have parked segments that
+      // need sending later
+      self.h.tick_var.notify_all();
+      Ok(n)
+   }
+
+   fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+   }
+}
+
+impl Drop for TcpStream {
+   /// Close the connection in an orderly way, like close() without SO_LINGER: our FIN
+   /// follows whatever is still queued, anything the peer sends from now on is discarded, and
+   /// the connection goes through FIN-WAIT and TIME-WAIT to be reaped like any other.
+   fn drop(&mut self) {
+      let mut cmg = self.h.manager.lock().unwrap();
+      let cm = &mut *cmg;
+      let c = match cm.connections.get_mut(&self.quad) {
+         Some(c) => c,
+         None => {
+            // gone already; nobody is left to pick up why
+            cm.aborted.remove(&self.quad);
+            return;
+         }
+      };
+      // whatever happens to it from now on, nobody is left to hear about it
+      c.attached = false;
+      if c.shutdown(&mut cm.egress, Shutdown::Both).is_err() {
+         // still connecting, with nothing to close in order
+         let _ = c.abort(&mut cm.egress);
+      }
+      cm.schedule(self.quad);
+      let _ = cm.egress.flush(&self.h.nic, &mut cm.capture, &mut cm.metrics);
+      self.h.tick_var.notify_all();
+   }
+}
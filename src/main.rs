@@ -1,66 +1,25 @@
-extern crate etherparse;
 use std::io;
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::io::prelude::*;
+use std::thread;
 
-mod tcp;
-
-#[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
-struct Quad{
-   src: (Ipv4Addr, u16),
-   dst: (Ipv4Addr, u16)
-}
+mod admin;
 
 fn main() -> io::Result<()>{
-    let mut connections: HashMap<Quad, tcp::Connection> = Default::default();
-    let mut nic = tun_tap::Iface::without_packet_info("tun0", tun_tap::Mode::Tun)?;
-    let mut buf = [0u8; 1504];
-    loop {
-       let nbytes = nic.recv(&mut buf [..])?;
-       //let eth_flags = u16::from_be_bytes([buf[0], buf[1]]);
-       //let eth_proto = u16::from_be_bytes([buf[2], buf[3]]);
-       //if eth_proto != 0x0800 {
-       //   continue;
-       //}
+    let mut i = trust::Interface::new()?;
+    let mut l = i.bind(9000)?;
 
-       match etherparse::Ipv4HeaderSlice::from_slice(&buf[..nbytes]){
-          Ok(iph) => {
-             let src = iph.source_addr();
-             let dst = iph.destination_addr();
-             let proto = iph.protocol();
-             if proto != 0x06 {
-                continue;
-             }
-             
-             match etherparse::TcpHeaderSlice::from_slice(&buf[iph.slice().len()..nbytes]){
-                Ok(tcph) => {
-                   use std::collections::hash_map::Entry;
-                   let datai = iph.slice().len() + tcph.slice().len();
-                   match connections.entry(Quad{
-                      src: (src, tcph.source_port()),
-                      dst: (dst, tcph.destination_port())
-                   }){
-                      Entry::Occupied(mut c) => {
-                         c.get_mut().on_packet(&mut nic, iph, tcph, &buf[datai..nbytes])?;
-                      },
-                      Entry::Vacant(mut e) => {
-                         if let Some(c) = tcp::Connection::accept(&mut nic, iph, tcph, &buf[datai..nbytes])? {
-                            e.insert(c);
-                         }
-                      }
-                   }
-                },
-                Err(e) => {
-                   eprintln!("Ignoring weird packet {:?}", e)
-                }
-             }
-
-          },
-          Err(e) => {
-             eprintln!("Ignoring weird packet {:?}", e)
-          }
+    // the interface is only needed by the control socket from here on. Once that is gone
+    // the interface shuts down with it, and accept() below fails
+    let control = std::env::var("TRUST_CONTROL_SOCKET").unwrap_or_else(|_| "/tmp/trust.sock".to_string());
+    thread::spawn(move || {
+       if let Err(e) = admin::serve(i, &control) {
+          eprintln!("control socket {} failed: {}", control, e);
        }
-      
+    });
+
+    loop {
+       let (mut stream, peer) = l.accept()?;
+       eprintln!("got connection from {}!", peer);
+       stream.write_all(b"hello from trust!\n")?;
     }
-    Ok(())
 }
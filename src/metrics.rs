@@ -0,0 +1,176 @@
+use std::time;
+
+/// Connection lifecycle events whose rates are tracked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateEvent {
+   /// a SYN was answered and a connection set up for it
+   PassiveOpen,
+   /// a connection never got past SYN-RECEIVED
+   FailedHandshake,
+   RstReceived,
+   RstSent,
+}
+
+impl RateEvent {
+   fn index(self) -> usize {
+      match self {
+         RateEvent::PassiveOpen => 0,
+         RateEvent::FailedHandshake => 1,
+         RateEvent::RstReceived => 2,
+         RateEvent::RstSent => 3,
+      }
+   }
+}
+
+/// A count of each `RateEvent`.
+#[derive(Debug, Clone, Default)]
+pub struct Rates {
+   pub passive_opens: u64,
+   pub failed_handshakes: u64,
+   pub rsts_received: u64,
+   pub rsts_sent: u64,
+}
+
+impl Rates {
+   fn from_counts(c: &[u64; 4]) -> Self {
+      Rates {
+         passive_opens: c[0],
+         failed_handshakes: c[1],
+         rsts_received: c[2],
+         rsts_sent: c[3],
+      }
+   }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RateReport {
+   /// events during the last complete second
+   pub last_second: Rates,
+   /// events since the interface was created
+   pub total: Rates,
+}
+
+pub type AlarmCallback = Box<dyn FnMut(RateEvent, u64) + Send>;
+
+/// Per-second event counts, with an alarm that fires the first time within a second that
+/// an event reaches its threshold. Scans and SYN floods show up here as a spike in passive
+/// opens, failed handshakes or RSTs sent long before anything else notices.
+#[derive(Default)]
+pub struct Metrics {
+   /// start of the second being counted in `current`
+   second: Option<time::Instant>,
+   current: [u64; 4],
+   last: [u64; 4],
+   total: [u64; 4],
+   thresholds: [Option<u64>; 4],
+   /// whether the alarm for an event already fired this second
+   alarmed: [bool; 4],
+   alarm: Option<AlarmCallback>,
+}
+
+impl Metrics {
+   pub fn record(&mut self, ev: RateEvent, now: time::Instant) {
+      self.roll(now);
+      let i = ev.index();
+      self.current[i] += 1;
+      self.total[i] += 1;
+      if let Some(threshold) = self.thresholds[i] {
+         if self.current[i] >= threshold && !self.alarmed[i] {
+            self.alarmed[i] = true;
+            if let Some(alarm) = self.alarm.as_mut() {
+               alarm(ev, self.current[i]);
+            }
+         }
+      }
+   }
+
+   fn roll(&mut self, now: time::Instant) {
+      let start = *self.second.get_or_insert(now);
+      let elapsed = now.saturating_duration_since(start);
+      if elapsed < time::Duration::from_secs(1) {
+         return;
+      }
+      // a gap of more than a second means the last full second had no events at all
+      self.last = if elapsed < time::Duration::from_secs(2) { self.current } else { [0; 4] };
+      self.current = [0; 4];
+      self.alarmed = [false; 4];
+      self.second = Some(now);
+   }
+
+   pub fn report(&mut self, now: time::Instant) -> RateReport {
+      self.roll(now);
+      RateReport {
+         last_second: Rates::from_counts(&self.last),
+         total: Rates::from_counts(&self.total),
+      }
+   }
+
+   pub fn set_threshold(&mut self, ev: RateEvent, per_second: Option<u64>) {
+      self.thresholds[ev.index()] = per_second;
+   }
+
+   pub fn set_alarm(&mut self, alarm: Option<AlarmCallback>) {
+      self.alarm = alarm;
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use std::sync::{Arc, Mutex};
+   use std::time::Duration;
+
+   fn ms(n: u64) -> Duration {
+      Duration::from_millis(n)
+   }
+
+   #[test]
+   fn alarm_fires_once_a_second_at_the_threshold() {
+      let fired = Arc::new(Mutex::new(Vec::new()));
+      let mut m = Metrics::default();
+      m.set_threshold(RateEvent::RstSent, Some(3));
+      let log = fired.clone();
+      m.set_alarm(Some(Box::new(move |ev, n| log.lock().unwrap().push((ev, n)))));
+      let t0 = time::Instant::now();
+      m.record(RateEvent::RstSent, t0);
+      m.record(RateEvent::RstSent, t0 + ms(100));
+      // other events don't count towards it
+      m.record(RateEvent::RstReceived, t0 + ms(150));
+      assert!(fired.lock().unwrap().is_empty());
+      m.record(RateEvent::RstSent, t0 + ms(200));
+      m.record(RateEvent::RstSent, t0 + ms(300));
+      assert_eq!(*fired.lock().unwrap(), vec![(RateEvent::RstSent, 3)]);
+      // a new second arms it again
+      for i in 0..3 {
+         m.record(RateEvent::RstSent, t0 + ms(1000 + i));
+      }
+      assert_eq!(*fired.lock().unwrap(), vec![(RateEvent::RstSent, 3), (RateEvent::RstSent, 3)]);
+   }
+
+   #[test]
+   fn report_has_the_last_full_second() {
+      let mut m = Metrics::default();
+      let t0 = time::Instant::now();
+      m.record(RateEvent::PassiveOpen, t0);
+      m.record(RateEvent::PassiveOpen, t0 + ms(500));
+      m.record(RateEvent::FailedHandshake, t0 + ms(900));
+      // still counting the first second
+      assert_eq!(m.report(t0 + ms(950)).last_second.passive_opens, 0);
+      let report = m.report(t0 + ms(1500));
+      assert_eq!(report.last_second.passive_opens, 2);
+      assert_eq!(report.last_second.failed_handshakes, 1);
+      assert_eq!(report.total.passive_opens, 2);
+   }
+
+   #[test]
+   fn gap_of_two_seconds_empties_the_last_one() {
+      let mut m = Metrics::default();
+      let t0 = time::Instant::now();
+      m.record(RateEvent::RstReceived, t0);
+      m.record(RateEvent::RstReceived, t0 + ms(100));
+      // the second after the one with events had none
+      let report = m.report(t0 + ms(2000));
+      assert_eq!(report.last_second.rsts_received, 0);
+      assert_eq!(report.total.rsts_received, 2);
+   }
+}
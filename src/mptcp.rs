@@ -0,0 +1,406 @@
+//! Multipath TCP (RFC 8684), as far as a single subflow goes. Both ends trade keys in the
+//! MP_CAPABLE options of the handshake, and from then on every segment carries a DSS option
+//! that maps its payload into the connection-level data sequence space and acknowledges
+//! the peer's data at that level. With one subflow the two sequence spaces move in
+//! lockstep, so the mapping is a fixed offset and the peer's own mappings need no
+//! reassembly of their own. Adding subflows (MP_JOIN) needs a second path, which an
+//! interface bound to one device doesn't have.
+//!
+//! Anything unexpected during the handshake falls back to plain TCP, as RFC 8684 S3.7
+//! allows: a peer without MPTCP, a version we don't speak, or one that insists on DSS
+//! checksums.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+use crate::options::{self, OptionWriter, TcpOption};
+
+const VERSION: u8 = 1;
+const MP_CAPABLE: u8 = 0;
+const DSS: u8 = 2;
+/// MP_CAPABLE flags: the sender requires DSS checksums, and uses HMAC-SHA256
+const CHECKSUM_REQUIRED: u8 = 0x80;
+const HMAC_SHA256: u8 = 0x01;
+/// DSS flags
+const DATA_FIN: u8 = 0x10;
+const DSN_8: u8 = 0x08;
+const MAPPING: u8 = 0x04;
+const DATA_ACK_8: u8 = 0x02;
+const DATA_ACK: u8 = 0x01;
+/// the most a DSS option of ours takes in the header, padding included
+pub const DSS_LEN: usize = 28;
+
+/// An MPTCP option we understand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MptcpOption {
+   Capable { version: u8, flags: u8, sender_key: Option<u64>, receiver_key: Option<u64> },
+   Dss { data_ack: Option<u64>, mapping: Option<Mapping>, data_fin: bool },
+}
+
+/// A DSS mapping: `len` bytes of data starting at `dsn` sit at subflow offset `ssn`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Mapping {
+   /// data sequence number, of which only the low 32 bits may have been sent
+   dsn: u64,
+   dsn_8: bool,
+   ssn: u32,
+   len: u16,
+}
+
+/// The MPTCP options among a header's options.
+fn parse(raw: &[u8]) -> impl Iterator<Item = MptcpOption> + '_ {
+   options::mptcp(raw).filter_map(parse_one)
+}
+
+fn parse_one(body: &[u8]) -> Option<MptcpOption> {
+   let (&first, rest) = body.split_first()?;
+   let (&flags, mut rest) = rest.split_first()?;
+   let mut take = |n: usize| -> Option<u64> {
+      if rest.len() < n {
+         return None;
+      }
+      let (field, tail) = rest.split_at(n);
+      rest = tail;
+      Some(field.iter().fold(0, |acc, &b| acc << 8 | b as u64))
+   };
+   match first >> 4 {
+      MP_CAPABLE => {
+         let sender_key = take(8);
+         let receiver_key = sender_key.and_then(|_| take(8));
+         Some(MptcpOption::Capable { version: first & 0x0f, flags, sender_key, receiver_key })
+      }
+      DSS => {
+         let data_ack = if flags & DATA_ACK != 0 {
+            Some(take(if flags & DATA_ACK_8 != 0 { 8 } else { 4 })?)
+         } else {
+            None
+         };
+         let mapping = if flags & MAPPING != 0 {
+            let dsn_8 = flags & DSN_8 != 0;
+            let dsn = take(if dsn_8 { 8 } else { 4 })?;
+            Some(Mapping { dsn, dsn_8, ssn: take(4)? as u32, len: take(2)? as u16 })
+         } else {
+            None
+         };
+         Some(MptcpOption::Dss { data_ack, mapping, data_fin: flags & DATA_FIN != 0 })
+      }
+      _ => None,
+   }
+}
+
+/// Whether a SYN offers MPTCP on terms we take: version 1 or later, and no checksums.
+pub fn offered(raw: &[u8]) -> bool {
+   parse(raw).any(|o| match o {
+      MptcpOption::Capable { version, flags, .. } => version >= VERSION && flags & CHECKSUM_REQUIRED == 0,
+      _ => false,
+   })
+}
+
+/// A fresh random key.
+fn new_key() -> u64 {
+   let mut h = RandomState::new().build_hasher();
+   h.write_u64(0);
+   h.finish()
+}
+
+/// The initial data sequence number that goes with a key: the low 64 bits of its SHA-256
+/// hash (RFC 8684 S3.1).
+fn idsn(key: u64) -> u64 {
+   let digest = sha256(&key.to_be_bytes());
+   u64::from_be_bytes([digest[24], digest[25], digest[26], digest[27], digest[28], digest[29], digest[30], digest[31]])
+}
+
+/// Widen a 32-bit offset into a 64-bit one, picking the value closest to the last one seen.
+#[derive(Debug, Default)]
+struct Unwrap {
+   last: u64,
+}
+
+impl Unwrap {
+   fn at(&mut self, off: u32) -> u64 {
+      let delta = off.wrapping_sub(self.last as u32) as i32 as i64;
+      let at = std::cmp::max(self.last as i64 + delta, 0) as u64;
+      self.last = std::cmp::max(self.last, at);
+      at
+   }
+}
+
+/// Widen the low 32 bits of a data sequence number, as sent in a short DSS field, into the
+/// value closest to `near`.
+fn widen(near: u64, low: u32) -> u64 {
+   let delta = low.wrapping_sub(near as u32) as i32 as i64;
+   (near as i64).wrapping_add(delta) as u64
+}
+
+/// The connection-level state of an MPTCP connection.
+#[derive(Debug)]
+pub struct Mptcp {
+   local_key: u64,
+   remote_key: Option<u64>,
+   /// we sent the first SYN, and so repeat the keys until the peer has shown it got them
+   initiator: bool,
+   /// the peer sent a DSS option, so it has both keys
+   confirmed: bool,
+   /// where our own data stands, as offsets from just past our SYN
+   snd: Unwrap,
+   /// where the peer's stands, likewise
+   rcv: Unwrap,
+   /// where the peer's DATA_FIN sits in its data sequence space, once it has sent one
+   data_fin: Option<u64>,
+}
+
+impl Mptcp {
+   pub fn new(initiator: bool) -> Self {
+      Mptcp {
+         local_key: new_key(),
+         remote_key: None,
+         initiator,
+         confirmed: false,
+         snd: Unwrap::default(),
+         rcv: Unwrap::default(),
+         data_fin: None,
+      }
+   }
+
+   /// The MP_CAPABLE option of our SYN, or of our SYN-ACK with our key. Returns whether it
+   /// fit.
+   pub fn push_syn(&self, w: &mut OptionWriter) -> bool {
+      let mut body = [0u8; 10];
+      body[0] = MP_CAPABLE << 4 | VERSION;
+      body[1] = HMAC_SHA256;
+      let len = if self.initiator {
+         2
+      } else {
+         body[2..].copy_from_slice(&self.local_key.to_be_bytes());
+         10
+      };
+      w.push(&TcpOption::Mptcp(&body[..len]))
+   }
+
+   /// The peer's SYN-ACK: MPTCP is on if it answered with its key, and we don't have to
+   /// checksum. Returns false to fall back.
+   pub fn take_syn_ack(&mut self, raw: &[u8]) -> bool {
+      self.remote_key = parse(raw).find_map(|o| match o {
+         MptcpOption::Capable { version: VERSION, flags, sender_key, .. } if flags & CHECKSUM_REQUIRED == 0 => {
+            sender_key
+         }
+         _ => None,
+      });
+      self.remote_key.is_some()
+   }
+
+   /// The peer's ACK of our SYN-ACK: MPTCP is on if it echoes both keys. Returns false to
+   /// fall back.
+   pub fn take_ack(&mut self, raw: &[u8]) -> bool {
+      let local = self.local_key;
+      self.remote_key = parse(raw).find_map(|o| match o {
+         MptcpOption::Capable { sender_key, receiver_key: Some(key), .. } if key == local => sender_key,
+         _ => None,
+      });
+      self.confirmed = self.remote_key.is_some();
+      self.confirmed
+   }
+
+   /// Take in the MPTCP options of a segment on the established connection. Returns whether
+   /// it brought the peer's DATA_FIN, which wants acknowledging.
+   pub fn take_segment(&mut self, raw: &[u8]) -> bool {
+      let mut new_fin = false;
+      for o in parse(raw) {
+         if let MptcpOption::Dss { mapping, data_fin, .. } = o {
+            self.confirmed = true;
+            if let Some(m) = mapping.filter(|_| data_fin && self.data_fin.is_none()) {
+               let dsn = if m.dsn_8 { m.dsn } else { widen(self.rcv_base().wrapping_add(self.rcv.last), m.dsn as u32) };
+               // the DATA_FIN takes the last place the mapping covers
+               self.data_fin = Some(dsn.wrapping_add(m.len as u64).wrapping_sub(1));
+               new_fin = true;
+            }
+         }
+      }
+      new_fin
+   }
+
+   fn rcv_base(&self) -> u64 {
+      self.remote_key.map_or(0, idsn).wrapping_add(1)
+   }
+
+   /// The options of a segment on the established connection: `seq_off` is where it starts
+   /// as an offset from just past our SYN, with `len` bytes of data and perhaps our FIN, and
+   /// `rcv_off` is how much of the peer's data we have, likewise. Until the peer has shown
+   /// it has our key, the initiator repeats MP_CAPABLE instead, carrying the first data's
+   /// length when it is the first data (RFC 8684 S3.1).
+   pub fn push(&mut self, w: &mut OptionWriter, seq_off: u32, len: usize, fin: bool, rcv_off: u32) {
+      let remote_key = match self.remote_key {
+         Some(key) => key,
+         None => return,
+      };
+      let first_data = seq_off == 0 && len > 0;
+      if self.initiator && !self.confirmed && ((len == 0 && !fin) || first_data) {
+         let mut body = [0u8; 20];
+         body[0] = MP_CAPABLE << 4 | VERSION;
+         body[1] = HMAC_SHA256;
+         body[2..10].copy_from_slice(&self.local_key.to_be_bytes());
+         body[10..18].copy_from_slice(&remote_key.to_be_bytes());
+         let n = if first_data {
+            body[18..].copy_from_slice(&(len as u16).to_be_bytes());
+            20
+         } else {
+            18
+         };
+         w.push(&TcpOption::Mptcp(&body[..n]));
+         return;
+      }
+      let rcv = self.rcv.at(rcv_off);
+      let mut data_ack = self.rcv_base().wrapping_add(rcv);
+      if self.data_fin == Some(data_ack) {
+         data_ack = data_ack.wrapping_add(1);
+      }
+      let mut body = [0u8; 24];
+      body[0] = DSS << 4;
+      body[1] = DATA_ACK | DATA_ACK_8;
+      body[2..10].copy_from_slice(&data_ack.to_be_bytes());
+      if len == 0 && !fin {
+         w.push(&TcpOption::Mptcp(&body[..10]));
+         return;
+      }
+      let dsn = idsn(self.local_key).wrapping_add(1).wrapping_add(self.snd.at(seq_off));
+      // a DATA_FIN on its own is mapped to no subflow data at all (RFC 8684 S3.3.3)
+      let ssn = if len == 0 { 0 } else { seq_off.wrapping_add(1) };
+      body[1] |= MAPPING | DSN_8 | if fin { DATA_FIN } else { 0 };
+      body[10..18].copy_from_slice(&dsn.to_be_bytes());
+      body[18..22].copy_from_slice(&ssn.to_be_bytes());
+      body[22..24].copy_from_slice(&((len + fin as usize) as u16).to_be_bytes());
+      w.push(&TcpOption::Mptcp(&body));
+   }
+}
+
+/// SHA-256 (FIPS 180-4), which MPTCP derives its data sequence numbers from.
+fn sha256(data: &[u8]) -> [u8; 32] {
+   const K: [u32; 64] = [
+      0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+      0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+      0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+      0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+      0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+      0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+      0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+      0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+   ];
+   let mut h: [u32; 8] = [
+      0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+   ];
+   // the message, a one bit, zeros up to 8 bytes short of a block boundary, and its length
+   let mut msg = data.to_vec();
+   msg.push(0x80);
+   while msg.len() % 64 != 56 {
+      msg.push(0);
+   }
+   msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+   for block in msg.chunks(64) {
+      let mut w = [0u32; 64];
+      for (i, word) in block.chunks(4).enumerate() {
+         w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+      }
+      for i in 16..64 {
+         let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+         let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+         w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+      }
+      let mut v = h;
+      for i in 0..64 {
+         let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+         let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+         let t1 = v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+         let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+         let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+         let t2 = s0.wrapping_add(maj);
+         v = [t1.wrapping_add(t2), v[0], v[1], v[2], v[3].wrapping_add(t1), v[4], v[5], v[6]];
+      }
+      for (a, b) in h.iter_mut().zip(v.iter()) {
+         *a = a.wrapping_add(*b);
+      }
+   }
+   let mut out = [0u8; 32];
+   for (i, word) in h.iter().enumerate() {
+      out[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+   }
+   out
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn sha256_known_answers() {
+      let hex = |d: [u8; 32]| d.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+      assert_eq!(hex(sha256(b"")), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+      assert_eq!(hex(sha256(b"abc")), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+      // two blocks
+      let long = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+      assert_eq!(hex(sha256(long)), "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+   }
+
+   #[test]
+   fn handshake_options_round_trip() {
+      let mut client = Mptcp::new(true);
+      let mut server = Mptcp::new(false);
+      let mut syn = OptionWriter::default();
+      client.push_syn(&mut syn);
+      assert!(offered(syn.as_slice()));
+
+      let mut syn_ack = OptionWriter::default();
+      server.push_syn(&mut syn_ack);
+      assert!(client.take_syn_ack(syn_ack.as_slice()));
+
+      // the third ACK repeats both keys
+      let mut ack = OptionWriter::default();
+      client.push(&mut ack, 0, 0, false, 0);
+      assert!(server.take_ack(ack.as_slice()));
+      assert_eq!(server.remote_key, Some(client.local_key));
+   }
+
+   #[test]
+   fn dss_maps_data_and_the_data_fin() {
+      let mut m = Mptcp::new(false);
+      m.remote_key = Some(7);
+      let mut w = OptionWriter::default();
+      m.push(&mut w, 100, 0, true, 50);
+      let dss = parse(w.as_slice()).next();
+      let dsn = idsn(m.local_key).wrapping_add(101);
+      let ack = idsn(7).wrapping_add(51);
+      assert_eq!(
+         dss,
+         Some(MptcpOption::Dss {
+            data_ack: Some(ack),
+            mapping: Some(Mapping { dsn, dsn_8: true, ssn: 0, len: 1 }),
+            data_fin: true,
+         })
+      );
+      assert!(w.as_slice().len() <= DSS_LEN);
+      // the peer's DATA_FIN right where its data ends is acked along with it
+      let mut fin = OptionWriter::default();
+      let mut peer = Mptcp::new(false);
+      peer.local_key = 7;
+      peer.remote_key = Some(m.local_key);
+      peer.push(&mut fin, 50, 0, true, 100);
+      assert!(m.take_segment(fin.as_slice()));
+      let mut w = OptionWriter::default();
+      m.push(&mut w, 101, 0, false, 50);
+      let dss = parse(w.as_slice()).next();
+      match dss {
+         Some(MptcpOption::Dss { data_ack, .. }) => assert_eq!(data_ack, Some(ack.wrapping_add(1))),
+         other => panic!("{:?}", other),
+      }
+   }
+
+   #[test]
+   fn offsets_unwrap_past_four_gigabytes() {
+      let mut u = Unwrap::default();
+      for i in 1..4u64 {
+         assert_eq!(u.at((i << 30) as u32), i << 30);
+      }
+      assert_eq!(u.at(5), (1 << 32) + 5);
+      // a retransmission from before the wrap
+      assert_eq!(u.at(u32::MAX - 20), u32::MAX as u64 - 20);
+   }
+}
@@ -0,0 +1,264 @@
+//! TCP options: parsing the option bytes of incoming headers into typed options, and laying
+//! out the options of outgoing ones. Parsing borrows from the header rather than copying, so
+//! it is fine on the per-segment path.
+
+/// end of option list
+const EOL: u8 = 0;
+const NOP: u8 = 1;
+const MSS: u8 = 2;
+const WSCALE: u8 = 3;
+const SACK_PERMITTED: u8 = 4;
+const SACK: u8 = 5;
+const TIMESTAMPS: u8 = 8;
+const MPTCP: u8 = 30;
+const FAST_OPEN: u8 = 34;
+/// room for options in a TCP header
+pub const MAX_LEN: usize = 40;
+
+/// One option. An option of a known kind with the wrong length comes out as `Unknown`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpOption<'a> {
+   Mss(u16),
+   WindowScale(u8),
+   SackPermitted,
+   Sack(SackBlocks<'a>),
+   Timestamps { val: u32, ecr: u32 },
+   /// a Fast Open cookie, empty for a cookie request (RFC 7413)
+   FastOpen(&'a [u8]),
+   /// a Multipath TCP option, subtype and all (RFC 8684)
+   Mptcp(&'a [u8]),
+   Unknown { kind: u8, data: &'a [u8] },
+}
+
+/// The blocks of a SACK option, as left and right edges.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SackBlocks<'a>(&'a [u8]);
+
+impl<'a> SackBlocks<'a> {
+   /// Blocks in wire format, eight bytes each; see `sack_block()`.
+   pub fn from_raw(raw: &'a [u8]) -> Self {
+      SackBlocks(raw)
+   }
+}
+
+/// The wire format of one SACK block.
+pub fn sack_block(left: u32, right: u32) -> [u8; 8] {
+   let mut b = [0u8; 8];
+   b[..4].copy_from_slice(&left.to_be_bytes());
+   b[4..].copy_from_slice(&right.to_be_bytes());
+   b
+}
+
+impl<'a> Iterator for SackBlocks<'a> {
+   type Item = (u32, u32);
+
+   fn next(&mut self) -> Option<(u32, u32)> {
+      if self.0.len() < 8 {
+         return None;
+      }
+      let (b, rest) = self.0.split_at(8);
+      self.0 = rest;
+      Some((u32::from_be_bytes([b[0], b[1], b[2], b[3]]), u32::from_be_bytes([b[4], b[5], b[6], b[7]])))
+   }
+}
+
+/// The options in a header's option bytes, in order. Lengths were checked on ingress, but a
+/// truncated option just ends the list.
+pub fn parse(options: &[u8]) -> Options<'_> {
+   Options(options)
+}
+
+pub struct Options<'a>(&'a [u8]);
+
+impl<'a> Iterator for Options<'a> {
+   type Item = TcpOption<'a>;
+
+   fn next(&mut self) -> Option<TcpOption<'a>> {
+      loop {
+         let kind = *self.0.first()?;
+         match kind {
+            EOL => {
+               self.0 = &[];
+               return None;
+            }
+            NOP => self.0 = &self.0[1..],
+            _ => break,
+         }
+      }
+      let kind = self.0[0];
+      let len = *self.0.get(1)? as usize;
+      if len < 2 || len > self.0.len() {
+         self.0 = &[];
+         return None;
+      }
+      let data = &self.0[2..len];
+      self.0 = &self.0[len..];
+      Some(match (kind, data) {
+         (MSS, &[hi, lo]) => TcpOption::Mss(u16::from_be_bytes([hi, lo])),
+         (WSCALE, &[shift]) => TcpOption::WindowScale(shift),
+         (SACK_PERMITTED, &[]) => TcpOption::SackPermitted,
+         (SACK, _) if data.len() % 8 == 0 => TcpOption::Sack(SackBlocks(data)),
+         (TIMESTAMPS, &[a, b, c, d, e, f, g, h]) => TcpOption::Timestamps {
+            val: u32::from_be_bytes([a, b, c, d]),
+            ecr: u32::from_be_bytes([e, f, g, h]),
+         },
+         (FAST_OPEN, _) => TcpOption::FastOpen(data),
+         (MPTCP, _) if !data.is_empty() => TcpOption::Mptcp(data),
+         _ => TcpOption::Unknown { kind, data },
+      })
+   }
+}
+
+pub fn mss(options: &[u8]) -> Option<u16> {
+   parse(options).find_map(|o| match o {
+      TcpOption::Mss(mss) => Some(mss),
+      _ => None,
+   })
+}
+
+pub fn window_scale(options: &[u8]) -> Option<u8> {
+   parse(options).find_map(|o| match o {
+      TcpOption::WindowScale(shift) => Some(shift),
+      _ => None,
+   })
+}
+
+pub fn sack_permitted(options: &[u8]) -> bool {
+   parse(options).any(|o| o == TcpOption::SackPermitted)
+}
+
+pub fn sack(options: &[u8]) -> Option<SackBlocks<'_>> {
+   parse(options).find_map(|o| match o {
+      TcpOption::Sack(blocks) => Some(blocks),
+      _ => None,
+   })
+}
+
+/// TSval and TSecr.
+pub fn timestamps(options: &[u8]) -> Option<(u32, u32)> {
+   parse(options).find_map(|o| match o {
+      TcpOption::Timestamps { val, ecr } => Some((val, ecr)),
+      _ => None,
+   })
+}
+
+pub fn fast_open(options: &[u8]) -> Option<&[u8]> {
+   parse(options).find_map(|o| match o {
+      TcpOption::FastOpen(cookie) => Some(cookie),
+      _ => None,
+   })
+}
+
+/// The bodies of the Multipath TCP options, of which a header may hold several.
+pub fn mptcp(options: &[u8]) -> impl Iterator<Item = &[u8]> {
+   parse(options).filter_map(|o| match o {
+      TcpOption::Mptcp(body) => Some(body),
+      _ => None,
+   })
+}
+
+/// Options for an outgoing header. Each option is preceded by as many NOPs as it takes to
+/// make it end on a four-byte boundary, so the list never needs end-of-list padding.
+pub struct OptionWriter {
+   buf: [u8; MAX_LEN],
+   len: usize,
+}
+
+impl Default for OptionWriter {
+   fn default() -> Self {
+      OptionWriter { buf: [0; MAX_LEN], len: 0 }
+   }
+}
+
+impl OptionWriter {
+   /// Append `opt`, unless the header has no room left for it. Returns whether it fit.
+   pub fn push(&mut self, opt: &TcpOption) -> bool {
+      let (kind, body): (u8, &[u8]) = match opt {
+         TcpOption::Mss(mss) => return self.put(MSS, &mss.to_be_bytes()),
+         TcpOption::WindowScale(shift) => return self.put(WSCALE, &[*shift]),
+         TcpOption::SackPermitted => (SACK_PERMITTED, &[]),
+         TcpOption::Sack(blocks) => (SACK, blocks.0),
+         TcpOption::Timestamps { val, ecr } => {
+            let mut b = [0u8; 8];
+            b[..4].copy_from_slice(&val.to_be_bytes());
+            b[4..].copy_from_slice(&ecr.to_be_bytes());
+            return self.put(TIMESTAMPS, &b);
+         }
+         TcpOption::FastOpen(cookie) => (FAST_OPEN, cookie),
+         TcpOption::Mptcp(body) => (MPTCP, body),
+         TcpOption::Unknown { kind, data } => (*kind, data),
+      };
+      self.put(kind, body)
+   }
+
+   fn put(&mut self, kind: u8, body: &[u8]) -> bool {
+      let len = 2 + body.len();
+      let pad = (4 - len % 4) % 4;
+      if self.len + pad + len > MAX_LEN {
+         return false;
+      }
+      for b in &mut self.buf[self.len..self.len + pad] {
+         *b = NOP;
+      }
+      let at = self.len + pad;
+      self.buf[at] = kind;
+      self.buf[at + 1] = len as u8;
+      self.buf[at + 2..at + len].copy_from_slice(body);
+      self.len = at + len;
+      true
+   }
+
+   /// Bytes left for more options.
+   pub fn room(&self) -> usize {
+      MAX_LEN - self.len
+   }
+
+   pub fn as_slice(&self) -> &[u8] {
+      &self.buf[..self.len]
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn written_options_parse_back() {
+      let block = sack_block(1000, 2000);
+      let written = [
+         TcpOption::Mss(1460),
+         TcpOption::SackPermitted,
+         TcpOption::WindowScale(7),
+         TcpOption::Timestamps { val: 1, ecr: 2 },
+         TcpOption::Sack(SackBlocks::from_raw(&block)),
+      ];
+      let mut w = OptionWriter::default();
+      for opt in &written {
+         assert!(w.push(opt));
+      }
+      assert_eq!(w.as_slice().len() % 4, 0);
+      assert_eq!(parse(w.as_slice()).collect::<Vec<_>>(), written);
+      assert_eq!(sack(w.as_slice()).unwrap().collect::<Vec<_>>(), vec![(1000, 2000)]);
+   }
+
+   #[test]
+   fn options_that_do_not_fit_are_left_out() {
+      let mut w = OptionWriter::default();
+      for _ in 0..3 {
+         assert!(w.push(&TcpOption::Timestamps { val: 0, ecr: 0 }));
+      }
+      assert!(!w.push(&TcpOption::Timestamps { val: 0, ecr: 0 }));
+      assert!(w.push(&TcpOption::Mss(536)));
+      assert_eq!(w.as_slice().len(), MAX_LEN);
+   }
+
+   #[test]
+   fn odd_lengths_and_truncation() {
+      // an MSS option with a 3-byte body, then one running past the end
+      let raw = [MSS, 5, 0, 0, 0, 1, TIMESTAMPS, 10, 0];
+      let opts: Vec<_> = parse(&raw).collect();
+      assert_eq!(opts, vec![TcpOption::Unknown { kind: MSS, data: &[0, 0, 0] }]);
+      assert_eq!(mss(&raw), None);
+      assert_eq!(timestamps(&raw), None);
+   }
+}
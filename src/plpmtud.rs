@@ -0,0 +1,166 @@
+//! Packetization layer path MTU discovery (RFC 4821). Rather than trust ICMP "fragmentation
+//! needed" messages, which firewalls often drop, a connection starts with segments small
+//! enough for nearly any path and now and then sends one larger probe segment. An ACK for
+//! the probe proves the path takes that size; losing it a few times in a row proves it
+//! doesn't. The search narrows down between the two by halves.
+//!
+//! A lost probe says nothing about congestion, so the connection doesn't cut its window for
+//! one, and resends its data in segments of the size already confirmed.
+
+use std::time::{Duration, Instant};
+
+/// where the search starts, small enough for nearly every path (RFC 4821 S7.2)
+pub const BASE_MTU: usize = 1024;
+/// the search stops once the bounds are this close
+const PRECISION: usize = 32;
+/// losses of the same probe size before the path is taken not to carry it (RFC 4821 S7.6.2)
+const MAX_PROBES: u32 = 3;
+/// after this long, sizes that failed before are tried again, in case the path changed
+/// (RFC 4821 S7.7)
+const RAISE_TIMER: Duration = Duration::from_secs(600);
+
+#[derive(Debug)]
+pub struct Plpmtud {
+   /// the largest MTU the path has been seen to take
+   low: usize,
+   /// the largest MTU that hasn't failed yet
+   high: usize,
+   /// the device MTU, beyond which no probe goes
+   mtu: usize,
+   /// the probe in flight: its size and the sequence range it covers
+   probe: Option<(usize, u32, u32)>,
+   /// the size being tried again after losing a probe of it, and how many were lost
+   retry: Option<(usize, u32)>,
+   /// when the search last gave up on a size
+   failed_at: Option<Instant>,
+}
+
+impl Plpmtud {
+   pub fn new(mtu: usize) -> Self {
+      Plpmtud {
+         low: std::cmp::min(BASE_MTU, mtu),
+         high: mtu,
+         mtu,
+         probe: None,
+         retry: None,
+         failed_at: None,
+      }
+   }
+
+   /// The MTU segments are sized for.
+   pub fn mtu(&self) -> usize {
+      self.low
+   }
+
+   /// The size of the next probe, if one is due: none while one is outstanding, or once
+   /// the search is done. `limit` is the largest MTU of any use, as the peer's MSS has it.
+   pub fn next_probe(&mut self, limit: usize, now: Instant) -> Option<usize> {
+      if self.failed_at.map_or(false, |at| now.saturating_duration_since(at) >= RAISE_TIMER) {
+         self.high = self.mtu;
+         self.failed_at = None;
+      }
+      let high = std::cmp::min(self.high, limit);
+      if self.probe.is_some() || high < self.low + PRECISION {
+         return None;
+      }
+      match self.retry {
+         Some((size, _)) if size <= high => Some(size),
+         _ if high - self.low <= 2 * PRECISION => Some(high),
+         _ => Some((self.low + high + 1) / 2),
+      }
+   }
+
+   /// A probe of `size` went out, covering `seq..end`.
+   pub fn sent(&mut self, size: usize, seq: u32, end: u32) {
+      self.probe = Some((size, seq, end));
+   }
+
+   /// Where the probe in flight starts.
+   pub fn probe_seq(&self) -> Option<u32> {
+      self.probe.map(|(_, seq, _)| seq)
+   }
+
+   /// The peer acknowledged everything before `ackn`. Returns whether that confirmed a
+   /// larger MTU.
+   pub fn on_ack(&mut self, ackn: u32) -> bool {
+      match self.probe {
+         Some((size, _, end)) if ackn.wrapping_sub(end) as i32 >= 0 => {
+            self.probe = None;
+            self.retry = None;
+            self.low = std::cmp::max(self.low, size);
+            self.high = std::cmp::max(self.high, self.low);
+            true
+         }
+         _ => false,
+      }
+   }
+
+   /// The probe in flight was lost.
+   pub fn on_loss(&mut self, now: Instant) {
+      let (size, _, _) = match self.probe.take() {
+         Some(probe) => probe,
+         None => return,
+      };
+      let losses = match self.retry {
+         Some((tried, n)) if tried == size => n + 1,
+         _ => 1,
+      };
+      if losses < MAX_PROBES {
+         self.retry = Some((size, losses));
+      } else {
+         self.retry = None;
+         self.high = size - 1;
+         self.failed_at = Some(now);
+      }
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn search_converges_on_the_path_mtu() {
+      let path = 1400;
+      let now = Instant::now();
+      let mut p = Plpmtud::new(9000);
+      let mut seq = 0u32;
+      while let Some(size) = p.next_probe(9000, now) {
+         p.sent(size, seq, seq + 100);
+         seq += 100;
+         if size <= path {
+            assert!(p.on_ack(seq));
+         } else {
+            p.on_loss(now);
+         }
+      }
+      assert!(p.mtu() <= path && p.mtu() + PRECISION > path, "{}", p.mtu());
+   }
+
+   #[test]
+   fn one_lost_probe_is_not_enough() {
+      let now = Instant::now();
+      let mut p = Plpmtud::new(1500);
+      let size = p.next_probe(1500, now).unwrap();
+      p.sent(size, 0, 100);
+      p.on_loss(now);
+      // the same size is tried again rather than written off
+      assert_eq!(p.next_probe(1500, now), Some(size));
+      for _ in 1..MAX_PROBES {
+         p.sent(size, 0, 100);
+         p.on_loss(now);
+      }
+      assert_ne!(p.next_probe(1500, now), Some(size));
+      // until the raise timer lets it be tried again
+      assert_eq!(p.mtu(), BASE_MTU);
+      assert!(p.next_probe(1500, now + RAISE_TIMER).is_some());
+   }
+
+   #[test]
+   fn probes_stay_within_the_limit() {
+      let now = Instant::now();
+      let mut p = Plpmtud::new(9000);
+      assert!(p.next_probe(1500, now).map_or(false, |size| size <= 1500));
+      assert_eq!(p.next_probe(BASE_MTU, now), None);
+   }
+}
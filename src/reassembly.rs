@@ -0,0 +1,264 @@
+//! The reassembly queue. Data that arrives past a hole in the sequence space waits here until
+//! the segments before it show up, and then goes to the application in order. It is kept in
+//! a ring the size of the receive window, allocated with the connection, at its offset from
+//! RCV.NXT: overlapping and duplicate segments simply land on the same bytes again, and
+//! holding data never allocates.
+//!
+//! Memory is bounded however the peer slices its segments: the ring caps the bytes held,
+//! and `MAX_RUNS` the number of disjoint runs (and so the bookkeeping, and the work of each
+//! insert). A segment past either limit is dropped, though the caller still acks it, so a
+//! flood of tiny out-of-order segments costs nothing but the ACKs. Where segments overlap,
+//! the copy that arrived last wins; a well-behaved peer resends the same bytes, and RFC 793
+//! leaves the choice to us.
+
+/// disjoint runs of data held at once; a segment that would start another one is dropped
+const MAX_RUNS: usize = 32;
+
+#[derive(Debug, Default)]
+pub struct Reassembly {
+   buf: Vec<u8>,
+   /// where `base` sits in `buf`
+   head: usize,
+   /// RCV.NXT as of the last call
+   base: u32,
+   /// the runs of data held, as sequence ranges in order
+   runs: Vec<(u32, u32)>,
+   /// where held segments that had PSH set end
+   pushes: Vec<u32>,
+   /// where the peer's FIN sits, if it came in past a hole
+   fin: Option<u32>,
+   /// the start of the segment that arrived last, whose run SACK reports first
+   latest: Option<u32>,
+}
+
+impl Reassembly {
+   pub fn new(size: usize) -> Self {
+      Reassembly {
+         buf: vec![0; size],
+         runs: Vec::with_capacity(MAX_RUNS),
+         pushes: Vec::with_capacity(MAX_RUNS),
+         ..Reassembly::default()
+      }
+   }
+
+   /// Bytes allocated for held data.
+   pub fn memory(&self) -> usize {
+      self.buf.capacity()
+   }
+
+   pub fn is_empty(&self) -> bool {
+      self.runs.is_empty() && self.fin.is_none()
+   }
+
+   /// Hold a segment starting at `seq`, somewhere past `nxt`. Returns whether it was kept:
+   /// one reaching beyond the ring, or that would start one run too many, is dropped for
+   /// the peer to send again.
+   pub fn insert(&mut self, nxt: u32, seq: u32, data: &[u8], psh: bool, fin: bool) -> bool {
+      self.advance(nxt);
+      let off = seq.wrapping_sub(self.base) as usize;
+      let end = seq.wrapping_add(data.len() as u32);
+      if off + data.len() > self.buf.len() {
+         return false;
+      }
+      if !data.is_empty() && !self.merge(seq, end) {
+         return false;
+      }
+      // a bare FIN takes no room, in a ring that may have none
+      if !data.is_empty() {
+         let at = (self.head + off) % self.buf.len();
+         let first = std::cmp::min(data.len(), self.buf.len() - at);
+         self.buf[at..at + first].copy_from_slice(&data[..first]);
+         self.buf[..data.len() - first].copy_from_slice(&data[first..]);
+      }
+      if psh && !self.pushes.contains(&end) && self.pushes.len() < MAX_RUNS {
+         self.pushes.push(end);
+      }
+      if fin {
+         self.fin = Some(end);
+      }
+      self.latest = Some(seq);
+      true
+   }
+
+   /// Add `seq..end` to the runs, joining any it overlaps or touches.
+   fn merge(&mut self, seq: u32, end: u32) -> bool {
+      let off = |s: u32| s.wrapping_sub(self.base);
+      let first = self.runs.iter().position(|&(_, e)| off(e) >= off(seq)).unwrap_or(self.runs.len());
+      let (mut start, mut stop) = (seq, end);
+      let mut last = first;
+      while last < self.runs.len() && off(self.runs[last].0) <= off(end) {
+         let (s, e) = self.runs[last];
+         if off(s) < off(start) {
+            start = s;
+         }
+         if off(e) > off(stop) {
+            stop = e;
+         }
+         last += 1;
+      }
+      if first == last {
+         if self.runs.len() == MAX_RUNS {
+            return false;
+         }
+         self.runs.insert(first, (start, stop));
+      } else {
+         self.runs[first] = (start, stop);
+         self.runs.drain(first + 1..last);
+      }
+      true
+   }
+
+   /// Catch up with RCV.NXT, forgetting whatever it has moved past.
+   fn advance(&mut self, nxt: u32) {
+      let n = nxt.wrapping_sub(self.base) as usize;
+      if n == 0 {
+         return;
+      }
+      if self.is_empty() || n >= self.buf.len() {
+         self.runs.clear();
+         self.pushes.clear();
+         self.fin = None;
+         self.base = nxt;
+         self.head = 0;
+         return;
+      }
+      self.head = (self.head + n) % self.buf.len();
+      self.base = nxt;
+      let off = |s: u32| s.wrapping_sub(nxt) as i32;
+      self.runs.retain(|&(_, e)| off(e) > 0);
+      if let Some(run) = self.runs.first_mut() {
+         if off(run.0) < 0 {
+            run.0 = nxt;
+         }
+      }
+      self.pushes.retain(|&p| off(p) > 0);
+      if self.fin.map_or(false, |f| off(f) < 0) {
+         self.fin = None;
+      }
+   }
+
+   /// Take the held data that continues the stream at `nxt`, if there is any, and whether
+   /// it ends where a segment with PSH did. A run that wraps around the ring, or has PSH
+   /// inside it, comes out in several pieces.
+   pub fn pop(&mut self, nxt: u32) -> Option<(&[u8], bool)> {
+      self.advance(nxt);
+      let (start, end) = *self.runs.first()?;
+      if start != nxt {
+         return None;
+      }
+      let mut len = end.wrapping_sub(start) as usize;
+      let mut psh = false;
+      if let Some(p) = self.pushes.iter().map(|&p| p.wrapping_sub(start) as usize).filter(|&p| p <= len).min() {
+         len = p;
+         psh = true;
+      }
+      if len > self.buf.len() - self.head {
+         len = self.buf.len() - self.head;
+         psh = false;
+      }
+      let at = self.head;
+      self.advance(nxt.wrapping_add(len as u32));
+      Some((&self.buf[at..at + len], psh))
+   }
+
+   /// Whether the peer's FIN is next at `nxt`, now that everything before it is in.
+   pub fn take_fin(&mut self, nxt: u32) -> bool {
+      if self.fin == Some(nxt) && self.runs.is_empty() {
+         self.fin = None;
+         true
+      } else {
+         false
+      }
+   }
+
+   /// The runs held, for SACK blocks: the one the latest segment went into first (RFC 2018
+   /// S4), then the rest in order.
+   pub fn sack_blocks(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+      let off = move |s: u32| s.wrapping_sub(self.base);
+      let latest = self
+         .latest
+         .and_then(|l| self.runs.iter().copied().find(|&(s, e)| off(s) <= off(l) && off(l) < off(e)));
+      latest.into_iter().chain(self.runs.iter().copied().filter(move |&r| Some(r) != latest))
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   fn drain(r: &mut Reassembly, mut nxt: u32) -> (Vec<u8>, u32) {
+      let mut out = Vec::new();
+      while let Some((data, _)) = r.pop(nxt) {
+         out.extend_from_slice(data);
+         nxt = nxt.wrapping_add(data.len() as u32);
+      }
+      (out, nxt)
+   }
+
+   #[test]
+   fn overlapping_segments_across_the_wrap() {
+      let nxt = u32::MAX - 5;
+      let mut r = Reassembly::new(64);
+      let stream: Vec<u8> = (0..40).collect();
+      // out of order, overlapping each other, and straddling 2^32
+      assert!(r.insert(nxt, nxt.wrapping_add(20), &stream[20..30], false, false));
+      assert!(r.insert(nxt, nxt.wrapping_add(8), &stream[8..14], false, false));
+      assert!(r.insert(nxt, nxt.wrapping_add(12), &stream[12..24], true, false));
+      assert!(r.insert(nxt, nxt.wrapping_add(30), &stream[30..40], false, true));
+      assert_eq!(r.sack_blocks().collect::<Vec<_>>(), vec![(nxt.wrapping_add(8), nxt.wrapping_add(40))]);
+      assert_eq!(r.pop(nxt).map(|(d, _)| d.len()), None);
+
+      // the hole is filled in order, so the rest follows
+      let (out, nxt) = drain(&mut r, nxt.wrapping_add(8));
+      assert_eq!(&out[..], &stream[8..40]);
+      assert!(r.take_fin(nxt));
+      assert!(r.is_empty());
+   }
+
+   #[test]
+   fn pieces_end_at_pushes() {
+      let mut r = Reassembly::new(64);
+      assert!(r.insert(0, 10, &[1; 10], true, false));
+      assert!(r.insert(0, 20, &[2; 10], false, false));
+      assert!(r.insert(0, 40, &[3; 10], false, false));
+      // the latest arrival's run first
+      assert_eq!(r.sack_blocks().collect::<Vec<_>>(), vec![(40, 50), (10, 30)]);
+      assert_eq!(r.pop(10), Some((&[1u8; 10][..], true)));
+      assert_eq!(r.pop(20), Some((&[2u8; 10][..], false)));
+      assert_eq!(r.pop(30), None);
+      // beyond the ring
+      assert!(!r.insert(30, 90, &[4; 10], false, false));
+   }
+
+   #[test]
+   fn runs_are_capped() {
+      let mut r = Reassembly::new(1024);
+      // one byte every other one past a hole at 0, until no further run fits
+      for i in 0..MAX_RUNS as u32 {
+         assert!(r.insert(0, 2 + 2 * i, &[1], false, false));
+      }
+      let beyond = 2 + 2 * MAX_RUNS as u32;
+      assert!(!r.insert(0, beyond, &[2], false, false));
+      assert_eq!(r.sack_blocks().count(), MAX_RUNS);
+      // growing or joining the runs held is still fine, and makes room again
+      assert!(r.insert(0, 3, &[1], false, false));
+      assert!(r.insert(0, beyond, &[2], false, false));
+   }
+
+   #[test]
+   fn the_last_copy_of_overlapping_bytes_wins() {
+      let mut r = Reassembly::new(64);
+      assert!(r.insert(0, 10, &[1; 10], false, false));
+      assert!(r.insert(0, 15, &[2; 10], false, false));
+      let (out, _) = drain(&mut r, 10);
+      assert_eq!(out, [[1u8; 5], [2; 5], [2; 5]].concat());
+   }
+
+   #[test]
+   fn empty_ring_holds_only_a_fin() {
+      let mut r = Reassembly::new(0);
+      assert!(!r.insert(10, 10, b"x", false, false));
+      assert!(r.insert(10, 10, &[], false, true));
+      assert!(r.take_fin(10));
+   }
+}
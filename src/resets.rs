@@ -0,0 +1,190 @@
+//! Connections we reset on purpose: on the operator's or the application's orders, by
+//! policy, or because a limit ran out. Each is counted and logged with its reason, so these
+//! resets can be told apart from the ones the protocol itself calls for. With
+//! `ListenOptions::rst_diagnostics` the RST also carries the reason as text (RFC 1122
+//! S4.2.2.12 lets an RST carry data), which shows up in the peer's packet captures.
+
+use std::collections::VecDeque;
+use std::net::SocketAddrV4;
+use std::time::SystemTime;
+
+/// entries kept in the reset log before the oldest are discarded
+const RESET_LOG_LEN: usize = 1024;
+
+/// Why we reset a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetReason {
+   /// `Interface::kill()`
+   Killed,
+   /// `TcpStream::abort()`
+   Aborted,
+   /// still open when a drain's deadline passed
+   Drained,
+   /// an accept filter rejected it
+   Rejected,
+   /// an accept filter routed it to a port nobody listens on
+   NoRoute,
+   /// its listener was closed before it was accepted
+   ListenerClosed,
+   /// refused because connection buffers are at the memory limit
+   OutOfMemory,
+   /// the handshake didn't complete in time
+   HandshakeTimeout,
+   /// the peer stopped acknowledging our retransmissions
+   RetransmitTimeout,
+   /// the peer stopped answering the probes of its zero window
+   PersistTimeout,
+   /// the peer stopped answering keepalive probes
+   KeepaliveTimeout,
+   /// both ends advertised a zero window for too long
+   ZeroWindowDeadlock,
+   /// the other end of a SYN proxy's relay failed
+   RelayFailed,
+}
+
+impl ResetReason {
+   pub const ALL: [ResetReason; 13] = [
+      ResetReason::Killed,
+      ResetReason::Aborted,
+      ResetReason::Drained,
+      ResetReason::Rejected,
+      ResetReason::NoRoute,
+      ResetReason::ListenerClosed,
+      ResetReason::OutOfMemory,
+      ResetReason::HandshakeTimeout,
+      ResetReason::RetransmitTimeout,
+      ResetReason::PersistTimeout,
+      ResetReason::KeepaliveTimeout,
+      ResetReason::ZeroWindowDeadlock,
+      ResetReason::RelayFailed,
+   ];
+
+   /// A short name, for stats.
+   pub fn name(self) -> &'static str {
+      match self {
+         ResetReason::Killed => "killed",
+         ResetReason::Aborted => "aborted",
+         ResetReason::Drained => "drained",
+         ResetReason::Rejected => "rejected",
+         ResetReason::NoRoute => "no_route",
+         ResetReason::ListenerClosed => "listener_closed",
+         ResetReason::OutOfMemory => "out_of_memory",
+         ResetReason::HandshakeTimeout => "handshake_timeout",
+         ResetReason::RetransmitTimeout => "retransmit_timeout",
+         ResetReason::PersistTimeout => "persist_timeout",
+         ResetReason::KeepaliveTimeout => "keepalive_timeout",
+         ResetReason::ZeroWindowDeadlock => "zero_window_deadlock",
+         ResetReason::RelayFailed => "relay_failed",
+      }
+   }
+
+   /// What the peer is told, if diagnostics are on.
+   pub fn describe(self) -> &'static str {
+      match self {
+         ResetReason::Killed => "connection killed by administrator",
+         ResetReason::Aborted => "connection aborted by application",
+         ResetReason::Drained => "server draining",
+         ResetReason::Rejected => "rejected by policy",
+         ResetReason::NoRoute => "no service for this request",
+         ResetReason::ListenerClosed => "listener closed",
+         ResetReason::OutOfMemory => "server out of memory",
+         ResetReason::HandshakeTimeout => "handshake timed out",
+         ResetReason::RetransmitTimeout => "retransmission timed out",
+         ResetReason::PersistTimeout => "window probes unanswered",
+         ResetReason::KeepaliveTimeout => "keepalive probes unanswered",
+         ResetReason::ZeroWindowDeadlock => "zero window deadlock",
+         ResetReason::RelayFailed => "backend connection failed",
+      }
+   }
+
+   fn index(self) -> usize {
+      ResetReason::ALL.iter().position(|&r| r == self).expect("listed in ALL")
+   }
+}
+
+/// A connection we reset.
+#[derive(Debug, Clone)]
+pub struct ResetEntry {
+   pub at: SystemTime,
+   pub local: SocketAddrV4,
+   pub remote: SocketAddrV4,
+   pub reason: ResetReason,
+}
+
+/// Counts of every reset by reason, and the most recent ones.
+#[derive(Debug, Default)]
+pub struct ResetLog {
+   counts: [u64; 13],
+   entries: VecDeque<ResetEntry>,
+}
+
+impl ResetLog {
+   pub fn record(&mut self, local: SocketAddrV4, remote: SocketAddrV4, reason: ResetReason) {
+      self.counts[reason.index()] += 1;
+      if self.entries.len() == RESET_LOG_LEN {
+         self.entries.pop_front();
+      }
+      self.entries.push_back(ResetEntry { at: SystemTime::now(), local, remote, reason });
+   }
+
+   pub fn counts(&self) -> Vec<(ResetReason, u64)> {
+      ResetReason::ALL.iter().map(|&r| (r, self.counts[r.index()])).collect()
+   }
+
+   pub fn entries(&self) -> Vec<ResetEntry> {
+      self.entries.iter().cloned().collect()
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use std::net::Ipv4Addr;
+
+   fn peer(port: u16) -> SocketAddrV4 {
+      SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), port)
+   }
+
+   fn local() -> SocketAddrV4 {
+      SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 9000)
+   }
+
+   #[test]
+   fn counted_per_reason() {
+      let mut log = ResetLog::default();
+      log.record(local(), peer(1), ResetReason::Killed);
+      log.record(local(), peer(2), ResetReason::OutOfMemory);
+      log.record(local(), peer(3), ResetReason::OutOfMemory);
+      let counts = log.counts();
+      assert_eq!(counts.len(), ResetReason::ALL.len());
+      for (reason, n) in counts {
+         let expected = match reason {
+            ResetReason::Killed => 1,
+            ResetReason::OutOfMemory => 2,
+            _ => 0,
+         };
+         assert_eq!(n, expected, "{}", reason.name());
+      }
+      let reasons: Vec<_> = log.entries().iter().map(|e| (e.remote.port(), e.reason)).collect();
+      assert_eq!(reasons, vec![
+         (1, ResetReason::Killed),
+         (2, ResetReason::OutOfMemory),
+         (3, ResetReason::OutOfMemory),
+      ]);
+   }
+
+   #[test]
+   fn log_keeps_only_the_latest() {
+      let mut log = ResetLog::default();
+      let total = RESET_LOG_LEN + 10;
+      for i in 0..total {
+         log.record(local(), peer(i as u16), ResetReason::Drained);
+      }
+      let entries = log.entries();
+      assert_eq!(entries.len(), RESET_LOG_LEN);
+      assert_eq!(entries[0].remote.port(), 10);
+      assert_eq!(entries.last().unwrap().remote.port() as usize, total - 1);
+      // the counts cover every reset, not just the ones still logged
+      assert!(log.counts().contains(&(ResetReason::Drained, total as u64)));
+   }
+}
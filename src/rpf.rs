@@ -0,0 +1,112 @@
+//! Reverse-path filtering for SYNs. A SYN whose source address the kernel would not route
+//! back out through our device can't have come from a real peer behind it: answering it
+//! only creates an embryonic connection for a spoofer and sends a SYN-ACK nowhere. The
+//! routes are read from the kernel's table once and on request, not per packet.
+
+use std::io;
+use std::net::Ipv4Addr;
+
+/// route flag: the route is up
+const RTF_UP: u32 = 0x1;
+
+#[derive(Debug, Clone, Default)]
+pub struct Routes {
+   /// destination and netmask of each route through the device, or `None` if the routing
+   /// table couldn't be read, in which case every address passes
+   nets: Option<Vec<(u32, u32)>>,
+}
+
+impl Routes {
+   /// The routes through `device` in the kernel's IPv4 routing table.
+   pub fn load(device: &str) -> io::Result<Self> {
+      let table = std::fs::read_to_string("/proc/net/route")?;
+      Ok(Routes { nets: Some(parse(&table, device)) })
+   }
+
+   /// Whether a reply to `addr` would go out through the device.
+   pub fn routable(&self, addr: Ipv4Addr) -> bool {
+      let addr = u32::from(addr);
+      match &self.nets {
+         Some(nets) => nets.iter().any(|&(dest, mask)| addr & mask == dest),
+         None => true,
+      }
+   }
+}
+
+/// The up routes through `device` in /proc/net/route, whose columns are Iface, Destination,
+/// Gateway, Flags, RefCnt, Use, Metric, Mask, ..., with addresses as hex numbers in host
+/// byte order.
+fn parse(table: &str, device: &str) -> Vec<(u32, u32)> {
+   let hex = |s: &str| u32::from_str_radix(s, 16).ok();
+   let addr = |s: &str| hex(s).map(|n| u32::from(Ipv4Addr::from(n.to_ne_bytes())));
+   table
+      .lines()
+      .skip(1)
+      .filter_map(|line| {
+         let cols: Vec<&str> = line.split_whitespace().collect();
+         if cols.len() < 8 || cols[0] != device || hex(cols[3])? & RTF_UP == 0 {
+            return None;
+         }
+         let (dest, mask) = (addr(cols[1])?, addr(cols[7])?);
+         Some((dest & mask, mask))
+      })
+      .collect()
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   /// A /proc/net/route line for `dest`/`mask` through `device`, in the table's byte order.
+   fn route(device: &str, dest: [u8; 4], mask: [u8; 4], flags: u32) -> String {
+      let hex = |a: [u8; 4]| format!("{:08X}", u32::from_ne_bytes(a));
+      format!("{}\t{}\t00000000\t{:04X}\t0\t0\t0\t{}\t0\t0\t0", device, hex(dest), flags, hex(mask))
+   }
+
+   fn table(routes: &[String]) -> String {
+      let mut table = String::from("Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n");
+      for r in routes {
+         table.push_str(r);
+         table.push('\n');
+      }
+      table
+   }
+
+   fn routes(table: &str) -> Routes {
+      Routes { nets: Some(parse(table, "tun0")) }
+   }
+
+   #[test]
+   fn only_up_routes_through_the_device() {
+      let table = table(&[
+         route("eth0", [0, 0, 0, 0], [0, 0, 0, 0], RTF_UP | 0x2),
+         route("tun0", [10, 0, 0, 0], [255, 255, 255, 0], RTF_UP),
+         route("tun0", [192, 168, 5, 0], [255, 255, 255, 0], 0),
+         route("tun0", [172, 16, 0, 0], [255, 240, 0, 0], RTF_UP),
+      ]);
+      assert_eq!(parse(&table, "tun0"), vec![
+         (u32::from(Ipv4Addr::new(10, 0, 0, 0)), u32::from(Ipv4Addr::new(255, 255, 255, 0))),
+         (u32::from(Ipv4Addr::new(172, 16, 0, 0)), u32::from(Ipv4Addr::new(255, 240, 0, 0))),
+      ]);
+      let routes = routes(&table);
+      assert!(routes.routable(Ipv4Addr::new(10, 0, 0, 7)));
+      assert!(routes.routable(Ipv4Addr::new(172, 20, 1, 1)));
+      // replies to these would go out another device, or through a route that's down
+      assert!(!routes.routable(Ipv4Addr::new(8, 8, 8, 8)));
+      assert!(!routes.routable(Ipv4Addr::new(192, 168, 5, 1)));
+   }
+
+   #[test]
+   fn default_route_through_the_device_passes_everything() {
+      let routes = routes(&table(&[route("tun0", [0, 0, 0, 0], [0, 0, 0, 0], RTF_UP | 0x2)]));
+      assert!(routes.routable(Ipv4Addr::new(8, 8, 8, 8)));
+      assert!(routes.routable(Ipv4Addr::new(10, 0, 0, 7)));
+   }
+
+   #[test]
+   fn unreadable_table_passes_everything() {
+      assert!(Routes::default().routable(Ipv4Addr::new(8, 8, 8, 8)));
+      // unlike a table with no routes through the device
+      assert!(!routes(&table(&[])).routable(Ipv4Addr::new(8, 8, 8, 8)));
+   }
+}
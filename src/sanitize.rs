@@ -0,0 +1,350 @@
+//! Structural checks on packets read from the device. They run before any header parsing,
+//! so malformed input is dropped and counted instead of reaching length arithmetic that
+//! trusts what the packet claims about itself. Packets with addresses no real peer can
+//! have are dropped here too, so they never create connection state.
+
+use std::net::Ipv4Addr;
+
+use crate::addrs::LocalAddrs;
+
+/// Why a packet was dropped before it reached a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Malformed {
+   /// shorter than a minimal IPv4 header
+   TruncatedIp,
+   /// IP version is not 4
+   NotIpv4,
+   /// IHL below 5 words, or past the end of the packet
+   BadIpHeaderLen,
+   /// total length shorter than the IP header, or longer than what was read
+   BadIpTotalLen,
+   /// a fragment; we don't do reassembly at the IP layer
+   Fragment,
+   /// not a TCP segment
+   NotTcp,
+   /// IP payload shorter than a minimal TCP header
+   TruncatedTcp,
+   /// TCP data offset below 5 words, or past the end of the segment
+   BadDataOffset,
+   /// an option with a length below 2 or running past the end of the header
+   BadOptions,
+   /// source and destination are the same address and port
+   SelfConnect,
+   /// one of our own addresses as the source, on a packet that came in from the wire
+   OwnSource,
+   /// a loopback, multicast, broadcast or unspecified source address
+   MartianSource,
+   /// a SYN, to a listener that checks, from an address we have no route back to
+   UnroutableSource,
+   /// passed these checks but the header parser still rejected it, which they are meant to
+   /// rule out
+   Unparsable,
+}
+
+/// Packets dropped by the ingress checks, by reason.
+#[derive(Debug, Clone, Default)]
+pub struct DropCounters {
+   pub truncated_ip: u64,
+   pub not_ipv4: u64,
+   pub bad_ip_header_len: u64,
+   pub bad_ip_total_len: u64,
+   pub fragments: u64,
+   pub not_tcp: u64,
+   pub truncated_tcp: u64,
+   pub bad_data_offset: u64,
+   pub bad_options: u64,
+   pub self_connect: u64,
+   pub own_source: u64,
+   pub martian_source: u64,
+   pub unroutable_source: u64,
+   pub unparsable: u64,
+   /// not malformed, but dropped because the ingress worker's queue was full
+   pub ingress_queue_full: u64,
+}
+
+impl DropCounters {
+   pub fn count(&mut self, why: Malformed) {
+      let counter = match why {
+         Malformed::TruncatedIp => &mut self.truncated_ip,
+         Malformed::NotIpv4 => &mut self.not_ipv4,
+         Malformed::BadIpHeaderLen => &mut self.bad_ip_header_len,
+         Malformed::BadIpTotalLen => &mut self.bad_ip_total_len,
+         Malformed::Fragment => &mut self.fragments,
+         Malformed::NotTcp => &mut self.not_tcp,
+         Malformed::TruncatedTcp => &mut self.truncated_tcp,
+         Malformed::BadDataOffset => &mut self.bad_data_offset,
+         Malformed::BadOptions => &mut self.bad_options,
+         Malformed::SelfConnect => &mut self.self_connect,
+         Malformed::OwnSource => &mut self.own_source,
+         Malformed::MartianSource => &mut self.martian_source,
+         Malformed::UnroutableSource => &mut self.unroutable_source,
+         Malformed::Unparsable => &mut self.unparsable,
+      };
+      *counter += 1;
+   }
+}
+
+/// Offsets into a packet that passed `check()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+   /// start of the TCP header
+   pub tcp: usize,
+   /// start of the TCP payload
+   pub data: usize,
+   /// end of the IP datagram; anything after it is link padding
+   pub end: usize,
+}
+
+/// Check `packet`, read from the device whose addresses are `ours`.
+pub fn check(packet: &[u8], ours: &LocalAddrs) -> Result<Layout, Malformed> {
+   if packet.len() < 20 {
+      return Err(Malformed::TruncatedIp);
+   }
+   if packet[0] >> 4 != 4 {
+      return Err(Malformed::NotIpv4);
+   }
+   let ihl = (packet[0] & 0x0f) as usize * 4;
+   if ihl < 20 || ihl > packet.len() {
+      return Err(Malformed::BadIpHeaderLen);
+   }
+   let total = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+   if total < ihl || total > packet.len() {
+      return Err(Malformed::BadIpTotalLen);
+   }
+   let more_fragments = packet[6] & 0x20 != 0;
+   let frag_offset = u16::from_be_bytes([packet[6] & 0x1f, packet[7]]);
+   if more_fragments || frag_offset != 0 {
+      return Err(Malformed::Fragment);
+   }
+   if packet[9] != 0x06 {
+      return Err(Malformed::NotTcp);
+   }
+
+   let segment = &packet[ihl..total];
+   if segment.len() < 20 {
+      return Err(Malformed::TruncatedTcp);
+   }
+   let doff = (segment[12] >> 4) as usize * 4;
+   if doff < 20 || doff > segment.len() {
+      return Err(Malformed::BadDataOffset);
+   }
+   check_options(&segment[20..doff])?;
+   check_addresses(packet, segment, ours)?;
+
+   Ok(Layout {
+      tcp: ihl,
+      data: ihl + doff,
+      end: total,
+   })
+}
+
+fn check_addresses(packet: &[u8], segment: &[u8], ours: &LocalAddrs) -> Result<(), Malformed> {
+   let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+   let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+   if src.is_loopback() || src.is_multicast() || src.is_broadcast() || src.is_unspecified() {
+      return Err(Malformed::MartianSource);
+   }
+   if src == dst {
+      // the destination is necessarily one of our addresses
+      if segment[0..2] == segment[2..4] {
+         return Err(Malformed::SelfConnect);
+      }
+      return Err(Malformed::OwnSource);
+   }
+   if ours.contains(src) {
+      return Err(Malformed::OwnSource);
+   }
+   Ok(())
+}
+
+fn check_options(mut opts: &[u8]) -> Result<(), Malformed> {
+   while let Some(&kind) = opts.first() {
+      match kind {
+         // end of option list
+         0 => return Ok(()),
+         // no-op
+         1 => opts = &opts[1..],
+         _ => {
+            let len = *opts.get(1).ok_or(Malformed::BadOptions)? as usize;
+            if len < 2 || len > opts.len() {
+               return Err(Malformed::BadOptions);
+            }
+            opts = &opts[len..];
+         }
+      }
+   }
+   Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   /// A well-formed SYN with an MSS option and `payload` appended.
+   fn segment(payload: &[u8]) -> Vec<u8> {
+      let total = 20 + 24 + payload.len();
+      let mut p = vec![0u8; total];
+      p[0] = 0x45;
+      p[2..4].copy_from_slice(&(total as u16).to_be_bytes());
+      p[8] = 64;
+      p[9] = 0x06;
+      p[12..16].copy_from_slice(&[10, 0, 0, 1]);
+      p[16..20].copy_from_slice(&[10, 0, 0, 2]);
+      p[20..22].copy_from_slice(&4321u16.to_be_bytes());
+      p[22..24].copy_from_slice(&9000u16.to_be_bytes());
+      p[32] = 6 << 4;
+      p[33] = 0x02;
+      p[40..44].copy_from_slice(&[2, 4, 0x05, 0xb4]);
+      p[44..].copy_from_slice(payload);
+      p
+   }
+
+   /// The addresses of the device the test segments are sent to.
+   fn ours() -> LocalAddrs {
+      LocalAddrs::new(vec![Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 3)])
+   }
+
+   #[test]
+   fn well_formed() {
+      let p = segment(b"hello");
+      assert_eq!(check(&p, &ours()), Ok(Layout { tcp: 20, data: 44, end: 49 }));
+   }
+
+   #[test]
+   fn link_padding_is_excluded() {
+      let mut p = segment(b"hello");
+      p.extend_from_slice(&[0; 6]);
+      assert_eq!(check(&p, &ours()).unwrap().end, 49);
+   }
+
+   #[test]
+   fn truncated_ip() {
+      let p = segment(&[]);
+      assert_eq!(check(&p[..19], &ours()), Err(Malformed::TruncatedIp));
+      assert_eq!(check(&[], &ours()), Err(Malformed::TruncatedIp));
+   }
+
+   #[test]
+   fn not_ipv4() {
+      let mut p = segment(&[]);
+      p[0] = 0x65;
+      assert_eq!(check(&p, &ours()), Err(Malformed::NotIpv4));
+   }
+
+   #[test]
+   fn bad_ip_header_len() {
+      let mut p = segment(&[]);
+      p[0] = 0x44;
+      assert_eq!(check(&p, &ours()), Err(Malformed::BadIpHeaderLen));
+      p[0] = 0x4f;
+      assert_eq!(check(&p[..40], &ours()), Err(Malformed::BadIpHeaderLen));
+   }
+
+   #[test]
+   fn bad_ip_total_len() {
+      let mut p = segment(&[]);
+      p[2..4].copy_from_slice(&19u16.to_be_bytes());
+      assert_eq!(check(&p, &ours()), Err(Malformed::BadIpTotalLen));
+      p[2..4].copy_from_slice(&1000u16.to_be_bytes());
+      assert_eq!(check(&p, &ours()), Err(Malformed::BadIpTotalLen));
+   }
+
+   #[test]
+   fn fragments() {
+      let mut p = segment(&[]);
+      p[6] = 0x20;
+      assert_eq!(check(&p, &ours()), Err(Malformed::Fragment));
+      p[6] = 0x00;
+      p[7] = 0x01;
+      assert_eq!(check(&p, &ours()), Err(Malformed::Fragment));
+      // don't-fragment is fine
+      p[6] = 0x40;
+      p[7] = 0x00;
+      assert!(check(&p, &ours()).is_ok());
+   }
+
+   #[test]
+   fn not_tcp() {
+      let mut p = segment(&[]);
+      p[9] = 0x11;
+      assert_eq!(check(&p, &ours()), Err(Malformed::NotTcp));
+   }
+
+   #[test]
+   fn truncated_tcp() {
+      let mut p = segment(&[]);
+      p.truncate(39);
+      p[2..4].copy_from_slice(&39u16.to_be_bytes());
+      assert_eq!(check(&p, &ours()), Err(Malformed::TruncatedTcp));
+   }
+
+   #[test]
+   fn bad_data_offset() {
+      let mut p = segment(&[]);
+      p[32] = 4 << 4;
+      assert_eq!(check(&p, &ours()), Err(Malformed::BadDataOffset));
+      p[32] = 7 << 4;
+      assert_eq!(check(&p, &ours()), Err(Malformed::BadDataOffset));
+   }
+
+   #[test]
+   fn bad_options() {
+      // zero length would loop forever in a naive parser
+      let mut p = segment(&[]);
+      p[41] = 0;
+      assert_eq!(check(&p, &ours()), Err(Malformed::BadOptions));
+      // runs past the header
+      p[41] = 5;
+      assert_eq!(check(&p, &ours()), Err(Malformed::BadOptions));
+      // kind without a length byte
+      p[40..44].copy_from_slice(&[1, 1, 1, 2]);
+      assert_eq!(check(&p, &ours()), Err(Malformed::BadOptions));
+      // padding after end-of-options is not looked at
+      p[40..44].copy_from_slice(&[1, 0, 0xff, 0xff]);
+      assert!(check(&p, &ours()).is_ok());
+   }
+
+   #[test]
+   fn self_connect() {
+      let mut p = segment(&[]);
+      p[12..16].copy_from_slice(&[10, 0, 0, 2]);
+      p[20..22].copy_from_slice(&9000u16.to_be_bytes());
+      assert_eq!(check(&p, &ours()), Err(Malformed::SelfConnect));
+   }
+
+   #[test]
+   fn own_source() {
+      let mut p = segment(&[]);
+      p[12..16].copy_from_slice(&[10, 0, 0, 2]);
+      assert_eq!(check(&p, &ours()), Err(Malformed::OwnSource));
+   }
+
+   #[test]
+   fn own_source_to_another_address() {
+      let mut p = segment(&[]);
+      p[12..16].copy_from_slice(&[10, 0, 0, 3]);
+      assert_eq!(check(&p, &ours()), Err(Malformed::OwnSource));
+      // without knowing our addresses, only src == dst gives it away
+      assert!(check(&p, &LocalAddrs::default()).is_ok());
+   }
+
+   #[test]
+   fn martian_source() {
+      for src in [[127, 0, 0, 1], [224, 0, 0, 5], [255, 255, 255, 255], [0, 0, 0, 0]].iter() {
+         let mut p = segment(&[]);
+         p[12..16].copy_from_slice(src);
+         assert_eq!(check(&p, &ours()), Err(Malformed::MartianSource), "{:?}", src);
+      }
+   }
+
+   #[test]
+   fn counters() {
+      let mut drops = DropCounters::default();
+      drops.count(Malformed::BadOptions);
+      drops.count(Malformed::BadOptions);
+      drops.count(Malformed::Fragment);
+      assert_eq!(drops.bad_options, 2);
+      assert_eq!(drops.fragments, 1);
+      assert_eq!(drops.truncated_ip, 0);
+   }
+}
@@ -0,0 +1,192 @@
+//! The send buffer. Application data is stored once, in reference-counted chunks, from the
+//! oldest unacknowledged byte on; segments in flight and their retransmissions are sliced
+//! out of the same chunks by sequence position, so nothing is copied until it goes into a
+//! packet, and acknowledging data drops or trims whole chunks rather than moving bytes.
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// Small writes are appended to the last chunk up to this size instead of each getting one.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone)]
+struct Chunk {
+   data: Arc<Vec<u8>>,
+   /// bytes before this have been acknowledged
+   start: usize,
+}
+
+impl Chunk {
+   fn bytes(&self) -> &[u8] {
+      &self.data[self.start..]
+   }
+}
+
+#[derive(Debug, Default)]
+pub struct SendBuffer {
+   chunks: VecDeque<Chunk>,
+   len: usize,
+}
+
+impl SendBuffer {
+   /// Bytes queued, sent or not.
+   pub fn len(&self) -> usize {
+      self.len
+   }
+
+   /// Bytes allocated for the queued data.
+   pub fn memory(&self) -> usize {
+      self.chunks.iter().map(|c| c.data.capacity()).sum()
+   }
+
+   /// Queue a copy of `data`.
+   pub fn push(&mut self, data: &[u8]) {
+      if data.is_empty() {
+         return;
+      }
+      self.len += data.len();
+      if let Some(last) = self.chunks.back_mut() {
+         if last.data.len() + data.len() <= CHUNK_SIZE {
+            if let Some(tail) = Arc::get_mut(&mut last.data) {
+               tail.extend_from_slice(data);
+               return;
+            }
+         }
+      }
+      self.chunks.push_back(Chunk { data: Arc::new(data.to_vec()), start: 0 });
+   }
+
+   /// Queue data the caller may keep a reference to, without copying it.
+   pub fn push_shared(&mut self, data: Arc<Vec<u8>>) {
+      if data.is_empty() {
+         return;
+      }
+      self.len += data.len();
+      self.chunks.push_back(Chunk { data, start: 0 });
+   }
+
+   /// Drop `n` acknowledged bytes off the front.
+   pub fn consume(&mut self, mut n: usize) {
+      n = std::cmp::min(n, self.len);
+      self.len -= n;
+      while n > 0 {
+         let front = self.chunks.front_mut().expect("length covers the chunks");
+         let left = front.data.len() - front.start;
+         if n < left {
+            front.start += n;
+            return;
+         }
+         n -= left;
+         self.chunks.pop_front();
+      }
+   }
+
+   pub fn clear(&mut self) {
+      self.chunks.clear();
+      self.len = 0;
+   }
+
+   /// The queued bytes from `offset` on, as a run of slices.
+   pub fn slices_from(&self, mut offset: usize) -> impl Iterator<Item = &[u8]> {
+      self.chunks.iter().filter_map(move |c| {
+         let bytes = c.bytes();
+         if offset >= bytes.len() {
+            offset -= bytes.len();
+            None
+         } else {
+            let rest = &bytes[offset..];
+            offset = 0;
+            Some(rest)
+         }
+      })
+   }
+
+   pub fn to_vec(&self) -> Vec<u8> {
+      let mut v = Vec::with_capacity(self.len);
+      for s in self.slices_from(0) {
+         v.extend_from_slice(s);
+      }
+      v
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn small_writes_share_a_chunk() {
+      let mut buf = SendBuffer::default();
+      buf.push(b"hello ");
+      buf.push(b"");
+      buf.push(b"world");
+      assert_eq!(buf.len(), 11);
+      assert_eq!(buf.chunks.len(), 1);
+      assert_eq!(buf.to_vec(), b"hello world");
+      // one that would take the chunk past its size starts another
+      buf.push(&[7; CHUNK_SIZE]);
+      assert_eq!(buf.chunks.len(), 2);
+      assert_eq!(buf.len(), 11 + CHUNK_SIZE);
+      assert!(buf.memory() >= buf.len());
+   }
+
+   #[test]
+   fn shared_data_is_neither_copied_nor_appended_to() {
+      let mut buf = SendBuffer::default();
+      let data = Arc::new(b"shared".to_vec());
+      buf.push_shared(data.clone());
+      assert!(Arc::ptr_eq(&buf.chunks[0].data, &data));
+      // the caller still holds it, so a small write after it gets a chunk of its own
+      buf.push(b"!");
+      assert_eq!(buf.chunks.len(), 2);
+      assert_eq!(*data, b"shared");
+      assert_eq!(buf.to_vec(), b"shared!");
+   }
+
+   #[test]
+   fn consume_trims_and_drops_chunks() {
+      let mut buf = SendBuffer::default();
+      buf.push_shared(Arc::new(b"abc".to_vec()));
+      buf.push_shared(Arc::new(b"defg".to_vec()));
+      buf.push_shared(Arc::new(b"hi".to_vec()));
+      let memory = buf.memory();
+      buf.consume(2);
+      assert_eq!(buf.len(), 7);
+      assert_eq!(buf.chunks.len(), 3);
+      // a partly acknowledged chunk is kept whole
+      assert_eq!(buf.memory(), memory);
+      buf.consume(3);
+      assert_eq!(buf.chunks.len(), 2);
+      assert_eq!(buf.to_vec(), b"fghi");
+      assert!(buf.memory() < memory);
+      // more than is queued empties it
+      buf.consume(100);
+      assert_eq!(buf.len(), 0);
+      assert_eq!(buf.memory(), 0);
+      assert!(buf.chunks.is_empty());
+   }
+
+   #[test]
+   fn slices_from_an_offset_span_chunks() {
+      let mut buf = SendBuffer::default();
+      buf.push_shared(Arc::new(b"abc".to_vec()));
+      buf.push_shared(Arc::new(b"defg".to_vec()));
+      buf.consume(1);
+      let slices = |offset| buf.slices_from(offset).collect::<Vec<_>>();
+      assert_eq!(slices(0), vec![&b"bc"[..], &b"defg"[..]]);
+      assert_eq!(slices(1), vec![&b"c"[..], &b"defg"[..]]);
+      assert_eq!(slices(2), vec![&b"defg"[..]]);
+      assert_eq!(slices(4), vec![&b"fg"[..]]);
+      assert!(slices(6).is_empty());
+   }
+
+   #[test]
+   fn clear_drops_everything() {
+      let mut buf = SendBuffer::default();
+      buf.push(b"data");
+      buf.clear();
+      assert_eq!(buf.len(), 0);
+      assert_eq!(buf.memory(), 0);
+      assert!(buf.to_vec().is_empty());
+   }
+}
@@ -0,0 +1,1404 @@
+//! Soak tests of sequence number wraparound. Two connections whose initial sequence numbers
+//! sit just short of 2^32 open, move data both ways and close over an in-memory loopback
+//! link, so the handshake, window, ACK and FIN arithmetic all runs across the wrap, and the
+//! data that comes out is checked byte for byte. The link also serves for tests that need a
+//! peer of our own, like a simultaneous open or an abort, and can lose and reorder packets
+//! to exercise reassembly and recovery. The multi-gigabyte runs wrap the
+//! sequence space again on the way and take a while, so they are ignored by default:
+//!
+//!     cargo test --release soak -- --ignored
+
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use crate::egress::Egress;
+use crate::fastopen::FastOpen;
+use crate::synproxy::{self, SynCookies};
+use crate::tcp::{self, Connection, ListenOptions};
+use crate::{ConnectionManager, Quad, ResetReason};
+
+const MTU: usize = 1500;
+const WINDOW: u32 = 1 << 20;
+/// bytes handed to the connection per write
+const CHUNK: usize = 64 * 1024;
+/// on an impaired link, one packet in this many is lost...
+const LOSS: u64 = 50;
+/// ...and one in this many overtakes the packet before it
+const REORDER: u64 = 20;
+
+fn client() -> SocketAddrV4 {
+   SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 50000)
+}
+
+fn server() -> SocketAddrV4 {
+   SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 9000)
+}
+
+/// The byte at `offset` in either direction's stream: a period prime to every segment and
+/// chunk size, so data delivered at the wrong offset doesn't go unnoticed.
+fn pattern(offset: u64) -> u8 {
+   (offset % 251) as u8
+}
+
+/// Losses and reordering on the way from one end to the other, from a fixed seed so a
+/// failure repeats.
+struct Impairment {
+   rng: u64,
+}
+
+impl Impairment {
+   fn new(seed: u64) -> Self {
+      Impairment { rng: seed }
+   }
+
+   /// True one time in `n`, more or less.
+   fn roll(&mut self, n: u64) -> bool {
+      self.rng ^= self.rng << 13;
+      self.rng ^= self.rng >> 7;
+      self.rng ^= self.rng << 17;
+      self.rng % n == 0
+   }
+}
+
+/// One end of the link: its connection, the packets it has sent that the other end hasn't
+/// seen yet, what happens to them on the way, and how far through the transfer it is each
+/// way.
+struct End {
+   conn: Connection,
+   egress: Egress,
+   impairment: Option<Impairment>,
+   /// larger packets vanish on the way, as at a router that drops them without a word
+   path_mtu: Option<usize>,
+   written: u64,
+   read: u64,
+   /// whether our side has been shut down
+   shut: bool,
+}
+
+impl End {
+   fn new(conn: Connection, egress: Egress) -> Self {
+      End { conn, egress, impairment: None, path_mtu: None, written: 0, read: 0, shut: false }
+   }
+
+   /// Keep a few windows queued until `total` has been written, then shut down our side.
+   fn top_up(&mut self, total: u64) {
+      if self.conn.is_connecting() || self.written == total && self.shut {
+         return;
+      }
+      let mut chunk = Vec::with_capacity(CHUNK);
+      while self.written < total && self.conn.info().unacked < 3 * WINDOW as usize {
+         let len = std::cmp::min(CHUNK as u64, total - self.written);
+         chunk.clear();
+         chunk.extend((self.written..self.written + len).map(pattern));
+         self.conn.enqueue(&mut self.egress, &chunk).expect("the connection is open for writing");
+         self.written += len;
+      }
+      if self.written == total {
+         self.conn.shutdown(&mut self.egress, Shutdown::Write).expect("shutting down");
+         self.shut = true;
+      }
+   }
+
+   /// Read everything received and check it against the pattern.
+   fn drain(&mut self) {
+      let mut buf = [0u8; CHUNK];
+      loop {
+         let (n, _, _) = self.conn.read_incoming(&mut buf);
+         if n == 0 {
+            self.conn.window_update(&mut self.egress).expect("sending a window update");
+            return;
+         }
+         for (i, &b) in buf[..n].iter().enumerate() {
+            let at = self.read + i as u64;
+            assert_eq!(b, pattern(at), "wrong byte at offset {}", at);
+         }
+         self.read += n as u64;
+      }
+   }
+}
+
+/// Hand every packet `from` has sent, and the link doesn't lose, to `to`. Returns whether
+/// there were any.
+fn deliver(from: &mut End, to: &mut End) -> bool {
+   let mut packets = from.egress.drain();
+   if let Some(mtu) = from.path_mtu {
+      packets.retain(|p| p.len() <= mtu);
+   }
+   if let Some(imp) = &mut from.impairment {
+      packets.retain(|_| !imp.roll(LOSS));
+      for i in 1..packets.len() {
+         if imp.roll(REORDER) {
+            packets.swap(i - 1, i);
+         }
+      }
+   }
+   for p in &packets {
+      receive(to, p);
+   }
+   !packets.is_empty()
+}
+
+/// Hand one packet to `to`.
+fn receive(to: &mut End, p: &[u8]) {
+   let (iph, tcph, data) = split(p);
+   to.conn.on_packet(&mut to.egress, iph, tcph, data).expect("segment processing");
+}
+
+/// A packet's headers and payload.
+fn split(p: &[u8]) -> (etherparse::Ipv4HeaderSlice<'_>, etherparse::TcpHeaderSlice<'_>, &[u8]) {
+   let iph = etherparse::Ipv4HeaderSlice::from_slice(p).expect("we sent a valid IPv4 header");
+   let ihl = iph.slice().len();
+   let tcph = etherparse::TcpHeaderSlice::from_slice(&p[ihl..]).expect("we sent a valid TCP header");
+   let data = &p[ihl + tcph.slice().len()..];
+   (iph, tcph, data)
+}
+
+/// A packet's sequence number, behind a 20-byte IPv4 header.
+fn seq_of(p: &[u8]) -> u32 {
+   u32::from_be_bytes([p[24], p[25], p[26], p[27]])
+}
+
+/// Run the timers of `end` that are due by `at`, sleeping until then.
+fn tick_at(end: &mut End, at: Instant) {
+   std::thread::sleep(at.saturating_duration_since(Instant::now()));
+   end.conn.on_tick(&mut end.egress).unwrap();
+}
+
+/// Run the timers of `end` until a retransmission timeout has fired, and return what it
+/// sent on the way, tail loss probes included.
+fn tick_until_timeout(end: &mut End) -> Vec<Vec<u8>> {
+   let mut sent = Vec::new();
+   while end.conn.info().retransmits == 0 {
+      let at = end.conn.next_deadline().expect("a timer running");
+      tick_at(end, at);
+      sent.extend(end.egress.drain());
+   }
+   sent
+}
+
+/// Write `n` segments' worth of the pattern, one write each, all within the initial
+/// window, and return the segments sent.
+fn small_segments(end: &mut End, n: usize) -> Vec<Vec<u8>> {
+   let len = 2100 / n as u64;
+   end.conn.set_nodelay(&mut end.egress, true).unwrap();
+   for _ in 0..n {
+      let part: Vec<u8> = (end.written..end.written + len).map(pattern).collect();
+      end.conn.enqueue(&mut end.egress, &part).unwrap();
+      end.written += len;
+   }
+   let sent = end.egress.drain();
+   assert_eq!(sent.len(), n);
+   sent
+}
+
+/// The TCP options of a packet with a 20-byte IPv4 header.
+fn options_of(p: &[u8]) -> &[u8] {
+   &p[40..20 + (p[32] >> 4) as usize * 4]
+}
+
+/// Where the option of `kind` starts in a packet with a 20-byte IPv4 header.
+fn option_at(p: &[u8], kind: u8) -> usize {
+   let mut at = 40;
+   while p[at] != kind {
+      at += if p[at] == 1 { 1 } else { p[at + 1] as usize };
+   }
+   at
+}
+
+/// The SACK blocks of a packet, in the order they come in.
+fn sack_blocks_of(p: &[u8]) -> Vec<(u32, u32)> {
+   crate::options::sack(options_of(p)).map_or(Vec::new(), |blocks| blocks.collect())
+}
+
+/// The sequence number just past a packet's payload.
+fn end_of(p: &[u8]) -> u32 {
+   let (_, tcph, data) = split(p);
+   tcph.sequence_number().wrapping_add(data.len() as u32)
+}
+
+fn opts(iss: u32) -> ListenOptions {
+   ListenOptions {
+      iss: Some(iss),
+      recv_window: WINDOW,
+      // room for all that `top_up()` keeps queued
+      send_buffer: 4 * WINDOW as usize,
+      delayed_ack: None,
+      ..ListenOptions::default()
+   }
+}
+
+fn egress() -> Egress {
+   let mut egress = Egress::default();
+   egress.set_mtu(MTU);
+   egress
+}
+
+/// Start opening a connection with both initial sequence numbers just below 2^32: `a` has
+/// sent its SYN, and `b` has accepted it and sent its SYN-ACK.
+fn open() -> (End, End) {
+   open_with(opts)
+}
+
+/// Like `open()`, with options of our own for both ends.
+fn open_with(opts: fn(u32) -> ListenOptions) -> (End, End) {
+   let mut egress_a = egress();
+   let conn = Connection::connect(&mut egress_a, &opts(u32::MAX - 1000), client(), server())
+      .expect("sending the SYN");
+   let mut a = End::new(conn, egress_a);
+
+   let mut egress_b = egress();
+   let syn = a.egress.drain().pop().expect("a SYN");
+   let iph = etherparse::Ipv4HeaderSlice::from_slice(&syn).unwrap();
+   let tcph = etherparse::TcpHeaderSlice::from_slice(&syn[iph.slice().len()..]).unwrap();
+   let conn = Connection::accept(&mut egress_b, &opts(u32::MAX - 100), &mut FastOpen::default(), iph, tcph, &[])
+      .expect("sending the SYN-ACK")
+      .expect("the SYN is accepted");
+   (a, End::new(conn, egress_b))
+}
+
+/// Open a connection whose handshake takes 200 ms. That is the minimum RTT for both ends,
+/// which gives RACK a 50 ms reordering window: segments delivered out of order are only
+/// declared lost when a test ticks the reorder timer.
+fn open_slowly() -> (End, End) {
+   let (mut a, mut b) = open();
+   std::thread::sleep(Duration::from_millis(200));
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   (a, b)
+}
+
+/// Open a connection, move `total` bytes each way and close it again.
+fn soak(total: u64) {
+   let (mut a, mut b) = open();
+   transfer(&mut a, &mut b, total);
+}
+
+/// Run the link until both ends have sent `total` bytes and closed, checking what arrives.
+fn transfer(a: &mut End, b: &mut End, total: u64) {
+   while !(a.conn.is_recv_closed() && b.conn.is_recv_closed()) {
+      a.top_up(total);
+      b.top_up(total);
+      let moved = deliver(a, b) | deliver(b, a);
+      a.drain();
+      b.drain();
+      if !moved {
+         // nothing in flight: only a timer can get things going again
+         let next = [a.conn.next_deadline(), b.conn.next_deadline()].iter().flatten().min().copied();
+         let next = next.expect("both ends are stuck with no timer running");
+         std::thread::sleep(next.saturating_duration_since(Instant::now()));
+         a.conn.on_tick(&mut a.egress).unwrap();
+         b.conn.on_tick(&mut b.egress).unwrap();
+      }
+   }
+   assert_eq!((a.read, b.read), (total, total));
+}
+
+#[test]
+fn transfer_across_wraparound() {
+   soak(16 << 20);
+}
+
+#[test]
+fn lossy_link() {
+   // whatever arrives past a hole is held until the hole is filled, and has to come out in
+   // the right place
+   let (mut a, mut b) = open();
+   a.impairment = Some(Impairment::new(1));
+   b.impairment = Some(Impairment::new(2));
+   transfer(&mut a, &mut b, 4 << 20);
+}
+
+#[test]
+#[ignore]
+fn multi_gigabyte_transfer() {
+   soak(5 << 30);
+}
+
+#[test]
+fn simultaneous_open() {
+   // each end's SYN crosses the other's on the link
+   let mut egress_a = egress();
+   let conn = Connection::connect(&mut egress_a, &opts(u32::MAX - 1000), client(), server()).unwrap();
+   let mut a = End::new(conn, egress_a);
+   let mut egress_b = egress();
+   let conn = Connection::connect(&mut egress_b, &opts(u32::MAX - 100), server(), client()).unwrap();
+   let mut b = End::new(conn, egress_b);
+   deliver(&mut a, &mut b);
+   deliver(&mut b, &mut a);
+   assert!(a.conn.is_syn_received() && b.conn.is_syn_received());
+   // the SYN-ACKs cross too, and each end's challenge ACK completes the other's handshake
+   transfer(&mut a, &mut b, 1 << 20);
+}
+
+#[test]
+fn window_follows_the_reader() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   let data: Vec<u8> = (0..2 * WINDOW as u64).map(pattern).collect();
+   b.conn.enqueue(&mut b.egress, &data).unwrap();
+   while deliver(&mut b, &mut a) | deliver(&mut a, &mut b) {}
+   // `a` hasn't read anything, so its window is closed with the buffer full
+   assert_eq!(a.conn.info().unread, WINDOW as usize);
+   // reading opens it again, and the peer hears about it without waiting to probe
+   a.drain();
+   while deliver(&mut a, &mut b) | deliver(&mut b, &mut a) {
+      a.drain();
+   }
+   assert_eq!(a.read, 2 * WINDOW as u64);
+}
+
+#[test]
+fn nodelay_sends_small_writes_at_once() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   a.conn.enqueue(&mut a.egress, b"one").unwrap();
+   a.conn.enqueue(&mut a.egress, b"two").unwrap();
+   // the second small segment waits for the first to be acked...
+   assert_eq!(a.egress.drain().len(), 1);
+   // ...unless Nagle is off
+   a.conn.set_nodelay(&mut a.egress, true).unwrap();
+   assert_eq!(a.egress.drain().len(), 1);
+   a.conn.enqueue(&mut a.egress, b"three").unwrap();
+   assert_eq!(a.egress.drain().len(), 1);
+}
+
+#[test]
+fn keepalive_gives_up_on_a_silent_peer() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   let idle = Duration::from_millis(20);
+   for end in [&mut a, &mut b] {
+      end.conn.set_keepalive(Some(idle));
+      end.conn.set_keepalive_interval(idle / 2);
+      end.conn.set_keepalive_count(2);
+   }
+   let tick = |end: &mut End| {
+      std::thread::sleep(end.conn.next_deadline().unwrap().saturating_duration_since(Instant::now()));
+      end.conn.on_tick(&mut end.egress).unwrap();
+   };
+   // an idle peer that answers stays connected: each end's probes are answered, and keep
+   // it from probing in turn
+   for _ in 0..4 {
+      tick(&mut a);
+      tick(&mut b);
+      while deliver(&mut a, &mut b) | deliver(&mut b, &mut a) {}
+   }
+   assert!(!a.conn.is_closed() && !b.conn.is_closed());
+   // one that has gone away gets two probes and then a reset
+   let mut sent = Vec::new();
+   while !a.conn.is_closed() {
+      tick(&mut a);
+      sent.extend(a.egress.drain());
+   }
+   let rst = |p: &Vec<u8>| {
+      let iph = etherparse::Ipv4HeaderSlice::from_slice(p).unwrap();
+      etherparse::TcpHeaderSlice::from_slice(&p[iph.slice().len()..]).unwrap().rst()
+   };
+   assert_eq!(sent.iter().map(rst).collect::<Vec<_>>(), vec![false, false, true]);
+   assert_eq!(a.conn.take_error().map(|e| e.kind()), Some(std::io::ErrorKind::TimedOut));
+}
+
+#[test]
+fn repeated_fin_in_close_wait() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   a.conn.shutdown(&mut a.egress, Shutdown::Write).unwrap();
+   let fin = a.egress.drain().pop().expect("a FIN");
+   receive(&mut b, &fin);
+   assert_eq!(b.conn.state_name(), "CLOSE-WAIT");
+   b.egress.drain();
+   // a retransmission of the FIN is a duplicate, but another one right at RCV.NXT is in the
+   // window; either way it's acked and changes nothing
+   let mut again = fin.clone();
+   // the sequence number, behind a 20-byte IPv4 header
+   let at = 20 + 4;
+   let seq = u32::from_be_bytes([again[at], again[at + 1], again[at + 2], again[at + 3]]);
+   again[at..at + 4].copy_from_slice(&seq.wrapping_add(1).to_be_bytes());
+   receive(&mut b, &fin);
+   receive(&mut b, &again);
+   assert_eq!(b.conn.state_name(), "CLOSE-WAIT");
+   assert_eq!(b.egress.drain().len(), 2);
+}
+
+#[test]
+fn data_without_ack_is_not_taken() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   let data: Vec<u8> = (0..100).map(pattern).collect();
+   b.conn.enqueue(&mut b.egress, &data).unwrap();
+   let seg = b.egress.drain().pop().expect("a data segment");
+   // the same segment without ACK is dropped (RFC 793 S3.9, "fifth"), so it mustn't count
+   // as received and turn the real one into a duplicate
+   let mut bare = seg.clone();
+   bare[20 + 13] &= !0x10;
+   receive(&mut a, &bare);
+   receive(&mut a, &seg);
+   a.drain();
+   assert_eq!(a.read, 100);
+}
+
+#[test]
+fn orphaned_fin_wait_2_times_out() {
+   let (mut a, mut b) = open_with(|iss| ListenOptions { msl: Duration::from_millis(10), ..opts(iss) });
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   // the application is gone, and the peer acks our FIN but never sends its own
+   a.conn.shutdown(&mut a.egress, Shutdown::Both).unwrap();
+   deliver(&mut a, &mut b);
+   deliver(&mut b, &mut a);
+   assert_eq!(a.conn.state_name(), "FIN-WAIT-2");
+   let deadline = a.conn.next_deadline().expect("a timer for FIN-WAIT-2");
+   std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+   a.conn.on_tick(&mut a.egress).unwrap();
+   assert!(a.conn.is_closed());
+}
+
+#[test]
+fn abort_resets_the_peer() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   // `a` has data it hasn't read when it aborts
+   b.conn.enqueue(&mut b.egress, b"unread").unwrap();
+   deliver(&mut b, &mut a);
+   a.conn.abort(&mut a.egress).unwrap();
+   assert!(a.conn.is_closed());
+   assert_eq!(a.conn.info().unread, 0);
+   deliver(&mut a, &mut b);
+   assert!(b.conn.is_closed());
+   assert_eq!(b.conn.take_error().map(|e| e.kind()), Some(std::io::ErrorKind::ConnectionReset));
+}
+
+#[test]
+fn repair_keeps_unread_data_and_options() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   a.conn.set_nodelay(&mut a.egress, true).unwrap();
+   let data: Vec<u8> = (0..100).map(pattern).collect();
+   b.conn.enqueue(&mut b.egress, &data).unwrap();
+   deliver(&mut b, &mut a);
+   a.conn.set_repair(&mut a.egress, true).unwrap();
+   let st = a.conn.repair_export().unwrap();
+   let cwnd = a.conn.info().cwnd;
+   // the bytes were acked, so the peer won't send them again: they must move with the state
+   a.conn = Connection::repair_import(st, MTU);
+   a.conn.set_repair(&mut a.egress, false).unwrap();
+   assert!(a.conn.nodelay());
+   assert_eq!(a.conn.info().cwnd, cwnd);
+   a.drain();
+   assert_eq!(a.read, 100);
+}
+
+#[test]
+fn passive_opens_pick_their_own_iss() {
+   // the SYN-ACK's sequence number, behind a 20-byte IPv4 header
+   let syn_ack_seq = || {
+      let (_, mut b) = open_with(|iss| ListenOptions { iss: None, ..opts(iss) });
+      let p = b.egress.drain().pop().expect("a SYN-ACK");
+      u32::from_be_bytes([p[24], p[25], p[26], p[27]])
+   };
+   // each open draws a fresh one rather than falling back to a fixed value
+   let seqs: Vec<u32> = (0..4).map(|_| syn_ack_seq()).collect();
+   assert!(seqs.windows(2).any(|w| w[0] != w[1]), "{:?}", seqs);
+}
+
+#[test]
+fn off_by_one_rst_gets_a_challenge_ack() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   b.conn.enqueue(&mut b.egress, b"x").unwrap();
+   let seg = b.egress.drain().pop().expect("a data segment");
+   // the sequence number and flags, behind a 20-byte IPv4 header
+   let at = 20 + 4;
+   let seq = u32::from_be_bytes([seg[at], seg[at + 1], seg[at + 2], seg[at + 3]]);
+   let mut rst = seg.clone();
+   rst[20 + 13] = 0x14;
+   rst[at..at + 4].copy_from_slice(&seq.wrapping_add(1).to_be_bytes());
+   // in the window, but one past RCV.NXT: answered with an ACK, and the connection stays
+   receive(&mut a, &rst);
+   assert!(!a.conn.is_closed());
+   assert_eq!(a.conn.state_name(), "ESTABLISHED");
+   let acks = a.egress.drain();
+   assert_eq!(acks.len(), 1);
+   let ack = &acks[0];
+   assert_eq!(ack[20 + 13] & 0x14, 0x10);
+   assert_eq!(u32::from_be_bytes([ack[28], ack[29], ack[30], ack[31]]), seq);
+   // the same RST right at RCV.NXT is believed
+   rst[at..at + 4].copy_from_slice(&seq.to_be_bytes());
+   receive(&mut a, &rst);
+   assert!(a.conn.is_closed());
+}
+
+#[test]
+fn mtu_probing_finds_a_black_hole() {
+   let (mut a, mut b) = open_with(|iss| ListenOptions { mtu_probing: true, ..opts(iss) });
+   a.path_mtu = Some(1300);
+   b.path_mtu = Some(1300);
+   // probes past 1300 bytes are lost, but the data they carried still gets through
+   transfer(&mut a, &mut b, 1 << 20);
+   for end in [&a, &b] {
+      let mtu = end.conn.info().path_mtu;
+      assert!(mtu > 1200 && mtu <= 1300, "{}", mtu);
+   }
+}
+
+#[test]
+fn multipath_with_one_subflow() {
+   let (mut a, mut b) = open_with(|iss| ListenOptions { mptcp: true, ..opts(iss) });
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   let data: Vec<u8> = (0..2000).map(pattern).collect();
+   a.conn.set_nodelay(&mut a.egress, true).unwrap();
+   a.conn.enqueue(&mut a.egress, &data).unwrap();
+   a.written = data.len() as u64;
+   let sent = a.egress.drain();
+   // the subtype of each segment's MPTCP option, behind the 20-byte IPv4 and TCP headers
+   let subtypes: Vec<_> = sent
+      .iter()
+      .map(|p| {
+         let options = &p[40..20 + (p[32] >> 4) as usize * 4];
+         crate::options::mptcp(options).next().map(|body| body[0] >> 4)
+      })
+      .collect();
+   // until `b` has answered with a DSS option of its own, the first data repeats the keys
+   assert_eq!(subtypes, vec![Some(0), Some(2)]);
+   for p in &sent {
+      receive(&mut b, p);
+   }
+   // every segment maps its data and acks the peer's at the data level, across the wrap of
+   // the subflow sequence numbers, and the DATA_FINs go with the FINs
+   transfer(&mut a, &mut b, 1 << 20);
+   assert!(a.conn.handshake.multipath && b.conn.handshake.multipath);
+}
+
+#[test]
+fn multipath_falls_back_to_tcp() {
+   // only the client offers it
+   let (mut a, mut b) = open_with(|iss| ListenOptions { mptcp: iss == u32::MAX - 1000, ..opts(iss) });
+   transfer(&mut a, &mut b, 64 << 10);
+   assert!(!a.conn.handshake.multipath && !b.conn.handshake.multipath);
+}
+
+#[test]
+fn rack_waits_out_the_reordering_window() {
+   let (mut a, mut b) = open_slowly();
+   let sent = small_segments(&mut a, 3);
+   // the later two arrive, too few for a fast retransmit, and their SACKs only show the
+   // first segment was overtaken: it may still turn up
+   for p in &sent[1..] {
+      receive(&mut b, p);
+   }
+   while deliver(&mut b, &mut a) {}
+   assert!(a.egress.drain().is_empty());
+   // so it is only declared lost once the window has passed, well before the probe
+   let reorder = a.conn.next_deadline().expect("the reorder timer");
+   assert!(reorder < Instant::now() + Duration::from_millis(100));
+   tick_at(&mut a, reorder);
+   let resent = a.egress.drain();
+   assert_eq!(resent.iter().map(|p| seq_of(p)).collect::<Vec<_>>(), vec![seq_of(&sent[0])]);
+   for p in &resent {
+      receive(&mut b, p);
+   }
+   b.drain();
+   assert_eq!(b.read, a.written);
+}
+
+#[test]
+fn tail_loss_probe_fires_after_the_pto() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   let sent = small_segments(&mut a, 3);
+   // the last segment is lost, and with nothing after it no ACK tells of the loss
+   for p in &sent[..2] {
+      receive(&mut b, p);
+   }
+   while deliver(&mut b, &mut a) {}
+   assert!(a.egress.drain().is_empty());
+   // the probe is due after two SRTTs, plus the delayed-ACK allowance for a lone segment,
+   // well before the RTO
+   let info = a.conn.info();
+   let pto = info.srtt.expect("an RTT sample") * 2 + Duration::from_millis(200);
+   let probe = a.conn.next_deadline().expect("the probe timer");
+   assert!(probe <= Instant::now() + pto);
+   assert!(probe < Instant::now() + info.rto / 2);
+   tick_at(&mut a, probe);
+   // with no new data, it is the last segment again
+   let probes = a.egress.drain();
+   assert_eq!(probes.iter().map(|p| seq_of(p)).collect::<Vec<_>>(), vec![seq_of(&sent[2])]);
+   for p in &probes {
+      receive(&mut b, p);
+   }
+   b.drain();
+   assert_eq!(b.read, a.written);
+}
+
+#[test]
+fn spurious_timeout_is_undone() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   let data: Vec<u8> = (0..8000).map(pattern).collect();
+   a.conn.enqueue(&mut a.egress, &data).unwrap();
+   a.written = data.len() as u64;
+   // the first window arrives, but its ACKs are held up past the RTO
+   for p in a.egress.drain() {
+      receive(&mut b, &p);
+   }
+   let mut acks = b.egress.drain();
+   let before = a.conn.info();
+   for p in tick_until_timeout(&mut a) {
+      receive(&mut b, &p);
+   }
+   acks.extend(b.egress.drain());
+   assert!(a.conn.info().cwnd < before.cwnd);
+   // the first ACK lets new data out, and the second acknowledges data that was never
+   // retransmitted: the original transmissions got there, and the window comes back
+   for p in &acks {
+      receive(&mut a, p);
+   }
+   let after = a.conn.info();
+   assert!(after.cwnd >= before.cwnd, "{} < {}", after.cwnd, before.cwnd);
+   assert_eq!(after.ssthresh, before.ssthresh);
+   transfer(&mut a, &mut b, data.len() as u64);
+}
+
+#[test]
+fn genuine_timeout_keeps_the_window_cut() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   let data: Vec<u8> = (0..8000).map(pattern).collect();
+   a.conn.enqueue(&mut a.egress, &data).unwrap();
+   a.written = data.len() as u64;
+   // the first window is lost, and so is any probe
+   let lost = a.egress.drain();
+   let before = a.conn.info();
+   let resent: Vec<_> = tick_until_timeout(&mut a)
+      .into_iter()
+      .filter(|p| seq_of(p) == seq_of(&lost[0]))
+      .collect();
+   assert_eq!(resent.len(), 1);
+   let cut = a.conn.info();
+   assert!(cut.cwnd < before.cwnd && cut.ssthresh < before.ssthresh);
+   receive(&mut b, &resent[0]);
+   // the ACK of the retransmission lets two new segments out first, whatever RACK then
+   // makes of the rest of the window
+   deliver(&mut b, &mut a);
+   let sent = a.egress.drain();
+   let new = |p: &Vec<u8>| !lost.iter().any(|l| seq_of(l) == seq_of(p));
+   assert!(sent.len() >= 2 && sent[..2].iter().all(new));
+   // the new segments arrive ahead of the hole and only draw duplicate ACKs, so the
+   // timeout was real: the window grows back from the cut, not from where it was
+   for p in &sent {
+      receive(&mut b, p);
+   }
+   while deliver(&mut b, &mut a) | deliver(&mut a, &mut b) {}
+   assert_eq!(a.conn.info().ssthresh, cut.ssthresh);
+   transfer(&mut a, &mut b, data.len() as u64);
+}
+
+#[test]
+fn sack_retransmits_only_the_holes() {
+   let (mut a, mut b) = open_slowly();
+   let sent = small_segments(&mut a, 5);
+   // the second and fourth arrive, too few for a fast retransmit; with no cumulative ACK,
+   // there is no RTT sample to shrink the reordering window
+   receive(&mut b, &sent[1]);
+   receive(&mut b, &sent[3]);
+   let acks = b.egress.drain();
+   let block = |i: usize| (seq_of(&sent[i]), end_of(&sent[i]));
+   assert_eq!(sack_blocks_of(acks.last().unwrap()), vec![block(3), block(1)]);
+   for p in &acks {
+      receive(&mut a, p);
+   }
+   assert!(a.egress.drain().is_empty());
+   // once the window has passed, the holes go again, and nothing SACKed does; the last
+   // segment is a tail the probe timer looks after
+   let reorder = a.conn.next_deadline().expect("the reorder timer");
+   tick_at(&mut a, reorder);
+   let resent: Vec<_> = a.egress.drain().iter().map(|p| seq_of(p)).collect();
+   assert_eq!(resent, vec![seq_of(&sent[0]), seq_of(&sent[2])]);
+}
+
+#[test]
+fn overlapping_sack_blocks_cover_every_segment_in_them() {
+   let (mut a, mut b) = open_slowly();
+   let sent = small_segments(&mut a, 5);
+   receive(&mut b, &sent[1]);
+   receive(&mut b, &sent[3]);
+   // the ACK carries two blocks, rewritten to overlap on the third segment and cover the
+   // second to the fourth between them
+   let mut ack = b.egress.drain().pop().expect("an ACK");
+   let at = option_at(&ack, 5);
+   assert_eq!(ack[at + 1], 2 + 2 * 8);
+   let blocks = [(seq_of(&sent[1]), end_of(&sent[2])), (seq_of(&sent[2]), end_of(&sent[3]))];
+   for (i, &(left, right)) in blocks.iter().enumerate() {
+      let block = crate::options::sack_block(left, right);
+      ack[at + 2 + i * 8..at + 10 + i * 8].copy_from_slice(&block);
+   }
+   receive(&mut a, &ack);
+   // only the first segment, sent before what was SACKed, is missing; the last is a tail
+   // the probe timer looks after
+   let reorder = a.conn.next_deadline().expect("the reorder timer");
+   tick_at(&mut a, reorder);
+   let resent: Vec<_> = a.egress.drain().iter().map(|p| seq_of(p)).collect();
+   assert_eq!(resent, vec![seq_of(&sent[0])]);
+}
+
+#[test]
+fn duplicate_below_the_ack_is_reported_with_d_sack() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   let sent = small_segments(&mut a, 2);
+   receive(&mut b, &sent[0]);
+   receive(&mut b, &sent[0]);
+   // the second copy is acknowledged with a D-SACK block for it, below the ACK itself
+   let acks = b.egress.drain();
+   assert!(sack_blocks_of(&acks[0]).is_empty());
+   let dup = acks.last().unwrap();
+   assert_eq!(sack_blocks_of(dup), vec![(seq_of(&sent[0]), end_of(&sent[0]))]);
+   assert_eq!(u32::from_be_bytes([dup[28], dup[29], dup[30], dup[31]]), end_of(&sent[0]));
+   // which the sender takes as no news: below SND.UNA, it marks nothing and sets off no
+   // retransmission
+   for p in &acks {
+      receive(&mut a, p);
+   }
+   assert!(a.egress.drain().is_empty());
+   receive(&mut b, &sent[1]);
+   while deliver(&mut b, &mut a) | deliver(&mut a, &mut b) {}
+   b.drain();
+   assert_eq!(b.read, a.written);
+   assert_eq!(a.conn.unacked(), 0);
+}
+
+
+/// A packet's TSval and TSecr.
+fn timestamps_of(p: &[u8]) -> (u32, u32) {
+   crate::options::timestamps(options_of(p)).expect("timestamps")
+}
+
+/// Rewrite a packet's TSval.
+fn set_tsval(p: &mut [u8], tsval: u32) {
+   let at = option_at(p, 8);
+   p[at + 2..at + 6].copy_from_slice(&tsval.to_be_bytes());
+}
+
+#[test]
+fn paws_drops_an_old_timestamp() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   let sent = small_segments(&mut b, 2);
+   receive(&mut a, &sent[0]);
+   let ts_recent = timestamps_of(&a.egress.drain().pop().expect("an ACK")).1;
+   // the next segment, as an old duplicate from before the sequence numbers wrapped would
+   // carry it
+   let mut old = sent[1].clone();
+   set_tsval(&mut old, ts_recent.wrapping_sub(1000));
+   receive(&mut a, &old);
+   // is not taken, and is answered with an ACK that still stops where it did
+   assert_eq!(a.conn.info().unread, split(&sent[0]).2.len());
+   let acks = a.egress.drain();
+   assert_eq!(acks.len(), 1);
+   assert_eq!(u32::from_be_bytes([acks[0][28], acks[0][29], acks[0][30], acks[0][31]]), end_of(&sent[0]));
+   assert_eq!(timestamps_of(&acks[0]).1, ts_recent);
+   // the genuine segment still gets in
+   receive(&mut a, &sent[1]);
+   a.drain();
+   assert_eq!(a.read, b.written);
+}
+
+#[test]
+fn in_window_segment_updates_ts_recent() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   let sent = small_segments(&mut b, 3);
+   let (tsval, _) = timestamps_of(&sent[0]);
+   // out of order, a later TSval is not taken up: the segment isn't at the left edge of
+   // the window, so it may be far older than its timestamp says (RFC 7323 S4.3)
+   let mut ahead = sent[2].clone();
+   set_tsval(&mut ahead, tsval.wrapping_add(500));
+   receive(&mut a, &ahead);
+   let echoed = timestamps_of(&a.egress.drain().pop().expect("an ACK")).1;
+   assert_ne!(echoed, tsval.wrapping_add(500));
+   // in order, it is, and the next ACK echoes it
+   let mut next = sent[0].clone();
+   set_tsval(&mut next, tsval.wrapping_add(1000));
+   receive(&mut a, &next);
+   assert_eq!(timestamps_of(&a.egress.drain().pop().expect("an ACK")).1, tsval.wrapping_add(1000));
+}
+
+/// Like `open()`, with the SYN changed on the way.
+fn open_editing(edit: fn(&mut Vec<u8>)) -> (End, End) {
+   let mut egress_a = egress();
+   let conn = Connection::connect(&mut egress_a, &opts(u32::MAX - 1000), client(), server()).unwrap();
+   let mut a = End::new(conn, egress_a);
+   let mut syn = a.egress.drain().pop().expect("a SYN");
+   edit(&mut syn);
+   let mut egress_b = egress();
+   let (iph, tcph, data) = split(&syn);
+   let conn = Connection::accept(&mut egress_b, &opts(u32::MAX - 100), &mut FastOpen::default(), iph, tcph, data)
+      .unwrap()
+      .expect("the SYN is accepted");
+   (a, End::new(conn, egress_b))
+}
+
+/// A packet's window field.
+fn window_of(p: &[u8]) -> u16 {
+   u16::from_be_bytes([p[34], p[35]])
+}
+
+/// The window scale shifts `end` applies to what it sends and what it receives.
+fn shifts(end: &mut End) -> (u8, u8) {
+   end.conn.set_repair(&mut end.egress, true).unwrap();
+   let st = end.conn.repair_export().unwrap();
+   end.conn.set_repair(&mut end.egress, false).unwrap();
+   (st.snd_wscale, st.rcv_wscale)
+}
+
+#[test]
+fn window_scale_is_negotiated() {
+   let (mut a, mut b) = open();
+   // the SYN-ACK's own window is never scaled
+   let syn_ack = b.egress.drain().pop().expect("a SYN-ACK");
+   assert_eq!(window_of(&syn_ack), u16::MAX);
+   let shift = syn_ack[option_at(&syn_ack, 3) + 2];
+   receive(&mut a, &syn_ack);
+   deliver(&mut a, &mut b);
+   // a 1 MB buffer takes a shift of 5 for the 16-bit field, both ways
+   assert_eq!(shift, 5);
+   assert_eq!(shifts(&mut a), (5, 5));
+   assert_eq!(shifts(&mut b), (5, 5));
+   // and later windows go out scaled down by it
+   b.conn.enqueue(&mut b.egress, b"x").unwrap();
+   let seg = b.egress.drain().pop().expect("a data segment");
+   assert_eq!(window_of(&seg) as u32, WINDOW >> 5);
+}
+
+#[test]
+fn window_scale_is_clamped_to_14() {
+   let (mut a, mut b) = open_editing(|syn| {
+      let at = option_at(syn, 3);
+      syn[at + 2] = 15;
+   });
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   // the peer's windows are scaled by no more than RFC 7323 S2.3 allows
+   assert_eq!(shifts(&mut b).0, 14);
+}
+
+#[test]
+fn no_window_scaling_without_the_option_in_the_syn() {
+   let (mut a, mut b) = open_editing(|syn| {
+      let at = option_at(syn, 3);
+      syn[at..at + 3].copy_from_slice(&[1, 1, 1]);
+   });
+   // the SYN-ACK doesn't offer a shift of its own either
+   let syn_ack = b.egress.drain().pop().expect("a SYN-ACK");
+   assert!(crate::options::window_scale(options_of(&syn_ack)).is_none());
+   receive(&mut a, &syn_ack);
+   deliver(&mut a, &mut b);
+   assert_eq!(shifts(&mut a), (0, 0));
+   assert_eq!(shifts(&mut b), (0, 0));
+   // so the 1 MB buffer is advertised as the most the field holds
+   b.conn.enqueue(&mut b.egress, b"x").unwrap();
+   let seg = b.egress.drain().pop().expect("a data segment");
+   assert_eq!(window_of(&seg), u16::MAX);
+}
+
+/// The ECN field of a packet's IP header.
+fn ecn_of(p: &[u8]) -> u8 {
+   p[1] & 3
+}
+
+/// Whether a packet has the TCP flag `flag` set.
+fn has_flag(p: &[u8], flag: u8) -> bool {
+   p[33] & flag != 0
+}
+
+const ECE: u8 = 0x40;
+const CWR: u8 = 0x80;
+
+#[test]
+fn ecn_is_negotiated() {
+   let (mut a, mut b) = open();
+   let syn_ack = b.egress.drain().pop().expect("a SYN-ACK");
+   assert!(has_flag(&syn_ack, ECE) && !has_flag(&syn_ack, CWR));
+   receive(&mut a, &syn_ack);
+   let ack = a.egress.drain().pop().expect("an ACK");
+   receive(&mut b, &ack);
+   // only new data is sent ECN-capable, ACKs aren't
+   assert_eq!(ecn_of(&ack), 0);
+   a.conn.enqueue(&mut a.egress, b"x").unwrap();
+   let data = a.egress.drain().pop().expect("a data segment");
+   assert_eq!(ecn_of(&data), 2);
+   receive(&mut b, &data);
+   assert_eq!(ecn_of(&b.egress.drain().pop().expect("an ACK")), 0);
+}
+
+#[test]
+fn no_ecn_unless_the_syn_asks_for_it() {
+   let (mut a, mut b) = open_editing(|syn| syn[33] &= !(ECE | CWR));
+   let syn_ack = b.egress.drain().pop().expect("a SYN-ACK");
+   assert!(!has_flag(&syn_ack, ECE));
+   receive(&mut a, &syn_ack);
+   deliver(&mut a, &mut b);
+   a.conn.enqueue(&mut a.egress, b"x").unwrap();
+   b.conn.enqueue(&mut b.egress, b"x").unwrap();
+   assert_eq!(ecn_of(&a.egress.drain().pop().expect("a data segment")), 0);
+   assert_eq!(ecn_of(&b.egress.drain().pop().expect("a data segment")), 0);
+}
+
+#[test]
+fn congestion_mark_is_echoed_until_cwr() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   a.conn.enqueue(&mut a.egress, &[0; 1000]).unwrap();
+   let mut marked = a.egress.drain().pop().expect("a data segment");
+   marked[1] |= 3;
+   receive(&mut b, &marked);
+   let ack = b.egress.drain().pop().expect("an ACK");
+   assert!(has_flag(&ack, ECE));
+   // the echo goes on in whatever the receiver sends until the sender answers
+   b.conn.enqueue(&mut b.egress, b"x").unwrap();
+   let data = b.egress.drain().pop().expect("a data segment");
+   assert!(has_flag(&data, ECE));
+   // the sender cuts its window once for the round trip, however many echoes it sees
+   let before = a.conn.info();
+   receive(&mut a, &ack);
+   let cut = a.conn.info();
+   assert!(cut.ssthresh < before.ssthresh);
+   receive(&mut a, &data);
+   assert_eq!(a.conn.info().ssthresh, cut.ssthresh);
+   a.egress.drain();
+   // and says so with CWR on its next new data
+   a.conn.enqueue(&mut a.egress, &[0; 1000]).unwrap();
+   let next = a.egress.drain().pop().expect("a data segment");
+   assert!(has_flag(&next, CWR));
+   receive(&mut b, &next);
+   assert!(!has_flag(&b.egress.drain().pop().expect("an ACK"), ECE));
+   assert_eq!(a.conn.info().ssthresh, cut.ssthresh);
+}
+
+/// Options for a listener that only takes segments from a directly connected peer.
+fn single_hop(iss: u32) -> ListenOptions {
+   ListenOptions { min_ttl: Some(255), ..opts(iss) }
+}
+
+#[test]
+fn gtsm_drops_a_syn_that_crossed_a_router() {
+   let mut egress_a = egress();
+   let conn = Connection::connect(&mut egress_a, &opts(u32::MAX - 1000), client(), server()).unwrap();
+   let mut a = End::new(conn, egress_a);
+   let mut syn = a.egress.drain().pop().expect("a SYN");
+   let mut egress_b = egress();
+   let mut accept = |syn: &[u8]| {
+      let (iph, tcph, data) = split(syn);
+      Connection::accept(&mut egress_b, &single_hop(u32::MAX - 100), &mut FastOpen::default(), iph, tcph, data)
+         .unwrap()
+   };
+   // one hop short of the TTL a directly connected peer sends with
+   syn[8] = 254;
+   assert!(accept(&syn).is_none());
+   syn[8] = 255;
+   assert!(accept(&syn).is_some());
+   assert_eq!(egress_b.drain().len(), 1);
+}
+
+#[test]
+fn gtsm_drops_later_segments_that_crossed_a_router() {
+   let mut egress_a = egress();
+   let mut conn = Connection::connect(&mut egress_a, &opts(u32::MAX - 1000), client(), server()).unwrap();
+   conn.set_ttl(255);
+   let mut a = End::new(conn, egress_a);
+   let mut syn = a.egress.drain().pop().expect("a SYN");
+   syn[8] = 255;
+   let mut egress_b = egress();
+   let (iph, tcph, data) = split(&syn);
+   let conn = Connection::accept(&mut egress_b, &single_hop(u32::MAX - 100), &mut FastOpen::default(), iph, tcph, data)
+      .unwrap()
+      .expect("the SYN is accepted");
+   let mut b = End::new(conn, egress_b);
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   a.conn.enqueue(&mut a.egress, b"spoofed?").unwrap();
+   let mut seg = a.egress.drain().pop().expect("a data segment");
+   seg[8] = 254;
+   receive(&mut b, &seg);
+   assert_eq!(b.conn.info().unread, 0);
+   assert!(b.egress.drain().is_empty());
+   seg[8] = 255;
+   receive(&mut b, &seg);
+   assert_eq!(b.conn.info().unread, 8);
+}
+
+/// Options that give up on a handshake after 300 ms.
+fn impatient(iss: u32) -> ListenOptions {
+   ListenOptions { handshake_timeout: Duration::from_millis(300), ..opts(iss) }
+}
+
+/// Run the timers of an embryonic `end`, whose final ACK never comes, until it gives up,
+/// and return what it sent on the way.
+fn tick_until_closed(end: &mut End) -> Vec<Vec<u8>> {
+   let started = Instant::now();
+   let mut sent = Vec::new();
+   while !end.conn.is_closed() {
+      assert!(end.conn.is_syn_received());
+      let at = end.conn.next_deadline().expect("a timer running");
+      tick_at(end, at);
+      sent.extend(end.egress.drain());
+   }
+   assert!(started.elapsed() >= Duration::from_millis(250));
+   sent
+}
+
+#[test]
+fn embryonic_connection_is_dropped_after_the_handshake_timeout() {
+   let (_, mut b) = open_with(impatient);
+   b.egress.drain();
+   let sent = tick_until_closed(&mut b);
+   assert!(b.conn.handshake_timed_out());
+   // quietly, unless asked to reset it
+   assert!(sent.iter().all(|p| p[33] & 0x04 == 0));
+   assert_eq!(b.conn.reset_reason(), None);
+   // and the manager reaps it, counting the failed handshake
+   let mut cm = ConnectionManager::default();
+   let q = Quad::from_addrs(server(), client());
+   cm.connections.insert(q, b.conn);
+   cm.reap(q);
+   assert!(cm.connections.is_empty());
+   assert_eq!(cm.metrics.report(Instant::now()).total.failed_handshakes, 1);
+   assert!(cm.resets.entries().is_empty());
+}
+
+#[test]
+fn handshake_timeout_can_reset_the_client() {
+   let (_, mut b) = open_with(|iss| ListenOptions { rst_on_handshake_timeout: true, ..impatient(iss) });
+   b.egress.drain();
+   let sent = tick_until_closed(&mut b);
+   assert!(b.conn.handshake_timed_out());
+   assert_eq!(sent.iter().filter(|p| p[33] & 0x04 != 0).count(), 1);
+   assert_eq!(b.conn.reset_reason(), Some(ResetReason::HandshakeTimeout));
+   let mut cm = ConnectionManager::default();
+   let q = Quad::from_addrs(server(), client());
+   cm.connections.insert(q, b.conn);
+   cm.reap(q);
+   let reasons: Vec<_> = cm.resets.entries().iter().map(|e| e.reason).collect();
+   assert_eq!(reasons, vec![ResetReason::HandshakeTimeout]);
+}
+
+#[test]
+fn syn_ack_options_fit_the_header() {
+   let all = |iss| ListenOptions { mptcp: true, fast_open: true, ..opts(iss) };
+   let mut egress_a = egress();
+   let conn = Connection::connect(&mut egress_a, &all(u32::MAX - 1000), client(), server()).unwrap();
+   let mut a = End::new(conn, egress_a);
+   // the client asks for a Fast Open cookie as well as offering MPTCP and timestamps
+   let mut syn = a.egress.drain().pop().expect("a SYN");
+   let end = 20 + (syn[32] >> 4) as usize * 4;
+   syn.splice(end..end, [1, 1, 34, 2].iter().copied());
+   syn[32] += 1 << 4;
+   let total = syn.len() as u16;
+   syn[2..4].copy_from_slice(&total.to_be_bytes());
+   let mut egress_b = egress();
+   let (iph, tcph, data) = split(&syn);
+   let conn = Connection::accept(&mut egress_b, &all(u32::MAX - 100), &mut FastOpen::default(), iph, tcph, data)
+      .unwrap()
+      .expect("the SYN is accepted");
+   let mut b = End::new(conn, egress_b);
+   // answering all three would take 48 bytes of options: MPTCP is what gives way
+   let syn_ack = b.egress.drain().pop().expect("a SYN-ACK");
+   let answered = options_of(&syn_ack);
+   assert!(crate::options::timestamps(answered).is_some());
+   assert!(crate::options::fast_open(answered).is_some());
+   assert!(crate::options::mptcp(answered).next().is_none());
+   receive(&mut a, &syn_ack);
+   deliver(&mut a, &mut b);
+   // and the connection goes on as it was answered, with timestamps on every segment
+   b.conn.enqueue(&mut b.egress, &[pattern(0)]).unwrap();
+   b.written = 1;
+   let sent = b.egress.drain();
+   assert!(sent.iter().all(|p| crate::options::timestamps(options_of(p)).is_some()));
+   for p in &sent {
+      receive(&mut a, p);
+   }
+   transfer(&mut a, &mut b, 64 << 10);
+   assert!(!a.conn.handshake.multipath && !b.conn.handshake.multipath);
+}
+
+#[test]
+fn syn_cookie_handshake() {
+   let mut egress_a = egress();
+   let conn = Connection::connect(&mut egress_a, &opts(u32::MAX - 1000), client(), server()).unwrap();
+   let mut a = End::new(conn, egress_a);
+   // the SYN is answered, and nothing is kept of it
+   let cookies = SynCookies::default();
+   let mut egress_b = egress();
+   let syn = a.egress.drain().pop().expect("a SYN");
+   let (iph, tcph, _) = split(&syn);
+   tcp::cookie_syn_ack(&mut egress_b, &opts(0), &cookies, iph, tcph);
+   for p in egress_b.drain() {
+      receive(&mut a, &p);
+   }
+   assert!(a.conn.is_established());
+   let mut ack = a.egress.drain().pop().expect("the ACK of the SYN-ACK");
+   // an ACK that doesn't bring the cookie back gets nowhere
+   let at = 20 + 8;
+   let cookie = u32::from_be_bytes([ack[at], ack[at + 1], ack[at + 2], ack[at + 3]]);
+   ack[at..at + 4].copy_from_slice(&cookie.wrapping_add(1).to_be_bytes());
+   let (iph, tcph, data) = split(&ack);
+   assert!(Connection::from_cookie(&mut egress_b, &opts(0), &cookies, iph, tcph, data).unwrap().is_none());
+   ack[at..at + 4].copy_from_slice(&cookie.to_be_bytes());
+   let (iph, tcph, data) = split(&ack);
+   let conn = Connection::from_cookie(&mut egress_b, &opts(0), &cookies, iph, tcph, data)
+      .unwrap()
+      .expect("the cookie checks out");
+   let mut b = End::new(conn, egress_b);
+   assert!(b.conn.is_established());
+   // without window scaling, the window stays within 64 KB
+   transfer(&mut a, &mut b, 1 << 20);
+}
+
+#[test]
+fn syn_proxy_relays_to_the_backend() {
+   let backend = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 3), 8080);
+   let proxy_opts = ListenOptions { syn_proxy: Some(backend), ..opts(u32::MAX - 100) };
+   let mut cm = ConnectionManager::default();
+   cm.egress.set_mtu(MTU);
+   // what the packet loop does with a packet for the proxy's listener or its connections
+   let ingress = |cm: &mut ConnectionManager, p: &[u8]| {
+      let (iph, tcph, data) = split(p);
+      let q = Quad {
+         src: (iph.source_addr(), tcph.source_port()),
+         dst: (iph.destination_addr(), tcph.destination_port()),
+      };
+      if let Some(c) = cm.connections.get_mut(&q) {
+         c.on_packet(&mut cm.egress, iph, tcph, data).unwrap();
+         synproxy::relay(cm, q).unwrap();
+      } else if tcph.syn() {
+         tcp::cookie_syn_ack(&mut cm.egress, &proxy_opts, &cm.syn_cookies, iph, tcph);
+      } else {
+         assert!(synproxy::admit(cm, q, backend, &proxy_opts, iph, tcph, data).unwrap());
+      }
+   };
+
+   let mut egress_a = egress();
+   let conn = Connection::connect(&mut egress_a, &opts(u32::MAX - 1000), client(), server()).unwrap();
+   let mut a = End::new(conn, egress_a);
+   for p in a.egress.drain() {
+      ingress(&mut cm, &p);
+   }
+   assert!(cm.connections.is_empty());
+   for p in cm.egress.drain() {
+      receive(&mut a, &p);
+   }
+   // the client's ACK proves it's there, and only now is the backend asked
+   for p in a.egress.drain() {
+      ingress(&mut cm, &p);
+   }
+   assert_eq!(cm.connections.len(), 2);
+   let syn = cm.egress.drain().pop().expect("a SYN to the backend");
+   let (iph, tcph, data) = split(&syn);
+   assert_eq!(iph.destination_addr(), *backend.ip());
+   let mut egress_d = egress();
+   let conn = Connection::accept(&mut egress_d, &opts(u32::MAX - 500), &mut FastOpen::default(), iph, tcph, data)
+      .unwrap()
+      .expect("the SYN is accepted");
+   let mut d = End::new(conn, egress_d);
+
+   // the client and the backend each send and close, and each gets what the other sent
+   let total = 1 << 20;
+   while !(a.conn.is_recv_closed() && d.conn.is_recv_closed()) {
+      a.top_up(total);
+      d.top_up(total);
+      let mut moved = false;
+      for p in a.egress.drain().into_iter().chain(d.egress.drain()) {
+         ingress(&mut cm, &p);
+         moved = true;
+      }
+      for p in cm.egress.drain() {
+         let to = if p[16..20] == client().ip().octets() { &mut a } else { &mut d };
+         receive(to, &p);
+         moved = true;
+      }
+      a.drain();
+      d.drain();
+      assert!(moved, "the relay is stuck");
+   }
+   assert_eq!((a.read, d.read), (total, total));
+}
+
+#[test]
+fn reset_after_the_handle_is_gone_leaves_no_error_behind() {
+   // `a`'s end is in the connection table with or without a handle, when the peer resets it
+   let reset_reaped = |attached: bool| {
+      let (mut a, mut b) = open();
+      deliver(&mut b, &mut a);
+      deliver(&mut a, &mut b);
+      let q = Quad::from_addrs(client(), server());
+      let mut cm = ConnectionManager::default();
+      a.conn.attached = attached;
+      cm.connections.insert(q, a.conn);
+      b.conn.abort(&mut b.egress).unwrap();
+      for p in b.egress.drain() {
+         let (iph, tcph, data) = split(&p);
+         cm.connections.get_mut(&q).unwrap().on_packet(&mut cm.egress, iph, tcph, data).unwrap();
+      }
+      assert!(cm.connections[&q].is_closed());
+      cm.reap(q);
+      cm.aborted.len()
+   };
+   // a handle can still read why...
+   assert_eq!(reset_reaped(true), 1);
+   // ...but nobody would ever take it out for one that was dropped
+   assert_eq!(reset_reaped(false), 0);
+}
+
+#[test]
+fn send_buffer_bounds_writes() {
+   let (mut a, mut b) = open_with(|iss| ListenOptions { send_buffer: 10_000, ..opts(iss) });
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   let data: Vec<u8> = (0..8_000).map(pattern).collect();
+   assert_eq!(a.conn.enqueue(&mut a.egress, &data).unwrap(), 8_000);
+   // a write the buffer only has room for part of is taken in part...
+   let more: Vec<u8> = (8_000..16_000).map(pattern).collect();
+   assert_eq!(a.conn.enqueue(&mut a.egress, &more).unwrap(), 2_000);
+   assert_eq!(a.conn.info().unacked, 10_000);
+   // ...and one that finds it full is turned away, with the buffer no longer writable
+   assert!(!a.conn.is_writable());
+   let err = a.conn.enqueue(&mut a.egress, &data).unwrap_err();
+   assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+   let err = a.conn.enqueue_urgent(&mut a.egress, b"!").unwrap_err();
+   assert_eq!(err.kind(), std::io::ErrorKind::WouldBlock);
+   // the peer's ACKs make room again
+   while deliver(&mut a, &mut b) | deliver(&mut b, &mut a) {
+      b.drain();
+   }
+   assert_eq!(b.read, 10_000);
+   assert!(a.conn.is_writable());
+   assert_eq!(a.conn.send_space(), 10_000);
+}
+
+#[test]
+fn memory_meter_follows_the_buffers() {
+   let (mut a, mut b) = open();
+   let memory = tcp::MemoryMeter::default();
+   a.conn.set_memory_meter(memory.clone());
+   b.conn.set_memory_meter(memory.clone());
+   let both = |a: &End, b: &End| a.conn.buffer_memory() + b.conn.buffer_memory();
+   assert_eq!(memory.total(), both(&a, &b));
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   // writes grow the send buffer, and ACKs shrink it again
+   let data: Vec<u8> = (0..200_000).map(pattern).collect();
+   a.conn.enqueue(&mut a.egress, &data).unwrap();
+   a.written = data.len() as u64;
+   let queued = memory.total();
+   assert_eq!(queued, both(&a, &b));
+   while deliver(&mut a, &mut b) | deliver(&mut b, &mut a) {
+      b.drain();
+   }
+   assert_eq!(b.read, data.len() as u64);
+   assert!(memory.total() < queued);
+   assert_eq!(memory.total(), both(&a, &b));
+   // and a connection that goes away takes its share with it
+   let left = b.conn.buffer_memory();
+   drop(a);
+   assert_eq!(memory.total(), left);
+}
+
+/// Interface state over a device that goes nowhere, for driving packets through `ingress()`.
+#[cfg(feature = "alloc-audit")]
+fn shared() -> crate::Shared {
+   use std::os::unix::net::UnixStream;
+   let (event_tx, event_rx) = UnixStream::pair().unwrap();
+   event_tx.set_nonblocking(true).unwrap();
+   event_rx.set_nonblocking(true).unwrap();
+   let (wake_tx, wake_rx) = UnixStream::pair().unwrap();
+   crate::Shared {
+      nic: crate::device::Device::null().unwrap(),
+      manager: Default::default(),
+      pending_var: Default::default(),
+      rcv_var: Default::default(),
+      tick_var: Default::default(),
+      event_tx,
+      event_rx,
+      wake_tx,
+      wake_rx,
+      ingress_drops: Default::default(),
+   }
+}
+
+#[test]
+#[cfg(feature = "alloc-audit")]
+fn ingress_of_data_does_not_allocate() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   let ih = shared();
+   {
+      let mut cmg = ih.manager.lock().unwrap();
+      let cm = &mut *cmg;
+      cm.connections.insert(Quad::from_addrs(server(), client()), b.conn);
+      // packet buffers are recycled, as they would have been after the handshake
+      let flow = crate::egress::Flow::NONE;
+      cm.egress.push(crate::Priority::Normal, flow, "ESTABLISHED", &[0; 40]);
+      cm.egress.flush(&ih.nic, &mut cm.capture, &mut cm.metrics).unwrap();
+   }
+   // the first data segments, and the ACKs they earn, find everything already in place;
+   // ingress() panics if processing them allocates
+   a.conn.set_nodelay(&mut a.egress, true).unwrap();
+   for i in 0..3 {
+      a.conn.enqueue(&mut a.egress, &[pattern(i); 100]).unwrap();
+      for p in a.egress.drain() {
+         assert!(crate::ingress(&ih, &p).unwrap());
+      }
+   }
+   let cm = ih.manager.lock().unwrap();
+   assert_eq!(cm.connections[&Quad::from_addrs(server(), client())].info().unread, 300);
+}
+
+#[test]
+#[cfg(feature = "alloc-audit")]
+fn ingress_of_acks_does_not_allocate() {
+   let (mut a, mut b) = open();
+   deliver(&mut b, &mut a);
+   deliver(&mut a, &mut b);
+   // more than the initial window is queued, so every ACK lets out new segments, whose
+   // places in the retransmission queue must be there already
+   let data: Vec<u8> = (0..256 * 1024).map(pattern).collect();
+   assert_eq!(b.conn.enqueue(&mut b.egress, &data).unwrap(), data.len());
+   let ih = shared();
+   let q = Quad::from_addrs(server(), client());
+   {
+      let mut cmg = ih.manager.lock().unwrap();
+      let cm = &mut *cmg;
+      cm.connections.insert(q, b.conn);
+      for p in b.egress.drain() {
+         receive(&mut a, &p);
+      }
+      let flow = crate::egress::Flow::NONE;
+      for _ in 0..64 {
+         cm.egress.push(crate::Priority::Normal, flow, "ESTABLISHED", &[0; 40]);
+      }
+      cm.egress.flush(&ih.nic, &mut cm.capture, &mut cm.metrics).unwrap();
+   }
+   // ingress() panics if processing an ACK allocates
+   for p in a.egress.drain() {
+      assert!(crate::ingress(&ih, &p).unwrap());
+   }
+   let cm = ih.manager.lock().unwrap();
+   assert!(cm.connections[&q].info().delivered > 0);
+}
@@ -0,0 +1,213 @@
+//! SYN proxy mode, for listeners that stand in front of a backend (`ListenOptions::
+//! syn_proxy`). SYNs are answered with SYN cookies (RFC 4987 S3.6), so a client costs no
+//! state until its ACK brings the cookie back and proves it is at the address it claims. Only
+//! then is its connection created and one opened to the backend on its behalf; the data is
+//! relayed between the two from then on. A flood of spoofed SYNs never gets past us.
+//!
+//! The cookie is all we remember of the SYN, and it only has room for the client's MSS, so
+//! the client's connection runs without window scaling, SACK or timestamps.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::io;
+use std::net::{Shutdown, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+use crate::tcp::{Connection, ListenOptions};
+use crate::{metrics, ConnectionManager, Quad, ResetReason};
+
+/// the MSS values a cookie can express; a client's MSS is rounded down to one of them
+const MSS_TABLE: [u16; 8] = [536, 1024, 1220, 1360, 1400, 1440, 1460, 8960];
+/// how often the time counter in cookies moves on; a cookie is good until the counter has
+/// moved twice
+const PERIOD: Duration = Duration::from_secs(64);
+/// the low bits of a cookie, which hold the keyed hash
+const HASH_MASK: u32 = (1 << 26) - 1;
+/// most data read from one end of a relay that the other end may hold unacknowledged
+const RELAY_BUFFER: usize = 64 * 1024;
+
+/// Makes and checks the initial sequence numbers of cookie SYN-ACKs: the time counter in the
+/// top three bits, the client's MSS as an index into `MSS_TABLE` in the next three, and a
+/// keyed hash of the connection, the client's ISN and both of those in the rest.
+pub struct SynCookies {
+   key: RandomState,
+   epoch: Instant,
+}
+
+impl Default for SynCookies {
+   fn default() -> Self {
+      SynCookies { key: RandomState::new(), epoch: Instant::now() }
+   }
+}
+
+impl SynCookies {
+   /// The cookie for a SYN from `remote` to `local` with initial sequence number `isn`,
+   /// offering `mss`.
+   pub fn make(&self, local: SocketAddrV4, remote: SocketAddrV4, isn: u32, mss: u16, now: Instant) -> u32 {
+      let count = self.count(now);
+      let index = MSS_TABLE.iter().rposition(|&m| m <= mss).unwrap_or(0) as u32;
+      (count & 7) << 29 | index << 26 | self.hash(local, remote, isn, count, index)
+   }
+
+   /// The client's MSS if `cookie` is one we made for this SYN and it hasn't expired.
+   pub fn check(&self, local: SocketAddrV4, remote: SocketAddrV4, isn: u32, cookie: u32, now: Instant) -> Option<u16> {
+      let count = self.count(now);
+      let index = cookie >> 26 & 7;
+      [count, count.wrapping_sub(1)]
+         .iter()
+         .find(|&&c| c & 7 == cookie >> 29)
+         .filter(|&&c| self.hash(local, remote, isn, c, index) == cookie & HASH_MASK)
+         .map(|_| MSS_TABLE[index as usize])
+   }
+
+   fn count(&self, now: Instant) -> u32 {
+      (now.saturating_duration_since(self.epoch).as_secs() / PERIOD.as_secs()) as u32
+   }
+
+   fn hash(&self, local: SocketAddrV4, remote: SocketAddrV4, isn: u32, count: u32, index: u32) -> u32 {
+      let mut h = self.key.build_hasher();
+      (local, remote, isn, count, index).hash(&mut h);
+      h.finish() as u32 & HASH_MASK
+   }
+}
+
+/// Take an ACK for `q`, which has no connection, on a listener in SYN proxy mode: if it
+/// brings back a valid cookie, create the client's connection and start opening one to
+/// `backend`. Returns whether the cookie checked out.
+pub fn admit<'a>(
+   cm: &mut ConnectionManager,
+   q: Quad,
+   backend: SocketAddrV4,
+   opts: &ListenOptions,
+   iph: etherparse::Ipv4HeaderSlice<'a>,
+   tcph: etherparse::TcpHeaderSlice<'a>,
+   data: &'a [u8],
+) -> io::Result<bool> {
+   let mut c = match Connection::from_cookie(&mut cm.egress, opts, &cm.syn_cookies, iph, tcph, data)? {
+      Some(c) => c,
+      None => return Ok(false),
+   };
+   cm.metrics.record(metrics::RateEvent::PassiveOpen, Instant::now());
+   c.set_memory_meter(cm.memory.clone());
+   cm.connections.insert(q, c);
+   let port = match cm.ephemeral_port(q.dst.0, backend) {
+      Some(port) => port,
+      None => {
+         let c = cm.connections.get_mut(&q).expect("inserted above");
+         c.reset(&mut cm.egress, ResetReason::RelayFailed)?;
+         return Ok(true);
+      }
+   };
+   let bq = Quad::from_addrs(SocketAddrV4::new(q.dst.0, port), backend);
+   let mut b = Connection::connect(&mut cm.egress, opts, bq.local(), backend)?;
+   b.set_memory_meter(cm.memory.clone());
+   cm.connections.insert(bq, b);
+   cm.relays.insert(q, bq);
+   cm.relays.insert(bq, q);
+   cm.schedule(bq);
+   Ok(true)
+}
+
+/// Move data both ways between `q` and the other end of its relay, if it has one, after
+/// anything happened to `q`. A connection that failed takes the other end down with it.
+pub fn relay(cm: &mut ConnectionManager, q: Quad) -> io::Result<()> {
+   let p = match cm.relays.get(&q) {
+      Some(&p) => p,
+      None => return Ok(()),
+   };
+   if cm.connections.get(&q).map_or(false, Connection::failed) {
+      if let Some(other) = cm.connections.get_mut(&p) {
+         if other.is_connecting() {
+            // the backend hasn't answered yet, so there is nobody to reset
+            other.abort(&mut cm.egress)?;
+         } else if !other.is_closed() && !other.is_time_wait() {
+            other.reset(&mut cm.egress, ResetReason::RelayFailed)?;
+         }
+      }
+   } else {
+      pipe(cm, q, p)?;
+      pipe(cm, p, q)?;
+   }
+   cm.schedule(p);
+   Ok(())
+}
+
+/// Move what `from` has received over to `to`, as far as `to` has room for, and pass on the
+/// FIN once everything before it has gone across.
+fn pipe(cm: &mut ConnectionManager, from: Quad, to: Quad) -> io::Result<()> {
+   let mut buf = [0u8; 16 * 1024];
+   loop {
+      let room = match cm.connections.get(&to) {
+         Some(b) if b.is_writable() => {
+            std::cmp::min(RELAY_BUFFER.saturating_sub(b.unacked()), b.send_space())
+         }
+         _ => return Ok(()),
+      };
+      let a = match cm.connections.get_mut(&from) {
+         Some(a) => a,
+         None => return Ok(()),
+      };
+      if room == 0 || !a.has_readable() {
+         break;
+      }
+      let len = std::cmp::min(room, buf.len());
+      let (n, _, _) = a.read_incoming(&mut buf[..len]);
+      a.window_update(&mut cm.egress)?;
+      let b = cm.connections.get_mut(&to).expect("looked up above");
+      b.enqueue(&mut cm.egress, &buf[..n])?;
+   }
+   let done = cm.connections.get(&from).map_or(false, |a| a.is_recv_closed() && !a.has_readable());
+   if done {
+      let b = cm.connections.get_mut(&to).expect("looked up above");
+      b.shutdown(&mut cm.egress, Shutdown::Write)?;
+   }
+   Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use std::net::Ipv4Addr;
+
+   fn local() -> SocketAddrV4 {
+      SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 80)
+   }
+
+   fn remote() -> SocketAddrV4 {
+      SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 50000)
+   }
+
+   #[test]
+   fn cookies_carry_the_mss() {
+      let cookies = SynCookies::default();
+      let now = Instant::now();
+      let cookie = cookies.make(local(), remote(), 1234, 1460, now);
+      assert_eq!(cookies.check(local(), remote(), 1234, cookie, now), Some(1460));
+      // rounded down to what the table has
+      let cookie = cookies.make(local(), remote(), 1234, 1300, now);
+      assert_eq!(cookies.check(local(), remote(), 1234, cookie, now), Some(1220));
+      let cookie = cookies.make(local(), remote(), 1234, 100, now);
+      assert_eq!(cookies.check(local(), remote(), 1234, cookie, now), Some(536));
+   }
+
+   #[test]
+   fn cookies_are_bound_to_the_syn() {
+      let cookies = SynCookies::default();
+      let now = Instant::now();
+      let cookie = cookies.make(local(), remote(), 1234, 1460, now);
+      assert_eq!(cookies.check(local(), remote(), 1235, cookie, now), None);
+      assert_eq!(cookies.check(remote(), local(), 1234, cookie, now), None);
+      assert_eq!(cookies.check(local(), remote(), 1234, cookie ^ 1, now), None);
+      // nor do they hold under another key
+      assert_eq!(SynCookies::default().check(local(), remote(), 1234, cookie, now), None);
+   }
+
+   #[test]
+   fn cookies_expire() {
+      let cookies = SynCookies::default();
+      let now = cookies.epoch;
+      let cookie = cookies.make(local(), remote(), 1234, 1460, now);
+      assert!(cookies.check(local(), remote(), 1234, cookie, now + PERIOD).is_some());
+      assert!(cookies.check(local(), remote(), 1234, cookie, now + 2 * PERIOD).is_none());
+   }
+}
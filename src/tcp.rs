@@ -1,129 +1,2632 @@
 use std::io;
+use std::collections::VecDeque;
+use std::net::{Ipv4Addr, Shutdown, SocketAddrV4};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time;
+
+use crate::cc::{self, CongestionAlgorithm, CongestionControl};
+use crate::egress::{Egress, Flow, Priority, MAX_MTU};
+use crate::fastopen::{self, FastOpen};
+use crate::goodput::Goodput;
+use crate::mptcp::{self, Mptcp};
+use crate::options::{self, OptionWriter, SackBlocks, TcpOption};
+use crate::plpmtud::Plpmtud;
+use crate::reassembly::Reassembly;
+use crate::resets::ResetReason;
+use crate::sendbuf::SendBuffer;
+use crate::synproxy::SynCookies;
+use crate::timer::{Timer, Timers};
+
+/// Log a line about a connection, but only if verbose tracing is on for it.
+macro_rules! trace {
+   ($c:expr, $($arg:tt)*) => {
+      if $c.verbose {
+         $c.log(format_args!($($arg)*));
+      }
+   };
+}
 
 enum State {
+   Closed,
+   SynSent,
    SynRcvd,
    Estab,
    FinWait1,
    FinWait2,
+   Closing,
+   CloseWait,
+   LastAck,
    TimeWait
 }
 
-impl State {
-   fn is_synchronized(&self) -> bool {
-      match *self {
-         State::SynRcvd => false,
-         State::Estab | State::FinWait1 | State::FinWait2 | State::TimeWait => true,
+impl State {
+   fn is_synchronized(&self) -> bool {
+      match *self {
+         State::Closed | State::SynSent | State::SynRcvd => false,
+         State::Estab
+         | State::FinWait1
+         | State::FinWait2
+         | State::Closing
+         | State::CloseWait
+         | State::LastAck
+         | State::TimeWait => true,
+      }
+   }
+}
+
+/// Settings for passive opens, consulted before any connection state is allocated, and for
+/// active ones through `Interface::connect_with()`.
+#[derive(Debug, Clone)]
+pub struct ListenOptions {
+   /// Generalized TTL Security Mechanism (RFC 5082): drop segments that arrive with a TTL
+   /// below this value. Single-hop peers send with TTL 255, so 255 rejects anything that
+   /// has crossed a router, which a remote spoofer cannot avoid.
+   pub min_ttl: Option<u8>,
+   /// how many established connections may wait for accept() before further SYNs are
+   /// dropped
+   pub backlog: usize,
+   /// how many connections may be in SYN-RECEIVED at once before further SYNs are dropped
+   pub syn_backlog: usize,
+   /// how long a connection may sit in SYN-RECEIVED, counted from the first SYN, before the
+   /// embryonic connection is dropped
+   pub handshake_timeout: time::Duration,
+   /// send an RST to the client when the handshake times out
+   pub rst_on_handshake_timeout: bool,
+   /// size of the receive buffer, and so the most receive window advertised to peers, which
+   /// shrinks as unread data fills the buffer; beyond 64 KB only if the peer agrees to
+   /// window scaling (RFC 7323); not 0
+   pub recv_window: u32,
+   /// most data written and not yet acknowledged by the peer that a connection holds; once
+   /// it is full, writes block, or fail with `WouldBlock` in non-blocking mode, until ACKs
+   /// make room
+   pub send_buffer: usize,
+   /// upper bound on the payload of segments we send, below what the device allows; no
+   /// less than 88 bytes
+   pub mss_clamp: Option<u16>,
+   /// start with segments small enough for nearly any path and probe for larger ones
+   /// (RFC 4821), for paths that drop the ICMP messages path MTU discovery relies on
+   pub mtu_probing: bool,
+   /// round low-priority timers (anything not on the data path) up to multiples of this,
+   /// so idle connections expire together and the stack wakes up less often
+   pub timer_granularity: Option<time::Duration>,
+   /// cap on the retransmission timeout as it doubles with every unanswered retransmission
+   pub max_rto: time::Duration,
+   /// abort the connection after this many retransmissions of the same segment go unanswered
+   pub max_retransmits: u32,
+   /// abort the connection after this many probes of the peer's zero window in a row go
+   /// unanswered
+   pub max_persist_probes: Option<u32>,
+   /// once the peer has been quiet this long, probe it with keepalives (RFC 1122
+   /// S4.2.3.6); `None` leaves idle connections alone
+   pub keepalive: Option<time::Duration>,
+   /// time between keepalive probes while they go unanswered
+   pub keepalive_interval: time::Duration,
+   /// reset the connection after this many keepalive probes in a row go unanswered
+   pub keepalive_count: u32,
+   /// abort the connection once both ends have advertised a zero window for this long with
+   /// data waiting on our side: each application waits for the other to read, and neither
+   /// buffer will ever drain
+   pub zero_window_timeout: Option<time::Duration>,
+   /// after the connection has been idle for an RTO, send at most this many segments before
+   /// waiting for an ACK, however much the window allows
+   pub restart_burst: Option<usize>,
+   /// instead of waiting for ACKs after the restart burst, spread the rest of the window
+   /// over one smoothed RTT
+   pub pace_restart: bool,
+   /// hold back the ACK for a single data segment up to this long, in case more data or a
+   /// reply comes along to carry it; every second segment is still acked right away
+   /// (RFC 5681 S4.2). `None` acks every data segment immediately.
+   pub delayed_ack: Option<time::Duration>,
+   /// send a write's last less-than-MSS segment at once, even while an earlier small
+   /// segment is unacknowledged, rather than wait to coalesce it with more (TCP_NODELAY)
+   pub nodelay: bool,
+   /// maximum segment lifetime; connections linger in TIME-WAIT for twice this long
+   pub msl: time::Duration,
+   /// let a new SYN for a connection in TIME-WAIT start a fresh connection right away if its
+   /// sequence number is beyond the old one's (RFC 1122 S4.2.2.13)
+   pub time_wait_reuse: bool,
+   /// TTL written into the IPv4 header of every segment sent on accepted connections
+   pub ttl: u8,
+   /// while the interface is short of buffer memory, answer SYNs with an RST instead of
+   /// dropping them, so clients fail fast rather than retry
+   pub rst_when_out_of_memory: bool,
+   /// congestion control algorithm for accepted connections
+   pub congestion_control: CongestionAlgorithm,
+   /// after a retransmission timeout, check with new data whether it was spurious and undo
+   /// the window reduction if so (F-RTO, RFC 5682)
+   pub frto: bool,
+   /// agree to timestamps (RFC 7323) when the peer offers them
+   pub timestamps: bool,
+   /// agree to explicit congestion notification (RFC 3168) when the peer asks for it
+   pub ecn: bool,
+   /// hand out TCP Fast Open cookies and take the data on SYNs that carry a valid one
+   /// (RFC 7413)
+   pub fast_open: bool,
+   /// offer, or agree to, Multipath TCP (RFC 8684) with a single subflow
+   pub mptcp: bool,
+   /// answer SYNs with SYN cookies, keeping no state until the client's ACK proves it's
+   /// there, then open a connection to this backend and relay between the two. Such
+   /// connections never reach accept(). See `synproxy`.
+   pub syn_proxy: Option<SocketAddrV4>,
+   /// never advertise a receive window larger than this many bytes, however large the
+   /// buffer, to bound what a peer can have in flight towards us
+   pub window_clamp: Option<u32>,
+   /// drop SYNs from addresses the routing table has no route back to through the device;
+   /// such SYNs are spoofed, and answering them only holds state for a peer that isn't there
+   pub reverse_path_check: bool,
+   /// let connections be opened from an address not (yet) configured on the device, such
+   /// as a virtual IP that moves here on failover (IP_FREEBIND)
+   pub freebind: bool,
+   /// put the reason in RSTs we send on purpose, as text for the peer's packet captures
+   pub rst_diagnostics: bool,
+   /// initial send sequence number to use instead of one of our choosing, for tests near
+   /// the wraparound; a predictable one lets off-path attackers guess their way into the
+   /// connection (RFC 6528)
+   pub iss: Option<u32>,
+}
+
+impl Default for ListenOptions {
+   fn default() -> Self {
+      ListenOptions {
+         min_ttl: None,
+         backlog: 128,
+         syn_backlog: 256,
+         handshake_timeout: time::Duration::from_secs(75),
+         rst_on_handshake_timeout: false,
+         recv_window: 1024,
+         send_buffer: 256 * 1024,
+         mss_clamp: None,
+         mtu_probing: false,
+         timer_granularity: None,
+         max_rto: time::Duration::from_secs(60),
+         max_retransmits: 15,
+         max_persist_probes: Some(15),
+         keepalive: None,
+         keepalive_interval: time::Duration::from_secs(75),
+         keepalive_count: 9,
+         zero_window_timeout: None,
+         restart_burst: None,
+         pace_restart: false,
+         delayed_ack: Some(time::Duration::from_millis(40)),
+         nodelay: false,
+         msl: time::Duration::from_secs(30),
+         time_wait_reuse: false,
+         ttl: DEFAULT_TTL,
+         rst_when_out_of_memory: false,
+         congestion_control: CongestionAlgorithm::default(),
+         frto: true,
+         timestamps: true,
+         ecn: true,
+         fast_open: false,
+         mptcp: false,
+         syn_proxy: None,
+         window_clamp: None,
+         reverse_path_check: false,
+         freebind: false,
+         rst_diagnostics: false,
+         iss: None,
+      }
+   }
+}
+
+impl ListenOptions {
+   /// Check settings that would leave connections unable to work, before any are created.
+   pub(crate) fn validate(&self) -> io::Result<()> {
+      if self.mss_clamp.map_or(false, |clamp| clamp < MIN_PEER_MSS) {
+         return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("mss_clamp is below the minimum of {}", MIN_PEER_MSS),
+         ));
+      }
+      if self.recv_window == 0 {
+         // there would be no buffer to reassemble anything in
+         return Err(io::Error::new(io::ErrorKind::InvalidInput, "recv_window is 0"));
+      }
+      Ok(())
+   }
+
+   fn ttl_ok(&self, iph: &etherparse::Ipv4HeaderSlice) -> bool {
+      match self.min_ttl {
+         Some(min) => iph.ttl() >= min,
+         None => true,
+      }
+   }
+}
+
+/// What we learned about the peer while setting up a passively opened connection.
+#[derive(Debug, Clone)]
+pub struct HandshakeInfo {
+   /// raw option bytes carried by the peer's SYN
+   pub syn_options: Vec<u8>,
+   /// when the first SYN for this connection arrived
+   pub first_syn: time::Instant,
+   /// how many times the peer retransmitted its SYN before the handshake completed
+   pub syn_retransmits: u32,
+   /// TTL of the peer's SYN as it arrived, a rough hint of how many hops away it is
+   pub syn_ttl: u8,
+   /// the data on the SYN was taken thanks to a Fast Open cookie
+   pub fast_open: bool,
+   /// both ends agreed to Multipath TCP
+   pub multipath: bool,
+}
+
+/// A summary of one connection, for monitoring.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+   pub local: SocketAddrV4,
+   pub remote: SocketAddrV4,
+   pub state: &'static str,
+   /// bytes the peer has acknowledged so far
+   pub delivered: u64,
+   /// bytes queued for sending that the peer has not acknowledged yet
+   pub unacked: usize,
+   /// bytes received that the application has not read yet
+   pub unread: usize,
+   pub srtt: Option<time::Duration>,
+   pub rto: time::Duration,
+   /// retransmissions since SND.UNA last advanced
+   pub retransmits: u32,
+   /// congestion window, in bytes
+   pub cwnd: usize,
+   /// slow start threshold, in bytes
+   pub ssthresh: usize,
+   /// largest packet sent, as far as the path is known to take it
+   pub path_mtu: usize,
+   /// bytes per second the peer acknowledged, over the last second
+   pub send_goodput: f64,
+   /// bytes per second received in order, over the last second
+   pub recv_goodput: f64,
+}
+
+/// Everything needed to recreate an established connection in a fresh Connection, in the
+/// spirit of Linux's TCP_REPAIR. The fields are plain values so the state can be shipped to
+/// another process for live migration or a seamless restart.
+#[derive(Debug, Clone)]
+pub struct RepairState {
+   pub local: SocketAddrV4,
+   pub remote: SocketAddrV4,
+   pub snd_iss: u32,
+   pub snd_una: u32,
+   pub snd_nxt: u32,
+   pub snd_wnd: u32,
+   pub snd_wl1: u32,
+   pub snd_wl2: u32,
+   pub rcv_irs: u32,
+   pub rcv_nxt: u32,
+   pub rcv_wnd: u32,
+   pub ttl: u8,
+   /// MSS the peer announced in its SYN
+   pub peer_mss: u16,
+   /// shift applied to the windows the peer advertises
+   pub snd_wscale: u8,
+   /// shift applied to the windows we advertise
+   pub rcv_wscale: u8,
+   /// our timestamp clock, if timestamps are in use
+   pub ts_clock: Option<u32>,
+   /// the peer's timestamp to echo
+   pub ts_recent: u32,
+   /// both ends agreed to ECN
+   pub ecn: bool,
+   /// data sent or queued but not yet acknowledged, starting at `snd_una`
+   pub unacked: Vec<u8>,
+   /// data received and acknowledged that the application has not read yet
+   pub incoming: Vec<u8>,
+   /// both ends agreed to SACK
+   pub sack_ok: bool,
+   /// congestion window, in bytes
+   pub cwnd: usize,
+   /// slow start threshold, in bytes
+   pub ssthresh: usize,
+   /// the options the connection was opened with, congestion control algorithm included
+   pub opts: ListenOptions,
+}
+
+/// Control bits of a hand-crafted segment.
+#[cfg(feature = "tooling")]
+#[derive(Debug, Clone, Default)]
+pub struct SegmentFlags {
+   pub syn: bool,
+   pub ack: bool,
+   pub fin: bool,
+   pub rst: bool,
+   pub psh: bool,
+   pub urg: bool,
+}
+
+/// Where F-RTO is after a retransmission timeout.
+#[derive(Debug, Clone, Copy)]
+enum Frto {
+   /// waiting for the first ACK after the retransmission
+   FirstAck,
+   /// two new segments went out; waiting for the ACK that tells
+   SecondAck,
+}
+
+/// A transmitted segment that occupies sequence space and has not been fully acknowledged.
+/// The payload itself stays in `Connection::unacked`, so only its position is remembered;
+/// retransmissions slice it out of the same shared chunks as the first transmission.
+#[derive(Debug, Clone, Copy)]
+struct InFlight {
+   seq: u32,
+   /// payload bytes, not counting SYN or FIN
+   len: u32,
+   syn: bool,
+   fin: bool,
+   /// when the segment was last sent
+   sent: time::Instant,
+   /// RTT samples from retransmitted segments are ambiguous and not taken (Karn's algorithm)
+   retransmitted: bool,
+   /// the peer has reported this segment in a SACK block
+   sacked: bool,
+   /// RACK has declared this transmission lost and it is due to be resent
+   lost: bool,
+}
+
+impl InFlight {
+   /// Sequence number just past this segment.
+   fn end(&self) -> u32 {
+      self.seq
+         .wrapping_add(self.len)
+         .wrapping_add(self.syn as u32)
+         .wrapping_add(self.fin as u32)
+   }
+}
+
+/// largest window scale shift (RFC 7323 S2.3)
+const MAX_WSCALE: u8 = 14;
+/// largest window a peer can advertise, with the largest shift
+const MAX_WINDOW: usize = (u16::MAX as usize) << MAX_WSCALE;
+/// retransmissions held back by the interface's limit are spread over this much time
+const STORM_SPREAD: time::Duration = time::Duration::from_secs(1);
+/// ECN field of the IPv4 header: ECN-capable transport, and congestion experienced
+const ECT0: u8 = 2;
+const ECN_CE: u8 = 3;
+/// bytes the timestamps option takes up in every segment, padding included
+const TIMESTAMPS_LEN: usize = 12;
+/// a TS.Recent unrefreshed for this long no longer counts for PAWS, as the peer's clock may
+/// have wrapped since (RFC 7323 S5.5)
+const PAWS_IDLE: time::Duration = time::Duration::from_secs(24 * 24 * 60 * 60);
+/// IPv4 and TCP headers without options
+const BASE_HEADERS: usize = 40;
+/// MSS assumed when the SYN carries none (RFC 1122 S4.2.2.6)
+const DEFAULT_PEER_MSS: u16 = 536;
+/// smaller MSS options are raised to this, as Linux does, so a peer can't make us send
+/// data a few bytes at a time
+const MIN_PEER_MSS: u16 = 88;
+
+/// Source of egress flow ids, unique for the lifetime of the process. 0 is `Flow::NONE`.
+static NEXT_FLOW: AtomicU64 = AtomicU64::new(1);
+
+/// Buffer memory held by all the connections that share it. Each connection adds or takes
+/// away what its own buffers grew or shrank by as they change, so the total is there to
+/// check against a limit without adding them all up.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MemoryMeter(Arc<AtomicUsize>);
+
+impl MemoryMeter {
+   pub fn total(&self) -> usize {
+      self.0.load(Ordering::Relaxed)
+   }
+}
+
+/// TTL of segments sent without a listener's options to go by
+pub(crate) const DEFAULT_TTL: u8 = 64;
+/// challenge ACKs a connection sends per second at most (RFC 5961 S7)
+const CHALLENGE_ACK_LIMIT: u32 = 10;
+
+/// Initial retransmission timeout (RFC 6298 S2.1).
+const INITIAL_RTO: time::Duration = time::Duration::from_secs(1);
+/// Lower bound on the computed retransmission timeout (RFC 6298 S2.4).
+const MIN_RTO: time::Duration = time::Duration::from_secs(1);
+
+pub struct Connection {
+   state: State,
+   send: SendSequenceSpace,
+   recv: RecvSequenceSpace,
+   ip: etherparse::Ipv4Header,
+   tcp: etherparse::TcpHeader,
+   /// data received in order that the application has not read yet
+   pub(crate) incoming: VecDeque<u8>,
+   /// data received past a hole, waiting for the hole to be filled
+   reassembly: Reassembly,
+   /// bytes ever appended to `incoming`, which places the stream offset of its front
+   received: u64,
+   /// stream offsets just past segments that carried PSH, if reads stop at them
+   psh_marks: Option<VecDeque<u64>>,
+   /// bytes that must be buffered before a read returns, as with SO_RCVLOWAT
+   recv_lowat: usize,
+   /// stream offsets just past each run of data appended to `incoming`, with when the
+   /// segment that completed it arrived; once it's full, runs are merged into the last
+   arrivals: VecDeque<(u64, time::Instant)>,
+   /// when the latest of them arrived
+   last_arrival: Option<time::Instant>,
+   /// the out-of-band byte: the last byte of urgent data, which is not part of `incoming`
+   urgent: Option<u8>,
+   /// data queued by the application that has not been acknowledged yet
+   unacked: SendBuffer,
+   /// options of the listener that accepted this connection
+   opts: ListenOptions,
+   pub(crate) handshake: HandshakeInfo,
+   /// in repair mode the connection neither sends nor processes segments
+   repair: bool,
+   /// artificial (delay, jitter) applied to everything we transmit
+   latency: Option<(time::Duration, time::Duration)>,
+   /// segments held back by `latency`, with the time they are due to go out; the buffers
+   /// come from the egress pool
+   delayed: VecDeque<(time::Instant, Vec<u8>)>,
+   /// xorshift state for picking jitter
+   rng: u64,
+   /// egress scheduling class
+   priority: Priority,
+   /// identifies this connection's packets to the egress scheduler
+   flow: u64,
+   /// share of the device under fair queueing, relative to other connections
+   weight: u32,
+   /// total sequence space the peer has acknowledged so far
+   delivered: u64,
+   /// sequence space acknowledged by the most recent ACK that advanced SND.UNA; a stretch ACK
+   /// covering many segments shows up as one large value rather than many ACKs
+   last_acked: u32,
+   /// the peer has torn down its end entirely (RST, or a FIN after its FIN), so nothing we
+   /// queue from now on can ever be delivered
+   peer_closed: bool,
+   /// the application has shut down its sending side: our FIN follows the data queued
+   write_shut: bool,
+   /// the application has shut down its receiving side: whatever arrives is acked and dropped
+   read_shut: bool,
+   /// segments sent but not yet acknowledged, oldest first
+   rtx_queue: VecDeque<InFlight>,
+   /// smoothed round-trip time, once a first sample has been taken
+   srtt: Option<time::Duration>,
+   /// round-trip time variation
+   rttvar: time::Duration,
+   /// current retransmission timeout
+   rto: time::Duration,
+   timers: Timers,
+   /// the deadline the interface's scheduler will next wake up this connection for
+   pub(crate) scheduled: Option<time::Instant>,
+   /// a `TcpStream` refers to the connection, and may still pick up its error
+   pub(crate) attached: bool,
+   /// where the buffer memory of the connection is counted, and how much of it is
+   memory: MemoryMeter,
+   charged: usize,
+   /// retransmissions since SND.UNA last advanced
+   retransmits: u32,
+   /// consecutive duplicate ACKs for SND.UNA
+   dup_acks: u32,
+   /// SND.NXT when loss recovery last began; until an ACK covers it, duplicate ACKs don't
+   /// start another fast retransmit (RFC 6582 S3.2)
+   recover: Option<u32>,
+   /// in fast recovery, retransmitting a hole for every partial ACK
+   fast_recovery: bool,
+   /// RACK (RFC 8985): when the most recently sent segment known to have been delivered was
+   /// sent, and where it ends
+   rack_xmit: Option<(time::Instant, u32)>,
+   /// round trip of that segment
+   rack_rtt: time::Duration,
+   /// lowest RTT seen, which sizes RACK's reordering window
+   min_rtt: Option<time::Duration>,
+   /// end of the tail loss probe in flight, if any
+   tlp_end: Option<u32>,
+   /// upper bound on the receive window we advertise, in bytes
+   window_clamp: Option<u32>,
+   /// right edge of the furthest receive window advertised so far
+   rcv_edge: u32,
+   /// F-RTO (RFC 5682) in progress after a timeout: the ACK awaited next, and SND.NXT when
+   /// the timeout fired
+   frto: Option<(Frto, u32)>,
+   cc: Box<dyn CongestionControl>,
+   /// log segments, state changes and timer events for this connection
+   verbose: bool,
+   /// both ends agreed to use selective acknowledgments (RFC 2018)
+   sack_ok: bool,
+   /// largest packet the device takes
+   mtu: usize,
+   /// the search for the largest packet the path takes, if the options ask for one
+   plpmtud: Option<Plpmtud>,
+   /// connection-level state, if both ends agreed to Multipath TCP
+   mptcp: Option<Mptcp>,
+   /// MSS the peer announced in its SYN
+   peer_mss: u16,
+   /// both ends agreed to scale windows (RFC 7323)
+   wscale_ok: bool,
+   /// shift applied to the windows the peer advertises
+   snd_wscale: u8,
+   /// shift applied to the windows we advertise
+   rcv_wscale: u8,
+   /// both ends agreed to timestamps (RFC 7323)
+   ts_ok: bool,
+   /// our timestamp clock counts milliseconds since this, from `ts_offset`
+   ts_base: time::Instant,
+   /// random start of our timestamp clock, so it gives away nothing about uptime
+   ts_offset: u32,
+   /// TS.Recent: the peer's timestamp to echo
+   ts_recent: u32,
+   /// when `ts_recent` was last updated
+   ts_recent_at: time::Instant,
+   /// Last.ACK.sent: the acknowledgment number of our latest segment
+   last_ack_sent: u32,
+   /// Fast Open cookie to hand the client in our SYN-ACK
+   tfo_cookie: Option<fastopen::Cookie>,
+   /// both ends agreed to explicit congestion notification (RFC 3168)
+   ecn_ok: bool,
+   /// a segment arrived marked with congestion: set ECE until the peer answers with CWR
+   ece_pending: bool,
+   /// the window was cut for an echoed mark: say so with CWR on the next new data
+   cwr_pending: bool,
+   /// further echoed marks are ignored until this is acknowledged, so the window is cut at
+   /// most once per round trip
+   ecn_recover: Option<u32>,
+   send_goodput: Goodput,
+   recv_goodput: Goodput,
+   /// data segments received since we last sent an ACK
+   ack_owed: u32,
+   /// gave up waiting for the handshake to complete
+   handshake_timed_out: bool,
+   /// opened by connect() rather than accepted from a listener
+   active_open: bool,
+   /// largest send window the peer has offered, which bounds how old an acceptable ACK
+   /// can be (RFC 5961 S5.2)
+   max_snd_wnd: u32,
+   /// challenge ACKs sent in the second since `challenge_since`
+   challenge_acks: u32,
+   challenge_since: time::Instant,
+   /// duplicate data to report in a D-SACK block on the next segment we send (RFC 2883)
+   dsack: Option<(u32, u32)>,
+   /// when we last sent new data
+   last_send: Option<time::Instant>,
+   /// segments that may still go out before the next ACK, while restarting after idle
+   burst_left: Option<usize>,
+   /// earliest time the congestion controller's pacing rate lets the next segment out
+   paced_until: Option<time::Instant>,
+   /// why the connection was aborted, for the application to pick up
+   error: Option<io::Error>,
+   /// why we reset the connection, if we did, for the reset log
+   reset_reason: Option<ResetReason>,
+   /// zero-window probes sent since the peer's window closed, for the backoff
+   persist_probes: u32,
+   /// zero-window probes sent since the peer last sent an ACK
+   probes_unanswered: u32,
+   /// since when both ends have advertised a zero window, as far as the probes have seen
+   zero_windows_since: Option<time::Instant>,
+   /// when the peer last sent an acceptable segment, which keepalive counts idle time from
+   last_heard: time::Instant,
+   /// keepalive probes sent since the peer was last heard from
+   keepalives_unanswered: u32,
+}
+
+impl Drop for Connection {
+   fn drop(&mut self) {
+      self.memory.0.fetch_sub(self.charged, Ordering::Relaxed);
+   }
+}
+
+
+//  Send Sequence Space (RFC793 S3.2 F4) 
+//
+//                   1         2          3          4
+//              ----------|----------|----------|----------
+//                     SND.UNA    SND.NXT    SND.UNA
+//                                          +SND.WND
+//
+//        1 - old sequence numbers which have been acknowledged
+//        2 - sequence numbers of unacknowledged data
+//        3 - sequence numbers allowed for new data transmission
+//        4 - future sequence numbers which are not yet allowed
+
+pub struct SendSequenceSpace {
+   /// send unacknowledged
+   una: u32,
+   /// send next
+   nxt: u32,
+   /// send window
+   wnd: u32,
+   /// send urgent pointer: just past the urgent data, until that is acknowledged
+   up: Option<u32>,
+   /// segment sequence number used for last window update
+   wl1: u32,
+   /// segment acknowledgment number used for last window update
+   wl2: u32,
+   /// initial send sequence number
+   iss: u32,
+   /// end of the last less-than-MSS segment sent (Minshall's variant of Nagle)
+   sml: u32,
+}
+
+//  Receive Sequence Space (RFC793 S3.2 F5) 
+//
+//                       1          2          3
+//                 ----------|----------|----------
+//                          RCV.NXT    RCV.NXT
+//                                   +RCV.WND
+//
+//        1 - old sequence numbers which have been acknowledged
+//        2 - sequence numbers allowed for new reception
+//        3 - future sequence numbers which are not yet allowed
+
+pub struct RecvSequenceSpace {
+   /// receive next
+   nxt: u32,
+   /// receive window
+   wnd: u32,
+   /// receive urgent pointer: just past the urgent data, until its last byte arrives
+   up: Option<u32>,
+   /// initial receive sequence number
+   irs: u32
+}
+
+impl Connection {
+   fn write(&mut self, egress: &mut Egress, seq: u32, limit: usize) -> io::Result<usize> {
+      let mut buf = [0u8; MAX_MTU];
+      self.tcp.sequence_number = seq;
+      self.tcp.acknowledgment_number = self.recv.nxt;
+      self.last_ack_sent = self.recv.nxt;
+      // the window field of a SYN is never scaled (RFC 7323 S2.2); otherwise round up, so
+      // scaling never pulls back the right edge. What we take in stops at the window itself,
+      // though, as the receive buffer has no room past it, and doesn't creep out by the
+      // rounding with every segment while the buffer fills
+      let shift = if self.tcp.syn { 0 } else { self.rcv_wscale };
+      let window = self.receive_window();
+      let field = std::cmp::min((window + (1 << shift) - 1) >> shift, u16::MAX as u32);
+      self.recv.wnd = std::cmp::min(window, field << shift);
+      let edge = self.recv.nxt.wrapping_add(self.recv.wnd);
+      if wrapping_lt(self.rcv_edge, edge) {
+         self.rcv_edge = edge;
+      }
+      self.tcp.window_size = field as u16;
+      // every segment before the end of the urgent data points there (RFC 6093 S4)
+      match self.send.up {
+         Some(up) if wrapping_lt(seq, up) => {
+            self.tcp.urg = true;
+            self.tcp.urgent_pointer = std::cmp::min(up.wrapping_sub(seq), u16::MAX as u32) as u16;
+         }
+         _ => {
+            self.tcp.urg = false;
+            self.tcp.urgent_pointer = 0;
+         }
+      }
+      // in a SYN-ACK, ECE agrees to ECN; afterwards it echoes a congestion mark
+      self.tcp.ece = self.ecn_ok && (self.tcp.syn || self.ece_pending);
+      let mut options = OptionWriter::default();
+      if self.tcp.syn {
+         // our MSS: whatever fits the device with option-free headers (RFC 6691)
+         let mss = std::cmp::min(self.mtu - BASE_HEADERS, u16::MAX as usize) as u16;
+         options.push(&TcpOption::Mss(mss));
+         if self.sack_ok {
+            options.push(&TcpOption::SackPermitted);
+         }
+         if self.wscale_ok {
+            options.push(&TcpOption::WindowScale(self.rcv_wscale));
+         }
+      }
+      if self.ts_ok {
+         options.push(&TcpOption::Timestamps { val: self.ts_now(), ecr: self.ts_recent });
+      }
+      if self.tcp.syn {
+         // all of them together come to more than 40 bytes, so whatever no longer fits after
+         // timestamps is left out, and off for the connection since the peer never hears of
+         // it: the Fast Open cookie, which the client can ask for again, and then MPTCP,
+         // which falls back to the plain TCP that a single subflow amounts to anyway
+         if let Some(cookie) = &self.tfo_cookie {
+            if !options.push(&TcpOption::FastOpen(cookie)) {
+               self.tfo_cookie = None;
+            }
+         }
+         if self.mptcp.as_ref().map_or(false, |m| !m.push_syn(&mut options)) {
+            self.mptcp = None;
+         }
+      }
+      let offset = seq.wrapping_sub(self.data_start()) as usize;
+      if !self.tcp.syn {
+         let rcv_off = self.rcv_offset();
+         if let Some(m) = self.mptcp.as_mut() {
+            // map the payload as it will be cut below: the MSS leaves room for this option
+            let len = std::cmp::min(limit, self.unacked.len().saturating_sub(offset));
+            let seq_off = seq.wrapping_sub(self.send.iss).wrapping_sub(1);
+            m.push(&mut options, seq_off, len, self.tcp.fin, rcv_off);
+         }
+      }
+      if !self.tcp.syn && limit == 0 {
+         // a D-SACK block goes first (RFC 2883 S4), then what we hold past a hole; three
+         // blocks still fit next to timestamps, fewer next to MPTCP's. Only on segments
+         // without data, which the blocks would cut short: a retransmission missing its tail
+         // leaves a hole of its own
+         let room = std::cmp::min(3, options.room().saturating_sub(4) / 8);
+         let mut blocks = [0u8; 24];
+         let mut n = 0;
+         if let Some((left, right)) = self.dsack.take().filter(|_| room > 0) {
+            blocks[..8].copy_from_slice(&options::sack_block(left, right));
+            n = 1;
+         }
+         if self.sack_ok {
+            for (left, right) in self.reassembly.sack_blocks().take(room - n) {
+               blocks[n * 8..n * 8 + 8].copy_from_slice(&options::sack_block(left, right));
+               n += 1;
+            }
+         }
+         if n > 0 {
+            options.push(&TcpOption::Sack(SackBlocks::from_raw(&blocks[..n * 8])));
+         }
+      }
+      self.tcp.set_options_raw(options.as_slice()).expect("options fit in the header");
+
+      // pick the payload out of the send queue, which starts at data_start(), and no more
+      // than fits the device
+      let headers = self.ip.header_len() as usize + self.tcp.header_len() as usize;
+      let max_data = std::cmp::min(
+         std::cmp::min(limit, self.unacked.len().saturating_sub(offset)),
+         self.mtu.saturating_sub(headers));
+      self.ip
+         .set_payload_len(self.tcp.header_len() as usize + max_data)
+         .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e)))?;
+      // push the segment that carries the end of what the application has written so far,
+      // so the peer hands it over rather than waiting for more (RFC 1122 S4.2.2.2)
+      self.tcp.psh = max_data > 0 && offset + max_data == self.unacked.len();
+      // only new data is ECN-capable, not control segments or retransmissions (RFC 3168
+      // S6.1.5)
+      let new_data = max_data > 0 && !wrapping_lt(seq, self.send.nxt);
+      self.ip.explicit_congestion_notification = if self.ecn_ok && new_data { ECT0 } else { 0 };
+      // an ECN-setup SYN has both ECE and CWR (RFC 3168 S6.1.1)
+      self.tcp.cwr = (self.cwr_pending && new_data) || (self.tcp.syn && !self.tcp.ack && self.ecn_ok);
+      if self.tcp.cwr && new_data {
+         self.cwr_pending = false;
+      }
+
+      // the payload goes in first, since the tcp checksum has to cover it
+      use std::io::Write;
+      let buf_len = buf.len();
+      let ip_header_ends_at = self.ip.header_len() as usize;
+      let tcp_header_ends_at = ip_header_ends_at + self.tcp.header_len() as usize;
+      let mut unwritten = &mut buf[tcp_header_ends_at..];
+      let mut payload_bytes = 0;
+      for chunk in self.unacked.slices_from(offset) {
+         if payload_bytes == max_data {
+            break;
+         }
+         payload_bytes += unwritten.write(&chunk[..std::cmp::min(max_data - payload_bytes, chunk.len())])?;
+      }
+      let payload_ends_at = buf_len - unwritten.len();
+
+      self.tcp.checksum = self.tcp
+         .calc_checksum_ipv4(&self.ip, &buf[tcp_header_ends_at..payload_ends_at])
+         .expect("failed to compute checksum");
+
+      // write out the headers
+      let mut unwritten = &mut buf[..tcp_header_ends_at];
+      let failed = |e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e));
+      self.ip.write(&mut unwritten).map_err(failed)?;
+      self.tcp.write(&mut unwritten).map_err(failed)?;
+      // every segment carries our latest ACK
+      self.ack_owed = 0;
+      self.timers.cancel(Timer::DelayedAck);
+
+      let sent = InFlight {
+         seq,
+         len: payload_bytes as u32,
+         syn: self.tcp.syn,
+         fin: self.tcp.fin,
+         sent: time::Instant::now(),
+         retransmitted: false,
+         sacked: false,
+         lost: false,
+      };
+      self.tcp.syn = false;
+      self.tcp.fin = false;
+      let next_seq = sent.end();
+      if wrapping_lt(self.send.nxt, next_seq) {
+         // new sequence space, rather than a retransmission or a bare ACK
+         self.send.nxt = next_seq;
+         self.last_send = Some(sent.sent);
+         self.rtx_queue.push_back(sent);
+         if !self.timers.is_armed(Timer::Retransmit) {
+            self.timers.set(Timer::Retransmit, sent.sent + self.rto);
+         }
+         self.arm_probe(sent.sent);
+      }
+      self.tcp.rst = false;
+      self.transmit(egress, &buf[..payload_ends_at])?;
+      Ok(payload_bytes)
+   }
+
+   /// Hand a finished segment to the egress queue, or park it if artificial latency is configured.
+   fn transmit(&mut self, egress: &mut Egress, packet: &[u8]) -> io::Result<()> {
+      match self.latency {
+         None => {
+            egress.push(self.priority, self.flow(), self.state_name(), packet);
+         }
+         Some((delay, jitter)) => {
+            let mut due = time::Instant::now() + delay + self.jitter(jitter);
+            // jitter must not reorder our own segments
+            if let Some(&(last, _)) = self.delayed.back() {
+               if last > due {
+                  due = last;
+               }
+            }
+            let mut buf = egress.buffer();
+            buf.extend_from_slice(packet);
+            self.delayed.push_back((due, buf));
+         }
+      }
+      Ok(())
+   }
+
+   /// Uniformly random duration in `[0, max]`.
+   fn jitter(&mut self, max: time::Duration) -> time::Duration {
+      self.rng ^= self.rng << 13;
+      self.rng ^= self.rng >> 7;
+      self.rng ^= self.rng << 17;
+      let max = max.as_micros() as u64;
+      if max == 0 {
+         return time::Duration::from_secs(0);
+      }
+      time::Duration::from_micros(self.rng % (max + 1))
+   }
+
+   /// Delay (plus up to `jitter` extra) everything this connection transmits, so applications
+   /// can test their timeout handling against a slow network. The delay queue is drained on
+   /// ticks, so the effective resolution is the tick interval. `None` turns it off; segments
+   /// already held back still go out when due.
+   pub fn set_latency(&mut self, latency: Option<(time::Duration, time::Duration)>) {
+      self.latency = latency;
+   }
+
+   /// Send an arbitrary segment on this connection's addresses without touching any of its
+   /// state, for tools that probe how a peer reacts to unexpected input.
+   #[cfg(feature = "tooling")]
+   pub fn inject(
+      &mut self,
+      egress: &mut Egress,
+      flags: &SegmentFlags,
+      seq: u32,
+      ack: u32,
+      payload: &[u8],
+   ) -> io::Result<()> {
+      let mut ip = self.ip.clone();
+      let mut tcp = self.tcp.clone();
+      tcp.sequence_number = seq;
+      tcp.acknowledgment_number = ack;
+      tcp.syn = flags.syn;
+      tcp.ack = flags.ack;
+      tcp.fin = flags.fin;
+      tcp.rst = flags.rst;
+      tcp.psh = flags.psh;
+      tcp.urg = flags.urg;
+
+      let invalid = |e| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e));
+      ip.set_payload_len(tcp.header_len() as usize + payload.len()).map_err(invalid)?;
+      tcp.checksum = tcp.calc_checksum_ipv4(&ip, payload).map_err(invalid)?;
+
+      let mut buf = Vec::with_capacity(ip.header_len() + tcp.header_len() as usize + payload.len());
+      ip.write(&mut buf).expect("writing to a Vec cannot fail");
+      tcp.write(&mut buf).expect("writing to a Vec cannot fail");
+      buf.extend_from_slice(payload);
+      self.transmit(egress, &buf)
+   }
+
+   pub fn set_priority(&mut self, priority: Priority) {
+      self.priority = priority;
+   }
+
+   pub fn set_weight(&mut self, weight: u32) {
+      self.weight = weight;
+   }
+
+   /// Whether a segment at `seq` carrying `len` bytes of data has any of it past the FIN the
+   /// peer already sent.
+   pub fn data_after_fin(&self, seq: u32, len: usize) -> bool {
+      let fin_received = match self.state {
+         State::Closing | State::CloseWait | State::LastAck | State::TimeWait => true,
+         _ => false,
+      };
+      let fin = self.recv.nxt.wrapping_sub(1);
+      fin_received && len > 0 && wrapping_lt(fin, seq.wrapping_add(len as u32))
+   }
+
+   /// Bytes allocated for the send and receive buffers.
+   pub fn buffer_memory(&self) -> usize {
+      self.incoming.capacity() + self.reassembly.memory() + self.unacked.memory()
+   }
+
+   /// Count the connection's buffer memory in `memory` from now on.
+   pub(crate) fn set_memory_meter(&mut self, memory: MemoryMeter) {
+      self.memory.0.fetch_sub(self.charged, Ordering::Relaxed);
+      self.memory = memory;
+      self.charged = 0;
+      self.recharge();
+   }
+
+   /// Bring the meter up to date with what the buffers hold now.
+   fn recharge(&mut self) {
+      let now = self.buffer_memory();
+      if now > self.charged {
+         self.memory.0.fetch_add(now - self.charged, Ordering::Relaxed);
+      } else {
+         self.memory.0.fetch_sub(self.charged - now, Ordering::Relaxed);
+      }
+      self.charged = now;
+   }
+
+   /// Limit the receive window to `bytes` from now on. A window already advertised is
+   /// not taken back, but closes as data fills it.
+   pub fn set_window_clamp(&mut self, bytes: Option<u32>) {
+      self.window_clamp = bytes;
+   }
+
+   /// Turn Nagle's algorithm off, or back on. A small segment it was holding back goes out
+   /// as soon as it is off.
+   pub fn set_nodelay(&mut self, egress: &mut Egress, nodelay: bool) -> io::Result<()> {
+      self.opts.nodelay = nodelay;
+      self.flush(egress)
+   }
+
+   pub fn nodelay(&self) -> bool {
+      self.opts.nodelay
+   }
+
+   /// Probe the peer once it has been quiet for `idle`, or stop probing with `None`.
+   pub fn set_keepalive(&mut self, idle: Option<time::Duration>) {
+      self.opts.keepalive = idle;
+      self.keepalives_unanswered = 0;
+      match idle {
+         Some(idle) => {
+            let at = self.coalesce(self.last_heard + idle);
+            self.timers.set(Timer::Keepalive, at);
+         }
+         None => self.timers.cancel(Timer::Keepalive),
+      }
+   }
+
+   pub fn keepalive(&self) -> Option<time::Duration> {
+      self.opts.keepalive
+   }
+
+   pub fn set_keepalive_interval(&mut self, interval: time::Duration) {
+      self.opts.keepalive_interval = interval;
+   }
+
+   pub fn keepalive_interval(&self) -> time::Duration {
+      self.opts.keepalive_interval
+   }
+
+   pub fn set_keepalive_count(&mut self, count: u32) {
+      self.opts.keepalive_count = count;
+   }
+
+   pub fn keepalive_count(&self) -> u32 {
+      self.opts.keepalive_count
+   }
+
+   /// Switch to another congestion control algorithm, carrying over the window.
+   pub fn set_congestion_control(&mut self, alg: CongestionAlgorithm) {
+      if alg == self.opts.congestion_control {
+         return;
+      }
+      let mss = self.mss();
+      self.cc = cc::switch(alg, &*self.cc, self.fast_recovery, mss);
+      if let Some(rtt) = self.min_rtt {
+         self.cc.on_rtt_sample(rtt);
+      }
+      self.opts.congestion_control = alg;
+   }
+
+   pub fn congestion_control(&self) -> CongestionAlgorithm {
+      self.opts.congestion_control
+   }
+
+   /// The receive window to advertise: the room left in the receive buffer, clamped, but
+   /// never so small that the right edge moves left of one already advertised (RFC 1122
+   /// S4.2.2.16). As the application reads, the edge only moves right in steps of half the
+   /// buffer or a full segment, whichever is less, so the peer isn't invited to send
+   /// slivers of data (receiver-side SWS avoidance, RFC 1122 S4.2.3.3).
+   fn receive_window(&self) -> u32 {
+      // the most the window field can express
+      let max = (u16::MAX as u32) << self.rcv_wscale;
+      let clamp = self.window_clamp.unwrap_or(u32::MAX);
+      let room = self.opts.recv_window.saturating_sub(self.incoming.len() as u32);
+      let target = std::cmp::min(std::cmp::min(room, clamp), max);
+      let promised = self.rcv_edge.wrapping_sub(self.recv.nxt);
+      let step = std::cmp::min(self.opts.recv_window / 2, self.mss() as u32);
+      if promised <= max && (promised > target || target - promised < step) {
+         promised
+      } else {
+         target
+      }
+   }
+
+   /// Our timestamp clock, in milliseconds.
+   fn ts_now(&self) -> u32 {
+      self.ts_offset.wrapping_add(self.ts_base.elapsed().as_millis() as u32)
+   }
+
+   /// Round-trip time from an echoed timestamp, if it is one we could have sent.
+   fn ts_rtt(&self, ecr: u32) -> Option<time::Duration> {
+      let ms = self.ts_now().wrapping_sub(ecr);
+      if ms as u128 > self.ts_base.elapsed().as_millis() {
+         return None;
+      }
+      Some(time::Duration::from_millis(ms as u64))
+   }
+
+   /// The send window a non-SYN segment advertises.
+   fn peer_window(&self, tcph: &etherparse::TcpHeaderSlice) -> u32 {
+      (tcph.window_size() as u32) << self.snd_wscale
+   }
+
+   pub fn set_ttl(&mut self, ttl: u8) {
+      self.ip.time_to_live = ttl;
+   }
+
+   pub fn ttl(&self) -> u8 {
+      self.ip.time_to_live
+   }
+
+   pub fn flow(&self) -> Flow {
+      Flow {
+         id: self.flow,
+         weight: self.weight,
+      }
+   }
+
+   /// Queue as much application data for transmission as the send buffer has room for and
+   /// send whatever the window allows. Returns how much was queued; with the buffer full,
+   /// fails with `WouldBlock`.
+   pub fn enqueue(&mut self, egress: &mut Egress, data: &[u8]) -> io::Result<usize> {
+      let n = self.take(data.len())?;
+      self.unacked.push(&data[..n]);
+      self.recharge();
+      self.flush(egress)?;
+      Ok(n)
+   }
+
+   /// How much of a write of `len` bytes the send buffer takes.
+   fn take(&self, len: usize) -> io::Result<usize> {
+      self.check_writable()?;
+      let n = std::cmp::min(len, self.send_space());
+      if n == 0 && len > 0 {
+         return Err(io::Error::new(io::ErrorKind::WouldBlock, "send buffer is full"));
+      }
+      Ok(n)
+   }
+
+   /// Cut everything queued but not sent yet into segments, whatever the windows say: the
+   /// work a large write costs once they are open. Returns the number of segments.
+   #[cfg(feature = "benchmarks")]
+   pub fn segment_queued(&mut self, egress: &mut Egress) -> io::Result<usize> {
+      let mss = self.mss();
+      let mut segments = 0;
+      while (self.send.nxt.wrapping_sub(self.data_start()) as usize) < self.unacked.len() {
+         let nxt = self.send.nxt;
+         self.write(egress, nxt, mss)?;
+         segments += 1;
+      }
+      Ok(segments)
+   }
+
+   /// Queue urgent data: segments up to its end carry the urgent pointer, and the peer takes
+   /// its last byte out of band. Urgent data queued before and not yet acknowledged is
+   /// folded into this, as only one urgent pointer is ever outstanding.
+   pub fn enqueue_urgent(&mut self, egress: &mut Egress, data: &[u8]) -> io::Result<usize> {
+      // the urgent pointer goes where the part the buffer takes ends
+      let data = &data[..self.take(data.len())?];
+      if !data.is_empty() {
+         let end = self.data_start().wrapping_add((self.unacked.len() + data.len()) as u32);
+         self.send.up = Some(end);
+      }
+      self.enqueue(egress, data)
+   }
+
+   /// Whether enqueue() would take data now.
+   pub fn is_writable(&self) -> bool {
+      self.check_writable().is_ok() && self.send_space() > 0
+   }
+
+   /// Room left in the send buffer.
+   pub fn send_space(&self) -> usize {
+      self.opts.send_buffer.saturating_sub(self.unacked.len())
+   }
+
+   /// Bytes written and not yet acknowledged by the peer.
+   pub fn unacked(&self) -> usize {
+      self.unacked.len()
+   }
+
+   fn check_writable(&self) -> io::Result<()> {
+      if self.write_shut {
+         return Err(io::Error::new(io::ErrorKind::BrokenPipe, "connection is shut down for writing"));
+      }
+      if self.peer_closed {
+         return Err(io::Error::new(io::ErrorKind::BrokenPipe, "peer has closed the connection"));
+      }
+      match self.state {
+         State::SynRcvd | State::Estab | State::CloseWait => Ok(()),
+         _ => Err(io::Error::new(io::ErrorKind::NotConnected, "connection is closing")),
+      }
+   }
+
+   /// Send queued data that has not been sent yet, as far as the peer's window allows.
+   fn flush(&mut self, egress: &mut Egress) -> io::Result<()> {
+      if self.repair {
+         return Ok(());
+      }
+      if let State::SynSent | State::SynRcvd = self.state {
+         // nothing but the SYN may be in flight before the handshake completes
+         return Ok(());
+      }
+      let mss = self.mss();
+      if let Some(burst) = self.opts.restart_burst {
+         let idle = self.last_send.map_or(false, |at| at.elapsed() >= self.rto);
+         if idle && self.send.nxt == self.send.una {
+            // the window is stale; don't dump all of it on the link at once
+            self.burst_left = Some(burst);
+         }
+      }
+      self.validate_cwnd();
+      loop {
+         let unsent = self.unacked.len().saturating_sub(self.send.nxt.wrapping_sub(self.data_start()) as usize);
+         let inflight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+         let cwnd = self.cc.cwnd() + self.limited_transmit();
+         let window = std::cmp::min(self.send.wnd as usize, cwnd).saturating_sub(inflight);
+         let len = std::cmp::min(std::cmp::min(unsent, window), mss);
+         if len == 0 {
+            if unsent > 0 && self.send.wnd == 0 && self.rtx_queue.is_empty() {
+               // nothing in flight will bring an ACK that opens the window
+               self.arm_persist();
+            }
+            return Ok(());
+         }
+         if len < mss && !self.opts.nodelay && !self.may_send_small() {
+            // hold the tail back until the outstanding small segment is acked
+            return Ok(());
+         }
+         let now = time::Instant::now();
+         if let Some(at) = self.paced_until.filter(|&at| at > now) {
+            // the congestion controller's pacing rate doesn't allow another segment yet
+            self.timers.set(Timer::Pace, at);
+            return Ok(());
+         }
+         if self.burst_left == Some(0) {
+            if self.opts.pace_restart && !self.timers.is_armed(Timer::Pace) {
+               self.timers.set(Timer::Pace, time::Instant::now() + self.pace_interval());
+            }
+            return Ok(());
+         }
+         let probe = self.mtu_probe(unsent, window);
+         let len = probe.map_or(len, |(_, len)| len);
+         let seq = self.send.nxt;
+         let n = self.write(egress, seq, len)?;
+         if n < mss {
+            self.send.sml = seq.wrapping_add(n as u32);
+         }
+         if let (Some((size, _)), Some(p)) = (probe, self.plpmtud.as_mut()) {
+            p.sent(size, seq, seq.wrapping_add(n as u32));
+         }
+         if let Some(rate) = self.cc.pacing_rate() {
+            self.paced_until = Some(now + time::Duration::from_secs_f64(n as f64 / rate));
+         }
+         if let Some(left) = self.burst_left.as_mut() {
+            *left -= 1;
+         }
+      }
+   }
+
+   /// The size of the next path MTU probe and the payload that makes it that large, if one
+   /// is due and there is the data and the window to send it: probes carry new data, and go
+   /// out only while nothing is being recovered (RFC 4821 S7.5).
+   fn mtu_probe(&mut self, unsent: usize, window: usize) -> Option<(usize, usize)> {
+      if self.recover.is_some() {
+         return None;
+      }
+      let options = self.option_len();
+      let limit = max_payload(options, &self.opts, self.mtu, self.peer_mss) + BASE_HEADERS + options;
+      let size = self.plpmtud.as_mut()?.next_probe(limit, time::Instant::now())?;
+      let len = size - BASE_HEADERS - options;
+      if unsent < len || window < len {
+         return None;
+      }
+      Some((size, len))
+   }
+
+   /// Decay cwnd if the application has left it unused for longer than an RTO and has data
+   /// again (RFC 7661), rather than sending a full window the path may no longer take.
+   fn validate_cwnd(&mut self) {
+      if self.send.nxt != self.send.una {
+         return;
+      }
+      let unsent = self.unacked.len() > self.send.nxt.wrapping_sub(self.data_start()) as usize;
+      let idle = match self.last_send {
+         Some(at) if unsent => at.elapsed(),
+         _ => return,
+      };
+      if idle > self.rto {
+         let rtos = (idle.as_nanos() / self.rto.as_nanos()) as u32;
+         let mss = self.mss();
+         self.cc.on_idle(rtos, mss);
+         // the decay accounts for the idle time so far
+         self.last_send = Some(time::Instant::now());
+      }
+   }
+
+   /// Room beyond cwnd for new data on the first two duplicate ACKs, one segment each, so
+   /// the ACKs that could trigger a fast retransmit keep coming even when the window is
+   /// small (RFC 3042). cwnd itself is left alone.
+   fn limited_transmit(&self) -> usize {
+      if self.fast_recovery || self.dup_acks > 2 {
+         return 0;
+      }
+      self.dup_acks as usize * self.mss()
+   }
+
+   /// Gap between paced segments, so that a window's worth is spread over one RTT.
+   fn pace_interval(&self) -> time::Duration {
+      let rtt = self.srtt.unwrap_or(self.rto);
+      let segments = std::cmp::max(self.send.wnd as usize / self.mss(), 1);
+      rtt / segments as u32
+   }
+
+   /// Act on timers; called periodically even when no segments arrive.
+   pub fn on_tick(&mut self, egress: &mut Egress) -> io::Result<()> {
+      let now = time::Instant::now();
+      let state = self.state_name();
+      while self.delayed.front().map_or(false, |&(due, _)| due <= now) {
+         let (_, packet) = self.delayed.pop_front().unwrap();
+         egress.push_owned(self.priority, self.flow(), state, packet);
+      }
+
+      if !self.repair && self.timers.expired(Timer::Retransmit, now) {
+         if let Err(wait) = egress.admit_retransmit(now) {
+            // the interface is retransmitting as fast as it may: try again later, at a random
+            // point so the waiting connections don't all come back at once
+            let retry = now + wait + self.jitter(STORM_SPREAD);
+            self.timers.set(Timer::Retransmit, retry);
+         } else if self.retransmits >= self.opts.max_retransmits {
+            if let State::SynSent = self.state {
+               // nothing to reset before the peer has answered
+               self.error = Some(io::Error::new(io::ErrorKind::TimedOut, "connection timed out"));
+               self.handshake_timed_out = true;
+               self.state = State::Closed;
+               self.trace_transition(state);
+               return Ok(());
+            }
+            // the peer is unreachable or gone (RFC 1122 S4.2.3.5)
+            let err = io::Error::new(io::ErrorKind::TimedOut, "retransmission timed out");
+            self.abort_with(egress, ResetReason::RetransmitTimeout, err)?;
+         } else {
+            // the peer may renege on data it has SACKed, so a timeout resends from SND.UNA
+            // regardless (RFC 2018 S8)
+            for seg in self.rtx_queue.iter_mut() {
+               seg.sacked = false;
+               seg.lost = false;
+            }
+            self.tlp_end = None;
+            self.timers.cancel(Timer::Probe);
+            self.timers.cancel(Timer::Reorder);
+            if self.retransmits == 0 {
+               // later timeouts for the same segment leave ssthresh alone (RFC 5681 S3.1)
+               let flight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+               let mss = self.mss();
+               self.cc.on_timeout(flight, mss);
+            }
+            // F-RTO only judges the first timeout; a second one means it wasn't spurious
+            let first = self.retransmits == 0;
+            self.frto = match self.state {
+               State::Estab if self.opts.frto && first => Some((Frto::FirstAck, self.send.nxt)),
+               _ => None,
+            };
+            self.recover = Some(self.send.nxt);
+            self.fast_recovery = false;
+            self.retransmit(egress)?;
+            self.retransmits += 1;
+            // back off (RFC 6298 S5.5); the next RTT sample recomputes it. The deadline gets
+            // up to a quarter on top, so connections that lost their segments to the same
+            // outage drift apart instead of retransmitting in lockstep
+            self.rto = std::cmp::min(self.rto * 2, self.opts.max_rto);
+            let jitter = self.jitter(self.rto / 4);
+            self.timers.set(Timer::Retransmit, now + self.rto + jitter);
+         }
+      }
+
+      if self.timers.expired(Timer::TimeWait, now) {
+         self.state = State::Closed;
+      }
+
+      if self.timers.expired(Timer::DelayedAck, now) {
+         let nxt = self.send.nxt;
+         self.write(egress, nxt, 0)?;
+      }
+
+      if !self.repair && self.timers.expired(Timer::Reorder, now) {
+         self.detect_loss(egress)?;
+      }
+
+      if !self.repair && self.timers.expired(Timer::Probe, now) {
+         self.send_probe(egress)?;
+      }
+
+      if !self.repair && self.timers.expired(Timer::Persist, now) {
+         self.persist(egress, now)?;
+      }
+
+      if !self.repair && self.timers.expired(Timer::Keepalive, now) {
+         self.send_keepalive(egress, now)?;
+      }
+
+      if self.timers.expired(Timer::Pace, now) {
+         if self.burst_left == Some(0) {
+            self.burst_left = Some(1);
+         }
+         self.flush(egress)?;
+      }
+
+      if self.timers.expired(Timer::Handshake, now) {
+         // SYN-ACK retransmissions happen within this budget, the clock starts at the first SYN
+         match self.state {
+            State::SynRcvd => {
+               if self.opts.rst_on_handshake_timeout {
+                  self.reset(egress, ResetReason::HandshakeTimeout)?;
+               }
+               if self.active_open {
+                  // a simultaneous open, whose connect() is still waiting
+                  self.error = Some(io::Error::new(io::ErrorKind::TimedOut, "connection timed out"));
+               }
+               self.handshake_timed_out = true;
+               self.state = State::Closed;
+            }
+            State::SynSent => {
+               self.error = Some(io::Error::new(io::ErrorKind::TimedOut, "connection timed out"));
+               self.handshake_timed_out = true;
+               self.state = State::Closed;
+            }
+            _ => {}
+         }
+      }
+      self.trace_transition(state);
+      Ok(())
+   }
+
+   /// When on_tick() next has something to do, if ever.
+   pub fn next_deadline(&self) -> Option<time::Instant> {
+      let delayed = self.delayed.front().map(|&(due, _)| due);
+      match (delayed, self.timers.next()) {
+         (Some(a), Some(b)) => Some(std::cmp::min(a, b)),
+         (a, b) => a.or(b),
+      }
+   }
+
+   /// Resend the oldest unacknowledged segment the peer hasn't SACKed.
+   fn retransmit(&mut self, egress: &mut Egress) -> io::Result<()> {
+      match self.rtx_queue.iter().position(|seg| !seg.sacked) {
+         Some(i) => self.resend(egress, i),
+         None => Ok(()),
+      }
+   }
+
+   /// Send the `i`th segment of the retransmission queue again.
+   fn resend(&mut self, egress: &mut Egress, i: usize) -> io::Result<()> {
+      if let Some(p) = self.plpmtud.as_mut() {
+         if p.probe_seq() == Some(self.rtx_queue[i].seq) {
+            p.on_loss(time::Instant::now());
+            trace!(self, "path MTU probe lost");
+         }
+      }
+      // a lost probe is too large for the path: resend it in segments that fit
+      let mss = self.mss() as u32;
+      while self.rtx_queue[i].len > mss {
+         let seg = self.rtx_queue[i];
+         let rest = InFlight { seq: seg.seq.wrapping_add(mss), len: seg.len - mss, syn: false, ..seg };
+         self.rtx_queue[i].len = mss;
+         self.rtx_queue[i].fin = false;
+         self.rtx_queue.insert(i + 1, rest);
+      }
+      let seg = &mut self.rtx_queue[i];
+      seg.retransmitted = true;
+      seg.lost = false;
+      seg.sent = time::Instant::now();
+      let seg = *seg;
+      trace!(self, "retransmitting seq={} len={} rto={:?}", seg.seq, seg.len, self.rto);
+      self.tcp.syn = seg.syn;
+      self.tcp.fin = seg.fin;
+      self.write(egress, seg.seq, seg.len as usize)?;
+      Ok(())
+   }
+
+   /// Enter TIME-WAIT, or restart its 2MSL timer if already there.
+   fn enter_time_wait(&mut self) {
+      self.state = State::TimeWait;
+      let expiry = self.coalesce(time::Instant::now() + self.opts.msl * 2);
+      self.timers.set(Timer::TimeWait, expiry);
+   }
+
+   /// Whether a SYN for this connection's quad may replace it: only out of TIME-WAIT, and only
+   /// with a sequence number past anything the old incarnation used, so no old duplicate can
+   /// be mistaken for new data.
+   pub fn reusable_for(&self, tcph: &etherparse::TcpHeaderSlice) -> bool {
+      if let State::TimeWait = self.state {
+         self.opts.time_wait_reuse
+            && tcph.syn()
+            && !tcph.ack()
+            && wrapping_lt(self.recv.nxt, tcph.sequence_number())
+      } else {
+         false
+      }
+   }
+
+   /// Acknowledge a data segment now, or arm the delayed-ACK timer so that a burst of
+   /// segments gets one cumulative ACK per two segments rather than one each.
+   fn ack_data(&mut self, egress: &mut Egress) -> io::Result<()> {
+      self.ack_owed += 1;
+      match self.opts.delayed_ack {
+         Some(delay) if self.ack_owed < 2 => {
+            if !self.timers.is_armed(Timer::DelayedAck) {
+               self.timers.set(Timer::DelayedAck, time::Instant::now() + delay);
+            }
+         }
+         _ => {
+            let nxt = self.send.nxt;
+            self.write(egress, nxt, 0)?;
+         }
+      }
+      Ok(())
+   }
+
+   /// Forget segments that `ackn` covers, trimming one that is only partly acknowledged, and
+   /// restart the retransmission timer for whatever is left (RFC 6298 S5.3).
+   fn on_acked(&mut self, ackn: u32, ts_rtt: Option<time::Duration>) {
+      let now = time::Instant::now();
+      let mut sample = None;
+      while let Some(seg) = self.rtx_queue.front_mut() {
+         if !wrapping_lt(ackn, seg.end()) {
+            if !seg.retransmitted {
+               sample = Some(now.saturating_duration_since(seg.sent));
+            }
+            let seg = self.rtx_queue.pop_front().unwrap();
+            if !seg.sacked {
+               self.rack_delivered(&seg, now);
+            }
+            continue;
+         }
+         if !seg.syn && wrapping_lt(seg.seq, ackn) {
+            let acked = std::cmp::min(ackn.wrapping_sub(seg.seq), seg.len);
+            seg.seq = seg.seq.wrapping_add(acked);
+            seg.len -= acked;
+         }
+         break;
+      }
+      if self.plpmtud.as_mut().map_or(false, |p| p.on_ack(ackn)) {
+         trace!(self, "path takes {} byte packets", self.path_mtu());
+      }
+      self.retransmits = 0;
+      // the ACK clock is running again
+      self.burst_left = None;
+      self.timers.cancel(Timer::Pace);
+      // the timing of a retransmitted segment is ambiguous (Karn's algorithm), but the
+      // timestamp echoed says which transmission the ACK answers (RFC 7323 S4.1)
+      if let Some(rtt) = sample.or(ts_rtt) {
+         self.on_rtt_sample(rtt);
+      }
+      if let Some(end) = self.tlp_end {
+         if !wrapping_lt(ackn, end) {
+            self.tlp_end = None;
+         }
+      }
+      if self.rtx_queue.is_empty() {
+         self.timers.cancel(Timer::Retransmit);
+         self.timers.cancel(Timer::Probe);
+         self.timers.cancel(Timer::Reorder);
+      } else {
+         self.timers.set(Timer::Retransmit, now + self.rto);
+         self.arm_probe(now);
+      }
+   }
+
+   /// RACK's view of delivery: remember the most recently sent segment that has arrived,
+   /// since anything sent before it and still unacknowledged after a reordering window is
+   /// lost (RFC 8985 S6.1).
+   fn rack_delivered(&mut self, seg: &InFlight, now: time::Instant) {
+      let rtt = now.saturating_duration_since(seg.sent);
+      if seg.retransmitted && self.min_rtt.map_or(false, |min| rtt < min) {
+         // acknowledges the original transmission, not the retransmission just sent
+         return;
+      }
+      let newer = match self.rack_xmit {
+         Some((at, end)) => seg.sent > at || (seg.sent == at && wrapping_lt(end, seg.end())),
+         None => true,
+      };
+      if newer {
+         self.rack_xmit = Some((seg.sent, seg.end()));
+         self.rack_rtt = rtt;
+      }
+   }
+
+   /// Declare lost every segment sent a reordering window before the most recent delivered
+   /// one, resend them, and arm the reorder timer for the ones that may still turn up
+   /// (RFC 8985 S6.2).
+   fn detect_loss(&mut self, egress: &mut Egress) -> io::Result<()> {
+      let (xmit, xmit_end) = match self.rack_xmit {
+         Some(x) => x,
+         None => return Ok(()),
+      };
+      let now = time::Instant::now();
+      let reo_wnd = self.min_rtt.map_or(time::Duration::from_secs(0), |min| min / 4);
+      let mut recheck: Option<time::Instant> = None;
+      let mut any_lost = false;
+      let mut probe_lost = false;
+      let probe = self.plpmtud.as_ref().and_then(Plpmtud::probe_seq);
+      for seg in self.rtx_queue.iter_mut().filter(|seg| !seg.sacked && !seg.lost) {
+         let sent_before = seg.sent < xmit || (seg.sent == xmit && wrapping_lt(seg.end(), xmit_end));
+         if !sent_before {
+            continue;
+         }
+         let deadline = seg.sent + self.rack_rtt + reo_wnd;
+         if deadline <= now {
+            seg.lost = true;
+            if probe == Some(seg.seq) {
+               probe_lost = true;
+            } else {
+               any_lost = true;
+            }
+         } else {
+            recheck = Some(recheck.map_or(deadline, |r| std::cmp::min(r, deadline)));
+         }
+      }
+      match recheck {
+         Some(at) => self.timers.set(Timer::Reorder, at),
+         None => self.timers.cancel(Timer::Reorder),
+      }
+      if !any_lost && !probe_lost {
+         return Ok(());
+      }
+      // a lost MTU probe alone is no sign of congestion (RFC 4821 S7.6.2)
+      if any_lost && !self.fast_recovery && self.recover.is_none() {
+         self.enter_recovery();
+      }
+      while let Some(i) = self.rtx_queue.iter().position(|seg| seg.lost) {
+         self.resend(egress, i)?;
+      }
+      Ok(())
+   }
+
+   /// F-RTO (RFC 5682 S2.1): after a timeout, the first ACK that advances SND.UNA without
+   /// covering everything sent before the timeout lets two new segments out. If the next ACK
+   /// advances SND.UNA as well, it acknowledges data that was never retransmitted, so the
+   /// original transmissions had arrived and the timeout was spurious. Anything else, and
+   /// recovery carries on as for a genuine timeout.
+   fn frto_ack(&mut self, egress: &mut Egress, ackn: u32, advanced: bool) -> io::Result<()> {
+      let (stage, recover) = match self.frto.take() {
+         Some(frto) => frto,
+         None => return Ok(()),
+      };
+      match stage {
+         Frto::FirstAck => {
+            if !advanced || !wrapping_lt(ackn, recover) {
+               return Ok(());
+            }
+            let mut sent = 0;
+            for _ in 0..2 {
+               sent += self.send_new(egress)?;
+            }
+            if sent > 0 {
+               self.frto = Some((Frto::SecondAck, recover));
+            }
+         }
+         Frto::SecondAck => {
+            if advanced {
+               trace!(self, "spurious retransmission timeout, restoring cwnd");
+               self.cc.undo_timeout();
+               self.recover = None;
+            }
+         }
+      }
+      Ok(())
+   }
+
+   /// Loss detected without a timeout: cut the window and repair holes as ACKs arrive,
+   /// until everything outstanding now has been acknowledged.
+   fn enter_recovery(&mut self) {
+      let flight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+      let mss = self.mss();
+      self.recover = Some(self.send.nxt);
+      self.fast_recovery = true;
+      self.timers.cancel(Timer::Probe);
+      self.cc.on_fast_retransmit(flight, mss);
+   }
+
+   /// An acceptable ACK may echo a congestion mark: cut the window as for a loss, unless it
+   /// was cut in this round trip already, and tell the peer with CWR.
+   fn on_ece(&mut self, tcph: &etherparse::TcpHeaderSlice, ackn: u32) {
+      if let Some(end) = self.ecn_recover {
+         if !wrapping_lt(ackn, end) {
+            self.ecn_recover = None;
+         }
+      }
+      if !self.ecn_ok || !tcph.ece() || self.ecn_recover.is_some() || self.fast_recovery {
+         return;
+      }
+      let flight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+      let mss = self.mss();
+      self.cc.on_ecn(flight, mss);
+      self.ecn_recover = Some(self.send.nxt);
+      self.cwr_pending = true;
+   }
+
+   /// Arm the tail loss probe timer: if the ACKs stop coming while data is outstanding,
+   /// losses at the end of a flight would otherwise wait for the RTO, as there is nothing
+   /// after them to trigger a fast retransmit (RFC 8985 S7.2).
+   fn arm_probe(&mut self, now: time::Instant) {
+      if !self.sack_ok || self.fast_recovery || self.tlp_end.is_some() || self.rtx_queue.is_empty() {
+         return;
+      }
+      let pto = match self.srtt {
+         Some(srtt) => {
+            let flight = self.send.nxt.wrapping_sub(self.send.una) as usize;
+            if flight <= self.mss() {
+               // a lone segment's ACK may be delayed
+               srtt * 2 + time::Duration::from_millis(200)
+            } else {
+               srtt * 2
+            }
+         }
+         None => time::Duration::from_secs(1),
+      };
+      self.timers.set(Timer::Probe, now + std::cmp::min(pto, self.rto));
+   }
+
+   /// Arm the persist timer, unless it is running already (RFC 1122 S4.2.2.17). The first
+   /// probe waits an RTO and every one after doubles that, up to the largest RTO.
+   fn arm_persist(&mut self) {
+      if self.timers.is_armed(Timer::Persist) {
+         return;
+      }
+      let backoff = self.rto * 2u32.saturating_pow(std::cmp::min(self.persist_probes, 16));
+      let at = time::Instant::now() + std::cmp::min(backoff, self.opts.max_rto);
+      self.timers.set(Timer::Persist, at);
+   }
+
+   /// The peer's window is still closed: probe it, unless the connection looks stuck for
+   /// good, in which case it is aborted rather than holding its buffers forever.
+   fn persist(&mut self, egress: &mut Egress, now: time::Instant) -> io::Result<()> {
+      let unsent = self.unacked.len() > self.send.nxt.wrapping_sub(self.data_start()) as usize;
+      if self.send.wnd != 0 || !unsent || !self.rtx_queue.is_empty() {
+         self.persist_probes = 0;
+         return Ok(());
+      }
+      if self.opts.max_persist_probes.map_or(false, |max| self.probes_unanswered >= max) {
+         let err = io::Error::new(io::ErrorKind::TimedOut, "zero window probes unanswered");
+         return self.abort_with(egress, ResetReason::PersistTimeout, err);
+      }
+      if self.receive_window() == 0 {
+         let since = *self.zero_windows_since.get_or_insert(now);
+         if self.opts.zero_window_timeout.map_or(false, |limit| now - since >= limit) {
+            let err = io::Error::new(io::ErrorKind::TimedOut, "both windows closed, deadlocked");
+            return self.abort_with(egress, ResetReason::ZeroWindowDeadlock, err);
+         }
+      } else {
+         self.zero_windows_since = None;
+      }
+      // a segment just below SND.UNA is outside the peer's window, so it answers with an
+      // ACK carrying the window it has now; unlike a byte of new data, this doesn't
+      // advance SND.NXT past what the window allows
+      trace!(self, "zero window probe #{}", self.persist_probes + 1);
+      let probe = self.send.una.wrapping_sub(1);
+      self.write(egress, probe, 0)?;
+      self.persist_probes += 1;
+      self.probes_unanswered += 1;
+      self.arm_persist();
+      Ok(())
+   }
+
+   /// The peer has been quiet for the keepalive time, or hasn't answered the last probe:
+   /// send another (RFC 1122 S4.2.3.6), or give up on it once enough have gone unanswered.
+   fn send_keepalive(&mut self, egress: &mut Egress, now: time::Instant) -> io::Result<()> {
+      let idle = match self.opts.keepalive {
+         Some(idle) => idle,
+         None => return Ok(()),
+      };
+      match self.state {
+         State::Estab | State::CloseWait => {}
+         // nothing to keep alive yet
+         State::SynSent | State::SynRcvd => {
+            self.timers.set(Timer::Keepalive, self.coalesce(now + idle));
+            return Ok(());
+         }
+         _ => return Ok(()),
+      }
+      let quiet = now.saturating_duration_since(self.last_heard);
+      if quiet < idle {
+         // heard from since the timer was set
+         self.keepalives_unanswered = 0;
+         self.timers.set(Timer::Keepalive, self.coalesce(self.last_heard + idle));
+         return Ok(());
+      }
+      let unsent = self.unacked.len() > self.send.nxt.wrapping_sub(self.data_start()) as usize;
+      if !self.rtx_queue.is_empty() || unsent {
+         // retransmissions and window probes already find out whether the peer is there
+         self.timers.set(Timer::Keepalive, self.coalesce(now + idle));
+         return Ok(());
+      }
+      if self.keepalives_unanswered >= self.opts.keepalive_count {
+         let err = io::Error::new(io::ErrorKind::TimedOut, "keepalive probes unanswered");
+         return self.abort_with(egress, ResetReason::KeepaliveTimeout, err);
+      }
+      // a segment just below SND.NXT carries nothing new, but is outside the peer's window,
+      // so it answers with an ACK
+      trace!(self, "keepalive probe #{}", self.keepalives_unanswered + 1);
+      let probe = self.send.nxt.wrapping_sub(1);
+      self.write(egress, probe, 0)?;
+      self.keepalives_unanswered += 1;
+      self.timers.set(Timer::Keepalive, self.coalesce(now + self.opts.keepalive_interval));
+      Ok(())
+   }
+
+   /// Send one segment of new data if there is any and the peer's window has room, however
+   /// much cwnd allows. Returns the bytes sent.
+   fn send_new(&mut self, egress: &mut Egress) -> io::Result<usize> {
+      let unsent = self.unacked.len().saturating_sub(self.send.nxt.wrapping_sub(self.data_start()) as usize);
+      let room = (self.send.wnd as usize).saturating_sub(self.send.nxt.wrapping_sub(self.send.una) as usize);
+      let len = std::cmp::min(std::cmp::min(unsent, room), self.mss());
+      if len == 0 {
+         return Ok(0);
+      }
+      let nxt = self.send.nxt;
+      self.write(egress, nxt, len)
+   }
+
+   /// Probe for a lost tail: send one new segment if there is one, or else the last one
+   /// again, so its ACK (or SACK) lets RACK or fast recovery repair the loss.
+   fn send_probe(&mut self, egress: &mut Egress) -> io::Result<()> {
+      if self.fast_recovery || self.rtx_queue.is_empty() {
+         return Ok(());
+      }
+      // set first, so the probe itself doesn't arm another
+      self.tlp_end = Some(self.send.nxt);
+      if self.send_new(egress)? == 0 {
+         let last = self.rtx_queue.len() - 1;
+         self.resend(egress, last)?;
+      }
+      self.tlp_end = Some(self.send.nxt);
+      trace!(self, "tail loss probe, ack expected up to {}", self.send.nxt);
+      self.timers.set(Timer::Retransmit, time::Instant::now() + self.rto);
+      Ok(())
+   }
+
+   /// Mark segments covered by the SACK blocks of an incoming ACK, so that retransmissions
+   /// skip them.
+   fn on_sack(&mut self, options: &[u8]) {
+      let blocks = match options::sack(options) {
+         Some(blocks) => blocks,
+         None => return,
+      };
+      let now = time::Instant::now();
+      for (left, right) in blocks {
+         for i in 0..self.rtx_queue.len() {
+            let seg = self.rtx_queue[i];
+            if !seg.sacked && !wrapping_lt(seg.seq, left) && !wrapping_lt(right, seg.end()) {
+               self.rtx_queue[i].sacked = true;
+               self.rack_delivered(&seg, now);
+            }
+         }
+      }
+   }
+
+   /// Fold a round-trip measurement into SRTT/RTTVAR and recompute the RTO (RFC 6298 S2).
+   fn on_rtt_sample(&mut self, rtt: time::Duration) {
+      match self.srtt {
+         None => {
+            self.srtt = Some(rtt);
+            self.rttvar = rtt / 2;
+         }
+         Some(srtt) => {
+            // RTTVAR <- 3/4 * RTTVAR + 1/4 * |SRTT - R'|, SRTT <- 7/8 * SRTT + 1/8 * R'
+            let err = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+            self.rttvar = self.rttvar * 3 / 4 + err / 4;
+            self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+         }
+      }
+      self.cc.on_rtt_sample(rtt);
+      // a timestamp sample of zero only says the ACK came back within the same millisecond;
+      // as the minimum, it would let RACK take the ACK of an original transmission for that
+      // of the retransmission just sent, and declare everything sent before it lost again
+      if rtt > time::Duration::from_secs(0) {
+         self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| std::cmp::min(min, rtt)));
+      }
+      let rto = self.srtt.unwrap() + self.rttvar * 4;
+      self.rto = std::cmp::min(std::cmp::max(rto, MIN_RTO), self.opts.max_rto);
+   }
+
+   fn handshake_deadline(&self) -> time::Instant {
+      self.coalesce(self.handshake.first_syn + self.opts.handshake_timeout)
+   }
+
+   /// Push a low-priority deadline out to the next multiple of the timer granularity. The
+   /// boundaries are counted from the Unix epoch so that all connections share them.
+   fn coalesce(&self, at: time::Instant) -> time::Instant {
+      let gran = match self.opts.timer_granularity {
+         Some(g) if g.as_micros() > 0 => g.as_micros(),
+         _ => return at,
+      };
+      let now = time::Instant::now();
+      let since_epoch = time::SystemTime::now()
+         .duration_since(time::UNIX_EPOCH)
+         .unwrap_or_default()
+         + at.saturating_duration_since(now);
+      let micros = since_epoch.as_micros();
+      let slack = (gran - micros % gran) % gran;
+      at + time::Duration::from_micros(slack as u64)
+   }
+
+   /// Send an RST for this connection and close it.
+   pub(crate) fn reset(&mut self, egress: &mut Egress, why: ResetReason) -> io::Result<()> {
+      trace!(self, "resetting: {}", why.describe());
+      self.reset_reason = Some(why);
+      if self.opts.rst_diagnostics {
+         let (nxt, ack) = (self.send.nxt, self.recv.nxt);
+         self.send_reset(egress, nxt, Some(ack), why.describe().as_bytes())?;
+      } else {
+         self.tcp.rst = true;
+         let nxt = self.send.nxt;
+         self.write(egress, nxt, 0)?;
+      }
+      self.state = State::Closed;
+      self.rtx_queue.clear();
+      self.timers.cancel(Timer::Retransmit);
+      Ok(())
+   }
+
+   /// Send an RST at `seq`, acknowledging `ack` if given and carrying `payload`. Built on its
+   /// own rather than by write(), which takes sequence numbers and data from the send queue.
+   fn send_reset(&mut self, egress: &mut Egress, seq: u32, ack: Option<u32>, payload: &[u8]) -> io::Result<()> {
+      let mut ip = self.ip.clone();
+      let mut tcp = self.tcp.clone();
+      tcp.sequence_number = seq;
+      tcp.acknowledgment_number = ack.unwrap_or(0);
+      tcp.ack = ack.is_some();
+      tcp.rst = true;
+      tcp.syn = false;
+      tcp.fin = false;
+      tcp.psh = false;
+      tcp.urg = false;
+      tcp.ece = false;
+      tcp.cwr = false;
+      tcp.set_options_raw(&[]).expect("no options always fit");
+      ip.set_payload_len(tcp.header_len() as usize + payload.len()).expect("the payload fits a segment");
+      ip.explicit_congestion_notification = 0;
+      tcp.checksum = tcp.calc_checksum_ipv4(&ip, payload).expect("failed to compute checksum");
+      let mut buf = Vec::with_capacity(ip.header_len() + tcp.header_len() as usize + payload.len());
+      ip.write(&mut buf).expect("writing to a Vec cannot fail");
+      tcp.write(&mut buf).expect("writing to a Vec cannot fail");
+      buf.extend_from_slice(payload);
+      self.transmit(egress, &buf)
+   }
+
+   /// Send an RST and close the connection, leaving `err` for the application to pick up.
+   pub(crate) fn abort_with(&mut self, egress: &mut Egress, why: ResetReason, err: io::Error) -> io::Result<()> {
+      self.error = Some(err);
+      self.reset(egress, why)
+   }
+
+   /// Close the connection at once, like close() with SO_LINGER set to zero: everything
+   /// queued or unread is discarded, and a peer that could still be expecting something from
+   /// us gets an RST (RFC 793 S3.9, ABORT call). One that never saw our SYN, or has already
+   /// sent its FIN and seen ours, is simply forgotten.
+   pub fn abort(&mut self, egress: &mut Egress) -> io::Result<()> {
+      match self.state {
+         State::SynRcvd | State::Estab | State::FinWait1 | State::FinWait2 | State::CloseWait => {
+            self.reset(egress, ResetReason::Aborted)?;
+         }
+         _ => {
+            self.state = State::Closed;
+            self.rtx_queue.clear();
+         }
+      }
+      self.unacked.clear();
+      self.incoming.clear();
+      self.arrivals.clear();
+      self.timers = Timers::default();
+      self.recharge();
+      Ok(())
+   }
+
+   /// Why we reset the connection, if we did.
+   pub fn reset_reason(&self) -> Option<ResetReason> {
+      self.reset_reason
+   }
+
+   pub fn info(&self) -> ConnectionInfo {
+      ConnectionInfo {
+         local: SocketAddrV4::new(Ipv4Addr::from(self.ip.source), self.tcp.source_port),
+         remote: SocketAddrV4::new(Ipv4Addr::from(self.ip.destination), self.tcp.destination_port),
+         state: self.state_name(),
+         delivered: self.delivered,
+         unacked: self.unacked.len(),
+         unread: self.incoming.len(),
+         srtt: self.srtt,
+         rto: self.rto,
+         retransmits: self.retransmits,
+         cwnd: self.cc.cwnd(),
+         ssthresh: self.cc.ssthresh(),
+         path_mtu: self.path_mtu(),
+         send_goodput: self.send_goodput.rate(time::Instant::now()),
+         recv_goodput: self.recv_goodput.rate(time::Instant::now()),
+      }
+   }
+
+   pub fn handshake_timed_out(&self) -> bool {
+      self.handshake_timed_out
+   }
+
+   /// Whether the connection ended in an RST, ours or the peer's, or timed out, rather than
+   /// closing in order.
+   pub fn failed(&self) -> bool {
+      self.error.is_some() || self.reset_reason.is_some()
+   }
+
+   /// Why the connection was aborted, if it was.
+   pub fn take_error(&mut self) -> Option<io::Error> {
+      self.error.take()
+   }
+
+   /// Turn verbose tracing of this connection on or off.
+   pub fn set_verbose(&mut self, on: bool) {
+      self.verbose = on;
+   }
+
+   fn log(&self, args: std::fmt::Arguments) {
+      eprintln!(
+         "[{}:{} <-> {}:{}] {}",
+         Ipv4Addr::from(self.ip.source),
+         self.tcp.source_port,
+         Ipv4Addr::from(self.ip.destination),
+         self.tcp.destination_port,
+         args
+      );
+   }
+
+   fn trace_transition(&self, before: &'static str) {
+      let after = self.state_name();
+      if before != after {
+         trace!(self, "{} -> {}", before, after);
+      }
+   }
+
+   /// Name of the current state, as used in captures.
+   pub fn state_name(&self) -> &'static str {
+      match self.state {
+         State::Closed => "CLOSED",
+         State::SynSent => "SYN-SENT",
+         State::SynRcvd => "SYN-RECEIVED",
+         State::Estab => "ESTABLISHED",
+         State::FinWait1 => "FIN-WAIT-1",
+         State::FinWait2 => "FIN-WAIT-2",
+         State::Closing => "CLOSING",
+         State::CloseWait => "CLOSE-WAIT",
+         State::LastAck => "LAST-ACK",
+         State::TimeWait => "TIME-WAIT",
+      }
+   }
+
+   /// Whether an active open is still waiting for the peer's SYN-ACK.
+   pub fn is_connecting(&self) -> bool {
+      if let State::SynSent = self.state {
+         true
+      } else {
+         false
+      }
+   }
+
+   /// Shut down one or both sides, like shutdown(2). Shutting down the sending side means
+   /// nothing more can be written, and our FIN goes out once everything already queued has
+   /// been sent; reads carry on until the peer closes its side. Shutting down the receiving
+   /// side discards what is buffered, and reads return end of file from then on. Shutting
+   /// down a side again, or once the connection is closing, does nothing.
+   pub fn shutdown(&mut self, egress: &mut Egress, how: Shutdown) -> io::Result<()> {
+      if let Shutdown::Read | Shutdown::Both = how {
+         self.read_shut = true;
+         self.incoming.clear();
+         self.arrivals.clear();
+         if let Some(marks) = &mut self.psh_marks {
+            marks.clear();
+         }
+         self.orphan_timeout();
+      }
+      if let Shutdown::Read = how {
+         return Ok(());
+      }
+      match self.state {
+         State::SynSent => {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "connection is not established yet"))
+         }
+         State::SynRcvd | State::Estab | State::CloseWait => {}
+         _ => return Ok(()),
+      }
+      self.write_shut = true;
+      self.fin_when_sent(egress)
+   }
+
+   /// In FIN-WAIT-2 with nobody reading, only the peer's FIN would close the connection, and
+   /// it may never come: give up after 2MSL, like TIME-WAIT, rather than hold the connection
+   /// forever.
+   fn orphan_timeout(&mut self) {
+      if let State::FinWait2 = self.state {
+         if self.read_shut && !self.timers.is_armed(Timer::TimeWait) {
+            let expiry = self.coalesce(time::Instant::now() + self.opts.msl * 2);
+            self.timers.set(Timer::TimeWait, expiry);
+         }
+      }
+   }
+
+   /// Send our FIN once we've been shut down and everything queued has been sent: from
+   /// ESTABLISHED on to FIN-WAIT-1, from CLOSE-WAIT on to LAST-ACK.
+   fn fin_when_sent(&mut self, egress: &mut Egress) -> io::Result<()> {
+      if !self.write_shut {
+         return Ok(());
+      }
+      let next = match self.state {
+         State::Estab => State::FinWait1,
+         State::CloseWait => State::LastAck,
+         _ => return Ok(()),
+      };
+      if self.send.nxt.wrapping_sub(self.data_start()) as usize == self.unacked.len() {
+         self.tcp.fin = true;
+         let nxt = self.send.nxt;
+         self.write(egress, nxt, 0)?;
+         self.state = next;
+      }
+      Ok(())
+   }
+
+   /// Answer a suspicious segment with an ACK of where we are, which only a peer that sees
+   /// our traffic can act on (RFC 5961). Limited per connection rather than interface-wide
+   /// as S7 suggests, since a shared limit tells an attacker when it has guessed a live
+   /// connection (CVE-2016-5696).
+   fn challenge_ack(&mut self, egress: &mut Egress) -> io::Result<()> {
+      let now = time::Instant::now();
+      if now.duration_since(self.challenge_since) >= time::Duration::from_secs(1) {
+         self.challenge_since = now;
+         self.challenge_acks = 0;
+      }
+      if self.challenge_acks < CHALLENGE_ACK_LIMIT {
+         self.challenge_acks += 1;
+         let nxt = self.send.nxt;
+         self.write(egress, nxt, 0)?;
+      }
+      Ok(())
+   }
+
+   /// Whether the peer has acknowledged our FIN, which is the last thing we send.
+   fn fin_acked(&self) -> bool {
+      self.send.una == self.send.nxt
+   }
+
+   /// Whether a passive open is still waiting for the ACK of its SYN-ACK.
+   pub fn is_syn_received(&self) -> bool {
+      if let State::SynRcvd = self.state {
+         true
+      } else {
+         false
+      }
+   }
+
+   pub fn is_established(&self) -> bool {
+      if let State::Estab = self.state {
+         true
+      } else {
+         false
+      }
+   }
+
+   /// Make reads stop at PSH boundaries, so the data of each pushed segment run can be told
+   /// apart. Boundaries of data already received are not known.
+   pub fn set_record_markers(&mut self, on: bool) {
+      self.psh_marks = if on { Some(VecDeque::new()) } else { None };
+   }
+
+   /// Don't let reads return until `bytes` are buffered, or the peer has closed its side.
+   /// Capped at the receive window, since the peer may stop sending before it fills.
+   pub fn set_recv_lowat(&mut self, bytes: usize) {
+      self.recv_lowat = std::cmp::min(std::cmp::max(bytes, 1), self.opts.recv_window as usize);
+   }
+
+   /// Whether a read would return data now.
+   pub fn has_readable(&self) -> bool {
+      !self.incoming.is_empty() && (self.incoming.len() >= self.recv_lowat || self.is_recv_closed())
+   }
+
+   /// Whether data is buffered but short of the low watermark, with more to come, so
+   /// nobody needs waking for it.
+   pub fn below_recv_lowat(&self) -> bool {
+      !self.incoming.is_empty() && !self.has_readable()
+   }
+
+   /// Take the out-of-band byte, if one has arrived since the last call.
+   pub fn take_urgent(&mut self) -> Option<u8> {
+      self.urgent.take()
+   }
+
+   /// When the most recent in-order data arrived, read or not.
+   pub fn last_data_instant(&self) -> Option<time::Instant> {
+      self.last_arrival
+   }
+
+   /// Hand the application the in-order data starting at `from`, which is RCV.NXT, taking out
+   /// the urgent byte if it's among it, and mark the end of the data if it was pushed.
+   fn take_data(&mut self, from: u32, data: &[u8], psh: bool, now: time::Instant) {
+      self.recv.nxt = from.wrapping_add(data.len() as u32);
+      match self.recv.up.map(|up| up.wrapping_sub(1).wrapping_sub(from) as usize) {
+         Some(at) if at < data.len() => {
+            // the last urgent byte is taken out of the stream
+            self.deliver(&data[..at], now);
+            self.urgent = Some(data[at]);
+            self.recv.up = None;
+            self.deliver(&data[at + 1..], now);
+         }
+         _ => self.deliver(data, now),
+      }
+      if psh {
+         if let Some(marks) = &mut self.psh_marks {
+            marks.push_back(self.received);
+         }
+      }
+   }
+
+   /// Append in-order data from a segment that arrived at `now` for the application.
+   fn deliver(&mut self, data: &[u8], now: time::Instant) {
+      if data.is_empty() || self.read_shut {
+         return;
+      }
+      self.incoming.extend(data);
+      self.received += data.len() as u64;
+      self.recv_goodput.record(data.len() as u64, now);
+      if self.arrivals.len() == self.arrivals.capacity() {
+         // rather than grow on the data path, the latest run takes this one in
+         let last = self.arrivals.back_mut().expect("capacity is never zero");
+         *last = (self.received, now);
+      } else {
+         self.arrivals.push_back((self.received, now));
       }
+      self.last_arrival = Some(now);
    }
-}
 
-pub struct Connection {
-   state: State,
-   send: SendSequenceSpace,
-   recv: RecvSequenceSpace,
-   ip: etherparse::Ipv4Header,
-   tcp: etherparse::TcpHeader,
-}
+   /// Move received data into `buf`, stopping at the next PSH boundary if record markers
+   /// are on. Returns the bytes read, whether they end at a boundary, and when the segment
+   /// that completed them arrived.
+   pub fn read_incoming(&mut self, buf: &mut [u8]) -> (usize, bool, Option<time::Instant>) {
+      let front = self.received - self.incoming.len() as u64;
+      let mut want = std::cmp::min(buf.len(), self.incoming.len());
+      if let Some(&mark) = self.psh_marks.as_ref().and_then(|m| m.front()) {
+         want = std::cmp::min(want, (mark - front) as usize);
+      }
+      let (head, tail) = self.incoming.as_slices();
+      let hread = std::cmp::min(want, head.len());
+      buf[..hread].copy_from_slice(&head[..hread]);
+      let tread = want - hread;
+      buf[hread..want].copy_from_slice(&tail[..tread]);
+      drop(self.incoming.drain(..want));
 
+      let front = front + want as u64;
+      let mut eor = false;
+      if let Some(marks) = &mut self.psh_marks {
+         while marks.front().map_or(false, |&m| m <= front) {
+            eor = marks.pop_front() == Some(front);
+         }
+      }
+      let arrived = self.arrivals.iter().find(|&&(end, _)| end >= front).map(|&(_, at)| at);
+      while self.arrivals.front().map_or(false, |&(end, _)| end <= front) {
+         self.arrivals.pop_front();
+      }
+      (want, eor, arrived)
+   }
 
-//  Send Sequence Space (RFC793 S3.2 F4) 
-//
-//                   1         2          3          4
-//              ----------|----------|----------|----------
-//                     SND.UNA    SND.NXT    SND.UNA
-//                                          +SND.WND
-//
-//        1 - old sequence numbers which have been acknowledged
-//        2 - sequence numbers of unacknowledged data
-//        3 - sequence numbers allowed for new data transmission
-//        4 - future sequence numbers which are not yet allowed
+   /// Tell the peer about room the application has made by reading, if the window has
+   /// opened far enough to be worth it; a peer that saw it closed waits for this rather
+   /// than its next window probe.
+   pub fn window_update(&mut self, egress: &mut Egress) -> io::Result<()> {
+      match self.state {
+         State::Estab | State::FinWait1 | State::FinWait2 if !self.is_recv_closed() => {}
+         _ => return Ok(()),
+      }
+      let edge = self.recv.nxt.wrapping_add(self.receive_window());
+      if wrapping_lt(self.rcv_edge, edge) {
+         let nxt = self.send.nxt;
+         self.write(egress, nxt, 0)?;
+      }
+      Ok(())
+   }
 
-pub struct SendSequenceSpace {
-   /// send unacknowledged
-   una: u32,
-   /// send next
-   nxt: u32,
-   /// send window
-   wnd: u16,
-   /// send urgent pointer
-   up:  bool,
-   /// segment sequence number used for last window update
-   wl1: usize,
-   /// segment acknowledgment number used for last window update
-   wl2: usize,
-   /// initial send sequence number
-   iss: u32
-}
+   /// Whether the peer is done sending, or we stopped receiving, so no more data will show
+   /// up in `incoming`.
+   pub fn is_recv_closed(&self) -> bool {
+      match self.state {
+         State::Closed | State::Closing | State::CloseWait | State::LastAck | State::TimeWait => true,
+         _ => self.peer_closed || self.read_shut,
+      }
+   }
 
-//  Receive Sequence Space (RFC793 S3.2 F5) 
-//
-//                       1          2          3
-//                 ----------|----------|----------
-//                          RCV.NXT    RCV.NXT
-//                                   +RCV.WND
-//
-//        1 - old sequence numbers which have been acknowledged
-//        2 - sequence numbers allowed for new reception
-//        3 - future sequence numbers which are not yet allowed
+   /// Whether the connection is gone for good and can be dropped from the connection table.
+   /// In TIME-WAIT: done as far as the application is concerned, just lingering.
+   pub fn is_time_wait(&self) -> bool {
+      if let State::TimeWait = self.state {
+         true
+      } else {
+         false
+      }
+   }
 
-pub struct RecvSequenceSpace {
-   /// receive next
-   nxt: u32,
-   /// receive window
-   wnd: u16,
-   /// receive urgent pointer
-   up: bool,
-   /// initial receive sequence number
-   irs: u32
-}
+   pub fn is_closed(&self) -> bool {
+      if let State::Closed = self.state {
+         true
+      } else {
+         false
+      }
+   }
 
-impl Connection {
-   pub fn write( &mut self, nic: &mut tun_tap::Iface, payload: &[u8]) -> io::Result<usize> {
-      let mut buf = [0u8; 1500];
-      self.tcp.sequence_number = self.send.nxt;
-      self.tcp.acknowledgment_number = self.recv.nxt;
+   /// Freeze or unfreeze the connection. While in repair mode incoming segments are dropped
+   /// and nothing is transmitted, so the state stays consistent for export; leaving repair
+   /// mode sends out anything that queued up in the meantime.
+   pub fn set_repair(&mut self, egress: &mut Egress, on: bool) -> io::Result<()> {
+      self.repair = on;
+      self.flush(egress)
+   }
 
-      let size = std::cmp::min(
-         buf.len(), 
-         self.tcp.header_len() as usize + self.ip.header_len() as usize + payload.len());  
-      self.ip.set_payload_len(size - self.ip.header_len() as usize);
-      self.tcp.checksum = self.tcp.calc_checksum_ipv4(&self.ip, &[]).expect("failed to compute checksum");
+   /// Snapshot the connection for import elsewhere. Only established connections in repair
+   /// mode can be exported.
+   pub fn repair_export(&self) -> io::Result<RepairState> {
+      if !self.repair {
+         return Err(io::Error::new(io::ErrorKind::InvalidInput, "connection is not in repair mode"));
+      }
+      if let State::Estab = self.state {
+      } else {
+         return Err(io::Error::new(io::ErrorKind::InvalidInput, "only established connections can be exported"));
+      }
+      if self.mptcp.is_some() {
+         return Err(io::Error::new(io::ErrorKind::InvalidInput, "multipath connections can't be exported"));
+      }
+      Ok(RepairState {
+         local: SocketAddrV4::new(Ipv4Addr::from(self.ip.source), self.tcp.source_port),
+         remote: SocketAddrV4::new(Ipv4Addr::from(self.ip.destination), self.tcp.destination_port),
+         snd_iss: self.send.iss,
+         snd_una: self.send.una,
+         snd_nxt: self.send.nxt,
+         snd_wnd: self.send.wnd,
+         snd_wl1: self.send.wl1,
+         snd_wl2: self.send.wl2,
+         rcv_irs: self.recv.irs,
+         rcv_nxt: self.recv.nxt,
+         rcv_wnd: self.recv.wnd,
+         snd_wscale: self.snd_wscale,
+         rcv_wscale: self.rcv_wscale,
+         ts_clock: if self.ts_ok { Some(self.ts_now()) } else { None },
+         ts_recent: self.ts_recent,
+         ecn: self.ecn_ok,
+         ttl: self.ip.time_to_live,
+         peer_mss: self.peer_mss,
+         unacked: self.unacked.to_vec(),
+         incoming: self.incoming.iter().copied().collect(),
+         sack_ok: self.sack_ok,
+         cwnd: self.cc.cwnd(),
+         ssthresh: self.cc.ssthresh(),
+         opts: self.opts.clone(),
+      })
+   }
 
-      // write out the headers
-      use std::io::Write;
-      let mut unwritten = &mut buf[..];
-      self.ip.write(&mut unwritten);
-      self.tcp.write(&mut unwritten);
-      let payload_bytes = unwritten.write(payload)?;
-      let unwritten = unwritten.len();
-      self.send.nxt = self.send.nxt.wrapping_add(payload_bytes as u32);
-      if self.tcp.syn {
-         self.send.nxt = self.send.nxt.wrapping_add(1);
-         self.tcp.syn = false;
+   /// Recreate an exported connection. Like with TCP_REPAIR, the result starts out in repair
+   /// mode and nothing is sent until it is switched off.
+   pub fn repair_import(st: RepairState, mtu: usize) -> Self {
+      let mut c = Connection::new(
+         State::Estab,
+         SendSequenceSpace{
+              iss: st.snd_iss,
+              una: st.snd_una,
+              nxt: st.snd_nxt,
+              wnd: st.snd_wnd,
+              up: None,
+              wl1: st.snd_wl1,
+              wl2: st.snd_wl2,
+              sml: st.snd_una,
+         },
+         RecvSequenceSpace{
+              nxt: st.rcv_nxt,
+              wnd: st.rcv_wnd,
+              irs: st.rcv_irs,
+              up: None,
+         },
+         etherparse::Ipv4Header::new(
+            0,
+            st.ttl,
+            etherparse::IpTrafficClass::Tcp,
+            st.local.ip().octets(),
+            st.remote.ip().octets(),
+         ),
+         etherparse::TcpHeader::new(
+            st.local.port(),
+            st.remote.port(),
+            st.snd_nxt,
+            // filled in by write()
+            0,
+         ),
+         st.opts,
+         mtu,
+         st.peer_mss,
+      );
+      c.sack_ok = st.sack_ok;
+      c.deliver(&st.incoming, time::Instant::now());
+      c.wscale_ok = st.snd_wscale != 0 || st.rcv_wscale != 0;
+      c.snd_wscale = st.snd_wscale;
+      c.rcv_wscale = st.rcv_wscale;
+      c.ecn_ok = st.ecn;
+      if let Some(clock) = st.ts_clock {
+         c.ts_ok = true;
+         c.ts_offset = clock;
+         c.ts_recent = st.ts_recent;
       }
-      if self.tcp.fin {
-         self.send.nxt = self.send.nxt.wrapping_add(1);
-         self.tcp.fin = false;
+      c.cc = cc::resume(c.opts.congestion_control, st.cwnd, st.ssthresh, c.mss());
+      c.unacked.push_shared(std::sync::Arc::new(st.unacked));
+      if st.snd_nxt != st.snd_una {
+         // whatever was in flight is resent once the connection leaves repair mode
+         c.rtx_queue.push_back(InFlight {
+            seq: st.snd_una,
+            len: st.snd_nxt.wrapping_sub(st.snd_una),
+            syn: false,
+            fin: false,
+            sent: time::Instant::now(),
+            retransmitted: true,
+            sacked: false,
+            lost: false,
+         });
+         c.timers.set(Timer::Retransmit, time::Instant::now() + c.rto);
       }
-      nic.send(&buf[.. buf.len() - unwritten])?;
-      Ok(payload_bytes)
+      c.repair = true;
+      c.tcp.ack = true;
+      c
+   }
+
+   fn new(
+      state: State,
+      send: SendSequenceSpace,
+      recv: RecvSequenceSpace,
+      ip: etherparse::Ipv4Header,
+      tcp: etherparse::TcpHeader,
+      opts: ListenOptions,
+      mtu: usize,
+      peer_mss: u16,
+   ) -> Self {
+      let plpmtud = if opts.mtu_probing { Some(Plpmtud::new(mtu)) } else { None };
+      let mss = max_payload(0, &opts, plpmtud.as_ref().map_or(mtu, Plpmtud::mtu), peer_mss);
+      let cc = cc::new(opts.congestion_control, mss);
+      let window_clamp = opts.window_clamp;
+      let rcv_edge = recv.nxt.wrapping_add(recv.wnd);
+      let max_snd_wnd = send.wnd;
+      // room for as many full segments as can ever be in flight, plus the SYN and FIN, so
+      // sending on the data path doesn't grow the queue: no more is in flight than the send
+      // buffer holds and the largest window the peer can advertise takes
+      let in_flight = std::cmp::min(opts.send_buffer, MAX_WINDOW) / std::cmp::max(mss, 1) + 2;
+      let mut c = Connection {
+         state,
+         send,
+         recv,
+         ip,
+         tcp,
+         // sized up front so that receiving within the window doesn't allocate
+         incoming: VecDeque::with_capacity(opts.recv_window as usize),
+         reassembly: Reassembly::new(opts.recv_window as usize),
+         received: 0,
+         psh_marks: None,
+         recv_lowat: 1,
+         // one run per full-sized segment of a full window, as ingress mustn't allocate
+         arrivals: VecDeque::with_capacity(opts.recv_window as usize / DEFAULT_PEER_MSS as usize + 1),
+         last_arrival: None,
+         urgent: None,
+         unacked: SendBuffer::default(),
+         opts,
+         handshake: HandshakeInfo {
+            syn_options: Vec::new(),
+            first_syn: time::Instant::now(),
+            syn_retransmits: 0,
+            syn_ttl: 0,
+            fast_open: false,
+            multipath: false,
+         },
+         repair: false,
+         latency: None,
+         delayed: VecDeque::new(),
+         rng: seed(),
+         priority: Priority::default(),
+         flow: NEXT_FLOW.fetch_add(1, Ordering::Relaxed),
+         weight: 1,
+         delivered: 0,
+         last_acked: 0,
+         peer_closed: false,
+         write_shut: false,
+         read_shut: false,
+         rtx_queue: VecDeque::with_capacity(in_flight),
+         srtt: None,
+         rttvar: time::Duration::from_secs(0),
+         rto: INITIAL_RTO,
+         timers: Timers::default(),
+         scheduled: None,
+         attached: false,
+         memory: MemoryMeter::default(),
+         charged: 0,
+         retransmits: 0,
+         dup_acks: 0,
+         recover: None,
+         fast_recovery: false,
+         rack_xmit: None,
+         rack_rtt: time::Duration::from_secs(0),
+         min_rtt: None,
+         tlp_end: None,
+         frto: None,
+         window_clamp,
+         rcv_edge,
+         cc,
+         verbose: false,
+         sack_ok: false,
+         mtu,
+         plpmtud,
+         mptcp: None,
+         peer_mss,
+         wscale_ok: false,
+         snd_wscale: 0,
+         rcv_wscale: 0,
+         ts_ok: false,
+         ts_base: time::Instant::now(),
+         ts_offset: (seed() >> 32) as u32,
+         ts_recent: 0,
+         ts_recent_at: time::Instant::now(),
+         last_ack_sent: 0,
+         tfo_cookie: None,
+         ecn_ok: false,
+         ece_pending: false,
+         cwr_pending: false,
+         ecn_recover: None,
+         send_goodput: Goodput::new(time::Instant::now()),
+         recv_goodput: Goodput::new(time::Instant::now()),
+         ack_owed: 0,
+         handshake_timed_out: false,
+         active_open: false,
+         max_snd_wnd,
+         challenge_acks: 0,
+         challenge_since: time::Instant::now(),
+         dsack: None,
+         last_send: None,
+         burst_left: None,
+         paced_until: None,
+         error: None,
+         reset_reason: None,
+         persist_probes: 0,
+         probes_unanswered: 0,
+         zero_windows_since: None,
+         last_heard: time::Instant::now(),
+         keepalives_unanswered: 0,
+      };
+      let keepalive = c.opts.keepalive;
+      c.set_keepalive(keepalive);
+      c
+   }
+
+   /// Minshall's variant of Nagle: a less-than-MSS segment may only go out if no previously
+   /// sent small segment is still unacknowledged. Unlike plain Nagle this does not stall a
+   /// request/response exchange behind the peer's delayed ACK, since the last segment of a
+   /// write is only held back by another small segment, not by any outstanding data.
+   fn may_send_small(&self) -> bool {
+      !wrapping_lt(self.send.una, self.send.sml)
+   }
+
+   /// Sequence number of the first byte in the send queue; our SYN occupies `iss` itself.
+   fn data_start(&self) -> u32 {
+      if self.send.una == self.send.iss {
+         self.send.iss.wrapping_add(1)
+      } else {
+         self.send.una
+      }
+   }
+
+   /// Largest payload that fits in a single segment, with the options every segment carries.
+   fn mss(&self) -> usize {
+      max_payload(self.option_len(), &self.opts, self.path_mtu(), self.peer_mss)
    }
 
-   pub fn send_rst(&mut self, nic: &mut tun_tap::Iface) -> io::Result<()>{
-       self.tcp.rst = true;
-       self.tcp.sequence_number = 0;
-       self.tcp.acknowledgment_number = 0;
-       self.write(nic, &[])?; 
-       Ok(())     
+   /// Bytes of options every data segment carries.
+   fn option_len(&self) -> usize {
+      let ts = if self.ts_ok { TIMESTAMPS_LEN } else { 0 };
+      let dss = if self.mptcp.is_some() { mptcp::DSS_LEN } else { 0 };
+      ts + dss
+   }
+
+   /// How much of the peer's data has arrived in order, as an offset from just past its SYN.
+   fn rcv_offset(&self) -> u32 {
+      let fin = match self.state {
+         State::CloseWait | State::LastAck | State::Closing | State::TimeWait => 1,
+         _ => 0,
+      };
+      self.recv.nxt.wrapping_sub(self.recv.irs).wrapping_sub(1 + fin)
+   }
+
+   /// Largest packet to send: the device's, or as much of it as the path is known to take.
+   fn path_mtu(&self) -> usize {
+      self.plpmtud.as_ref().map_or(self.mtu, Plpmtud::mtu)
+   }
+
+   /// Answer a segment that doesn't fit the connection as it stands with an RST, numbered
+   /// as RFC 793 S3.4 has it; see `rst_numbers()`.
+   fn send_rst(&mut self, egress: &mut Egress, tcph: &etherparse::TcpHeaderSlice, data: &[u8]) -> io::Result<()> {
+      if tcph.rst() {
+         return Ok(());
+      }
+      let (seq, ack) = rst_numbers(tcph, data);
+      self.send_reset(egress, seq, ack, &[])
    }
 
    pub fn on_packet<'a>(
-           &mut self, 
-           nic: &mut tun_tap::Iface,
+           &mut self,
+           egress: &mut Egress,
+           iph: etherparse::Ipv4HeaderSlice<'a>,
+           tcph: etherparse::TcpHeaderSlice<'a>,
+           data: &'a [u8],
+   ) -> io::Result<()>{
+        trace!(
+           self,
+           "rcvd {}{}{}{}{} seq={} ack={} wnd={} len={}",
+           if tcph.syn() { "S" } else { "" },
+           if tcph.fin() { "F" } else { "" },
+           if tcph.rst() { "R" } else { "" },
+           if tcph.psh() { "P" } else { "" },
+           if tcph.ack() { "." } else { "" },
+           tcph.sequence_number(),
+           tcph.acknowledgment_number(),
+           tcph.window_size(),
+           data.len()
+        );
+        let before = self.state_name();
+        let r = self.process(egress, iph, tcph, data);
+        self.trace_transition(before);
+        // ACKs free send buffer, and data may have grown the receive buffer
+        self.recharge();
+        r
+   }
+
+   fn process<'a>(
+           &mut self,
+           egress: &mut Egress,
            iph: etherparse::Ipv4HeaderSlice<'a>,
            tcph: etherparse::TcpHeaderSlice<'a>,
            data: &'a [u8],
    ) -> io::Result<()>{
+        if self.repair || !self.opts.ttl_ok(&iph) {
+           return Ok(());
+        }
+
+        if let State::SynSent = self.state {
+           return self.syn_sent(egress, iph, tcph, data);
+        }
+
+        if let State::SynRcvd = self.state {
+           if tcph.syn() && !tcph.ack() && tcph.sequence_number() == self.recv.irs {
+              // the client didn't get our SYN-ACK (yet); send it again rather than a bare ACK
+              self.handshake.syn_retransmits += 1;
+              self.retransmit(egress)?;
+              return Ok(());
+           }
+        }
+
+        if tcph.syn() && !tcph.rst() {
+           // in window or not, a SYN can't belong to a synchronized connection: challenge it
+           // (RFC 5961 S4). The SYN-ACK of a simultaneous open is acked this way too, which
+           // completes the handshake at the other end
+           return self.challenge_ack(egress);
+        }
+
+        // PAWS (RFC 7323 S5.3): a timestamp older than the last one taken in belongs to an old
+        // duplicate, perhaps from before the sequence numbers wrapped
+        let ts = if self.ts_ok { options::timestamps(tcph.options()) } else { None };
+        let ts_valid = self.ts_recent_at.elapsed() < PAWS_IDLE;
+        if let Some((tsval, _)) = ts {
+           if !tcph.rst() && ts_valid && wrapping_lt(tsval, self.ts_recent) {
+              let nxt = self.send.nxt;
+              self.write(egress, nxt, 0)?;
+              return Ok(());
+           }
+        }
+
         // First check sequence numbers are valid (RFC793 S3.3)
         let seqn = tcph.sequence_number();
         let strt = self.recv.nxt.wrapping_sub(1);
@@ -136,7 +2639,7 @@ impl Connection {
            slen += 1;
         }
  
-        let wend = self.recv.nxt.wrapping_add(self.recv.wnd as u32);
+        let wend = self.recv.nxt.wrapping_add(self.recv.wnd);
         let okay = if slen == 0 {
            // zero-length segment has separate rules for acceptance
            if self.recv.wnd == 0 {
@@ -152,8 +2655,9 @@ impl Connection {
            }
         } else {
            if self.recv.wnd == 0 {
-              false
-           } else if !is_between_wrapped(strt, seqn, wend) && !is_between_wrapped(strt, seqn.wrapping_add(slen-1) as u32-1 , wend){
+              // a window probe: its data can't be taken, but its ACK is still valid
+              seqn == self.recv.nxt
+           } else if !is_between_wrapped(strt, seqn, wend) && !is_between_wrapped(strt, seqn.wrapping_add(slen - 1), wend){
               false
            } else {
               true
@@ -161,12 +2665,107 @@ impl Connection {
         };
 
         if !okay {
-           self.write(nic, &[])?;
+           if let State::TimeWait = self.state {
+              if tcph.fin() {
+                 // a retransmitted FIN: our ACK was lost, so give the new one time to arrive
+                 self.peer_closed = true;
+                 self.enter_time_wait();
+              }
+           }
+           // an unacceptable RST is dropped rather than acked (RFC793 S3.9)
+           if !tcph.rst() {
+              let data_seq = seqn.wrapping_add(tcph.syn() as u32);
+              let data_end = data_seq.wrapping_add(data.len() as u32);
+              if self.sack_ok && !data.is_empty() && !wrapping_lt(self.recv.nxt, data_end) {
+                 // all of it arrived before, e.g. a spurious retransmission
+                 self.dsack = Some((data_seq, data_end));
+              }
+              let nxt = self.send.nxt;
+              self.write(egress, nxt, 0)?;
+           }
+           return Ok(());
+        }
+        self.last_heard = time::Instant::now();
+
+        if let Some((tsval, _)) = ts {
+           // only from a segment covering what our last ACK asked for, so delayed ACKs
+           // don't inflate the RTT the peer measures (RFC 7323 S4.3)
+           if !wrapping_lt(self.last_ack_sent, seqn) && (!ts_valid || !wrapping_lt(tsval, self.ts_recent)) {
+              self.ts_recent = tsval;
+              self.ts_recent_at = time::Instant::now();
+           }
+        }
+
+        if self.ecn_ok {
+           // a CWR may itself be marked, so that mark still needs echoing (RFC 3168 S6.1.3)
+           if tcph.cwr() {
+              self.ece_pending = false;
+           }
+           if iph.explicit_congestion_notification() == ECN_CE {
+              self.ece_pending = true;
+           }
+        }
+
+        if tcph.rst() {
+           if seqn != self.recv.nxt {
+              // in the window but not exactly where we are: a blind attacker guessing, or a
+              // peer whose RST lost a race with our data. Only the latter sees our ACK and
+              // can send its RST again with the right number (RFC 5961 S3.2)
+              return self.challenge_ack(egress);
+           }
+           // RFC 793 S3.9, "check the RST bit"
+           let err = match self.state {
+              // back to LISTEN, which for us means dropping the embryonic connection: the
+              // listener never gave it out
+              State::SynRcvd if !self.active_open => None,
+              State::SynRcvd => Some(io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused")),
+              // the application is done with these, or never had the connection
+              State::Closing | State::LastAck | State::TimeWait => None,
+              _ if self.state.is_synchronized() => {
+                 Some(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset by peer"))
+              }
+              _ => None,
+           };
+           // whatever is still queued either way can never be delivered
+           self.error = err;
+           self.unacked.clear();
+           self.rtx_queue.clear();
+           self.state = State::Closed;
            return Ok(());
         }
 
-        self.recv.nxt = seqn.wrapping_add(slen);
-        // TODO: If not acceptable send an ACK
+        if tcph.ack() && self.state.is_synchronized() {
+           // an ACK for data not sent yet, or older than any window the peer ever offered,
+           // didn't come from the peer: drop the segment, data and all (RFC 5961 S5.2)
+           let ackn = tcph.acknowledgment_number();
+           let oldest = self.send.una.wrapping_sub(self.max_snd_wnd);
+           if wrapping_lt(ackn, oldest) || wrapping_lt(self.send.nxt, ackn) {
+              return self.challenge_ack(egress);
+           }
+        }
+
+        // only keep the part of the payload that falls inside the receive window; whatever
+        // sticks out on either side is dropped and the peer finds out from our ACK
+        let data_seq = seqn.wrapping_add(tcph.syn() as u32);
+        let skip = if wrapping_lt(data_seq, self.recv.nxt) {
+           std::cmp::min(self.recv.nxt.wrapping_sub(data_seq) as usize, data.len())
+        } else {
+           0
+        };
+        if self.sack_ok && skip > 0 {
+           // the start of the segment is a duplicate; the ACK for it reports that
+           self.dsack = Some((data_seq, data_seq.wrapping_add(skip as u32)));
+        }
+        let from = data_seq.wrapping_add(skip as u32);
+        let room = if wrapping_lt(from, wend) { wend.wrapping_sub(from) as usize } else { 0 };
+        let keep = std::cmp::min(data.len() - skip, room);
+        let trimmed = keep < data.len();
+        let mut fin = tcph.fin() && skip + keep == data.len();
+        let data = &data[skip..skip + keep];
+
+        // anything past a hole waits in the reassembly queue, and is only acked once the
+        // hole is filled. RCV.NXT moves below, as the text and the FIN are taken
+        let in_order = from == self.recv.nxt;
 
         if !tcph.ack() {
            return Ok(());
@@ -177,81 +2776,459 @@ impl Connection {
            if is_between_wrapped(self.send.una.wrapping_sub(1), ackn, self.send.nxt.wrapping_add(1)){
              //must have ACKed our SYN, since we detected at least one acked byte, and we have only sent one byte (the SYN)
              self.state = State::Estab;
+             self.timers.cancel(Timer::Handshake);
+             // without both keys echoed, the peer has fallen back to plain TCP
+             if !self.mptcp.as_mut().map_or(false, |m| m.take_ack(tcph.options())) {
+                self.mptcp = None;
+             }
+             self.handshake.multipath = self.mptcp.is_some();
             } else {
-             // TODO: <SEQ=SEG.ACK><CTL=RST>
+             // acks something we never sent (RFC 793 S3.9, "SYN-RECEIVED STATE")
+             return self.send_rst(egress, &tcph, data);
             }
+        } else if self.mptcp.as_mut().map_or(false, |m| m.take_segment(tcph.options())) && slen == 0 {
+           // a DATA_FIN on its own, which the peer waits to see acked at the data level
+           // before it closes the subflow
+           let nxt = self.send.nxt;
+           self.write(egress, nxt, 0)?;
         }
 
-         if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-              if !is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)){
-                 return Ok(());
-              }
-              self.send.una = ackn;
-              assert!(data.is_empty());
-              
-              // Now lets terminate the connection!
-              // TODO: needs to be stored in the retransmission queue!
-              if let State::Estab = self.state {
-                 self.tcp.fin = true;
-                 self.write(nic, &[])?;
-                 self.state = State::FinWait1;
+         if let State::Estab
+         | State::FinWait1
+         | State::FinWait2
+         | State::Closing
+         | State::CloseWait
+         | State::LastAck = self.state
+         {
+              match check_ack(self.send.una, self.send.nxt, ackn) {
+                 AckCheck::Unsent => {
+                    // acks something not yet sent: ack back and drop the segment (RFC 793 S3.9)
+                    let nxt = self.send.nxt;
+                    self.write(egress, nxt, 0)?;
+                    return Ok(());
+                 }
+                 // an old duplicate, reordered behind later ACKs: it says nothing about what
+                 // the peer holds now, so leave the dup-ACK count, the congestion state and
+                 // the send window alone, but still take the segment's data
+                 AckCheck::Old => {}
+                 AckCheck::Duplicate | AckCheck::New => {
+                    self.probes_unanswered = 0;
+                    let start = self.data_start();
+                    if wrapping_lt(start, ackn) {
+                       let acked = std::cmp::min(ackn.wrapping_sub(start) as usize, self.unacked.len());
+                       self.unacked.consume(acked);
+                    }
+                    // count acknowledged bytes rather than ACKs, so stretch ACKs and compressed ACK
+                    // bursts account for exactly the data they cover
+                    let newly_acked = ackn.wrapping_sub(self.send.una);
+                    // the send queue starts at the ACK now, and whatever is sent below, like a
+                    // retransmission after a partial ACK, is picked out of it from SND.UNA on
+                    self.send.una = ackn;
+                    if newly_acked > 0 {
+                       self.delivered += newly_acked as u64;
+                       self.send_goodput.record(newly_acked as u64, time::Instant::now());
+                       self.last_acked = newly_acked;
+                       self.dup_acks = 0;
+                       let ts_rtt = ts.and_then(|(_, ecr)| self.ts_rtt(ecr));
+                       self.on_acked(ackn, ts_rtt);
+                       self.frto_ack(egress, ackn, true)?;
+                       let mss = self.mss();
+                       let flight = self.send.nxt.wrapping_sub(ackn) as usize;
+                       match self.recover {
+                          Some(recover) if wrapping_lt(ackn, recover) => {
+                             if self.fast_recovery {
+                                // a partial ACK: the segment after the one just repaired was lost
+                                // too, so resend it now rather than leave recovery and wait for
+                                // the RTO (RFC 6582 S3.2 step 5)
+                                self.cc.on_partial_ack(newly_acked as usize, mss);
+                                self.retransmit(egress)?;
+                             } else {
+                                self.cc.on_ack(newly_acked as usize, flight, mss);
+                             }
+                          }
+                          _ => {
+                             self.recover = None;
+                             self.fast_recovery = false;
+                             self.cc.on_ack(newly_acked as usize, flight, mss);
+                          }
+                       }
+                    } else if slen == 0 && self.peer_window(&tcph) == self.send.wnd && self.send.nxt != self.send.una {
+                       // a duplicate ACK (RFC 5681 S2): the peer got a segment past a hole
+                       self.frto_ack(egress, ackn, false)?;
+                       self.dup_acks += 1;
+                       let mss = self.mss();
+                       if self.fast_recovery {
+                          self.cc.on_dup_ack(mss);
+                       } else if self.dup_acks == 3 && self.recover.is_none() {
+                          // fast retransmit (RFC 5681 S3.2), without waiting for the RTO; a
+                          // lost MTU probe is no sign of congestion, though (RFC 4821 S7.6.2)
+                          let oldest = self.rtx_queue.iter().find(|seg| !seg.sacked).map(|seg| seg.seq);
+                          if oldest.is_none() || oldest != self.plpmtud.as_ref().and_then(Plpmtud::probe_seq) {
+                             self.enter_recovery();
+                          }
+                          self.retransmit(egress)?;
+                       }
+                    }
+                    self.on_ece(&tcph, ackn);
+                    if self.send.up.map_or(false, |up| !wrapping_lt(ackn, up)) {
+                       self.send.up = None;
+                    }
+                    if self.sack_ok {
+                       self.on_sack(tcph.options());
+                    }
+                    self.detect_loss(egress)?;
+
+                    // update the send window (RFC793 S3.9, SEGMENT ARRIVES, ESTABLISHED STATE)
+                    if wrapping_lt(self.send.wl1, seqn) || (self.send.wl1 == seqn && !wrapping_lt(ackn, self.send.wl2)) {
+                       self.send.wnd = self.peer_window(&tcph);
+                       self.max_snd_wnd = std::cmp::max(self.max_snd_wnd, self.send.wnd);
+                       self.send.wl1 = seqn;
+                       self.send.wl2 = ackn;
+                    }
+                    if self.send.wnd > 0 {
+                       self.timers.cancel(Timer::Persist);
+                       self.persist_probes = 0;
+                       self.zero_windows_since = None;
+                    }
+                    self.flush(egress)?;
+                 }
               }
+
+              // if we've been shut down, our FIN may be next now that more is out
+              self.fin_when_sent(egress)?;
          }
 
          if let State::FinWait1 = self.state {
-              if self.send.una == self.send.iss + 2 {
-                 // our FIN has been acked
+              if self.fin_acked() {
                  self.state = State::FinWait2;
+                 self.orphan_timeout();
+              }
+         }
+
+         if let State::Closing = self.state {
+              if self.fin_acked() {
+                 self.enter_time_wait();
+              }
+         }
+
+         if let State::LastAck = self.state {
+              if self.fin_acked() {
+                 // nothing left on either side
+                 self.state = State::Closed;
               }
          }
 
-         if tcph.fin(){
+         if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+            if tcph.urg() {
+               // the pointer only ever moves forward (RFC 793 S3.9, sixth, check the URG bit)
+               let up = seqn.wrapping_add(tcph.urgent_pointer() as u32);
+               if self.recv.up.map_or(true, |old| wrapping_lt(old, up)) && wrapping_lt(from, up) {
+                  self.recv.up = Some(up);
+               }
+            }
+            if !in_order && (!data.is_empty() || fin) {
+               // hold it, and ack at once: the duplicate ACK, and our SACK blocks, tell the
+               // peer about the hole (RFC 5681 S4.2)
+               let nxt = self.recv.nxt;
+               self.reassembly.insert(nxt, from, data, tcph.psh(), fin);
+               fin = false;
+               let nxt = self.send.nxt;
+               self.write(egress, nxt, 0)?;
+            } else if !data.is_empty() {
+               let now = time::Instant::now();
+               self.take_data(from, data, tcph.psh(), now);
+               // the segment may have filled a hole, so what was held behind it follows
+               let mut held = std::mem::take(&mut self.reassembly);
+               let mut filled = false;
+               while let Some((more, psh)) = held.pop(self.recv.nxt) {
+                  let at = self.recv.nxt;
+                  self.take_data(at, more, psh, now);
+                  filled = true;
+               }
+               if held.take_fin(self.recv.nxt) {
+                  fin = true;
+               }
+               self.reassembly = held;
+               if filled {
+                  // the peer is waiting to hear about it (RFC 5681 S4.2)
+                  let nxt = self.send.nxt;
+                  self.write(egress, nxt, 0)?;
+               } else {
+                  self.ack_data(egress)?;
+               }
+            } else if trimmed {
+               // e.g. a probe against our zero window: ack it with the current window
+               let nxt = self.send.nxt;
+               self.write(egress, nxt, 0)?;
+            }
+         }
+
+         if fin {
+            if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+               // the FIN takes up a sequence number of its own
+               self.recv.nxt = self.recv.nxt.wrapping_add(1);
+            }
             match self.state {
+               State::Estab => {
+                  // the peer is done sending, but we may not be: ack its FIN, with ours if
+                  // we've been shut down and everything queued is out already
+                  self.state = State::CloseWait;
+                  self.fin_when_sent(egress)?;
+                  if let State::CloseWait = self.state {
+                     let nxt = self.send.nxt;
+                     self.write(egress, nxt, 0)?;
+                  }
+               }
+               State::FinWait1 => {
+                  // both ends closed at once (RFC 793 S3.5), and our FIN isn't acked yet or
+                  // we'd be in FIN-WAIT-2 by now: ack theirs and wait for that in CLOSING
+                  let nxt = self.send.nxt;
+                  self.write(egress, nxt, 0)?;
+                  self.state = State::Closing;
+               }
                State::FinWait2 => {
                   // We are done with the connection
-                  self.write(nic, &[])?;
-                  self.state = State::TimeWait;
+                  let nxt = self.send.nxt;
+                  self.write(egress, nxt, 0)?;
+                  self.enter_time_wait();
+               }
+               State::TimeWait => {
+                  // the peer retransmitted its FIN, so it never saw our ACK; ack it again
+                  self.peer_closed = true;
+                  let nxt = self.send.nxt;
+                  self.write(egress, nxt, 0)?;
+               }
+               State::CloseWait | State::Closing | State::LastAck => {
+                  // the peer has closed already, so this FIN changes nothing; just ack it
+                  // (RFC 793 S3.9, "eighth, check the FIN bit")
+                  let nxt = self.send.nxt;
+                  self.write(egress, nxt, 0)?;
                }
-               _ => unimplemented!(),
+               // gone, or not synchronized yet: nothing to ack
+               State::Closed | State::SynSent | State::SynRcvd => {}
             }
          }
       
          Ok(())
     }
-    pub fn accept<'a>(nic: &mut tun_tap::Iface,
+
+   /// A segment arriving in SYN-SENT (RFC 793 S3.9, "If the state is SYN-SENT"). Nothing
+   /// about the peer is known yet, so the usual sequence number checks don't apply.
+   fn syn_sent<'a>(
+           &mut self,
+           egress: &mut Egress,
+           iph: etherparse::Ipv4HeaderSlice<'a>,
+           tcph: etherparse::TcpHeaderSlice<'a>,
+           data: &'a [u8],
+   ) -> io::Result<()> {
+      let ackn = tcph.acknowledgment_number();
+      if tcph.ack() && !is_between_wrapped(self.send.iss, ackn, self.send.nxt.wrapping_add(1)) {
+         // doesn't ack our SYN, e.g. left over from an earlier connection on the same ports
+         return self.send_rst(egress, &tcph, data);
+      }
+      if tcph.rst() {
+         // without an ACK there is no telling that the RST answers our SYN
+         if tcph.ack() {
+            self.error = Some(io::Error::new(io::ErrorKind::ConnectionRefused, "connection refused"));
+            self.state = State::Closed;
+         }
+         return Ok(());
+      }
+      if !tcph.syn() {
+         return Ok(());
+      }
+
+      let seqn = tcph.sequence_number();
+      self.recv.irs = seqn;
+      self.recv.nxt = seqn.wrapping_add(1);
+      // the window in our SYN counts from here, now that we know where here is
+      self.rcv_edge = self.recv.nxt.wrapping_add(self.recv.wnd);
+      let ts = self.take_syn_options(&iph, &tcph);
+      if !tcph.ack() {
+         // both ends sent a SYN at once (RFC 793 S3.4, figure 8): answer with our SYN again,
+         // now acking theirs, and wait in SYN-RECEIVED for them to ack ours. An
+         // ECN-setup SYN has both ECE and CWR (RFC 3168 S6.1.1)
+         self.ecn_ok = self.ecn_ok && tcph.ece() && tcph.cwr();
+         self.send.wnd = tcph.window_size() as u32;
+         self.max_snd_wnd = self.send.wnd;
+         self.send.wl1 = seqn;
+         self.state = State::SynRcvd;
+         self.tcp.ack = true;
+         self.tcp.syn = true;
+         let iss = self.send.iss;
+         self.write(egress, iss, 0)?;
+         return Ok(());
+      }
+
+      // an ECN-setup SYN-ACK has ECE but not CWR (RFC 3168 S6.1.1)
+      self.ecn_ok = self.ecn_ok && tcph.ece() && !tcph.cwr();
+      // the window of a SYN is never scaled
+      self.send.wnd = tcph.window_size() as u32;
+      self.max_snd_wnd = self.send.wnd;
+      self.send.wl1 = seqn;
+      self.send.wl2 = ackn;
+      self.send.una = ackn;
+      self.send.sml = ackn;
+      let ts_rtt = ts.and_then(|(_, ecr)| self.ts_rtt(ecr));
+      self.on_acked(ackn, ts_rtt);
+      self.state = State::Estab;
+      self.timers.cancel(Timer::Handshake);
+      self.tcp.ack = true;
+      self.tcp.cwr = false;
+
+      if !data.is_empty() {
+         let len = std::cmp::min(data.len(), self.recv.wnd as usize);
+         self.deliver(&data[..len], time::Instant::now());
+         self.recv.nxt = self.recv.nxt.wrapping_add(len as u32);
+      }
+      let nxt = self.send.nxt;
+      self.write(egress, nxt, 0)?;
+      self.flush(egress)
+   }
+
+   /// Take in the options of the peer's SYN or SYN-ACK in answer to ours: everything we
+   /// offered only holds if the peer offered it back. Returns its TSval and TSecr if
+   /// timestamps are on.
+   fn take_syn_options(
+      &mut self,
+      iph: &etherparse::Ipv4HeaderSlice,
+      tcph: &etherparse::TcpHeaderSlice,
+   ) -> Option<(u32, u32)> {
+      self.handshake.syn_options = tcph.options().to_vec();
+      self.handshake.syn_ttl = iph.ttl();
+      self.peer_mss = options::mss(tcph.options())
+         .map_or(DEFAULT_PEER_MSS, |mss| std::cmp::max(mss, MIN_PEER_MSS));
+      self.sack_ok = options::sack_permitted(tcph.options());
+      let ts = options::timestamps(tcph.options()).filter(|_| self.ts_ok);
+      match ts {
+         Some((tsval, _)) => {
+            self.ts_recent = tsval;
+            self.ts_recent_at = time::Instant::now();
+         }
+         None => self.ts_ok = false,
+      }
+      match syn_wscale(tcph.options()) {
+         Some(shift) => self.snd_wscale = shift,
+         None => {
+            self.wscale_ok = false;
+            self.rcv_wscale = 0;
+         }
+      }
+      // a SYN-ACK without the peer's key, or a simultaneous open, falls back to plain TCP
+      if !self.mptcp.as_mut().map_or(false, |m| m.take_syn_ack(tcph.options())) {
+         self.mptcp = None;
+      }
+      self.handshake.multipath = self.mptcp.is_some();
+      ts
+   }
+
+   /// Start an active open from `local` to `remote`: the SYN goes out right away and is
+   /// retransmitted with backoff until the peer answers, `opts.max_retransmits` runs out or
+   /// `opts.handshake_timeout` passes. The SYN offers whatever `opts` allows, and each
+   /// option is dropped again unless the SYN-ACK agrees to it.
+   pub fn connect(
+      egress: &mut Egress,
+      opts: &ListenOptions,
+      local: SocketAddrV4,
+      remote: SocketAddrV4,
+   ) -> io::Result<Self> {
+      let iss = opts.iss.unwrap_or_else(initial_sequence);
+      let mut c = Connection::new(
+         State::SynSent,
+         SendSequenceSpace {
+            iss,
+            una: iss,
+            nxt: iss,
+            wnd: 0,
+            up: None,
+            wl1: 0,
+            wl2: 0,
+            sml: iss,
+         },
+         RecvSequenceSpace {
+            nxt: 0,
+            wnd: opts.recv_window,
+            irs: 0,
+            up: None,
+         },
+         etherparse::Ipv4Header::new(
+            0,
+            opts.ttl,
+            etherparse::IpTrafficClass::Tcp,
+            local.ip().octets(),
+            remote.ip().octets(),
+         ),
+         etherparse::TcpHeader::new(
+            local.port(),
+            remote.port(),
+            iss,
+            // filled in by write()
+            0,
+         ),
+         opts.clone(),
+         egress.mtu(),
+         DEFAULT_PEER_MSS,
+      );
+      c.sack_ok = true;
+      c.ts_ok = opts.timestamps;
+      c.wscale_ok = true;
+      c.rcv_wscale = wscale_for(opts.recv_window);
+      c.ecn_ok = opts.ecn;
+      c.active_open = true;
+      if opts.mptcp {
+         c.mptcp = Some(Mptcp::new(true));
+      }
+      let hs = c.handshake_deadline();
+      c.timers.set(Timer::Handshake, hs);
+
+      c.tcp.syn = true;
+      c.tcp.ack = false;
+      c.write(egress, iss, 0)?;
+      Ok(c)
+   }
+
+    pub fn accept<'a>(egress: &mut Egress,
+           opts: &ListenOptions,
+           fast_open: &mut FastOpen,
            iph: etherparse::Ipv4HeaderSlice<'a>,
            tcph: etherparse::TcpHeaderSlice<'a>,
            data: &'a [u8],
     ) -> io::Result<Option<Self>>
     {
-                  let mut buf = [0u8; 1500];
                   if !tcph.syn(){
                      // Only expected syn package
                      return Ok(None);
                   }
+                  if !opts.ttl_ok(&iph) {
+                     return Ok(None);
+                  }
 
-                  let iss = 0;
-                  let wnd = 1024;
-                  let mut c = Connection {
-                     state: State::SynRcvd,
-                     send: SendSequenceSpace{
+                  let iss = opts.iss.unwrap_or_else(initial_sequence);
+                  let wnd = opts.recv_window;
+                  let peer_mss = options::mss(tcph.options())
+                     .map_or(DEFAULT_PEER_MSS, |mss| std::cmp::max(mss, MIN_PEER_MSS));
+                  let mut c = Connection::new(
+                     State::SynRcvd,
+                     SendSequenceSpace{
                           iss,
                           una: iss,
                           nxt: iss,
-                          wnd: wnd,
-                          up: false,
-                          wl1: 0,
+                          // the window of a SYN is never scaled
+                          wnd: tcph.window_size() as u32,
+                          up: None,
+                          wl1: tcph.sequence_number(),
                           wl2: 0,
+                          sml: iss,
                      },
-                     recv: RecvSequenceSpace{
-                          nxt: tcph.sequence_number() + 1,
-                          wnd: tcph.window_size(),
+                     RecvSequenceSpace{
+                          nxt: tcph.sequence_number().wrapping_add(1),
+                          wnd: wnd,
                           irs: tcph.sequence_number(),
-                          up: false,
+                          up: None,
                      },
-                     ip: etherparse::Ipv4Header::new(
+                     etherparse::Ipv4Header::new(
                         0,
-                        64,
+                        opts.ttl,
                         etherparse::IpTrafficClass::Tcp,
                         [
                            iph.destination()[0], iph.destination()[1],iph.destination()[2], iph.destination()[3],
@@ -260,22 +3237,314 @@ impl Connection {
                            iph.source()[0], iph.source()[1], iph.source()[2], iph.source()[3],
                         ]
                      ),
-                     tcp: etherparse::TcpHeader::new(
+                     etherparse::TcpHeader::new(
                         tcph.destination_port(),
                         tcph.source_port(),
                         iss,
-                        wnd,
-                     )
-                  };
+                        // filled in by write()
+                        0,
+                     ),
+                     opts.clone(),
+                     egress.mtu(),
+                     peer_mss,
+                  );
+                  c.handshake.syn_options = tcph.options().to_vec();
+                  c.handshake.syn_ttl = iph.ttl();
+                  c.sack_ok = options::sack_permitted(tcph.options());
+                  // an ECN-setup SYN has both ECE and CWR (RFC 3168 S6.1.1)
+                  c.ecn_ok = opts.ecn && tcph.ece() && tcph.cwr();
+                  if let Some((tsval, _)) = options::timestamps(tcph.options()).filter(|_| opts.timestamps) {
+                     c.ts_ok = true;
+                     c.ts_recent = tsval;
+                  }
+                  if let Some(shift) = syn_wscale(tcph.options()) {
+                     // only answered with our own scale if the peer offered one (RFC 7323 S2.2)
+                     c.wscale_ok = true;
+                     c.snd_wscale = shift;
+                     c.rcv_wscale = wscale_for(opts.recv_window);
+                  }
+                  if opts.mptcp && mptcp::offered(tcph.options()) {
+                     c.mptcp = Some(Mptcp::new(false));
+                  }
+                  if let Some(cookie) = options::fast_open(tcph.options()).filter(|_| opts.fast_open) {
+                     let client = SocketAddrV4::new(iph.source_addr(), tcph.source_port());
+                     let verdict = fast_open.check(client, tcph.sequence_number(), cookie, time::Instant::now());
+                     c.tfo_cookie = verdict.cookie;
+                     if verdict.accept_data && !data.is_empty() {
+                        // readable before the handshake completes; the SYN-ACK acknowledges it
+                        let len = std::cmp::min(data.len(), wnd as usize);
+                        c.deliver(&data[..len], time::Instant::now());
+                        c.recv.nxt = c.recv.nxt.wrapping_add(len as u32);
+                        c.handshake.fast_open = true;
+                     }
+                  }
+                  let hs = c.handshake_deadline();
+                  c.timers.set(Timer::Handshake, hs);
 
                   c.tcp.syn = true;   
                   c.tcp.ack = true;
-                  c.write(nic, &[])?;
+                  c.write(egress, iss, 0)?;
                   Ok(Some(c))
     }
+
+   /// Create the connection for an ACK that may answer a cookie SYN-ACK (see
+   /// `cookie_syn_ack()`), if the cookie it acknowledges checks out. It starts out as if
+   /// the SYN-ACK had been sent from SYN-RECEIVED, with the client's MSS from the cookie and
+   /// no other options, and takes the ACK, and whatever data it carries, from there.
+   pub fn from_cookie<'a>(
+      egress: &mut Egress,
+      opts: &ListenOptions,
+      cookies: &SynCookies,
+      iph: etherparse::Ipv4HeaderSlice<'a>,
+      tcph: etherparse::TcpHeaderSlice<'a>,
+      data: &'a [u8],
+   ) -> io::Result<Option<Self>> {
+      if tcph.syn() || tcph.rst() || !tcph.ack() || !opts.ttl_ok(&iph) {
+         return Ok(None);
+      }
+      let local = SocketAddrV4::new(iph.destination_addr(), tcph.destination_port());
+      let remote = SocketAddrV4::new(iph.source_addr(), tcph.source_port());
+      let irs = tcph.sequence_number().wrapping_sub(1);
+      let iss = tcph.acknowledgment_number().wrapping_sub(1);
+      let peer_mss = match cookies.check(local, remote, irs, iss, time::Instant::now()) {
+         Some(mss) => mss,
+         None => return Ok(None),
+      };
+      let mut c = Connection::new(
+         State::SynRcvd,
+         SendSequenceSpace {
+            iss,
+            una: iss,
+            nxt: iss.wrapping_add(1),
+            // the SYN-ACK offered no window scaling
+            wnd: tcph.window_size() as u32,
+            up: None,
+            wl1: tcph.sequence_number(),
+            wl2: 0,
+            sml: iss,
+         },
+         RecvSequenceSpace {
+            nxt: tcph.sequence_number(),
+            wnd: std::cmp::min(opts.recv_window, u16::MAX as u32),
+            irs,
+            up: None,
+         },
+         etherparse::Ipv4Header::new(
+            0,
+            opts.ttl,
+            etherparse::IpTrafficClass::Tcp,
+            local.ip().octets(),
+            remote.ip().octets(),
+         ),
+         etherparse::TcpHeader::new(
+            local.port(),
+            remote.port(),
+            iss,
+            // filled in by write()
+            0,
+         ),
+         opts.clone(),
+         egress.mtu(),
+         peer_mss,
+      );
+      c.handshake.syn_ttl = iph.ttl();
+      c.tcp.ack = true;
+      c.on_packet(egress, iph, tcph, data)?;
+      Ok(Some(c))
+   }
+}
+
+/// Answer a SYN with a SYN-ACK whose initial sequence number is a SYN cookie, keeping no
+/// state (RFC 4987 S3.6). The cookie remembers the client's MSS and nothing else, so no
+/// other option is offered.
+pub fn cookie_syn_ack<'a>(egress: &mut Egress,
+       opts: &ListenOptions,
+       cookies: &SynCookies,
+       iph: etherparse::Ipv4HeaderSlice<'a>,
+       tcph: etherparse::TcpHeaderSlice<'a>,
+)
+{
+   if !tcph.syn() || tcph.ack() || !opts.ttl_ok(&iph) {
+      return;
+   }
+   let local = SocketAddrV4::new(iph.destination_addr(), tcph.destination_port());
+   let remote = SocketAddrV4::new(iph.source_addr(), tcph.source_port());
+   let peer_mss = options::mss(tcph.options()).unwrap_or(DEFAULT_PEER_MSS);
+   let cookie = cookies.make(local, remote, tcph.sequence_number(), peer_mss, time::Instant::now());
+   let mut tcp = etherparse::TcpHeader::new(local.port(), remote.port(), cookie, 0);
+   tcp.syn = true;
+   tcp.ack = true;
+   tcp.acknowledgment_number = tcph.sequence_number().wrapping_add(1);
+   tcp.window_size = std::cmp::min(opts.recv_window, u16::MAX as u32) as u16;
+   let mut options = OptionWriter::default();
+   let mss = std::cmp::min(egress.mtu() - BASE_HEADERS, u16::MAX as usize) as u16;
+   options.push(&TcpOption::Mss(mss));
+   tcp.set_options_raw(options.as_slice()).expect("options fit in the header");
+   let ip = etherparse::Ipv4Header::new(
+      tcp.header_len(),
+      opts.ttl,
+      etherparse::IpTrafficClass::Tcp,
+      local.ip().octets(),
+      remote.ip().octets(),
+   );
+   tcp.checksum = tcp.calc_checksum_ipv4(&ip, &[]).expect("failed to compute checksum");
+   let mut buf = egress.buffer();
+   ip.write(&mut buf).expect("writing to a Vec cannot fail");
+   tcp.write(&mut buf).expect("writing to a Vec cannot fail");
+   egress.push_owned(Priority::default(), Flow::NONE, "LISTEN", buf);
+}
+
+/// Turn down a SYN that no connection will be created for, with an RST that acknowledges it
+/// (RFC 793 S3.4, "Reset Generation").
+pub fn refuse<'a>(egress: &mut Egress,
+       opts: &ListenOptions,
+       why: ResetReason,
+       iph: etherparse::Ipv4HeaderSlice<'a>,
+       tcph: etherparse::TcpHeaderSlice<'a>,
+       data: &'a [u8],
+)
+{
+   let text = if opts.rst_diagnostics { why.describe().as_bytes() } else { &[] };
+   send_stray_reset(egress, opts.ttl, &iph, &tcph, data, text);
+}
+
+/// Answer a segment for a connection that doesn't exist with an RST (RFC 793 S3.4, "Reset
+/// Generation"). An RST itself is never answered.
+pub fn reset_stray<'a>(egress: &mut Egress,
+       ttl: u8,
+       iph: etherparse::Ipv4HeaderSlice<'a>,
+       tcph: etherparse::TcpHeaderSlice<'a>,
+       data: &'a [u8],
+)
+{
+   if !tcph.rst() {
+      send_stray_reset(egress, ttl, &iph, &tcph, data, &[]);
+   }
+}
+
+fn send_stray_reset(
+   egress: &mut Egress,
+   ttl: u8,
+   iph: &etherparse::Ipv4HeaderSlice,
+   tcph: &etherparse::TcpHeaderSlice,
+   data: &[u8],
+   text: &[u8],
+) {
+   let (seq, ack) = rst_numbers(tcph, data);
+   let mut tcp = etherparse::TcpHeader::new(tcph.destination_port(), tcph.source_port(), seq, 0);
+   tcp.rst = true;
+   tcp.ack = ack.is_some();
+   tcp.acknowledgment_number = ack.unwrap_or(0);
+   let ip = etherparse::Ipv4Header::new(
+      tcp.header_len() + text.len() as u16,
+      ttl,
+      etherparse::IpTrafficClass::Tcp,
+      iph.destination_addr().octets(),
+      iph.source_addr().octets(),
+   );
+   tcp.checksum = tcp.calc_checksum_ipv4(&ip, text).expect("failed to compute checksum");
+   let mut buf = egress.buffer();
+   ip.write(&mut buf).expect("writing to a Vec cannot fail");
+   tcp.write(&mut buf).expect("writing to a Vec cannot fail");
+   buf.extend_from_slice(text);
+   egress.push_owned(Priority::default(), Flow::NONE, "CLOSED", buf);
+}
+
+/// Sequence and acknowledgment numbers of an RST answering `tcph` (RFC 793 S3.4): if the
+/// segment has an ACK, the RST goes where it says we are, which is where the peer looks
+/// for it; otherwise it goes at 0 and acks everything the segment occupied.
+fn rst_numbers(tcph: &etherparse::TcpHeaderSlice, data: &[u8]) -> (u32, Option<u32>) {
+   if tcph.ack() {
+      (tcph.acknowledgment_number(), None)
+   } else {
+      let len = data.len() as u32 + tcph.syn() as u32 + tcph.fin() as u32;
+      (0, Some(tcph.sequence_number().wrapping_add(len)))
+   }
+}
+
+/// Largest payload for a segment carrying `options` bytes of options: what fits the device,
+/// and no more than the peer's MSS, which counts no options (RFC 6691), nor the configured
+/// clamp.
+fn max_payload(options: usize, opts: &ListenOptions, mtu: usize, peer_mss: u16) -> usize {
+   let mss = std::cmp::min(
+      mtu.saturating_sub(BASE_HEADERS + options),
+      (peer_mss as usize).saturating_sub(options));
+   match opts.mss_clamp {
+      Some(clamp) => std::cmp::min(mss, clamp as usize),
+      None => mss,
+   }
+}
+
+/// The shift of a SYN's window scale option, if it has one; larger shifts are taken as the
+/// largest allowed (RFC 7323 S2.3).
+pub(crate) fn syn_wscale(options: &[u8]) -> Option<u8> {
+   options::window_scale(options).map(|shift| std::cmp::min(shift, MAX_WSCALE))
+}
+
+/// The smallest shift that lets the window field express `window`.
+fn wscale_for(window: u32) -> u8 {
+   let mut shift = 0;
+   while shift < MAX_WSCALE && window >> shift > u16::MAX as u32 {
+      shift += 1;
+   }
+   shift
 }
 
-fn is_between_wrapped(start:u32, x:u32, end: u32) -> bool {
+/// An initial send sequence number that can't be predicted from outside, so that segments
+/// can't be spoofed into the connection blind (RFC 6528).
+fn initial_sequence() -> u32 {
+   use std::hash::{BuildHasher, Hasher};
+   // the std hasher's keys are random, which is the secret a clock alone would lack
+   let mut h = std::collections::hash_map::RandomState::new().build_hasher();
+   h.write_u64(seed());
+   h.finish() as u32
+}
+
+fn seed() -> u64 {
+   let t = time::SystemTime::now()
+      .duration_since(time::UNIX_EPOCH)
+      .unwrap_or_default();
+   // xorshift gets stuck on zero
+   (t.as_nanos() as u64) | 1
+}
+
+/// Where an acknowledgment number falls relative to the send sequence space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AckCheck {
+   /// below SND.UNA
+   Old,
+   /// equal to SND.UNA
+   Duplicate,
+   /// SND.UNA < SEG.ACK =< SND.NXT
+   New,
+   /// beyond SND.NXT
+   Unsent,
+}
+
+fn check_ack(una: u32, nxt: u32, ack: u32) -> AckCheck {
+   if ack == una {
+      AckCheck::Duplicate
+   } else if is_between_wrapped(una, ack, nxt.wrapping_add(1)) {
+      AckCheck::New
+   } else if wrapping_lt(ack, una) {
+      AckCheck::Old
+   } else {
+      AckCheck::Unsent
+   }
+}
+
+fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
+   // From RFC1323:
+   //     TCP determines if a data segment is "old" or "new" by testing
+   //     whether its sequence number is within 2**31 bytes of the left edge
+   //     of the window, and if it is not, discarding the data as "old".  To
+   //     insure that new data is never mistakenly considered old and vice-
+   //     versa, the left edge of the sender's window has to be at most
+   //     2**31 away from the right edge of the receiver's window.
+   lhs.wrapping_sub(rhs) > (1 << 31)
+}
+
+pub(crate) fn is_between_wrapped(start:u32, x:u32, end: u32) -> bool {
    use std::cmp::Ordering;
    match start.cmp(&x){
       Ordering::Equal => return false,
@@ -324,4 +3593,112 @@ fn is_between_wrapped(start:u32, x:u32, end: u32) -> bool {
    }
 
    true
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   #[test]
+   fn wrapping_lt_across_zero() {
+      assert!(wrapping_lt(1, 2));
+      assert!(!wrapping_lt(2, 1));
+      assert!(!wrapping_lt(7, 7));
+      assert!(wrapping_lt(u32::MAX, 0));
+      assert!(wrapping_lt(u32::MAX - 10, 10));
+      assert!(!wrapping_lt(10, u32::MAX - 10));
+   }
+
+   #[test]
+   fn between_across_zero() {
+      assert!(is_between_wrapped(u32::MAX - 1, u32::MAX, 1));
+      assert!(is_between_wrapped(u32::MAX - 1, 0, 1));
+      assert!(!is_between_wrapped(u32::MAX - 1, 1, 1));
+      assert!(!is_between_wrapped(u32::MAX - 1, u32::MAX - 1, 1));
+      assert!(!is_between_wrapped(u32::MAX - 1, 5, 1));
+   }
+
+   #[test]
+   fn ack_classes() {
+      assert_eq!(check_ack(1000, 2000, 1000), AckCheck::Duplicate);
+      assert_eq!(check_ack(1000, 2000, 1500), AckCheck::New);
+      assert_eq!(check_ack(1000, 2000, 2000), AckCheck::New);
+      assert_eq!(check_ack(1000, 2000, 999), AckCheck::Old);
+      assert_eq!(check_ack(1000, 2000, 2001), AckCheck::Unsent);
+   }
+
+   #[test]
+   fn ack_classes_across_wraparound() {
+      let una = u32::MAX - 100;
+      let nxt = 100;
+      assert_eq!(check_ack(una, nxt, una), AckCheck::Duplicate);
+      assert_eq!(check_ack(una, nxt, u32::MAX), AckCheck::New);
+      assert_eq!(check_ack(una, nxt, 0), AckCheck::New);
+      assert_eq!(check_ack(una, nxt, nxt), AckCheck::New);
+      assert_eq!(check_ack(una, nxt, una - 1), AckCheck::Old);
+      assert_eq!(check_ack(una, nxt, u32::MAX - 5000), AckCheck::Old);
+      assert_eq!(check_ack(una, nxt, nxt + 1), AckCheck::Unsent);
+      assert_eq!(check_ack(una, nxt, 5000), AckCheck::Unsent);
+   }
+
+   #[test]
+   fn rst_numbers_follow_the_segment() {
+      let header = |syn, fin, ack: Option<u32>| {
+         let mut tcp = etherparse::TcpHeader::new(1, 2, u32::MAX - 5, 0);
+         tcp.syn = syn;
+         tcp.fin = fin;
+         tcp.ack = ack.is_some();
+         tcp.acknowledgment_number = ack.unwrap_or(0);
+         let mut raw = Vec::new();
+         tcp.write(&mut raw).unwrap();
+         raw
+      };
+      let raw = header(true, false, None);
+      let tcph = etherparse::TcpHeaderSlice::from_slice(&raw).unwrap();
+      assert_eq!(rst_numbers(&tcph, &[0; 10]), (0, Some(5)));
+      let raw = header(false, true, None);
+      let tcph = etherparse::TcpHeaderSlice::from_slice(&raw).unwrap();
+      assert_eq!(rst_numbers(&tcph, &[]), (0, Some(u32::MAX - 4)));
+      let raw = header(false, false, Some(7000));
+      let tcph = etherparse::TcpHeaderSlice::from_slice(&raw).unwrap();
+      assert_eq!(rst_numbers(&tcph, &[0; 10]), (7000, None));
+   }
+
+   #[test]
+   fn mss_clamp_has_a_floor() {
+      let clamped = |clamp| ListenOptions { mss_clamp: clamp, ..ListenOptions::default() }.validate();
+      // a clamp of 0 would leave nothing to send data in
+      assert_eq!(clamped(Some(0)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+      assert!(clamped(Some(MIN_PEER_MSS - 1)).is_err());
+      assert!(clamped(Some(MIN_PEER_MSS)).is_ok());
+      assert!(clamped(None).is_ok());
+   }
+
+   #[test]
+   fn recv_window_is_not_zero() {
+      let window = |recv_window| ListenOptions { recv_window, ..ListenOptions::default() }.validate();
+      assert_eq!(window(0).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+      assert!(window(1).is_ok());
+   }
+
+   #[test]
+   fn window_scale_shifts() {
+      assert_eq!(wscale_for(u16::MAX as u32), 0);
+      assert_eq!(wscale_for(u16::MAX as u32 + 1), 1);
+      assert_eq!(wscale_for(1 << 20), 5);
+      // a window past what shift 14 expresses is advertised as the most it can
+      assert_eq!(wscale_for(u32::MAX), MAX_WSCALE);
+      assert_eq!(syn_wscale(&[1, 3, 3, 15]), Some(MAX_WSCALE));
+      assert_eq!(syn_wscale(&[1, 3, 3, 7]), Some(7));
+      assert_eq!(syn_wscale(&[1, 1, 1, 1]), None);
+   }
+
+   #[test]
+   fn old_ack_just_below_una_after_wrap() {
+      // SND.UNA has just wrapped; ACKs from before the wrap are old, not far in the future
+      assert_eq!(check_ack(5, 50, u32::MAX), AckCheck::Old);
+      assert_eq!(check_ack(0, 50, u32::MAX), AckCheck::Old);
+      assert_eq!(check_ack(0, 0, u32::MAX), AckCheck::Old);
+      assert_eq!(check_ack(0, 0, 1), AckCheck::Unsent);
+   }
+}
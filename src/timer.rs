@@ -0,0 +1,182 @@
+//! Timers. Each connection keeps one deadline per kind of timer in `Timers`, and the
+//! interface keeps a `Scheduler` of (deadline, connection) so the tick loop only visits
+//! connections that have something due instead of scanning all of them on every wakeup.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+   /// resend the oldest unacknowledged segment
+   Retransmit,
+   /// release the next segment of a paced flight
+   Pace,
+   /// give up on a connection stuck in SYN-RECEIVED
+   Handshake,
+   /// acknowledge data nothing else has acknowledged yet
+   DelayedAck,
+   /// leave TIME-WAIT after 2MSL, or a FIN-WAIT-2 nobody reads from
+   TimeWait,
+   /// re-check segments RACK couldn't yet declare lost for reordering
+   Reorder,
+   /// send a tail loss probe
+   Probe,
+   /// probe the peer's zero window
+   Persist,
+   /// check on a peer that has gone quiet
+   Keepalive,
+}
+
+const KINDS: usize = 9;
+
+impl Timer {
+   fn index(self) -> usize {
+      match self {
+         Timer::Retransmit => 0,
+         Timer::Pace => 1,
+         Timer::Handshake => 2,
+         Timer::DelayedAck => 3,
+         Timer::TimeWait => 4,
+         Timer::Reorder => 5,
+         Timer::Probe => 6,
+         Timer::Persist => 7,
+         Timer::Keepalive => 8,
+      }
+   }
+}
+
+/// The armed timers of one connection.
+#[derive(Debug, Default)]
+pub struct Timers {
+   at: [Option<Instant>; KINDS],
+}
+
+impl Timers {
+   pub fn set(&mut self, t: Timer, at: Instant) {
+      self.at[t.index()] = Some(at);
+   }
+
+   pub fn cancel(&mut self, t: Timer) {
+      self.at[t.index()] = None;
+   }
+
+   pub fn is_armed(&self, t: Timer) -> bool {
+      self.at[t.index()].is_some()
+   }
+
+   /// Whether `t` has fired by `now`. A timer that has fired is disarmed.
+   pub fn expired(&mut self, t: Timer, now: Instant) -> bool {
+      match self.at[t.index()] {
+         Some(at) if at <= now => {
+            self.at[t.index()] = None;
+            true
+         }
+         _ => false,
+      }
+   }
+
+   /// The earliest armed deadline.
+   pub fn next(&self) -> Option<Instant> {
+      self.at.iter().filter_map(|&at| at).min()
+   }
+}
+
+/// Deadlines of many connections, earliest first. Entries are never removed when a
+/// deadline moves; the owner records which deadline it last scheduled for each key and
+/// skips entries that no longer match when they come due.
+pub struct Scheduler<K> {
+   heap: BinaryHeap<Reverse<(Instant, K)>>,
+}
+
+impl<K: Ord> Default for Scheduler<K> {
+   fn default() -> Self {
+      Scheduler {
+         heap: BinaryHeap::new(),
+      }
+   }
+}
+
+impl<K: Ord + Copy> Scheduler<K> {
+   pub fn schedule(&mut self, at: Instant, key: K) {
+      self.heap.push(Reverse((at, key)));
+   }
+
+   /// The next entry due by `now`, if any.
+   pub fn pop_due(&mut self, now: Instant) -> Option<(Instant, K)> {
+      match self.heap.peek() {
+         Some(&Reverse((at, _))) if at <= now => self.heap.pop().map(|Reverse(e)| e),
+         _ => None,
+      }
+   }
+
+   pub fn next(&self) -> Option<Instant> {
+      self.heap.peek().map(|&Reverse((at, _))| at)
+   }
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+   use std::time::Duration;
+
+   fn ms(n: u64) -> Duration {
+      Duration::from_millis(n)
+   }
+
+   #[test]
+   fn timers_report_the_earliest_deadline() {
+      let now = Instant::now();
+      let mut t = Timers::default();
+      assert_eq!(t.next(), None);
+      t.set(Timer::Keepalive, now + ms(300));
+      t.set(Timer::Retransmit, now + ms(200));
+      t.set(Timer::DelayedAck, now + ms(40));
+      assert_eq!(t.next(), Some(now + ms(40)));
+      t.cancel(Timer::DelayedAck);
+      assert_eq!(t.next(), Some(now + ms(200)));
+      // setting a timer again moves it rather than adding another
+      t.set(Timer::Retransmit, now + ms(400));
+      assert_eq!(t.next(), Some(now + ms(300)));
+   }
+
+   #[test]
+   fn expired_timers_fire_once() {
+      let now = Instant::now();
+      let mut t = Timers::default();
+      t.set(Timer::Retransmit, now + ms(200));
+      assert!(!t.expired(Timer::Retransmit, now + ms(199)));
+      assert!(t.expired(Timer::Retransmit, now + ms(200)));
+      assert!(!t.is_armed(Timer::Retransmit));
+      assert!(!t.expired(Timer::Retransmit, now + ms(300)));
+   }
+
+   #[test]
+   fn scheduler_pops_in_deadline_order() {
+      let now = Instant::now();
+      let mut s = Scheduler::default();
+      s.schedule(now + ms(30), 'c');
+      s.schedule(now + ms(10), 'a');
+      s.schedule(now + ms(20), 'b');
+      // a key scheduled again keeps its old entry too, for the owner to skip
+      s.schedule(now + ms(5), 'c');
+      assert_eq!(s.next(), Some(now + ms(5)));
+      // nothing before it is due
+      assert_eq!(s.pop_due(now), None);
+      let due: Vec<_> = std::iter::from_fn(|| s.pop_due(now + ms(20))).collect();
+      assert_eq!(due, vec![(now + ms(5), 'c'), (now + ms(10), 'a'), (now + ms(20), 'b')]);
+      assert_eq!(s.next(), Some(now + ms(30)));
+      assert_eq!(s.pop_due(now + ms(30)), Some((now + ms(30), 'c')));
+      assert_eq!(s.next(), None);
+   }
+
+   #[test]
+   fn scheduler_breaks_ties_by_key() {
+      let now = Instant::now();
+      let mut s = Scheduler::default();
+      s.schedule(now, 2);
+      s.schedule(now, 1);
+      assert_eq!(s.pop_due(now), Some((now, 1)));
+      assert_eq!(s.pop_due(now), Some((now, 2)));
+   }
+}
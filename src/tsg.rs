@@ -0,0 +1,247 @@
+//! Time-sequence graphs. Turns a capture into an xplot data file in the format tcptrace
+//! writes, showing one direction of one connection: the segments sent as vertical bars
+//! from their first to their last sequence number, retransmissions in red, the
+//! cumulative ACK line in green and the edge of the advertised window in yellow, all
+//! against time. Open the result with `xplot` or `jPlot`.
+
+use std::io;
+use std::io::prelude::*;
+use std::fs::File;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::Path;
+
+use crate::addrs::LocalAddrs;
+use crate::sanitize;
+use crate::tcp;
+
+/// Graph the data `local` sent to `remote`, as recorded in the capture at `pcap`, into `out`.
+/// Sequence numbers are relative to the first one seen and times to the first packet of the
+/// connection.
+pub fn time_sequence_graph(
+   pcap: &Path,
+   local: SocketAddrV4,
+   remote: SocketAddrV4,
+   out: &Path,
+) -> io::Result<()> {
+   let mut data = Vec::new();
+   File::open(pcap)?.read_to_end(&mut data)?;
+   let mut out = io::BufWriter::new(File::create(out)?);
+
+   writeln!(out, "double double")?;
+   writeln!(out, "title\n{} ==> {}", local, remote)?;
+   writeln!(out, "xlabel\ntime\nylabel\nsequence number")?;
+
+   let mut base: Option<(f64, u32)> = None;
+   let mut highest: Option<u32> = None;
+   let mut last_ack: Option<(f64, u32, u32)> = None;
+   // window scale shifts offered in each direction's SYN; both must offer one to use them
+   let mut local_wscale: Option<u8> = None;
+   let mut remote_wscale: Option<u8> = None;
+   for (ts, packet) in packets(&data)? {
+      // the capture has our own segments too, so no source counts as spoofed
+      let layout = match sanitize::check(packet, &LocalAddrs::default()) {
+         Ok(layout) => layout,
+         Err(_) => continue,
+      };
+      let tcph = match etherparse::TcpHeaderSlice::from_slice(&packet[layout.tcp..layout.end]) {
+         Ok(tcph) => tcph,
+         Err(_) => continue,
+      };
+      let src = SocketAddrV4::new(Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]), tcph.source_port());
+      let dst = SocketAddrV4::new(Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]), tcph.destination_port());
+      let outgoing = if src == local && dst == remote {
+         true
+      } else if src == remote && dst == local {
+         false
+      } else {
+         continue;
+      };
+      if tcph.syn() {
+         let shift = tcp::syn_wscale(tcph.options());
+         if outgoing {
+            local_wscale = shift;
+         } else {
+            remote_wscale = shift;
+         }
+      }
+
+      if outgoing {
+         let (t0, isn) = *base.get_or_insert((ts, tcph.sequence_number()));
+         let t = ts - t0;
+         let len = (layout.end - layout.data) as u32 + tcph.syn() as u32 + tcph.fin() as u32;
+         if len == 0 {
+            continue;
+         }
+         let seq = tcph.sequence_number().wrapping_sub(isn);
+         let end = seq.wrapping_add(len);
+         let retransmit = highest.map_or(false, |h| seq < h);
+         if retransmit {
+            writeln!(out, "red")?;
+         } else {
+            writeln!(out, "white")?;
+            highest = Some(end);
+         }
+         writeln!(out, "line {:.6} {} {:.6} {}", t, seq, t, end)?;
+         writeln!(out, "darrow {:.6} {}", t, seq)?;
+         writeln!(out, "uarrow {:.6} {}", t, end)?;
+         if retransmit {
+            writeln!(out, "atext {:.6} {}\nR", t, end)?;
+         }
+      } else if tcph.ack() {
+         // the peer's ACKs only make sense once we know where our sequence space starts
+         let (t0, isn) = match base {
+            Some(base) => base,
+            None => continue,
+         };
+         let t = ts - t0;
+         let ack = tcph.acknowledgment_number().wrapping_sub(isn);
+         let shift = match (local_wscale, remote_wscale) {
+            (Some(_), Some(shift)) if !tcph.syn() => shift,
+            _ => 0,
+         };
+         let edge = ack.wrapping_add((tcph.window_size() as u32) << shift);
+         if let Some((pt, pack, pedge)) = last_ack {
+            // step lines: each value holds until the next ACK changes it
+            writeln!(out, "green\nline {:.6} {} {:.6} {}", pt, pack, t, pack)?;
+            writeln!(out, "line {:.6} {} {:.6} {}", t, pack, t, ack)?;
+            writeln!(out, "yellow\nline {:.6} {} {:.6} {}", pt, pedge, t, pedge)?;
+            writeln!(out, "line {:.6} {} {:.6} {}", t, pedge, t, edge)?;
+         }
+         writeln!(out, "green\ndot {:.6} {}", t, ack)?;
+         last_ack = Some((t, ack, edge));
+      }
+   }
+   writeln!(out, "go")?;
+   out.flush()
+}
+
+/// The records of a pcap file, with their timestamps in seconds.
+fn packets(data: &[u8]) -> io::Result<Vec<(f64, &[u8])>> {
+   let bad = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("not a pcap file: {}", what));
+   if data.len() < 24 {
+      return Err(bad("truncated header"));
+   }
+   let le = match data[..4] {
+      [0xd4, 0xc3, 0xb2, 0xa1] => true,
+      [0xa1, 0xb2, 0xc3, 0xd4] => false,
+      _ => return Err(bad("bad magic")),
+   };
+   let word = |b: &[u8]| {
+      let b = [b[0], b[1], b[2], b[3]];
+      if le { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) }
+   };
+
+   let mut records = Vec::new();
+   let mut rest = &data[24..];
+   while rest.len() >= 16 {
+      let secs = word(&rest[0..4]);
+      let micros = word(&rest[4..8]);
+      let len = word(&rest[8..12]) as usize;
+      if rest.len() < 16 + len {
+         // cut off mid-packet, as happens when the capture is still being written
+         break;
+      }
+      records.push((secs as f64 + micros as f64 / 1e6, &rest[16..16 + len]));
+      rest = &rest[16 + len..];
+   }
+   Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+   use super::*;
+
+   const LOCAL: ([u8; 4], u16) = ([10, 0, 0, 1], 50000);
+   const REMOTE: ([u8; 4], u16) = ([10, 0, 0, 2], 9000);
+
+   const SYN: u8 = 0x02;
+   const ACK: u8 = 0x10;
+
+   /// A segment from `src` to the other end, or to `REMOTE` from a third address, with
+   /// `len` bytes of data and the window scale option `wscale`, if any.
+   fn segment(src: ([u8; 4], u16), seq: u32, ack: u32, flags: u8, window: u16, wscale: Option<u8>, len: usize) -> Vec<u8> {
+      let dst = if src == REMOTE { LOCAL } else { REMOTE };
+      let options = if wscale.is_some() { 4 } else { 0 };
+      let total = 40 + options + len;
+      let mut p = vec![0u8; total];
+      p[0] = 0x45;
+      p[2..4].copy_from_slice(&(total as u16).to_be_bytes());
+      p[8] = 64;
+      p[9] = 0x06;
+      p[12..16].copy_from_slice(&src.0);
+      p[16..20].copy_from_slice(&dst.0);
+      p[20..22].copy_from_slice(&src.1.to_be_bytes());
+      p[22..24].copy_from_slice(&dst.1.to_be_bytes());
+      p[24..28].copy_from_slice(&seq.to_be_bytes());
+      p[28..32].copy_from_slice(&ack.to_be_bytes());
+      p[32] = (((20 + options) / 4) << 4) as u8;
+      p[33] = flags;
+      p[34..36].copy_from_slice(&window.to_be_bytes());
+      if let Some(shift) = wscale {
+         p[40..44].copy_from_slice(&[1, 3, 3, shift]);
+      }
+      p
+   }
+
+   /// A little-endian pcap file of raw IP packets, each at its time in microseconds.
+   fn pcap(packets: &[(u32, Vec<u8>)]) -> Vec<u8> {
+      let mut file = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+      file.extend_from_slice(&[0; 8]);
+      file.extend_from_slice(&65535u32.to_le_bytes());
+      file.extend_from_slice(&101u32.to_le_bytes());
+      for (micros, p) in packets {
+         file.extend_from_slice(&(micros / 1_000_000).to_le_bytes());
+         file.extend_from_slice(&(micros % 1_000_000).to_le_bytes());
+         file.extend_from_slice(&(p.len() as u32).to_le_bytes());
+         file.extend_from_slice(&(p.len() as u32).to_le_bytes());
+         file.extend_from_slice(p);
+      }
+      file
+   }
+
+   #[test]
+   fn graph_of_a_small_exchange() {
+      let other = ([10, 0, 0, 3], 50001);
+      let capture = pcap(&[
+         (1_000_000, segment(LOCAL, 1000, 0, SYN, 65535, Some(2), 0)),
+         (1_100_000, segment(REMOTE, 5000, 1001, SYN | ACK, 1000, Some(3), 0)),
+         (1_200_000, segment(LOCAL, 1001, 5001, ACK, 65535, None, 0)),
+         (1_300_000, segment(LOCAL, 1001, 5001, ACK, 65535, None, 100)),
+         // another connection's segment is left out of the graph
+         (1_350_000, segment(other, 1001, 5001, ACK, 65535, None, 100)),
+         (1_400_000, segment(LOCAL, 1101, 5001, ACK, 65535, None, 100)),
+         (1_500_000, segment(REMOTE, 5001, 1101, ACK, 500, None, 0)),
+         (1_600_000, segment(LOCAL, 1101, 5001, ACK, 65535, None, 100)),
+         (1_700_000, segment(REMOTE, 5001, 1201, ACK, 500, None, 0)),
+      ]);
+      let dir = std::env::temp_dir();
+      let input = dir.join(format!("tsg-test-{}.pcap", std::process::id()));
+      let output = dir.join(format!("tsg-test-{}.xpl", std::process::id()));
+      std::fs::write(&input, capture).unwrap();
+      let local = SocketAddrV4::new(LOCAL.0.into(), LOCAL.1);
+      let remote = SocketAddrV4::new(REMOTE.0.into(), REMOTE.1);
+      time_sequence_graph(&input, local, remote, &output).unwrap();
+      let graph = std::fs::read_to_string(&output).unwrap();
+      let _ = std::fs::remove_file(&input);
+      let _ = std::fs::remove_file(&output);
+
+      assert!(graph.starts_with("double double\ntitle\n10.0.0.1:50000 ==> 10.0.0.2:9000\n"));
+      assert!(graph.ends_with("go\n"));
+      // the SYN and both data segments, with sequence numbers from the ISN, and the second
+      // one's retransmission in red; the bare ACK isn't drawn
+      assert!(graph.contains("white\nline 0.000000 0 0.000000 1\n"));
+      assert!(graph.contains("white\nline 0.300000 1 0.300000 101\n"));
+      assert!(graph.contains("white\nline 0.400000 101 0.400000 201\n"));
+      assert!(graph.contains("red\nline 0.600000 101 0.600000 201\n"));
+      assert!(graph.contains("atext 0.600000 201\nR\n"));
+      assert_eq!(graph.matches("line 0.350000").count(), 0);
+      assert_eq!(graph.matches("white\n").count(), 3);
+      assert_eq!(graph.matches("red\n").count(), 1);
+      // the ACK line, and the window's edge: unscaled in the SYN-ACK, scaled by the
+      // remote's shift of 3 after it
+      assert!(graph.contains("green\ndot 0.100000 1\n"));
+      assert!(graph.contains("green\ndot 0.500000 101\n"));
+      assert!(graph.contains("yellow\nline 0.100000 1001 0.500000 1001\nline 0.500000 1001 0.500000 4101\n"));
+      assert!(graph.contains("yellow\nline 0.500000 4101 0.700000 4101\nline 0.700000 4101 0.700000 4201\n"));
+   }
+}